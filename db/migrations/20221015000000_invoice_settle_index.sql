@@ -0,0 +1,4 @@
+CREATE TABLE invoicesettleindex (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    settle_index UNSIGNED BIG INT NOT NULL
+);
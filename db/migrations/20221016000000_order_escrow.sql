@@ -0,0 +1,2 @@
+ALTER TABLE orders ADD COLUMN escrow BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE orders ADD COLUMN invoice_preimage VARCHAR NOT NULL DEFAULT '';
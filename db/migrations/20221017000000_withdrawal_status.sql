@@ -0,0 +1,2 @@
+ALTER TABLE withdrawals ADD COLUMN status VARCHAR NOT NULL DEFAULT 'succeeded';
+ALTER TABLE withdrawals ADD COLUMN failure_reason VARCHAR NOT NULL DEFAULT '';
@@ -0,0 +1,7 @@
+ALTER TABLE adminsettings ADD COLUMN max_withdrawal_fee_sat UNSIGNED BIG INT NOT NULL DEFAULT 1000;
+ALTER TABLE adminsettings ADD COLUMN max_withdrawal_fee_basis_points INTEGER NOT NULL DEFAULT 100;
+ALTER TABLE adminsettings ADD COLUMN withdrawal_fee_paid_by_user BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE withdrawals ADD COLUMN fee_limit_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE withdrawals ADD COLUMN fee_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE withdrawals ADD COLUMN fee_paid_by_user BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1 @@
+ALTER TABLE orders ADD COLUMN paid_from_balance BOOLEAN NOT NULL DEFAULT false;
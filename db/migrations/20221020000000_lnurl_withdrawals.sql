@@ -0,0 +1,9 @@
+CREATE TABLE lnurlwithdrawals (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    public_id VARCHAR NOT NULL,
+    user_id INTEGER NOT NULL,
+    k1 VARCHAR NOT NULL UNIQUE,
+    used BOOLEAN NOT NULL,
+    withdrawal_public_id VARCHAR NOT NULL,
+    created_time_ms UNSIGNED BIG INT NOT NULL
+);
@@ -0,0 +1,9 @@
+ALTER TABLE adminsettings ADD COLUMN order_invoice_expiry_s INTEGER NOT NULL DEFAULT 86400;
+ALTER TABLE adminsettings ADD COLUMN user_account_invoice_expiry_s INTEGER NOT NULL DEFAULT 600;
+
+-- Existing invoices were created with the expiry intervals that were hard-coded before.
+ALTER TABLE orders ADD COLUMN invoice_expiry_time_ms UNSIGNED BIG INT NOT NULL DEFAULT 0;
+UPDATE orders SET invoice_expiry_time_ms = created_time_ms + 86400000;
+
+ALTER TABLE useraccounts ADD COLUMN invoice_expiry_time_ms UNSIGNED BIG INT NOT NULL DEFAULT 0;
+UPDATE useraccounts SET invoice_expiry_time_ms = created_time_ms + 600000;
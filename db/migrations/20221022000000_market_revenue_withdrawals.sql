@@ -0,0 +1 @@
+ALTER TABLE withdrawals ADD COLUMN from_market_revenue BOOLEAN NOT NULL DEFAULT false;
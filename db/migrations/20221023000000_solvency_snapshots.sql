@@ -0,0 +1,9 @@
+CREATE TABLE solvencysnapshots (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    channel_balance_sat UNSIGNED BIG INT NOT NULL,
+    unsettled_balance_sat UNSIGNED BIG INT NOT NULL,
+    wallet_balance_sat UNSIGNED BIG INT NOT NULL,
+    total_market_liabilities_sat BIG INT NOT NULL,
+    pending_escrow_sat UNSIGNED BIG INT NOT NULL,
+    created_time_ms UNSIGNED BIG INT NOT NULL
+);
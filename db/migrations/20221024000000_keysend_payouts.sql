@@ -0,0 +1,2 @@
+ALTER TABLE usersettings ADD COLUMN payout_node_pubkey VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE withdrawals ADD COLUMN keysend_pubkey VARCHAR NOT NULL DEFAULT '';
@@ -0,0 +1,3 @@
+ALTER TABLE usersettings ADD COLUMN auto_payout_enabled BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE usersettings ADD COLUMN auto_payout_threshold_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE usersettings ADD COLUMN auto_payout_target VARCHAR NOT NULL DEFAULT '';
@@ -0,0 +1,5 @@
+ALTER TABLE orders ADD COLUMN onchain_address VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE orders ADD COLUMN onchain_pending_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN onchain_received_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN onchain_buyer_credit_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN onchain_expired BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,10 @@
+CREATE TABLE exchangerates (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    currency VARCHAR NOT NULL,
+    fiat_cents_per_btc UNSIGNED BIG INT NOT NULL,
+    updated_time_ms UNSIGNED BIG INT NOT NULL
+);
+ALTER TABLE listings ADD COLUMN fiat_price_cents UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN fiat_currency VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE orders ADD COLUMN fiat_cents_per_btc UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN listing_fiat_price_cents UNSIGNED BIG INT NOT NULL DEFAULT 0;
@@ -0,0 +1,14 @@
+ALTER TABLE orders ADD COLUMN disputed BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE orders ADD COLUMN dispute_opened_time_ms UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN dispute_resolved BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE orders ADD COLUMN dispute_resolved_time_ms UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN dispute_buyer_refund_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN dispute_seller_credit_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+CREATE TABLE disputemessages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    public_id VARCHAR NOT NULL,
+    order_id INTEGER NOT NULL,
+    author_user_id INTEGER NOT NULL,
+    text VARCHAR NOT NULL,
+    created_time_ms UNSIGNED BIG INT NOT NULL
+);
@@ -0,0 +1,11 @@
+ALTER TABLE adminsettings ADD COLUMN order_auto_finalize_days INTEGER NOT NULL DEFAULT 14;
+ALTER TABLE adminsettings ADD COLUMN early_finalization_min_amount_sold_sat UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE adminsettings ADD COLUMN early_finalization_min_rating INTEGER NOT NULL DEFAULT 4;
+
+ALTER TABLE orders ADD COLUMN shipped_time_ms UNSIGNED BIG INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN finalized BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE orders ADD COLUMN finalized_time_ms UNSIGNED BIG INT NOT NULL DEFAULT 0;
+
+-- Sellers of orders that were already shipped have already been credited.
+UPDATE orders SET shipped_time_ms = payment_time_ms WHERE shipped;
+UPDATE orders SET finalized = true, finalized_time_ms = payment_time_ms WHERE shipped AND NOT disputed;
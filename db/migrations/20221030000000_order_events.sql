@@ -0,0 +1,28 @@
+CREATE TABLE orderevents (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id INTEGER NOT NULL,
+    status VARCHAR NOT NULL,
+    actor_user_id INTEGER NOT NULL,
+    created_time_ms UNSIGNED BIG INT NOT NULL
+);
+
+-- Rebuild the history of existing orders from the times that were recorded.
+-- Cancellation times were not recorded, so the payment time is used instead.
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'unpaid', buyer_user_id, created_time_ms FROM orders;
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'paid', 0, payment_time_ms FROM orders WHERE paid;
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'expired', 0, invoice_expiry_time_ms FROM orders WHERE onchain_expired;
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'canceled_by_seller', seller_user_id, payment_time_ms FROM orders WHERE canceled_by_seller;
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'canceled_by_buyer', buyer_user_id, payment_time_ms FROM orders WHERE canceled_by_buyer;
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'shipped', seller_user_id, shipped_time_ms FROM orders WHERE shipped;
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'finalized', 0, finalized_time_ms FROM orders WHERE finalized;
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'disputed', buyer_user_id, dispute_opened_time_ms FROM orders WHERE disputed;
+INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms)
+SELECT id, 'dispute_resolved', 0, dispute_resolved_time_ms FROM orders WHERE dispute_resolved;
@@ -0,0 +1,10 @@
+CREATE TABLE ordermessages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    public_id VARCHAR NOT NULL,
+    order_id INTEGER NOT NULL,
+    author_user_id INTEGER NOT NULL,
+    text VARCHAR NOT NULL,
+    read_by_buyer BOOLEAN NOT NULL,
+    read_by_seller BOOLEAN NOT NULL,
+    created_time_ms UNSIGNED BIG INT NOT NULL
+);
@@ -0,0 +1,13 @@
+ALTER TABLE listings ADD COLUMN quantity INTEGER NOT NULL DEFAULT 0;
+
+-- Listings created before stock was tracked had no limit on the units that
+-- could be ordered. So that they do not sell out on upgrade, they get a
+-- default stock of 100 remaining units on top of the units already sold.
+-- Sellers can set the real stock from the listing page.
+WITH legacy_stock AS (SELECT 100 AS default_remaining_quantity)
+UPDATE listings SET quantity = (SELECT default_remaining_quantity FROM legacy_stock) + (
+ SELECT COALESCE(SUM(orders.quantity), 0)
+ FROM orders
+ WHERE orders.listing_id = listings.id
+ AND NOT (orders.canceled_by_seller OR orders.canceled_by_buyer OR orders.onchain_expired)
+);
@@ -0,0 +1,32 @@
+CREATE TABLE cartitems (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    public_id VARCHAR NOT NULL,
+    user_id INTEGER NOT NULL,
+    listing_id INTEGER NOT NULL,
+    shipping_option_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL,
+    created_time_ms UNSIGNED BIG INT NOT NULL,
+    UNIQUE(user_id, listing_id)
+);
+
+CREATE TABLE orderitems (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id INTEGER NOT NULL,
+    listing_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL,
+    price_sat UNSIGNED BIG INT NOT NULL,
+    fee_rate_basis_points INTEGER NOT NULL,
+    market_fee_sat UNSIGNED BIG INT NOT NULL
+);
+
+-- Orders created before line items had a single listing. Their shipping cost
+-- is included in the price of the line.
+INSERT INTO orderitems (order_id, listing_id, quantity, price_sat, fee_rate_basis_points, market_fee_sat)
+SELECT
+ orders.id,
+ orders.listing_id,
+ orders.quantity,
+ orders.amount_owed_sat / orders.quantity,
+ COALESCE((SELECT listings.fee_rate_basis_points FROM listings WHERE listings.id = orders.listing_id), 0),
+ orders.amount_owed_sat - orders.seller_credit_sat
+FROM orders;
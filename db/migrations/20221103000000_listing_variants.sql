@@ -0,0 +1,28 @@
+CREATE TABLE listingvariants (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    public_id VARCHAR NOT NULL,
+    listing_id INTEGER NOT NULL,
+    title VARCHAR NOT NULL,
+    price_sat UNSIGNED BIG INT NOT NULL,
+    quantity INTEGER NOT NULL
+);
+
+ALTER TABLE orderitems ADD COLUMN variant_id INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE orderitems ADD COLUMN variant_title VARCHAR NOT NULL DEFAULT '';
+
+-- A cart can hold more than one variant of the same listing.
+CREATE TABLE cartitems_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    public_id VARCHAR NOT NULL,
+    user_id INTEGER NOT NULL,
+    listing_id INTEGER NOT NULL,
+    variant_id INTEGER NOT NULL,
+    shipping_option_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL,
+    created_time_ms UNSIGNED BIG INT NOT NULL,
+    UNIQUE(user_id, listing_id, variant_id)
+);
+INSERT INTO cartitems_new (id, public_id, user_id, listing_id, variant_id, shipping_option_id, quantity, created_time_ms)
+SELECT id, public_id, user_id, listing_id, 0, shipping_option_id, quantity, created_time_ms FROM cartitems;
+DROP TABLE cartitems;
+ALTER TABLE cartitems_new RENAME TO cartitems;
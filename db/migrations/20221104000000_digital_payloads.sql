@@ -0,0 +1,13 @@
+ALTER TABLE listings ADD COLUMN digital BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE digitalpayloads (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    public_id VARCHAR NOT NULL,
+    listing_id INTEGER NOT NULL,
+    payload_name VARCHAR NOT NULL,
+    payload_data BLOB NOT NULL,
+    order_id INTEGER NOT NULL,
+    encrypted_payload TEXT NOT NULL,
+    created_time_ms UNSIGNED BIG INT NOT NULL,
+    delivered_time_ms UNSIGNED BIG INT NOT NULL
+);
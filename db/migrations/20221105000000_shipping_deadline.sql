@@ -0,0 +1,5 @@
+ALTER TABLE adminsettings ADD COLUMN order_shipping_deadline_days INTEGER NOT NULL DEFAULT 30;
+
+-- Set on orders that were canceled because the seller did not ship them in
+-- time. Each one counts as a strike against the seller.
+ALTER TABLE orders ADD COLUMN shipping_expired BOOLEAN NOT NULL DEFAULT false;
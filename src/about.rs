@@ -1,7 +1,6 @@
 use crate::base::BaseContext;
-use crate::config::Config;
 use crate::db::Db;
-use crate::lightning;
+use crate::lightning::LightningBackend;
 use crate::models::AdminSettings;
 use rocket::fairing::AdHoc;
 use rocket::request::FlashMessage;
@@ -11,6 +10,7 @@ use rocket_auth::AdminUser;
 use rocket_auth::User;
 use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
+use std::sync::Arc;
 
 #[derive(Debug, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -27,7 +27,7 @@ impl Context {
         flash: Option<(String, String)>,
         user: Option<User>,
         admin_user: Option<AdminUser>,
-        config: &Config,
+        lightning_backend: &dyn LightningBackend,
     ) -> Result<Context, String> {
         let base_context = BaseContext::raw(&mut db, user.clone(), admin_user.clone())
             .await
@@ -35,7 +35,7 @@ impl Context {
         let admin_settings = AdminSettings::single(&mut db)
             .await
             .map_err(|_| "failed to update market name.")?;
-        let lightning_node_pubkey = get_lightning_node_pubkey(config)
+        let lightning_node_pubkey = get_lightning_node_pubkey(lightning_backend)
             .await
             .unwrap_or_else(|_| "".to_string());
         Ok(Context {
@@ -47,21 +47,10 @@ impl Context {
     }
 }
 
-async fn get_lightning_node_pubkey(config: &Config) -> Result<String, String> {
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
-    )
-    .await
-    .expect("failed to get lightning client");
-    let get_info_resp = lightning_client
-        // All calls require at least empty parameter
-        .get_info(tonic_openssl_lnd::lnrpc::GetInfoRequest {})
-        .await
-        .expect("failed to get lightning node info")
-        .into_inner();
+async fn get_lightning_node_pubkey(
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
+    let get_info_resp = lightning_backend.get_info().await?;
     Ok(get_info_resp.identity_pubkey)
 }
 
@@ -71,12 +60,18 @@ async fn index(
     db: Connection<Db>,
     user: Option<User>,
     admin_user: Option<AdminUser>,
-    config: &State<Config>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
 ) -> Result<Template, String> {
     let flash = flash.map(FlashMessage::into_inner);
-    let context = Context::raw(db, flash, user, admin_user, config.inner())
-        .await
-        .map_err(|_| "failed to get template context.")?;
+    let context = Context::raw(
+        db,
+        flash,
+        user,
+        admin_user,
+        lightning_backend.inner().as_ref(),
+    )
+    .await
+    .map_err(|_| "failed to get template context.")?;
     Ok(Template::render("about", context))
 }
 
@@ -1,11 +1,12 @@
 use crate::base::BaseContext;
-use crate::config::Config;
 use crate::db::Db;
 use crate::lightning;
-use crate::models::UserAccount;
+use crate::lightning::LightningBackend;
+use crate::models::{AdminSettings, UserAccount};
 use crate::util;
 use rocket::fairing::AdHoc;
 use rocket::request::FlashMessage;
+use rocket::response::Flash;
 use rocket::response::Redirect;
 use rocket::serde::Serialize;
 use rocket::State;
@@ -14,6 +15,7 @@ use rocket_auth::User;
 use rocket_auth::Users;
 use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
+use std::sync::Arc;
 
 #[derive(Debug, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -26,6 +28,8 @@ struct Context {
     admin_user: Option<AdminUser>,
     qr_svg_base64: String,
     lightning_node_pubkey: String,
+    invoice_expired: bool,
+    invoice_expiry_minutes_remaining: u64,
 }
 
 impl Context {
@@ -35,7 +39,7 @@ impl Context {
         id: &str,
         user: User,
         admin_user: Option<AdminUser>,
-        config: &Config,
+        lightning_backend: &dyn LightningBackend,
         users: &Users,
     ) -> Result<Context, String> {
         let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
@@ -47,9 +51,14 @@ impl Context {
         let maybe_account_user = users.get_by_id(user_account.user_id).await.ok();
         let qr_svg_bytes = util::generate_qr(&user_account.invoice_payment_request);
         let qr_svg_base64 = util::to_base64(&qr_svg_bytes);
-        let lightning_node_pubkey = get_lightning_node_pubkey(config)
+        let lightning_node_pubkey = get_lightning_node_pubkey(lightning_backend)
             .await
             .unwrap_or_else(|_| "".to_string());
+        let now = util::current_time_millis();
+        let invoice_expired = now >= user_account.invoice_expiry_time_ms;
+        let invoice_expiry_minutes_remaining = util::millis_to_minutes_rounded_up(
+            user_account.invoice_expiry_time_ms.saturating_sub(now),
+        );
         Ok(Context {
             base_context,
             flash,
@@ -59,26 +68,90 @@ impl Context {
             admin_user,
             qr_svg_base64,
             lightning_node_pubkey,
+            invoice_expired,
+            invoice_expiry_minutes_remaining,
         })
     }
 }
 
-async fn get_lightning_node_pubkey(config: &Config) -> Result<String, String> {
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
+async fn get_lightning_node_pubkey(
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
+    let get_info_resp = lightning_backend.get_info().await?;
+    Ok(get_info_resp.identity_pubkey)
+}
+
+#[put("/<id>/regenerate_invoice")]
+async fn regenerate_invoice(
+    id: &str,
+    mut db: Connection<Db>,
+    user: User,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match regenerate_user_account_invoice(id, &mut db, user, lightning_backend.inner().as_ref())
+        .await
+    {
+        Ok(_) => Ok(Flash::success(
+            Redirect::to(format!("/{}/{}", "account_activation", id)),
+            "New invoice created.",
+        )),
+        Err(e) => {
+            error_!("DB update({}) error: {}", id, e);
+            Err(Flash::error(
+                Redirect::to(format!("/{}/{}", "account_activation", id)),
+                e,
+            ))
+        }
+    }
+}
+
+async fn regenerate_user_account_invoice(
+    user_account_id: &str,
+    db: &mut Connection<Db>,
+    user: User,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let user_account = UserAccount::single_by_public_id(db, user_account_id)
+        .await
+        .map_err(|_| "failed to get user account.")?;
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+
+    if user_account.user_id != user.id() {
+        return Err("User is not the account owner.".to_string());
+    };
+    if user_account.paid {
+        return Err("account is already paid.".to_string());
+    };
+
+    lightning::cancel_expired_invoice(
+        lightning_backend,
+        &user_account.invoice_hash,
+        user_account.invoice_expiry_time_ms,
     )
-    .await
-    .expect("failed to get lightning client");
-    let get_info_resp = lightning_client
-        // All calls require at least empty parameter
-        .get_info(tonic_openssl_lnd::lnrpc::GetInfoRequest {})
+    .await?;
+
+    let invoice_expiry_s = admin_settings.user_account_invoice_expiry_s;
+    let invoice = lightning_backend
+        .create_invoice(user_account.amount_owed_sat, invoice_expiry_s)
         .await
-        .expect("failed to get lightning node info")
-        .into_inner();
-    Ok(get_info_resp.identity_pubkey)
+        .map_err(|_| "failed to get new invoice.")?;
+    let is_updated = UserAccount::update_invoice(
+        db,
+        user_account.id.unwrap(),
+        &util::to_hex(&invoice.r_hash),
+        &invoice.payment_request,
+        now + (invoice_expiry_s as u64) * 1000,
+    )
+    .await
+    .map_err(|_| "failed to update user account invoice.")?;
+    if !is_updated {
+        return Err("account is already paid.".to_string());
+    }
+
+    Ok(())
 }
 
 #[get("/<id>")]
@@ -88,15 +161,23 @@ async fn index(
     db: Connection<Db>,
     user: Option<User>,
     admin_user: Option<AdminUser>,
-    config: &State<Config>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
     users: &State<Users>,
 ) -> Result<Template, Redirect> {
     let flash = flash.map(FlashMessage::into_inner);
     match user {
         Some(user) => {
-            let context = Context::raw(db, flash, id, user, admin_user, config, users)
-                .await
-                .map_err(|_| Redirect::to(uri!("/login")))?;
+            let context = Context::raw(
+                db,
+                flash,
+                id,
+                user,
+                admin_user,
+                lightning_backend.inner().as_ref(),
+                users,
+            )
+            .await
+            .map_err(|_| Redirect::to(uri!("/login")))?;
             Ok(Template::render("accountactivation", context))
         }
         None => Err(Redirect::to(uri!("/login"))),
@@ -105,6 +186,6 @@ async fn index(
 
 pub fn account_activation_stage() -> AdHoc {
     AdHoc::on_ignite("Account Activation Stage", |rocket| async {
-        rocket.mount("/account_activation", routes![index])
+        rocket.mount("/account_activation", routes![index, regenerate_invoice])
     })
 }
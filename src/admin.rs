@@ -1,6 +1,6 @@
 use crate::base::BaseContext;
 use crate::db::Db;
-use crate::models::UserAccount;
+use crate::models::{SolvencySnapshot, UserAccount};
 use rocket::fairing::AdHoc;
 use rocket::request::FlashMessage;
 use rocket::serde::Serialize;
@@ -15,6 +15,7 @@ struct Context {
     base_context: BaseContext,
     flash: Option<(String, String)>,
     num_users: u64,
+    maybe_latest_solvency_snapshot: Option<SolvencySnapshot>,
     user: Option<User>,
     admin_user: Option<AdminUser>,
 }
@@ -32,10 +33,14 @@ impl Context {
         let num_users = UserAccount::number_of_users(&mut db)
             .await
             .map_err(|_| "failed to get number of users.")?;
+        let maybe_latest_solvency_snapshot = SolvencySnapshot::latest(&mut db)
+            .await
+            .map_err(|_| "failed to get latest solvency snapshot.")?;
         Ok(Context {
             base_context,
             flash,
             num_users,
+            maybe_latest_solvency_snapshot,
             user,
             admin_user,
         })
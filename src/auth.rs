@@ -1,7 +1,6 @@
 use crate::base::BaseContext;
-use crate::config::Config;
 use crate::db::Db;
-use crate::lightning;
+use crate::lightning::LightningBackend;
 use crate::models::{AdminSettings, UserAccount};
 use crate::util;
 use rocket::fairing::AdHoc;
@@ -15,6 +14,7 @@ use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
 use serde_json::json;
 use std::result::Result;
+use std::sync::Arc;
 
 #[catch(401)]
 fn not_authorized() -> Redirect {
@@ -54,7 +54,7 @@ async fn post_signup(
     auth: Auth<'_>,
     form: Form<Signup>,
     mut db: Connection<Db>,
-    config: &State<Config>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
     users: &State<Users>,
 ) -> Result<Redirect, String> {
     let admin_settings = AdminSettings::single(&mut db)
@@ -83,7 +83,7 @@ async fn post_signup(
         .get_by_email(&signup.email.to_lowercase())
         .await
         .unwrap();
-    create_user_account(&mut db, user, config.inner().clone())
+    create_user_account(&mut db, user, lightning_backend.inner().as_ref())
         .await
         .map_err(|_| "failed to create new user account.")?;
 
@@ -105,7 +105,7 @@ async fn logout(auth: Auth<'_>, mut db: Connection<Db>) -> Result<Template, Stri
 async fn create_user_account(
     db: &mut Connection<Db>,
     user: User,
-    config: Config,
+    lightning_backend: &dyn LightningBackend,
 ) -> Result<(), String> {
     let now = util::current_time_millis();
 
@@ -114,22 +114,11 @@ async fn create_user_account(
         .map_err(|_| "failed to update market name.")?;
     let amount_owed_sat: u64 = admin_settings.user_bond_price_sat;
 
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
-    )
-    .await
-    .expect("failed to get lightning client");
-    let invoice = lightning_client
-        .add_invoice(tonic_openssl_lnd::lnrpc::Invoice {
-            value_msat: (amount_owed_sat as i64) * 1000,
-            ..Default::default()
-        })
+    let invoice_expiry_s = admin_settings.user_account_invoice_expiry_s;
+    let invoice = lightning_backend
+        .create_invoice(amount_owed_sat, invoice_expiry_s)
         .await
-        .expect("failed to get new invoice")
-        .into_inner();
+        .map_err(|_| "failed to get new invoice.")?;
 
     let user_account = UserAccount {
         id: None,
@@ -142,6 +131,7 @@ async fn create_user_account(
         invoice_payment_request: invoice.payment_request,
         created_time_ms: now,
         payment_time_ms: 0,
+        invoice_expiry_time_ms: now + (invoice_expiry_s as u64) * 1000,
     };
 
     UserAccount::insert(user_account, db)
@@ -0,0 +1,73 @@
+use crate::lightning::LightningBackend;
+use crate::models::UserSettings;
+use crate::payout_resolver::PayoutResolver;
+use crate::withdraw;
+use sqlx::pool::PoolConnection;
+use sqlx::Sqlite;
+use std::sync::Arc;
+
+/// Pays out the balance of every user with automatic payouts enabled whose
+/// balance has reached their threshold.
+pub async fn run_auto_payouts(
+    lightning_backend: Arc<dyn LightningBackend>,
+    payout_resolver: Arc<dyn PayoutResolver>,
+    mut conn: PoolConnection<Sqlite>,
+) -> Result<(), String> {
+    let user_ids = UserSettings::all_user_ids_with_auto_payout(&mut conn)
+        .await
+        .map_err(|_| "failed to get users with auto payout.")?;
+
+    for user_id in user_ids {
+        // A failed payout for one user should not block the others.
+        match run_auto_payout(&mut conn, &*lightning_backend, &*payout_resolver, user_id).await {
+            Ok(_) => (),
+            Err(e) => println!("Auto payout failed for user {}: {:?}", user_id, e),
+        }
+    }
+    Ok(())
+}
+
+async fn run_auto_payout(
+    conn: &mut PoolConnection<Sqlite>,
+    lightning_backend: &dyn LightningBackend,
+    payout_resolver: &dyn PayoutResolver,
+    user_id: i32,
+) -> Result<(), String> {
+    let user_settings = UserSettings::single(conn, user_id)
+        .await
+        .map_err(|_| "failed to get user settings.")?;
+    let amount_sat = withdraw::max_withdrawable_sat(conn, user_id).await?;
+    if amount_sat == 0 || amount_sat < user_settings.auto_payout_threshold_sat {
+        return Ok(());
+    }
+
+    let withdrawal_public_id = if user_settings.auto_payout_target.is_empty() {
+        withdraw::send_keysend_withdrawal(Some(amount_sat), conn, user_id, lightning_backend)
+            .await?
+    } else {
+        // A target that cannot be resolved is only logged, and retried on the
+        // next run. Recording it as a failed withdrawal would use up the
+        // daily withdrawal limit of the user.
+        let invoice_payment_request = payout_resolver
+            .resolve_invoice(
+                &user_settings.auto_payout_target,
+                amount_sat,
+                lightning_backend,
+            )
+            .await
+            .map_err(|e| format!("failed to resolve payout target: {}", e))?;
+        withdraw::send_withdrawal(
+            &invoice_payment_request,
+            None,
+            conn,
+            user_id,
+            lightning_backend,
+        )
+        .await?
+    };
+    println!(
+        "Auto payout of {} sats for user {}: {:?}",
+        amount_sat, user_id, withdrawal_public_id
+    );
+    Ok(())
+}
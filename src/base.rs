@@ -1,5 +1,7 @@
 use crate::db::Db;
-use crate::models::{AccountInfo, AdminInfo, AdminSettings};
+use crate::exchange_rate_update::MAX_EXCHANGE_RATE_AGE_MS;
+use crate::models::{AccountInfo, AdminInfo, AdminSettings, ExchangeRate};
+use crate::util;
 use rocket::serde::Serialize;
 use rocket_auth::AdminUser;
 use rocket_auth::User;
@@ -13,6 +15,7 @@ pub struct BaseContext {
     admin_user: Option<AdminUser>,
     admin_info: Option<AdminInfo>,
     admin_settings: Option<AdminSettings>,
+    exchange_rate: Option<ExchangeRate>,
 }
 
 impl BaseContext {
@@ -40,12 +43,19 @@ impl BaseContext {
         let admin_settings = AdminSettings::single(db)
             .await
             .map_err(|_| "failed to get admin settings.")?;
+        let exchange_rate = ExchangeRate::latest_updated_after(
+            db,
+            util::current_time_millis().saturating_sub(MAX_EXCHANGE_RATE_AGE_MS),
+        )
+        .await
+        .map_err(|_| "failed to get exchange rate.")?;
         Ok(BaseContext {
             user,
             account_info,
             admin_user,
             admin_info,
             admin_settings: Some(admin_settings),
+            exchange_rate,
         })
     }
 }
@@ -0,0 +1,540 @@
+use crate::base::BaseContext;
+use crate::config::Config;
+use crate::db::Db;
+use crate::digital_delivery;
+use crate::exchange_rate_update::MAX_EXCHANGE_RATE_AGE_MS;
+use crate::lightning::LightningBackend;
+use crate::models::{
+    AdminSettings, CartCheckoutInfo, CartItem, CartItemDisplay, CartItemInput, ExchangeRate,
+    Listing, ListingVariant, Order, OrderItem, RocketAuthUser, ShippingOption, UserSettings,
+};
+use crate::prepare_order;
+use crate::user_account::ActiveUser;
+use crate::util;
+use pgp::composed::{Deserializable, Message};
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket::State;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+use std::sync::Arc;
+
+const MAX_CART_ITEMS: u32 = 50;
+
+/// The items in the cart from one seller, which are checked out together as
+/// one order.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CartSellerGroup {
+    seller_user_id: i32,
+    maybe_seller_user: Option<RocketAuthUser>,
+    seller_pgp_key: String,
+    cart_item_displays: Vec<CartItemDisplay>,
+    items_amount_sat: u64,
+    shipping_option: ShippingOption,
+    total_amount_sat: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    cart_seller_groups: Vec<CartSellerGroup>,
+}
+
+impl Context {
+    pub async fn raw(
+        flash: Option<(String, String)>,
+        mut db: Connection<Db>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let maybe_exchange_rate = ExchangeRate::latest_updated_after(
+            &mut db,
+            util::current_time_millis().saturating_sub(MAX_EXCHANGE_RATE_AGE_MS),
+        )
+        .await
+        .map_err(|_| "failed to get exchange rate.")?;
+        let cart_item_displays =
+            cart_item_displays_for_user(&mut db, user.id(), maybe_exchange_rate.as_ref()).await?;
+        let mut cart_seller_groups: Vec<CartSellerGroup> = vec![];
+        for seller_cart_item_displays in group_by_seller(cart_item_displays) {
+            let seller_user_id = seller_cart_item_displays[0].listing.user_id;
+            let maybe_seller_user = RocketAuthUser::single(&mut db, seller_user_id).await.ok();
+            let seller_user_settings = UserSettings::single(&mut db, seller_user_id)
+                .await
+                .map_err(|_| "failed to get seller user settings.")?;
+            let items_amount_sat = seller_cart_item_displays
+                .iter()
+                .map(|d| (d.cart_item.quantity as u64) * d.unit_price_sat)
+                .sum::<u64>();
+            let shipping_option = combined_shipping_option(&seller_cart_item_displays);
+            cart_seller_groups.push(CartSellerGroup {
+                seller_user_id,
+                maybe_seller_user,
+                seller_pgp_key: seller_user_settings.pgp_key,
+                cart_item_displays: seller_cart_item_displays,
+                items_amount_sat,
+                total_amount_sat: items_amount_sat + shipping_option.price_sat,
+                shipping_option,
+            });
+        }
+        Ok(Context {
+            base_context,
+            flash,
+            cart_seller_groups,
+        })
+    }
+}
+
+/// Returns the items in the cart of a user, priced at the given exchange rate.
+/// Items whose listing, variant or shipping option no longer exists are
+/// skipped.
+async fn cart_item_displays_for_user(
+    db: &mut Connection<Db>,
+    user_id: i32,
+    maybe_exchange_rate: Option<&ExchangeRate>,
+) -> Result<Vec<CartItemDisplay>, String> {
+    let cart_items = CartItem::all_for_user(db, user_id)
+        .await
+        .map_err(|_| "failed to get cart items.")?;
+    let mut cart_item_displays = vec![];
+    for cart_item in cart_items {
+        let maybe_listing = Listing::single(db, cart_item.listing_id).await.ok();
+        let maybe_shipping_option = ShippingOption::single(db, cart_item.shipping_option_id)
+            .await
+            .ok();
+        let maybe_variant = if cart_item.variant_id == 0 {
+            None
+        } else {
+            match ListingVariant::single(db, cart_item.variant_id).await {
+                Ok(variant) => Some(variant),
+                Err(_) => continue,
+            }
+        };
+        if let (Some(listing), Some(shipping_option)) = (maybe_listing, maybe_shipping_option) {
+            let unit_price_sat =
+                unit_price_sat(&listing, maybe_variant.as_ref(), maybe_exchange_rate);
+            cart_item_displays.push(CartItemDisplay {
+                cart_item,
+                listing,
+                maybe_variant,
+                shipping_option,
+                unit_price_sat,
+            });
+        }
+    }
+    Ok(cart_item_displays)
+}
+
+/// Returns the price of one unit of a cart item, as shown in the cart. If no
+/// exchange rate is available for a fiat-pegged listing, the last sat price of
+/// the listing is shown, and checkout fails until the rate is updated.
+fn unit_price_sat(
+    listing: &Listing,
+    maybe_variant: Option<&ListingVariant>,
+    maybe_exchange_rate: Option<&ExchangeRate>,
+) -> u64 {
+    match maybe_variant {
+        Some(variant) => variant.price_sat,
+        None => prepare_order::listing_price_sat(listing, maybe_exchange_rate)
+            .unwrap_or(listing.price_sat),
+    }
+}
+
+/// Splits the cart items into one group for each seller.
+fn group_by_seller(cart_item_displays: Vec<CartItemDisplay>) -> Vec<Vec<CartItemDisplay>> {
+    let mut groups: Vec<Vec<CartItemDisplay>> = vec![];
+    for cart_item_display in cart_item_displays {
+        match groups
+            .iter_mut()
+            .find(|g| g[0].listing.user_id == cart_item_display.listing.user_id)
+        {
+            Some(group) => group.push(cart_item_display),
+            None => groups.push(vec![cart_item_display]),
+        }
+    }
+    groups
+}
+
+/// Items from the same seller are shipped together, so the order is charged
+/// once for the most expensive of the selected shipping options.
+fn combined_shipping_option(cart_item_displays: &[CartItemDisplay]) -> ShippingOption {
+    cart_item_displays
+        .iter()
+        .map(|d| d.shipping_option.clone())
+        .max_by_key(|shipping_option| shipping_option.price_sat)
+        .unwrap()
+}
+
+#[post("/add/<id>", data = "<cart_item_form>")]
+async fn add(
+    id: &str,
+    cart_item_form: Form<CartItemInput>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+) -> Flash<Redirect> {
+    let cart_item_input = cart_item_form.into_inner();
+
+    match add_cart_item(id, cart_item_input, &mut db, active_user.user).await {
+        Ok(_) => Flash::success(Redirect::to(uri!("/cart", index())), "Item added to cart."),
+        Err(e) => Flash::error(Redirect::to(format!("/{}/{}", "listing", id)), e),
+    }
+}
+
+async fn add_cart_item(
+    listing_id: &str,
+    cart_item_input: CartItemInput,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let listing = Listing::single_by_public_id(db, listing_id)
+        .await
+        .map_err(|_| "failed to get listing")?;
+    let shipping_option =
+        ShippingOption::single_by_public_id(db, &cart_item_input.shipping_option_id)
+            .await
+            .map_err(|_| "failed to get shipping option.")?;
+    let variants = ListingVariant::all_for_listing(db, listing.id.unwrap())
+        .await
+        .map_err(|_| "failed to get variants for listing.")?;
+    let maybe_variant = match cart_item_input.variant_id.as_deref() {
+        Some(variant_id) if !variant_id.is_empty() => Some(
+            ListingVariant::single_by_public_id(db, variant_id)
+                .await
+                .map_err(|_| "failed to get variant.")?,
+        ),
+        _ => None,
+    };
+    let variant_id = maybe_variant
+        .as_ref()
+        .map(|variant| variant.id.unwrap())
+        .unwrap_or(0);
+    let quantity = cart_item_input.quantity.unwrap_or(0);
+    let remaining_quantity = Listing::remaining_quantity(db, listing.id.unwrap())
+        .await
+        .map_err(|_| "failed to get remaining quantity of listing.")?;
+    // Other variants of the listing already in the cart share its stock.
+    let other_variants_quantity: u32 = CartItem::all_for_user(db, user.id())
+        .await
+        .map_err(|_| "failed to get cart items.")?
+        .iter()
+        .filter(|cart_item| {
+            cart_item.listing_id == listing.id.unwrap() && cart_item.variant_id != variant_id
+        })
+        .map(|cart_item| cart_item.quantity)
+        .sum();
+    let remaining_quantity = remaining_quantity.saturating_sub(other_variants_quantity);
+
+    if listing.user_id == user.id() {
+        return Err("Listing belongs to same user as buyer.".to_string());
+    };
+    if !listing.approved {
+        return Err("Listing has not been approved by admin.".to_string());
+    };
+    if listing.deactivated_by_seller || listing.deactivated_by_admin {
+        return Err("Listing has been deactivated.".to_string());
+    };
+    if shipping_option.listing_id != listing.id.unwrap() {
+        return Err("Shipping option not associated with listing.".to_string());
+    };
+    if user.is_admin {
+        return Err("Admin user cannot add items to a cart.".to_string());
+    };
+    if quantity == 0 {
+        return Err("Quantity must be postive.".to_string());
+    };
+    if quantity > remaining_quantity {
+        return Err(format!(
+            "Only {} items remaining in stock.",
+            remaining_quantity
+        ));
+    };
+    if !variants.is_empty() && maybe_variant.is_none() {
+        return Err("A variant must be chosen.".to_string());
+    };
+    if let Some(variant) = &maybe_variant {
+        if variant.listing_id != listing.id.unwrap() {
+            return Err("Variant not associated with listing.".to_string());
+        };
+        let remaining_variant_quantity =
+            ListingVariant::remaining_quantity(db, variant.id.unwrap())
+                .await
+                .map_err(|_| "failed to get remaining quantity of variant.")?;
+        if quantity > remaining_variant_quantity {
+            return Err(format!(
+                "Only {} items of {} remaining in stock.",
+                remaining_variant_quantity, variant.title
+            ));
+        };
+    };
+
+    let cart_item = CartItem {
+        id: None,
+        public_id: util::create_uuid(),
+        user_id: user.id(),
+        listing_id: listing.id.unwrap(),
+        variant_id,
+        shipping_option_id: shipping_option.id.unwrap(),
+        quantity,
+        created_time_ms: util::current_time_millis(),
+    };
+    CartItem::insert_or_update(cart_item, MAX_CART_ITEMS, db).await?;
+
+    Ok(())
+}
+
+#[delete("/<id>")]
+async fn remove(id: &str, mut db: Connection<Db>, active_user: ActiveUser) -> Flash<Redirect> {
+    match CartItem::delete_with_public_id(&mut db, active_user.user.id(), id).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/cart", index())),
+            "Item removed from cart.",
+        ),
+        Err(_) => Flash::error(
+            Redirect::to(uri!("/cart", index())),
+            "Failed to remove item from cart.",
+        ),
+    }
+}
+
+#[post("/checkout", data = "<checkout_form>")]
+async fn checkout(
+    checkout_form: Form<CartCheckoutInfo>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+    config: &State<Config>,
+) -> Flash<Redirect> {
+    let checkout_info = checkout_form.into_inner();
+
+    match create_seller_order(
+        checkout_info,
+        &mut db,
+        active_user.user,
+        lightning_backend.inner().as_ref(),
+        config.hold_invoice_escrow,
+    )
+    .await
+    {
+        Ok(order_id) => Flash::success(
+            Redirect::to(format!("/{}/{}", "order", order_id)),
+            "Order successfully created.",
+        ),
+        Err(e) => {
+            error_!("DB insertion error: {}", e);
+            Flash::error(Redirect::to(uri!("/cart", index())), e)
+        }
+    }
+}
+
+/// Creates one order with a line item for each item in the cart from the
+/// given seller, and returns the public id of the order.
+async fn create_seller_order(
+    checkout_info: CartCheckoutInfo,
+    db: &mut Connection<Db>,
+    user: User,
+    lightning_backend: &dyn LightningBackend,
+    escrow: bool,
+) -> Result<String, String> {
+    let now = util::current_time_millis();
+    // Fiat-pegged listings are converted to sats at the current exchange rate,
+    // which is kept on the order.
+    let maybe_exchange_rate =
+        ExchangeRate::latest_updated_after(db, now.saturating_sub(MAX_EXCHANGE_RATE_AGE_MS))
+            .await
+            .map_err(|_| "failed to get exchange rate.")?;
+    let seller_cart_item_displays =
+        cart_item_displays_for_user(db, user.id(), maybe_exchange_rate.as_ref())
+            .await?
+            .into_iter()
+            .filter(|d| d.listing.user_id == checkout_info.seller_user_id)
+            .collect::<Vec<_>>();
+    let shipping_instructions = checkout_info.shipping_instructions;
+
+    if seller_cart_item_displays.is_empty() {
+        return Err("No items from this seller in cart.".to_string());
+    };
+    let (message, _) =
+        Message::from_string(&shipping_instructions).map_err(|_| "Invalid PGP message.")?;
+    info!("message: {:?}", &message);
+
+    if shipping_instructions.is_empty() {
+        return Err("Shipping instructions cannot be empty.".to_string());
+    };
+    if shipping_instructions.len() > 4096 {
+        return Err("Shipping instructions length is too long.".to_string());
+    };
+    if user.is_admin {
+        return Err("Admin user cannot create an order.".to_string());
+    };
+    for cart_item_display in &seller_cart_item_displays {
+        let listing = &cart_item_display.listing;
+        if listing.user_id == user.id() {
+            return Err("Listing belongs to same user as buyer.".to_string());
+        };
+        if !listing.approved {
+            return Err(format!("{} has not been approved by admin.", listing.title));
+        };
+        if listing.deactivated_by_seller || listing.deactivated_by_admin {
+            return Err(format!("{} has been deactivated.", listing.title));
+        };
+    }
+    if seller_cart_item_displays
+        .iter()
+        .any(|cart_item_display| cart_item_display.listing.digital)
+    {
+        digital_delivery::check_buyer_pgp_key(db, user.id()).await?;
+    };
+
+    let mut order_items = vec![];
+    for cart_item_display in &seller_cart_item_displays {
+        let listing = &cart_item_display.listing;
+        let listing_price_sat = match &cart_item_display.maybe_variant {
+            Some(variant) => variant.price_sat,
+            None => prepare_order::listing_price_sat(listing, maybe_exchange_rate.as_ref())?,
+        };
+        let quantity = cart_item_display.cart_item.quantity;
+        order_items.push(OrderItem {
+            id: None,
+            order_id: 0,
+            listing_id: listing.id.unwrap(),
+            quantity,
+            price_sat: listing_price_sat,
+            fee_rate_basis_points: listing.fee_rate_basis_points,
+            market_fee_sat: prepare_order::market_fee_sat(
+                (quantity as u64) * listing_price_sat,
+                listing.fee_rate_basis_points,
+            ),
+            variant_id: cart_item_display.cart_item.variant_id,
+            variant_title: cart_item_display
+                .maybe_variant
+                .as_ref()
+                .map(|variant| variant.title.clone())
+                .unwrap_or_default(),
+        });
+    }
+    let shipping_option = combined_shipping_option(&seller_cart_item_displays);
+    let shipping_listing = seller_cart_item_displays
+        .iter()
+        .find(|d| d.listing.id == Some(shipping_option.listing_id))
+        .map(|d| &d.listing)
+        .unwrap();
+    let shipping_market_fee_sat = prepare_order::market_fee_sat(
+        shipping_option.price_sat,
+        shipping_listing.fee_rate_basis_points,
+    );
+    let amount_owed_sat: u64 = order_items
+        .iter()
+        .map(|i| (i.quantity as u64) * i.price_sat)
+        .sum::<u64>()
+        + shipping_option.price_sat;
+    let market_fee_sat: u64 =
+        order_items.iter().map(|i| i.market_fee_sat).sum::<u64>() + shipping_market_fee_sat;
+    let seller_credit_sat: u64 = amount_owed_sat - market_fee_sat;
+    let first_listing = &seller_cart_item_displays[0].listing;
+
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    let invoice_expiry_s = admin_settings.order_invoice_expiry_s;
+    let (invoice_hash, invoice_payment_request, invoice_preimage) =
+        prepare_order::create_order_invoice(
+            lightning_backend,
+            amount_owed_sat,
+            escrow,
+            invoice_expiry_s,
+        )
+        .await?;
+
+    let order = Order {
+        id: None,
+        public_id: util::create_uuid(),
+        quantity: order_items.iter().map(|i| i.quantity).sum(),
+        buyer_user_id: user.id(),
+        seller_user_id: checkout_info.seller_user_id,
+        listing_id: first_listing.id.unwrap(),
+        shipping_option_id: shipping_option.id.unwrap(),
+        shipping_instructions: shipping_instructions.to_string(),
+        amount_owed_sat,
+        seller_credit_sat,
+        paid: false,
+        shipped: false,
+        canceled_by_seller: false,
+        canceled_by_buyer: false,
+        reviewed: false,
+        invoice_hash,
+        invoice_payment_request,
+        review_rating: 0,
+        review_text: "".to_string(),
+        created_time_ms: now,
+        payment_time_ms: 0,
+        review_time_ms: 0,
+        escrow,
+        invoice_preimage,
+        paid_from_balance: false,
+        invoice_expiry_time_ms: now + (invoice_expiry_s as u64) * 1000,
+        onchain_address: "".to_string(),
+        onchain_pending_sat: 0,
+        onchain_received_sat: 0,
+        onchain_buyer_credit_sat: 0,
+        onchain_expired: false,
+        fiat_currency: maybe_exchange_rate
+            .as_ref()
+            .map(|exchange_rate| exchange_rate.currency.clone())
+            .unwrap_or_default(),
+        fiat_cents_per_btc: maybe_exchange_rate
+            .as_ref()
+            .map(|exchange_rate| exchange_rate.fiat_cents_per_btc)
+            .unwrap_or(0),
+        listing_fiat_price_cents: first_listing.fiat_price_cents,
+        disputed: false,
+        dispute_opened_time_ms: 0,
+        dispute_resolved: false,
+        dispute_resolved_time_ms: 0,
+        dispute_buyer_refund_sat: 0,
+        dispute_seller_credit_sat: 0,
+        shipped_time_ms: 0,
+        finalized: false,
+        finalized_time_ms: 0,
+        shipping_expired: false,
+    };
+
+    let order_id = Order::insert(order, order_items, prepare_order::MAX_UNPAID_ORDERS, db).await?;
+    CartItem::delete_all_for_seller(db, user.id(), checkout_info.seller_user_id)
+        .await
+        .map_err(|_| "failed to remove checked out items from cart.")?;
+    let new_order = Order::single(db, order_id)
+        .await
+        .map_err(|_| "New order could not be found after inserting.")?;
+
+    Ok(new_order.public_id)
+}
+
+#[get("/")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    db: Connection<Db>,
+    active_user: ActiveUser,
+    admin_user: Option<AdminUser>,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(flash, db, active_user.user, admin_user)
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("cart", context))
+}
+
+pub fn cart_stage() -> AdHoc {
+    AdHoc::on_ignite("Cart Stage", |rocket| async {
+        rocket.mount("/cart", routes![index, add, remove, checkout])
+    })
+}
@@ -13,6 +13,17 @@ pub struct Config {
     pub lnd_port: u32,
     pub lnd_tls_cert_path: String,
     pub lnd_macaroon_path: String,
+    pub lightning_backend: String,
+    pub hold_invoice_escrow: bool,
+    pub external_url: String,
+    pub payout_resolver: String,
+    pub onchain_payments: bool,
+    pub onchain_min_confirmations: u32,
+    pub onchain_underpayment_tolerance_basis_points: u64,
+    pub onchain_overpayment_tolerance_basis_points: u64,
+    pub fiat_currency: String,
+    pub exchange_rate_provider: String,
+    pub exchange_rate_file: String,
 }
 
 impl Default for Config {
@@ -25,6 +36,17 @@ impl Default for Config {
             lnd_port: 10009,
             lnd_tls_cert_path: "~/.lnd/tls.cert".into(),
             lnd_macaroon_path: "~/.lnd/data/chain/bitcoin/testnet/admin.macaroon".into(),
+            lightning_backend: "lnd".into(),
+            hold_invoice_escrow: false,
+            external_url: "http://localhost:8000".into(),
+            payout_resolver: "lnurl".into(),
+            onchain_payments: false,
+            onchain_min_confirmations: 3,
+            onchain_underpayment_tolerance_basis_points: 100,
+            onchain_overpayment_tolerance_basis_points: 100,
+            fiat_currency: "".into(),
+            exchange_rate_provider: "file".into(),
+            exchange_rate_file: "exchange_rates.json".into(),
         }
     }
 }
@@ -1,7 +1,6 @@
 use crate::base::BaseContext;
-use crate::config::Config;
 use crate::db::Db;
-use crate::lightning;
+use crate::lightning::LightningBackend;
 use crate::models::{UserAccount, WithdrawalInfo};
 use crate::user_account::ActiveUser;
 use rocket::fairing::AdHoc;
@@ -16,6 +15,7 @@ use rocket_auth::User;
 use rocket_auth::Users;
 use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
+use std::sync::Arc;
 
 #[derive(Debug, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -58,7 +58,7 @@ async fn new(
     mut db: Connection<Db>,
     active_user: ActiveUser,
     _admin_user: Option<AdminUser>,
-    config: &State<Config>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
 ) -> Result<Flash<Redirect>, Flash<Redirect>> {
     let withdrawal_info = withdrawal_form.into_inner();
     match withdraw_account_deactivation_funds(
@@ -66,7 +66,7 @@ async fn new(
         active_user.user_account,
         &mut db,
         active_user.user.clone(),
-        config.inner().clone(),
+        lightning_backend.inner().as_ref(),
     )
     .await
     {
@@ -89,31 +89,18 @@ async fn withdraw_account_deactivation_funds(
     user_account: UserAccount,
     db: &mut Connection<Db>,
     _user: User,
-    config: Config,
+    lightning_backend: &dyn LightningBackend,
 ) -> Result<(), String> {
     if withdrawal_info.invoice_payment_request.is_empty() {
         return Err("Invoice payment request cannot be empty.".to_string());
     };
 
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
-    )
-    .await
-    .expect("failed to get lightning client");
-    let decoded_pay_req = lightning_client
-        .decode_pay_req(tonic_openssl_lnd::lnrpc::PayReqString {
-            pay_req: withdrawal_info.invoice_payment_request.clone(),
-        })
-        .await
-        .map_err(|_| "failed to decode payment request string.")?
-        .into_inner();
+    let decoded_pay_req = lightning_backend
+        .decode_pay_req(&withdrawal_info.invoice_payment_request)
+        .await?;
     let amount_sat: u64 = decoded_pay_req.num_satoshis.try_into().unwrap();
     let invoice_payment_request = withdrawal_info.invoice_payment_request;
-    let send_deactivation_funds_ret =
-        send_account_deactivation_funds(invoice_payment_request, config);
+    let send_deactivation_funds_ret = lightning_backend.pay_invoice(&invoice_payment_request);
     UserAccount::do_deactivation(amount_sat, user_account, db, send_deactivation_funds_ret)
         .await
         .map_err(|e| {
@@ -124,35 +111,6 @@ async fn withdraw_account_deactivation_funds(
     Ok(())
 }
 
-async fn send_account_deactivation_funds(
-    invoice_payment_request: String,
-    config: Config,
-) -> Result<tonic_openssl_lnd::lnrpc::SendResponse, String> {
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
-    )
-    .await
-    .expect("failed to get lightning client");
-    let send_response = lightning_client
-        .send_payment_sync(tonic_openssl_lnd::lnrpc::SendRequest {
-            payment_request: invoice_payment_request,
-            ..Default::default()
-        })
-        .await
-        .map_err(|e| format!("failed to send payment: {:?}", e))?
-        .into_inner();
-    if send_response.payment_preimage.is_empty() {
-        return Err(format!(
-            "Send Payment failure: {:?}.",
-            send_response.payment_error
-        ));
-    }
-    Ok(send_response)
-}
-
 #[get("/")]
 async fn index(
     flash: Option<FlashMessage<'_>>,
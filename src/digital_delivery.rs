@@ -0,0 +1,151 @@
+use crate::lightning::LightningBackend;
+use crate::models::{DigitalPayload, Listing, Order, OrderItem, OrderStatus, UserSettings};
+use crate::order_finalization;
+use crate::util;
+use pgp::crypto::SymmetricKeyAlgorithm;
+use pgp::types::KeyTrait;
+use pgp::{Deserializable, Message, SignedPublicKey};
+use sqlx::pool::PoolConnection;
+use sqlx::{Sqlite, SqliteConnection};
+use std::collections::HashMap;
+
+/// Delivers the payloads of the digital items of a paid order, encrypted to
+/// the PGP key of the buyer.
+///
+/// The order is marked as shipped by the seller when all of its items are
+/// digital. Payloads that were already delivered for the order are not
+/// delivered again.
+pub async fn deliver_order(
+    conn: &mut PoolConnection<Sqlite>,
+    lightning_backend: &dyn LightningBackend,
+    order_id: i32,
+) -> Result<(), String> {
+    let order = Order::single(conn, order_id)
+        .await
+        .map_err(|_| "failed to get order.")?;
+    if !order.status().can_transition_to(OrderStatus::Shipped) {
+        return Ok(());
+    }
+
+    let order_items = OrderItem::all_for_order(conn, order_id)
+        .await
+        .map_err(|_| "failed to get order items.")?;
+    let mut digital_order_items = vec![];
+    let mut all_items_digital = true;
+    for order_item in order_items {
+        let listing = Listing::single(conn, order_item.listing_id)
+            .await
+            .map_err(|_| "failed to get listing.")?;
+        if listing.digital {
+            digital_order_items.push(order_item);
+        } else {
+            all_items_digital = false;
+        }
+    }
+    if digital_order_items.is_empty() {
+        return Ok(());
+    }
+
+    let buyer_user_settings = UserSettings::single(conn, order.buyer_user_id)
+        .await
+        .map_err(|_| "failed to get buyer user settings.")?;
+    let (buyer_public_key, _) = SignedPublicKey::from_string(&buyer_user_settings.pgp_key)
+        .map_err(|_| "buyer PGP key is invalid.")?;
+    let delivered_payloads = DigitalPayload::all_for_order(conn, order_id)
+        .await
+        .map_err(|_| "failed to get delivered payloads.")?;
+
+    // Items of the same listing are delivered together, so that they do not
+    // pick the same unassigned payloads.
+    let mut listing_quantities: HashMap<i32, u32> = HashMap::new();
+    for order_item in digital_order_items {
+        *listing_quantities.entry(order_item.listing_id).or_insert(0) += order_item.quantity;
+    }
+
+    let mut encrypted_payloads = vec![];
+    for (listing_id, quantity) in listing_quantities {
+        let num_delivered = delivered_payloads
+            .iter()
+            .filter(|digital_payload| digital_payload.listing_id == listing_id)
+            .count() as u32;
+        let num_missing = quantity.saturating_sub(num_delivered);
+        if num_missing == 0 {
+            continue;
+        }
+        let digital_payloads =
+            DigitalPayload::unassigned_for_listing(conn, listing_id, num_missing)
+                .await
+                .map_err(|_| "failed to get payloads for listing.")?;
+        if (digital_payloads.len() as u32) < num_missing {
+            return Err("not enough payloads left to deliver order.".to_string());
+        }
+        for digital_payload in digital_payloads {
+            let encrypted_payload = encrypt_payload(&buyer_public_key, &digital_payload)?;
+            encrypted_payloads.push((digital_payload.id.unwrap(), encrypted_payload));
+        }
+    }
+
+    let now = util::current_time_millis();
+    DigitalPayload::mark_as_delivered(conn, order_id, encrypted_payloads, now).await?;
+
+    if !all_items_digital {
+        return Ok(());
+    }
+
+    let finalized =
+        order_finalization::is_eligible_for_early_finalization(conn, order.seller_user_id).await?;
+
+    if order.escrow {
+        let settle_hold_invoice_ret =
+            lightning_backend.settle_hold_invoice(util::from_hex(&order.invoice_preimage));
+        return Order::mark_as_shipped_with_escrow(
+            conn,
+            order_id,
+            order.seller_user_id,
+            now,
+            finalized,
+            settle_hold_invoice_ret,
+        )
+        .await;
+    }
+
+    Order::mark_as_shipped(conn, order_id, order.seller_user_id, now, finalized).await
+}
+
+/// Checks that the buyer has a PGP key that payloads can be encrypted to, so
+/// that a digital order can be delivered as soon as it is paid.
+pub async fn check_buyer_pgp_key(
+    db: &mut SqliteConnection,
+    buyer_user_id: i32,
+) -> Result<(), String> {
+    let buyer_user_settings = UserSettings::single(db, buyer_user_id)
+        .await
+        .map_err(|_| "failed to get buyer user settings.")?;
+    SignedPublicKey::from_string(&buyer_user_settings.pgp_key)
+        .map_err(|_| "A valid PGP key must be set in your account to buy digital items.")?;
+    Ok(())
+}
+
+/// Returns the payload as an armored PGP message. Keys usually encrypt with a
+/// subkey, so the primary key is only used when it has no encryption subkey.
+fn encrypt_payload(
+    public_key: &SignedPublicKey,
+    digital_payload: &DigitalPayload,
+) -> Result<String, String> {
+    let message =
+        Message::new_literal_bytes(&digital_payload.payload_name, &digital_payload.payload_data);
+    let mut rng = rand::thread_rng();
+    let encrypted_message = match public_key
+        .public_subkeys
+        .iter()
+        .find(|subkey| subkey.is_encryption_key())
+    {
+        Some(subkey) => message.encrypt_to_keys(&mut rng, SymmetricKeyAlgorithm::AES128, &[subkey]),
+        None => message.encrypt_to_keys(&mut rng, SymmetricKeyAlgorithm::AES128, &[public_key]),
+    }
+    .map_err(|_| "failed to encrypt payload.")?;
+    let armored_message = encrypted_message
+        .to_armored_string(None)
+        .map_err(|_| "failed to armor encrypted payload.")?;
+    Ok(armored_message)
+}
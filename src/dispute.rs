@@ -0,0 +1,284 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::models::{
+    DisputeMessage, DisputeMessageInput, DisputeResolutionInput, Order, OrderStatus, RocketAuthUser,
+};
+use crate::user_account::ActiveUser;
+use crate::util;
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::Flash;
+use rocket::response::Redirect;
+use rocket::serde::Serialize;
+use rocket_auth::AdminUser;
+use rocket_auth::User;
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+
+// How long after payment the buyer can open a dispute.
+pub const DISPUTE_WINDOW_MS: u64 = 2592000000;
+const MAX_DISPUTE_MESSAGE_LENGTH: usize = 4096;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    order: Order,
+    maybe_buyer_user: Option<RocketAuthUser>,
+    maybe_seller_user: Option<RocketAuthUser>,
+    dispute_messages: Vec<DisputeMessage>,
+    user: User,
+    admin_user: Option<AdminUser>,
+    can_open_dispute: bool,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        order_id: &str,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let order = Order::single_by_public_id(&mut db, order_id)
+            .await
+            .map_err(|_| "failed to get order.")?;
+        if admin_user.is_none()
+            && order.buyer_user_id != user.id()
+            && order.seller_user_id != user.id()
+        {
+            return Err("User is not the order buyer or seller.".to_string());
+        }
+        let maybe_buyer_user = RocketAuthUser::single(&mut db, order.buyer_user_id)
+            .await
+            .ok();
+        let maybe_seller_user = RocketAuthUser::single(&mut db, order.seller_user_id)
+            .await
+            .ok();
+        let dispute_messages = DisputeMessage::all_for_order(&mut db, order.id.unwrap())
+            .await
+            .map_err(|_| "failed to get dispute messages.")?;
+        let can_open_dispute =
+            order.buyer_user_id == user.id() && is_disputable(&order, util::current_time_millis());
+        Ok(Context {
+            base_context,
+            flash,
+            order,
+            maybe_buyer_user,
+            maybe_seller_user,
+            dispute_messages,
+            user,
+            admin_user,
+            can_open_dispute,
+        })
+    }
+}
+
+/// Returns true if the buyer can still open a dispute on the order.
+pub fn is_disputable(order: &Order, now: u64) -> bool {
+    order.status().can_transition_to(OrderStatus::Disputed)
+        && order.payment_time_ms + DISPUTE_WINDOW_MS > now
+}
+
+fn validate_dispute_message(text: &str) -> Result<(), String> {
+    if text.is_empty() {
+        return Err("Message cannot be empty.".to_string());
+    };
+    if text.len() > MAX_DISPUTE_MESSAGE_LENGTH {
+        return Err("Message length is too long.".to_string());
+    };
+    Ok(())
+}
+
+#[post("/<id>/open", data = "<dispute_message_form>")]
+async fn open(
+    id: &str,
+    dispute_message_form: Form<DisputeMessageInput>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+) -> Flash<Redirect> {
+    let dispute_message_info = dispute_message_form.into_inner();
+
+    match open_dispute(id, dispute_message_info, &mut db, active_user.user).await {
+        Ok(_) => Flash::success(
+            Redirect::to(format!("/{}/{}", "dispute", id)),
+            "Dispute opened.",
+        ),
+        Err(e) => Flash::error(Redirect::to(format!("/{}/{}", "dispute", id)), e),
+    }
+}
+
+async fn open_dispute(
+    order_id: &str,
+    dispute_message_info: DisputeMessageInput,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let order = Order::single_by_public_id(db, order_id)
+        .await
+        .map_err(|_| "failed to get order.")?;
+
+    if order.buyer_user_id != user.id() {
+        return Err("User is not the order buyer.".to_string());
+    };
+    if !is_disputable(&order, now) {
+        return Err("Order cannot be disputed.".to_string());
+    };
+    validate_dispute_message(&dispute_message_info.text)?;
+
+    let dispute_message = DisputeMessage {
+        id: None,
+        public_id: util::create_uuid(),
+        order_id: order.id.unwrap(),
+        author_user_id: user.id(),
+        text: dispute_message_info.text,
+        created_time_ms: now,
+    };
+    Order::open_dispute(
+        db,
+        order.id.unwrap(),
+        dispute_message,
+        now.saturating_sub(DISPUTE_WINDOW_MS),
+        now,
+    )
+    .await
+}
+
+#[post("/<id>/new_message", data = "<dispute_message_form>")]
+async fn new_message(
+    id: &str,
+    dispute_message_form: Form<DisputeMessageInput>,
+    mut db: Connection<Db>,
+    user: User,
+    admin_user: Option<AdminUser>,
+) -> Flash<Redirect> {
+    let dispute_message_info = dispute_message_form.into_inner();
+
+    match add_dispute_message(id, dispute_message_info, &mut db, user, admin_user).await {
+        Ok(_) => Flash::success(
+            Redirect::to(format!("/{}/{}", "dispute", id)),
+            "Message posted.",
+        ),
+        Err(e) => Flash::error(Redirect::to(format!("/{}/{}", "dispute", id)), e),
+    }
+}
+
+async fn add_dispute_message(
+    order_id: &str,
+    dispute_message_info: DisputeMessageInput,
+    db: &mut Connection<Db>,
+    user: User,
+    admin_user: Option<AdminUser>,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let order = Order::single_by_public_id(db, order_id)
+        .await
+        .map_err(|_| "failed to get order.")?;
+
+    if admin_user.is_none() && order.buyer_user_id != user.id() && order.seller_user_id != user.id()
+    {
+        return Err("User is not the order buyer or seller.".to_string());
+    };
+    if order.status() != OrderStatus::Disputed {
+        return Err("Order does not have an open dispute.".to_string());
+    };
+    validate_dispute_message(&dispute_message_info.text)?;
+
+    let dispute_message = DisputeMessage {
+        id: None,
+        public_id: util::create_uuid(),
+        order_id: order.id.unwrap(),
+        author_user_id: user.id(),
+        text: dispute_message_info.text,
+        created_time_ms: now,
+    };
+    DisputeMessage::insert(dispute_message, db)
+        .await
+        .map_err(|_| "failed to insert dispute message.")?;
+
+    Ok(())
+}
+
+#[post("/<id>/resolve", data = "<dispute_resolution_form>")]
+async fn resolve(
+    id: &str,
+    dispute_resolution_form: Form<DisputeResolutionInput>,
+    mut db: Connection<Db>,
+    user: User,
+    _admin_user: AdminUser,
+) -> Flash<Redirect> {
+    let dispute_resolution_info = dispute_resolution_form.into_inner();
+
+    match resolve_dispute(id, dispute_resolution_info, &mut db, user).await {
+        Ok(_) => Flash::success(
+            Redirect::to(format!("/{}/{}", "dispute", id)),
+            "Dispute resolved.",
+        ),
+        Err(e) => Flash::error(Redirect::to(format!("/{}/{}", "dispute", id)), e),
+    }
+}
+
+async fn resolve_dispute(
+    order_id: &str,
+    dispute_resolution_info: DisputeResolutionInput,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let order = Order::single_by_public_id(db, order_id)
+        .await
+        .map_err(|_| "failed to get order.")?;
+    let buyer_refund_sat = dispute_resolution_info
+        .buyer_refund_sat
+        .ok_or("Buyer refund amount is required.")?;
+
+    if order.status() != OrderStatus::Disputed {
+        return Err("Order does not have an open dispute.".to_string());
+    };
+    if buyer_refund_sat > order.amount_owed_sat {
+        return Err("Buyer refund cannot be more than the order amount.".to_string());
+    };
+
+    // The seller keeps the usual credit on the part that is not refunded,
+    // and the market keeps the rest as its fee.
+    let seller_credit_sat = order
+        .seller_credit_sat
+        .min(order.amount_owed_sat - buyer_refund_sat);
+    Order::resolve_dispute(
+        db,
+        order.id.unwrap(),
+        user.id(),
+        buyer_refund_sat,
+        seller_credit_sat,
+        now,
+    )
+    .await
+}
+
+#[get("/<id>")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    id: &str,
+    db: Connection<Db>,
+    user: User,
+    admin_user: Option<AdminUser>,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, id, flash, user, admin_user)
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("dispute", context))
+}
+
+pub fn dispute_stage() -> AdHoc {
+    AdHoc::on_ignite("Dispute Stage", |rocket| async {
+        rocket.mount("/dispute", routes![index, open, new_message, resolve])
+    })
+}
@@ -0,0 +1,53 @@
+use crate::config::Config;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Returns the price of a bitcoin in a fiat currency.
+///
+/// Managed as Rocket state as an `Arc<dyn ExchangeRateProvider>`, like the
+/// lightning backend.
+#[rocket::async_trait]
+pub trait ExchangeRateProvider: Send + Sync {
+    async fn get_fiat_cents_per_btc(&self, currency: &str) -> Result<u64, String>;
+}
+
+/// Creates the exchange rate provider selected by the "exchange_rate_provider"
+/// config value.
+pub fn new_exchange_rate_provider(
+    config: &Config,
+) -> Result<Arc<dyn ExchangeRateProvider>, String> {
+    match config.exchange_rate_provider.as_str() {
+        "file" => Ok(Arc::new(FileExchangeRateProvider {
+            path: config.exchange_rate_file.clone(),
+        })),
+        other => Err(format!("Unknown exchange rate provider: {:?}", other)),
+    }
+}
+
+/// Reads static exchange rates from a JSON file that maps currency codes to
+/// the price of a bitcoin, for example `{"USD": 20000.00}`.
+///
+/// The file is read again on every update, so it can be edited while the
+/// market is running, without any network access.
+pub struct FileExchangeRateProvider {
+    path: String,
+}
+
+#[rocket::async_trait]
+impl ExchangeRateProvider for FileExchangeRateProvider {
+    async fn get_fiat_cents_per_btc(&self, currency: &str) -> Result<u64, String> {
+        let contents = rocket::tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| format!("failed to read exchange rate file: {:?}", e))?;
+        let prices_per_btc: HashMap<String, f64> = serde_json::from_str(&contents)
+            .map_err(|e| format!("failed to decode exchange rate file: {:?}", e))?;
+        let price_per_btc = prices_per_btc
+            .get(currency)
+            .ok_or(format!("No exchange rate for currency: {:?}", currency))?;
+        let fiat_cents_per_btc = (price_per_btc * 100.0).round();
+        if !fiat_cents_per_btc.is_finite() || fiat_cents_per_btc < 1.0 {
+            return Err(format!("Invalid exchange rate: {:?}", price_per_btc));
+        }
+        Ok(fiat_cents_per_btc as u64)
+    }
+}
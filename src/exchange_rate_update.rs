@@ -0,0 +1,34 @@
+use crate::exchange_rate_provider::ExchangeRateProvider;
+use crate::models::{ExchangeRate, Listing};
+use crate::util;
+use sqlx::pool::PoolConnection;
+use sqlx::Sqlite;
+use std::sync::Arc;
+
+// Cached exchange rates older than this are not shown or used for new orders.
+pub const MAX_EXCHANGE_RATE_AGE_MS: u64 = 3600000;
+
+/// Caches the current exchange rate of the market currency, and converts the
+/// prices of fiat-pegged listings to sats at the new rate.
+pub async fn update_exchange_rate(
+    exchange_rate_provider: Arc<dyn ExchangeRateProvider>,
+    mut conn: PoolConnection<Sqlite>,
+    currency: &str,
+) -> Result<(), String> {
+    let fiat_cents_per_btc = exchange_rate_provider
+        .get_fiat_cents_per_btc(currency)
+        .await?;
+    let exchange_rate = ExchangeRate {
+        currency: currency.to_string(),
+        fiat_cents_per_btc,
+        updated_time_ms: util::current_time_millis(),
+    };
+    ExchangeRate::update(exchange_rate, &mut conn)
+        .await
+        .map_err(|_| "failed to update exchange rate.")?;
+    Listing::update_fiat_pegged_prices(&mut conn, fiat_cents_per_btc)
+        .await
+        .map_err(|_| "failed to update fiat-pegged listing prices.")?;
+
+    Ok(())
+}
@@ -1,7 +1,615 @@
+use crate::config::Config;
+use crate::lnd_client_pool::LndClientPool;
+use crate::mock_lightning::MockLightningBackend;
+use crate::rocket::futures::stream::BoxStream;
+use crate::rocket::futures::StreamExt;
+use crate::rocket::futures::TryStreamExt;
+use crate::util;
+use std::collections::HashMap;
+use std::sync::Arc;
 use tonic_openssl_lnd::connect_invoices;
 use tonic_openssl_lnd::connect_lightning;
+use tonic_openssl_lnd::connect_router;
+use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
 use tonic_openssl_lnd::LndInvoicesClient;
 use tonic_openssl_lnd::LndLightningClient;
+use tonic_openssl_lnd::LndRouterClient;
+
+/// Number of blocks before the HTLCs of an accepted hold invoice expire. This
+/// must leave enough time for the seller to ship an escrow order.
+const HOLD_INVOICE_CLTV_EXPIRY: u64 = 432;
+
+/// Number of seconds LND keeps looking for a route before failing a payment.
+const SEND_PAYMENT_TIMEOUT_S: i32 = 60;
+
+/// The gRPC status code LND returns when tracking a payment it never started.
+const GRPC_STATUS_NOT_FOUND: i32 = 5;
+
+/// The custom record type that carries the preimage of a keysend payment.
+const KEYSEND_PREIMAGE_RECORD_TYPE: u64 = 5482373484;
+
+pub type InvoiceStream = BoxStream<'static, Result<tonic_openssl_lnd::lnrpc::Invoice, String>>;
+pub type TransactionStream =
+    BoxStream<'static, Result<tonic_openssl_lnd::lnrpc::Transaction, String>>;
+
+/// The set of Lightning node operations used by the market.
+///
+/// Managed as Rocket state as an `Arc<dyn LightningBackend>`, so that
+/// handlers and background tasks do not depend on a specific node
+/// implementation.
+#[rocket::async_trait]
+pub trait LightningBackend: Send + Sync {
+    async fn create_invoice(
+        &self,
+        amount_sat: u64,
+        expiry_s: u32,
+    ) -> Result<tonic_openssl_lnd::lnrpc::AddInvoiceResponse, String>;
+
+    /// Creates an invoice that is accepted, but not settled, when it is paid.
+    async fn create_hold_invoice(
+        &self,
+        amount_sat: u64,
+        payment_hash: Vec<u8>,
+        expiry_s: u32,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::AddHoldInvoiceResp, String>;
+
+    /// Settles an accepted hold invoice by revealing its preimage.
+    async fn settle_hold_invoice(
+        &self,
+        preimage: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::SettleInvoiceResp, String>;
+
+    async fn cancel_invoice(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String>;
+
+    async fn lookup_invoice(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::lnrpc::Invoice, String>;
+
+    /// Returns a page of invoices ordered by add index. When `reversed` is
+    /// set, the page ends before `index_offset` (or at the newest invoice if
+    /// it is zero) instead of starting after it.
+    async fn list_invoices(
+        &self,
+        index_offset: u64,
+        num_max_invoices: u64,
+        reversed: bool,
+    ) -> Result<tonic_openssl_lnd::lnrpc::ListInvoiceResponse, String>;
+
+    /// Returns a stream of invoice updates, starting after the given settle index.
+    async fn subscribe_invoices(&self, settle_index: u64) -> Result<InvoiceStream, String>;
+
+    async fn decode_pay_req(
+        &self,
+        pay_req: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::PayReq, String>;
+
+    async fn pay_invoice(
+        &self,
+        pay_req: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::SendResponse, String>;
+
+    /// Starts paying an invoice, without waiting for the payment to complete.
+    ///
+    /// The amount must be given for an invoice without an amount, and only
+    /// then. The outcome of the payment is found later with `track_payment`.
+    async fn send_payment(
+        &self,
+        pay_req: &str,
+        amount_sat: Option<u64>,
+        fee_limit_sat: u64,
+    ) -> Result<(), String>;
+
+    /// Starts a keysend payment to a node, without waiting for the payment to
+    /// complete. The payment hash is the hash of the given preimage, so the
+    /// outcome of the payment is found later with `track_payment`.
+    async fn send_keysend_payment(
+        &self,
+        dest_pubkey: Vec<u8>,
+        amount_sat: u64,
+        preimage: Vec<u8>,
+        fee_limit_sat: u64,
+    ) -> Result<(), String>;
+
+    /// Returns the current state of an outgoing payment, or `None` if no
+    /// payment was ever started for the given payment hash.
+    async fn track_payment(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<Option<tonic_openssl_lnd::lnrpc::Payment>, String>;
+
+    async fn get_info(&self) -> Result<tonic_openssl_lnd::lnrpc::GetInfoResponse, String>;
+
+    /// Checks a message signature made by a node with `signmessage`, and
+    /// returns the pubkey of the node that signed it.
+    async fn verify_message(
+        &self,
+        msg: &[u8],
+        signature: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::VerifyMessageResponse, String>;
+
+    async fn channel_balance(
+        &self,
+    ) -> Result<tonic_openssl_lnd::lnrpc::ChannelBalanceResponse, String>;
+
+    /// Returns the on-chain balance of the node's wallet.
+    async fn wallet_balance(
+        &self,
+    ) -> Result<tonic_openssl_lnd::lnrpc::WalletBalanceResponse, String>;
+
+    /// Returns a new address of the node's wallet.
+    async fn new_address(&self) -> Result<String, String>;
+
+    /// Returns every transaction of the node's wallet.
+    async fn get_transactions(&self) -> Result<Vec<tonic_openssl_lnd::lnrpc::Transaction>, String>;
+
+    /// Streams the wallet transactions as they are first seen, and again when
+    /// they are first confirmed.
+    async fn subscribe_transactions(&self) -> Result<TransactionStream, String>;
+
+    /// Checks that the node is reachable, reconnecting if needed.
+    async fn check_health(&self) -> Result<(), String> {
+        self.get_info().await?;
+        Ok(())
+    }
+}
+
+/// Creates the lightning backend selected by the "lightning_backend" config value.
+pub fn new_lightning_backend(config: &Config) -> Result<Arc<dyn LightningBackend>, String> {
+    match config.lightning_backend.as_str() {
+        "lnd" => Ok(Arc::new(LndLightningBackend::new(config))),
+        "mock" => Ok(Arc::new(MockLightningBackend::new())),
+        other => Err(format!("Unknown lightning backend: {:?}", other)),
+    }
+}
+
+/// Makes sure that an expired invoice can no longer be paid, so that it can be
+/// replaced with a new one.
+pub async fn cancel_expired_invoice(
+    lightning_backend: &dyn LightningBackend,
+    invoice_hash: &str,
+    invoice_expiry_time_ms: u64,
+) -> Result<(), String> {
+    if util::current_time_millis() < invoice_expiry_time_ms {
+        return Err("Invoice has not expired yet.".to_string());
+    }
+    let payment_hash = util::from_hex(invoice_hash);
+    let invoice = lightning_backend
+        .lookup_invoice(payment_hash.clone())
+        .await?;
+    if invoice.state == InvoiceState::Settled as i32
+        || invoice.state == InvoiceState::Accepted as i32
+    {
+        return Err("Invoice has already been paid.".to_string());
+    }
+    if invoice.state != InvoiceState::Canceled as i32 {
+        lightning_backend.cancel_invoice(payment_hash).await?;
+    }
+    Ok(())
+}
+
+pub struct LndLightningBackend {
+    client_pool: LndClientPool,
+}
+
+impl LndLightningBackend {
+    pub fn new(config: &Config) -> LndLightningBackend {
+        LndLightningBackend {
+            client_pool: LndClientPool::new(config),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl LightningBackend for LndLightningBackend {
+    async fn create_invoice(
+        &self,
+        amount_sat: u64,
+        expiry_s: u32,
+    ) -> Result<tonic_openssl_lnd::lnrpc::AddInvoiceResponse, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .add_invoice(tonic_openssl_lnd::lnrpc::Invoice {
+                value_msat: (amount_sat as i64) * 1000,
+                expiry: expiry_s as i64,
+                ..Default::default()
+            })
+            .await;
+        let invoice = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to get new invoice: {:?}", e))?
+            .into_inner();
+        Ok(invoice)
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        amount_sat: u64,
+        payment_hash: Vec<u8>,
+        expiry_s: u32,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::AddHoldInvoiceResp, String> {
+        let mut invoices_client = self.client_pool.clients().await?.invoices;
+        let response = invoices_client
+            .add_hold_invoice(tonic_openssl_lnd::invoicesrpc::AddHoldInvoiceRequest {
+                hash: payment_hash,
+                value_msat: (amount_sat as i64) * 1000,
+                cltv_expiry: HOLD_INVOICE_CLTV_EXPIRY,
+                expiry: expiry_s as i64,
+                ..Default::default()
+            })
+            .await;
+        let hold_invoice = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to add hold invoice: {:?}", e))?
+            .into_inner();
+        Ok(hold_invoice)
+    }
+
+    async fn settle_hold_invoice(
+        &self,
+        preimage: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::SettleInvoiceResp, String> {
+        let mut invoices_client = self.client_pool.clients().await?.invoices;
+        let response = invoices_client
+            .settle_invoice(tonic_openssl_lnd::invoicesrpc::SettleInvoiceMsg { preimage })
+            .await;
+        let settle_response = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to settle invoice: {:?}", e))?
+            .into_inner();
+        Ok(settle_response)
+    }
+
+    async fn cancel_invoice(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String> {
+        let mut invoices_client = self.client_pool.clients().await?.invoices;
+        let response = invoices_client
+            .cancel_invoice(tonic_openssl_lnd::invoicesrpc::CancelInvoiceMsg { payment_hash })
+            .await;
+        let cancel_response = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to cancel invoice: {:?}", e))?
+            .into_inner();
+        Ok(cancel_response)
+    }
+
+    async fn lookup_invoice(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::lnrpc::Invoice, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .lookup_invoice(tonic_openssl_lnd::lnrpc::PaymentHash {
+                r_hash: payment_hash,
+                ..Default::default()
+            })
+            .await;
+        let invoice = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("Failed to lookup invoice: {:?}", e))?
+            .into_inner();
+        Ok(invoice)
+    }
+
+    async fn list_invoices(
+        &self,
+        index_offset: u64,
+        num_max_invoices: u64,
+        reversed: bool,
+    ) -> Result<tonic_openssl_lnd::lnrpc::ListInvoiceResponse, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .list_invoices(tonic_openssl_lnd::lnrpc::ListInvoiceRequest {
+                index_offset,
+                num_max_invoices,
+                reversed,
+                ..Default::default()
+            })
+            .await;
+        let list_invoices_response = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("Failed to list invoices: {:?}", e))?
+            .into_inner();
+        Ok(list_invoices_response)
+    }
+
+    async fn subscribe_invoices(&self, settle_index: u64) -> Result<InvoiceStream, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let invoice_subscription = tonic_openssl_lnd::lnrpc::InvoiceSubscription {
+            settle_index,
+            ..Default::default()
+        };
+        let response = lightning_client
+            .subscribe_invoices(invoice_subscription)
+            .await;
+        let update_stream = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("Failed to call subscribe invoices: {:?}", e))?
+            .into_inner()
+            .map_err(|e| format!("Invoice subscription failed: {:?}", e))
+            .boxed();
+        Ok(update_stream)
+    }
+
+    async fn decode_pay_req(
+        &self,
+        pay_req: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::PayReq, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .decode_pay_req(tonic_openssl_lnd::lnrpc::PayReqString {
+                pay_req: pay_req.to_string(),
+            })
+            .await;
+        let decoded_pay_req = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|_| "failed to decode payment request string.".to_string())?
+            .into_inner();
+        Ok(decoded_pay_req)
+    }
+
+    async fn pay_invoice(
+        &self,
+        pay_req: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::SendResponse, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .send_payment_sync(tonic_openssl_lnd::lnrpc::SendRequest {
+                payment_request: pay_req.to_string(),
+                ..Default::default()
+            })
+            .await;
+        let send_response = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to send payment: {:?}", e))?
+            .into_inner();
+        if send_response.payment_preimage.is_empty() {
+            return Err(format!(
+                "Send Payment failure: {:?}.",
+                send_response.payment_error
+            ));
+        }
+        Ok(send_response)
+    }
+
+    async fn send_payment(
+        &self,
+        pay_req: &str,
+        amount_sat: Option<u64>,
+        fee_limit_sat: u64,
+    ) -> Result<(), String> {
+        let mut router_client = self.client_pool.clients().await?.router;
+        let response = router_client
+            .send_payment_v2(tonic_openssl_lnd::routerrpc::SendPaymentRequest {
+                payment_request: pay_req.to_string(),
+                amt: amount_sat.unwrap_or(0) as i64,
+                timeout_seconds: SEND_PAYMENT_TIMEOUT_S,
+                fee_limit_sat: fee_limit_sat as i64,
+                ..Default::default()
+            })
+            .await;
+        let mut payment_stream = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to send payment: {:?}", e))?
+            .into_inner();
+        // The first update means that the payment was started. The stream is
+        // dropped after that, and the payment keeps going in LND.
+        payment_stream
+            .message()
+            .await
+            .map_err(|e| format!("failed to send payment: {:?}", e))?
+            .ok_or("failed to send payment: no payment update received.")?;
+        Ok(())
+    }
+
+    async fn send_keysend_payment(
+        &self,
+        dest_pubkey: Vec<u8>,
+        amount_sat: u64,
+        preimage: Vec<u8>,
+        fee_limit_sat: u64,
+    ) -> Result<(), String> {
+        let mut router_client = self.client_pool.clients().await?.router;
+        let payment_hash = util::sha256(&preimage);
+        let dest_custom_records = HashMap::from([(KEYSEND_PREIMAGE_RECORD_TYPE, preimage)]);
+        let response = router_client
+            .send_payment_v2(tonic_openssl_lnd::routerrpc::SendPaymentRequest {
+                dest: dest_pubkey,
+                amt: amount_sat as i64,
+                payment_hash,
+                dest_custom_records,
+                dest_features: vec![tonic_openssl_lnd::lnrpc::FeatureBit::TlvOnionOpt as i32],
+                timeout_seconds: SEND_PAYMENT_TIMEOUT_S,
+                fee_limit_sat: fee_limit_sat as i64,
+                ..Default::default()
+            })
+            .await;
+        let mut payment_stream = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to send keysend payment: {:?}", e))?
+            .into_inner();
+        payment_stream
+            .message()
+            .await
+            .map_err(|e| format!("failed to send keysend payment: {:?}", e))?
+            .ok_or("failed to send keysend payment: no payment update received.")?;
+        Ok(())
+    }
+
+    async fn track_payment(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<Option<tonic_openssl_lnd::lnrpc::Payment>, String> {
+        let mut router_client = self.client_pool.clients().await?.router;
+        let first_update = match router_client
+            .track_payment_v2(tonic_openssl_lnd::routerrpc::TrackPaymentRequest {
+                payment_hash,
+                no_inflight_updates: false,
+            })
+            .await
+        {
+            Ok(response) => response.into_inner().message().await,
+            Err(status) => Err(status),
+        };
+        // The first update is the current state of the payment.
+        match self.client_pool.checked(first_update).await {
+            Ok(Some(payment)) => Ok(Some(payment)),
+            Ok(None) => Err("failed to track payment: no payment update received.".to_string()),
+            Err(status) if status.code() as i32 == GRPC_STATUS_NOT_FOUND => Ok(None),
+            Err(status) => Err(format!("failed to track payment: {:?}", status)),
+        }
+    }
+
+    async fn get_info(&self) -> Result<tonic_openssl_lnd::lnrpc::GetInfoResponse, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            // All calls require at least empty parameter
+            .get_info(tonic_openssl_lnd::lnrpc::GetInfoRequest {})
+            .await;
+        let get_info_resp = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to get lightning node info: {:?}", e))?
+            .into_inner();
+        Ok(get_info_resp)
+    }
+
+    async fn verify_message(
+        &self,
+        msg: &[u8],
+        signature: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::VerifyMessageResponse, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .verify_message(tonic_openssl_lnd::lnrpc::VerifyMessageRequest {
+                msg: msg.to_vec(),
+                signature: signature.to_string(),
+            })
+            .await;
+        let verify_message_resp = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to verify message: {:?}", e))?
+            .into_inner();
+        Ok(verify_message_resp)
+    }
+
+    async fn channel_balance(
+        &self,
+    ) -> Result<tonic_openssl_lnd::lnrpc::ChannelBalanceResponse, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .channel_balance(tonic_openssl_lnd::lnrpc::ChannelBalanceRequest {})
+            .await;
+        let channel_balance_resp = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to get channel balance: {:?}", e))?
+            .into_inner();
+        Ok(channel_balance_resp)
+    }
+
+    async fn wallet_balance(
+        &self,
+    ) -> Result<tonic_openssl_lnd::lnrpc::WalletBalanceResponse, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .wallet_balance(tonic_openssl_lnd::lnrpc::WalletBalanceRequest {})
+            .await;
+        let wallet_balance_resp = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to get wallet balance: {:?}", e))?
+            .into_inner();
+        Ok(wallet_balance_resp)
+    }
+
+    async fn new_address(&self) -> Result<String, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .new_address(tonic_openssl_lnd::lnrpc::NewAddressRequest {
+                r#type: tonic_openssl_lnd::lnrpc::AddressType::WitnessPubkeyHash as i32,
+                ..Default::default()
+            })
+            .await;
+        let new_address_resp = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to get new address: {:?}", e))?
+            .into_inner();
+        Ok(new_address_resp.address)
+    }
+
+    async fn get_transactions(&self) -> Result<Vec<tonic_openssl_lnd::lnrpc::Transaction>, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .get_transactions(tonic_openssl_lnd::lnrpc::GetTransactionsRequest {
+                // Include unconfirmed transactions.
+                end_height: -1,
+                ..Default::default()
+            })
+            .await;
+        let transaction_details = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("failed to get transactions: {:?}", e))?
+            .into_inner();
+        Ok(transaction_details.transactions)
+    }
+
+    async fn subscribe_transactions(&self) -> Result<TransactionStream, String> {
+        let mut lightning_client = self.client_pool.clients().await?.lightning;
+        let response = lightning_client
+            .subscribe_transactions(tonic_openssl_lnd::lnrpc::GetTransactionsRequest::default())
+            .await;
+        let update_stream = self
+            .client_pool
+            .checked(response)
+            .await
+            .map_err(|e| format!("Failed to call subscribe transactions: {:?}", e))?
+            .into_inner()
+            .map_err(|e| format!("Transaction subscription failed: {:?}", e))
+            .boxed();
+        Ok(update_stream)
+    }
+
+    async fn check_health(&self) -> Result<(), String> {
+        self.client_pool.check_health().await
+    }
+}
 
 pub async fn get_lnd_lightning_client(
     lnd_host: String,
@@ -9,7 +617,6 @@ pub async fn get_lnd_lightning_client(
     lnd_tls_cert_path: String,
     lnd_macaroon_path: String,
 ) -> Result<LndLightningClient, String> {
-    // TODO: don't use unwrap.
     let client = connect_lightning(lnd_host, lnd_port, lnd_tls_cert_path, lnd_macaroon_path)
         .await
         .map_err(|e| format!("Failed to get lightning lnd client: {:?}", e))?;
@@ -23,10 +630,22 @@ pub async fn get_lnd_invoices_client(
     lnd_tls_cert_path: String,
     lnd_macaroon_path: String,
 ) -> Result<LndInvoicesClient, String> {
-    // TODO: don't use unwrap.
     let client = connect_invoices(lnd_host, lnd_port, lnd_tls_cert_path, lnd_macaroon_path)
         .await
         .map_err(|e| format!("Failed to get lightning invoices client: {:?}", e))?;
 
     Ok(client)
 }
+
+pub async fn get_lnd_router_client(
+    lnd_host: String,
+    lnd_port: u32,
+    lnd_tls_cert_path: String,
+    lnd_macaroon_path: String,
+) -> Result<LndRouterClient, String> {
+    let client = connect_router(lnd_host, lnd_port, lnd_tls_cert_path, lnd_macaroon_path)
+        .await
+        .map_err(|e| format!("Failed to get lightning router client: {:?}", e))?;
+
+    Ok(client)
+}
@@ -1,8 +1,13 @@
 use crate::base::BaseContext;
 use crate::db::Db;
-use crate::models::{Listing, ListingDisplay, ShippingOption};
+use crate::models::{
+    Listing, ListingDisplay, ListingQuantityInput, ListingVariant, ListingVariantDisplay,
+    ShippingOption,
+};
+use crate::new_listing::MAX_LISTING_QUANTITY;
 use crate::user_account::ActiveUser;
 use rocket::fairing::AdHoc;
+use rocket::form::Form;
 use rocket::request::FlashMessage;
 use rocket::response::Flash;
 use rocket::response::Redirect;
@@ -18,6 +23,8 @@ struct Context {
     base_context: BaseContext,
     flash: Option<(String, String)>,
     listing_display: ListingDisplay,
+    remaining_quantity: u32,
+    variant_displays: Vec<ListingVariantDisplay>,
     user: Option<User>,
     admin_user: Option<AdminUser>,
 }
@@ -44,11 +51,28 @@ impl Context {
         {
             return Err("Listing is not approved.".to_string());
         };
+        let remaining_quantity =
+            Listing::remaining_quantity(&mut db, listing_display.listing.id.unwrap())
+                .await
+                .map_err(|_| "failed to get remaining quantity.")?;
+        let mut variant_displays = vec![];
+        for variant in listing_display.variants.clone() {
+            let remaining_quantity =
+                ListingVariant::remaining_quantity(&mut db, variant.id.unwrap())
+                    .await
+                    .map_err(|_| "failed to get remaining quantity of variant.")?;
+            variant_displays.push(ListingVariantDisplay {
+                variant,
+                remaining_quantity,
+            });
+        }
 
         Ok(Context {
             base_context,
             flash,
             listing_display,
+            remaining_quantity,
+            variant_displays,
             user,
             admin_user,
         })
@@ -243,6 +267,56 @@ async fn deactivate_listing_as_admin(db: &mut Connection<Db>, id: &str) -> Resul
     Ok(())
 }
 
+#[post("/<id>/update_quantity", data = "<quantity_form>")]
+async fn update_quantity(
+    id: &str,
+    quantity_form: Form<ListingQuantityInput>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+) -> Flash<Redirect> {
+    let quantity_input = quantity_form.into_inner();
+
+    match change_quantity(&mut db, id, quantity_input, active_user.user).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/listing", index(id))),
+            "Quantity successfully updated.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/listing", index(id))), e),
+    }
+}
+
+async fn change_quantity(
+    db: &mut Connection<Db>,
+    id: &str,
+    quantity_input: ListingQuantityInput,
+    user: User,
+) -> Result<(), String> {
+    let listing = Listing::single_by_public_id(db, id)
+        .await
+        .map_err(|_| "failed to get listing")?;
+    let new_quantity = quantity_input.quantity.unwrap_or(0);
+    if listing.user_id != user.id() {
+        return Err("Listing belongs to a different user.".to_string());
+    };
+    if new_quantity > MAX_LISTING_QUANTITY {
+        return Err("Quantity is too high.".to_string());
+    };
+    let variants = ListingVariant::all_for_listing(db, listing.id.unwrap())
+        .await
+        .map_err(|_| "failed to get variants for listing")?;
+    if !variants.is_empty() {
+        return Err("Quantity is set on each variant of this listing.".to_string());
+    };
+    if listing.digital {
+        return Err("Quantity is the number of payloads of this listing.".to_string());
+    };
+
+    Listing::set_quantity(db, listing.id.unwrap(), new_quantity)
+        .await
+        .map_err(|_| "failed to update quantity")?;
+    Ok(())
+}
+
 #[get("/<id>")]
 async fn index(
     flash: Option<FlashMessage<'_>>,
@@ -262,7 +336,15 @@ pub fn listing_stage() -> AdHoc {
     AdHoc::on_ignite("Listing Stage", |rocket| async {
         rocket.mount(
             "/listing",
-            routes![index, submit, approve, reject, deactivate, admin_deactivate],
+            routes![
+                index,
+                submit,
+                approve,
+                reject,
+                deactivate,
+                admin_deactivate,
+                update_quantity
+            ],
         )
     })
 }
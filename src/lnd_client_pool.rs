@@ -0,0 +1,182 @@
+use crate::config::Config;
+use crate::lightning::{get_lnd_invoices_client, get_lnd_lightning_client, get_lnd_router_client};
+use crate::util;
+use rocket::tokio::sync::Mutex;
+use tonic_openssl_lnd::LndClientError;
+use tonic_openssl_lnd::LndInvoicesClient;
+use tonic_openssl_lnd::LndLightningClient;
+use tonic_openssl_lnd::LndRouterClient;
+
+const RECONNECT_INITIAL_BACKOFF_MS: u64 = 1000;
+const RECONNECT_MAX_BACKOFF_MS: u64 = 60000;
+const CONNECT_TIMEOUT_S: u64 = 30;
+
+/// The gRPC status code of a call that could not reach LND.
+const GRPC_STATUS_UNAVAILABLE: i32 = 14;
+
+#[derive(Clone)]
+pub struct LndClients {
+    pub lightning: LndLightningClient,
+    pub invoices: LndInvoicesClient,
+    pub router: LndRouterClient,
+}
+
+#[derive(Default)]
+struct LndConnectionState {
+    clients: Option<LndClients>,
+    connecting: bool,
+    consecutive_failures: u32,
+    next_connect_attempt_time_ms: u64,
+    last_error: Option<String>,
+}
+
+/// A long-lived connection to LND, shared by all requests and background tasks.
+///
+/// The connection is opened on first use. When a health check fails, it is
+/// dropped and reopened on a later call, waiting longer after each failed
+/// attempt.
+pub struct LndClientPool {
+    lnd_host: String,
+    lnd_port: u32,
+    lnd_tls_cert_path: String,
+    lnd_macaroon_path: String,
+    state: Mutex<LndConnectionState>,
+}
+
+impl LndClientPool {
+    pub fn new(config: &Config) -> LndClientPool {
+        LndClientPool {
+            lnd_host: config.lnd_host.clone(),
+            lnd_port: config.lnd_port,
+            lnd_tls_cert_path: config.lnd_tls_cert_path.clone(),
+            lnd_macaroon_path: config.lnd_macaroon_path.clone(),
+            state: Mutex::new(LndConnectionState::default()),
+        }
+    }
+
+    /// Returns the clients for the current connection, connecting first if needed.
+    ///
+    /// The state is not locked while connecting, so other calls fail right
+    /// away instead of waiting for a slow or unreachable node.
+    pub async fn clients(&self) -> Result<LndClients, String> {
+        {
+            let mut state = self.state.lock().await;
+            if let Some(clients) = &state.clients {
+                return Ok(clients.clone());
+            }
+            if state.connecting {
+                return Err("LND is unavailable, connection in progress.".to_string());
+            }
+            let now = util::current_time_millis();
+            if now < state.next_connect_attempt_time_ms {
+                return Err(format!(
+                    "LND is unavailable, next reconnect attempt in {} ms: {}",
+                    state.next_connect_attempt_time_ms - now,
+                    state.last_error.clone().unwrap_or_default(),
+                ));
+            }
+            state.connecting = true;
+        }
+        let connect_result = rocket::tokio::time::timeout(
+            rocket::tokio::time::Duration::from_secs(CONNECT_TIMEOUT_S),
+            self.connect(),
+        )
+        .await
+        .unwrap_or_else(|_| Err("LND connection timed out.".to_string()));
+        let mut state = self.state.lock().await;
+        state.connecting = false;
+        match connect_result {
+            Ok(clients) => {
+                state.clients = Some(clients.clone());
+                state.consecutive_failures = 0;
+                state.last_error = None;
+                Ok(clients)
+            }
+            Err(e) => {
+                LndClientPool::record_failure(&mut state, e.clone());
+                Err(e)
+            }
+        }
+    }
+
+    /// Checks the current connection with a GetInfo call, and drops it if
+    /// the call fails.
+    pub async fn check_health(&self) -> Result<(), String> {
+        let mut clients = self.clients().await?;
+        match clients
+            .lightning
+            .get_info(tonic_openssl_lnd::lnrpc::GetInfoRequest {})
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let error = format!("LND health check failed: {:?}", e);
+                let mut state = self.state.lock().await;
+                state.clients = None;
+                LndClientPool::record_failure(&mut state, error.clone());
+                Err(error)
+            }
+        }
+    }
+
+    /// Returns the given result, checking the connection first if it failed
+    /// because LND could not be reached. Errors returned by LND itself, such
+    /// as an unknown invoice or a failed payment, leave the connection as is.
+    pub async fn checked<T>(&self, result: Result<T, LndClientError>) -> Result<T, LndClientError> {
+        if let Err(status) = &result {
+            if is_connection_error(status) {
+                self.check_health().await.ok();
+            }
+        }
+        result
+    }
+
+    async fn connect(&self) -> Result<LndClients, String> {
+        let lightning = get_lnd_lightning_client(
+            self.lnd_host.clone(),
+            self.lnd_port,
+            self.lnd_tls_cert_path.clone(),
+            self.lnd_macaroon_path.clone(),
+        )
+        .await?;
+        let invoices = get_lnd_invoices_client(
+            self.lnd_host.clone(),
+            self.lnd_port,
+            self.lnd_tls_cert_path.clone(),
+            self.lnd_macaroon_path.clone(),
+        )
+        .await?;
+        let router = get_lnd_router_client(
+            self.lnd_host.clone(),
+            self.lnd_port,
+            self.lnd_tls_cert_path.clone(),
+            self.lnd_macaroon_path.clone(),
+        )
+        .await?;
+        Ok(LndClients {
+            lightning,
+            invoices,
+            router,
+        })
+    }
+
+    fn record_failure(state: &mut LndConnectionState, error: String) {
+        let backoff_ms = RECONNECT_INITIAL_BACKOFF_MS
+            .saturating_mul(1 << state.consecutive_failures.min(16))
+            .min(RECONNECT_MAX_BACKOFF_MS);
+        println!(
+            "LND connection failed, retrying in {} ms: {}",
+            backoff_ms, error
+        );
+        state.consecutive_failures += 1;
+        state.next_connect_attempt_time_ms = util::current_time_millis() + backoff_ms;
+        state.last_error = Some(error);
+    }
+}
+
+/// Returns true if a call failed in the transport, before LND could handle it.
+fn is_connection_error(status: &LndClientError) -> bool {
+    status.code() as i32 == GRPC_STATUS_UNAVAILABLE
+        || status.message().starts_with("transport error")
+        || status.message().starts_with("h2 protocol error")
+}
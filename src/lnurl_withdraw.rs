@@ -0,0 +1,307 @@
+use crate::base::BaseContext;
+use crate::config::Config;
+use crate::db::Db;
+use crate::lightning::LightningBackend;
+use crate::models::{AdminSettings, LnurlWithdrawal, Withdrawal};
+use crate::user_account::ActiveUser;
+use crate::util;
+use crate::withdraw;
+use rocket::fairing::AdHoc;
+use rocket::request::FlashMessage;
+use rocket::response::Flash;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+use rocket_auth::AdminUser;
+use rocket_auth::User;
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+use std::sync::Arc;
+
+const LNURL_WITHDRAW_EXPIRY_MS: u64 = 600000;
+const MIN_WITHDRAWABLE_SAT: u64 = 1;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    lnurl_withdrawal: LnurlWithdrawal,
+    lnurl: String,
+    qr_svg_base64: String,
+    expiry_time_ms: u64,
+    expired: bool,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        lnurl_withdrawal_id: &str,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+        config: &Config,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let lnurl_withdrawal = LnurlWithdrawal::single_by_public_id(&mut db, lnurl_withdrawal_id)
+            .await
+            .map_err(|_| "failed to get lnurl withdrawal.")?;
+        if lnurl_withdrawal.user_id != user.id() {
+            return Err("User is not the lnurl withdrawal owner.".to_string());
+        }
+        let lnurl = util::encode_lnurl(&request_url(config, &lnurl_withdrawal.k1));
+        let qr_svg_bytes = util::generate_qr(&lnurl);
+        let qr_svg_base64 = util::to_base64(&qr_svg_bytes);
+        let expiry_time_ms = lnurl_withdrawal.created_time_ms + LNURL_WITHDRAW_EXPIRY_MS;
+        let expired = util::current_time_millis() > expiry_time_ms;
+        Ok(Context {
+            base_context,
+            flash,
+            lnurl_withdrawal,
+            lnurl,
+            qr_svg_base64,
+            expiry_time_ms,
+            expired,
+        })
+    }
+}
+
+/// The first response of the LNURL-withdraw protocol (LUD-03).
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct WithdrawRequest {
+    tag: String,
+    callback: String,
+    k1: String,
+    default_description: String,
+    min_withdrawable: u64,
+    max_withdrawable: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LnurlStatus {
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl LnurlStatus {
+    fn ok() -> LnurlStatus {
+        LnurlStatus {
+            status: "OK".to_string(),
+            reason: None,
+        }
+    }
+
+    fn error(reason: String) -> LnurlStatus {
+        LnurlStatus {
+            status: "ERROR".to_string(),
+            reason: Some(reason),
+        }
+    }
+}
+
+fn request_url(config: &Config, k1: &str) -> String {
+    format!("{}/lnurl_withdraw/request/{}", config.external_url, k1)
+}
+
+fn callback_url(config: &Config) -> String {
+    format!("{}/lnurl_withdraw/callback", config.external_url)
+}
+
+#[post("/new")]
+async fn new(
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match create_lnurl_withdrawal(&mut db, active_user.user.clone()).await {
+        Ok(lnurl_withdrawal_public_id) => Ok(Flash::success(
+            Redirect::to(format!(
+                "/{}/{}",
+                "lnurl_withdraw", lnurl_withdrawal_public_id
+            )),
+            "LNURL-withdraw link created.",
+        )),
+        Err(e) => {
+            error_!("LNURL withdrawal error: {}", e);
+            Err(Flash::error(Redirect::to("/withdraw"), e))
+        }
+    }
+}
+
+async fn create_lnurl_withdrawal(db: &mut Connection<Db>, user: User) -> Result<String, String> {
+    let now = util::current_time_millis();
+
+    if user.is_admin {
+        return Err("Admin user cannot withdraw funds.".to_string());
+    }
+
+    let lnurl_withdrawal = LnurlWithdrawal {
+        id: None,
+        public_id: util::create_uuid(),
+        user_id: user.id(),
+        k1: util::to_hex(&util::create_preimage()),
+        used: false,
+        withdrawal_public_id: "".to_string(),
+        created_time_ms: now,
+    };
+    let public_id = lnurl_withdrawal.public_id.clone();
+    LnurlWithdrawal::insert(lnurl_withdrawal, db)
+        .await
+        .map_err(|_| "failed to insert lnurl withdrawal.")?;
+
+    Ok(public_id)
+}
+
+/// Checks that the request exists and can still be redeemed.
+async fn get_redeemable_lnurl_withdrawal(
+    db: &mut Connection<Db>,
+    k1: &str,
+) -> Result<LnurlWithdrawal, String> {
+    let now = util::current_time_millis();
+    let one_day_in_ms = 24 * 60 * 60 * 1000;
+
+    let lnurl_withdrawal = LnurlWithdrawal::single_by_k1(db, k1)
+        .await
+        .map_err(|_| "Withdraw request not found.")?;
+    if lnurl_withdrawal.used {
+        return Err("Withdraw request already used.".to_string());
+    }
+    if now > lnurl_withdrawal.created_time_ms + LNURL_WITHDRAW_EXPIRY_MS {
+        return Err("Withdraw request expired.".to_string());
+    }
+    let withdrawal_count =
+        Withdrawal::count_for_user_since_time_ms(db, lnurl_withdrawal.user_id, now - one_day_in_ms)
+            .await
+            .map_err(|_| "failed to get withdrawal count.")?;
+    if withdrawal_count >= withdraw::MAX_WITHDRAWALS_PER_USER_PER_DAY {
+        return Err(format!(
+            "More than {:?} withdrawals in a single day not allowed.",
+            withdraw::MAX_WITHDRAWALS_PER_USER_PER_DAY,
+        ));
+    }
+    Ok(lnurl_withdrawal)
+}
+
+#[get("/request/<k1>")]
+async fn request(
+    k1: &str,
+    mut db: Connection<Db>,
+    config: &State<Config>,
+) -> Result<Json<WithdrawRequest>, Json<LnurlStatus>> {
+    match get_withdraw_request(k1, &mut db, config).await {
+        Ok(withdraw_request) => Ok(Json(withdraw_request)),
+        Err(e) => Err(Json(LnurlStatus::error(e))),
+    }
+}
+
+async fn get_withdraw_request(
+    k1: &str,
+    db: &mut Connection<Db>,
+    config: &Config,
+) -> Result<WithdrawRequest, String> {
+    let lnurl_withdrawal = get_redeemable_lnurl_withdrawal(db, k1).await?;
+    let max_withdrawable_sat = withdraw::max_withdrawable_sat(db, lnurl_withdrawal.user_id).await?;
+    if max_withdrawable_sat < MIN_WITHDRAWABLE_SAT {
+        return Err("Insufficient funds for withdrawal.".to_string());
+    }
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    Ok(WithdrawRequest {
+        tag: "withdrawRequest".to_string(),
+        callback: callback_url(config),
+        k1: lnurl_withdrawal.k1,
+        default_description: format!("Withdrawal from {}", admin_settings.market_name),
+        min_withdrawable: MIN_WITHDRAWABLE_SAT * 1000,
+        max_withdrawable: max_withdrawable_sat * 1000,
+    })
+}
+
+#[get("/callback?<k1>&<pr>")]
+async fn callback(
+    k1: &str,
+    pr: &str,
+    mut db: Connection<Db>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+) -> Json<LnurlStatus> {
+    match redeem_lnurl_withdrawal(k1, pr, &mut db, lightning_backend.inner().as_ref()).await {
+        Ok(_) => Json(LnurlStatus::ok()),
+        Err(e) => {
+            error_!("LNURL withdrawal error: {}", e);
+            Json(LnurlStatus::error(e))
+        }
+    }
+}
+
+async fn redeem_lnurl_withdrawal(
+    k1: &str,
+    invoice_payment_request: &str,
+    db: &mut Connection<Db>,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+
+    let lnurl_withdrawal = get_redeemable_lnurl_withdrawal(db, k1).await?;
+    let decoded_pay_req = lightning_backend
+        .decode_pay_req(invoice_payment_request)
+        .await?;
+    let amount_sat: u64 = decoded_pay_req.num_satoshis.try_into().unwrap();
+    let max_withdrawable_sat = withdraw::max_withdrawable_sat(db, lnurl_withdrawal.user_id).await?;
+    if amount_sat < MIN_WITHDRAWABLE_SAT || amount_sat > max_withdrawable_sat {
+        return Err(format!(
+            "Amount must be between {} and {} sats.",
+            MIN_WITHDRAWABLE_SAT, max_withdrawable_sat
+        ));
+    }
+
+    let lnurl_withdrawal_id = lnurl_withdrawal.id.unwrap();
+    let is_unused =
+        LnurlWithdrawal::mark_as_used(db, lnurl_withdrawal_id, now - LNURL_WITHDRAW_EXPIRY_MS)
+            .await
+            .map_err(|_| "failed to mark withdraw request as used.")?;
+    if !is_unused {
+        return Err("Withdraw request already used.".to_string());
+    }
+
+    let withdrawal_public_id = withdraw::send_withdrawal(
+        invoice_payment_request,
+        None,
+        db,
+        lnurl_withdrawal.user_id,
+        lightning_backend,
+    )
+    .await?;
+    LnurlWithdrawal::set_withdrawal_public_id(db, lnurl_withdrawal_id, &withdrawal_public_id)
+        .await
+        .map_err(|_| "failed to update withdraw request.")?;
+
+    Ok(())
+}
+
+#[get("/<id>")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    id: &str,
+    db: Connection<Db>,
+    active_user: ActiveUser,
+    admin_user: Option<AdminUser>,
+    config: &State<Config>,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, id, flash, active_user.user, admin_user, config)
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("lnurlwithdraw", context))
+}
+
+pub fn lnurl_withdraw_stage() -> AdHoc {
+    AdHoc::on_ignite("LNURL Withdraw Stage", |rocket| async {
+        rocket.mount("/lnurl_withdraw", routes![index, new, request, callback])
+    })
+}
@@ -11,18 +11,30 @@ mod activate_account;
 mod active_users;
 mod admin;
 mod auth;
+mod auto_payout;
 mod base;
+mod cart;
 mod config;
 mod db;
 mod deactivate_account;
 mod deactivated_listings;
 mod delete_listing;
+mod digital_delivery;
 mod disabled_users;
+mod dispute;
+mod disputes;
+mod exchange_rate_provider;
+mod exchange_rate_update;
 mod image_util;
 mod lightning;
 mod listing;
 mod listings;
+mod lnd_client_pool;
+mod lnurl_withdraw;
 mod market_liabilities;
+mod market_revenue;
+mod market_withdraw;
+mod mock_lightning;
 mod models;
 mod my_account_balance;
 mod my_active_listings;
@@ -36,23 +48,36 @@ mod my_unsubmitted_listings;
 mod new_listing;
 mod order;
 mod order_expiry;
+mod order_finalization;
 mod payment_processor;
+mod payout_resolver;
 mod prepare_order;
 mod review_pending_listings;
 mod routes;
 mod search;
 mod seller_history;
+mod solvency;
+mod solvency_check;
 mod top_sellers;
+mod unread_messages;
+mod update_auto_payout;
+mod update_digital_payloads;
 mod update_fee_rate;
+mod update_finalization;
+mod update_invoice_expiry;
 mod update_listing_images;
+mod update_listing_variants;
 mod update_market_name;
 mod update_max_allowed_users;
+mod update_payout_node;
 mod update_pgp_info;
+mod update_shipping_deadline;
 mod update_shipping_options;
 mod update_squeaknode_info;
 mod update_user_bond_price;
 mod update_user_pgp_info;
 mod update_user_squeaknode_info;
+mod update_withdrawal_fee_limit;
 mod user;
 mod user_account;
 mod user_account_expiry;
@@ -60,6 +85,7 @@ mod user_profile;
 mod util;
 mod withdraw;
 mod withdrawal;
+mod withdrawal_processor;
 
 #[launch]
 fn rocket() -> _ {
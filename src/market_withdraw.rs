@@ -0,0 +1,232 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::lightning::LightningBackend;
+use crate::models::{
+    AccountInfo, AdminSettings, Withdrawal, WithdrawalInfo, WithdrawalStatus,
+    MIN_WITHDRAWAL_FEE_LIMIT_SAT,
+};
+use crate::util;
+use crate::withdraw;
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::Flash;
+use rocket::response::Redirect;
+use rocket::serde::Serialize;
+use rocket::State;
+use rocket_auth::AdminUser;
+use rocket_auth::User;
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+use std::sync::Arc;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    market_revenue_sat: i64,
+    total_market_liabilities_sat: i64,
+    channel_balance_sat: u64,
+    max_withdrawable_sat: u64,
+    min_withdrawal_fee_limit_sat: u64,
+    admin_settings: AdminSettings,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+        lightning_backend: &dyn LightningBackend,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let market_revenue_sat = AccountInfo::total_market_revenue_sat(&mut db)
+            .await
+            .map_err(|_| "failed to get market revenue.")?;
+        let total_market_liabilities_sat = AccountInfo::total_market_liabilities_sat(&mut db)
+            .await
+            .map_err(|_| "failed to get total market liabilities.")?;
+        let channel_balance_sat = get_channel_balance_sat(lightning_backend).await?;
+        let max_withdrawable_sat = max_withdrawable_sat(&mut db, lightning_backend).await?;
+        let admin_settings = AdminSettings::single(&mut db)
+            .await
+            .map_err(|_| "failed to get admin settings.")?;
+        Ok(Context {
+            base_context,
+            flash,
+            market_revenue_sat,
+            total_market_liabilities_sat,
+            channel_balance_sat,
+            max_withdrawable_sat,
+            min_withdrawal_fee_limit_sat: MIN_WITHDRAWAL_FEE_LIMIT_SAT,
+            admin_settings,
+        })
+    }
+}
+
+#[post("/new", data = "<withdrawal_form>")]
+async fn new(
+    withdrawal_form: Form<WithdrawalInfo>,
+    mut db: Connection<Db>,
+    user: User,
+    _admin_user: AdminUser,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let withdrawal_info = withdrawal_form.into_inner();
+    match withdraw_market_revenue(
+        withdrawal_info.clone(),
+        &mut db,
+        user,
+        lightning_backend.inner().as_ref(),
+    )
+    .await
+    {
+        Ok(withdrawal_public_id) => Ok(Flash::success(
+            Redirect::to(format!("/{}/{}", "withdrawal", withdrawal_public_id)),
+            "Withdrawal payment sent.",
+        )),
+        Err(e) => {
+            error_!("Market withdrawal error: {}", e);
+            Err(Flash::error(
+                Redirect::to(uri!("/market_withdraw", index())),
+                e,
+            ))
+        }
+    }
+}
+
+async fn withdraw_market_revenue(
+    withdrawal_info: WithdrawalInfo,
+    db: &mut Connection<Db>,
+    user: User,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
+    let now = util::current_time_millis();
+    let invoice_payment_request = withdrawal_info.invoice_payment_request;
+
+    if invoice_payment_request.is_empty() {
+        return Err("Invoice payment request cannot be empty.".to_string());
+    };
+
+    let max_withdrawable_sat = max_withdrawable_sat(db, lightning_backend).await?;
+    let decoded_pay_req = lightning_backend
+        .decode_pay_req(&invoice_payment_request)
+        .await?;
+    let invoice_amount_sat: u64 = decoded_pay_req.num_satoshis.try_into().unwrap();
+    let amount_sat = match (invoice_amount_sat, withdrawal_info.amount_sat) {
+        (0, Some(amount_sat)) => amount_sat,
+        (0, None) => max_withdrawable_sat,
+        (invoice_amount_sat, None) => invoice_amount_sat,
+        (invoice_amount_sat, Some(amount_sat)) => {
+            if amount_sat != invoice_amount_sat {
+                return Err("Amount does not match the invoice amount.".to_string());
+            }
+            amount_sat
+        }
+    };
+    if amount_sat == 0 {
+        return Err("Withdrawal amount must be greater than zero.".to_string());
+    }
+    if amount_sat > max_withdrawable_sat {
+        return Err(format!(
+            "Amount cannot be more than {} sats.",
+            max_withdrawable_sat
+        ));
+    }
+    // Only an invoice without an amount can be paid with a chosen amount.
+    let maybe_send_amount_sat = if invoice_amount_sat == 0 {
+        Some(amount_sat)
+    } else {
+        None
+    };
+
+    // The market always pays the routing fee of its own withdrawals.
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    let withdrawal = Withdrawal {
+        id: None,
+        public_id: util::create_uuid(),
+        user_id: user.id(),
+        amount_sat,
+        invoice_hash: decoded_pay_req.payment_hash,
+        invoice_payment_request,
+        created_time_ms: now,
+        status: WithdrawalStatus::Pending,
+        failure_reason: "".to_string(),
+        fee_limit_sat: admin_settings.withdrawal_fee_limit_sat(amount_sat),
+        fee_sat: 0,
+        fee_paid_by_user: false,
+        from_market_revenue: true,
+        keysend_pubkey: "".to_string(),
+    };
+
+    withdraw::record_and_send_withdrawal(withdrawal, maybe_send_amount_sat, db, lightning_backend)
+        .await
+}
+
+async fn get_channel_balance_sat(lightning_backend: &dyn LightningBackend) -> Result<u64, String> {
+    let channel_balance_resp = lightning_backend.channel_balance().await?;
+    Ok(channel_balance_resp
+        .local_balance
+        .map(|local_balance| local_balance.sat)
+        .unwrap_or(0))
+}
+
+/// Returns the largest amount the market can withdraw, leaving room for the
+/// routing fee.
+///
+/// The amount is limited both by the market revenue, and by the funds in the
+/// node that are not owed to users.
+async fn max_withdrawable_sat(
+    db: &mut Connection<Db>,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<u64, String> {
+    let market_revenue_sat = AccountInfo::total_market_revenue_sat(db)
+        .await
+        .map_err(|_| "failed to get market revenue.")?;
+    let total_market_liabilities_sat = AccountInfo::total_market_liabilities_sat(db)
+        .await
+        .map_err(|_| "failed to get total market liabilities.")?;
+    let channel_balance_sat: i64 = get_channel_balance_sat(lightning_backend)
+        .await?
+        .try_into()
+        .unwrap();
+    let available_sat = market_revenue_sat.min(channel_balance_sat - total_market_liabilities_sat);
+    let available_sat: u64 = available_sat.max(0).try_into().unwrap();
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    Ok(available_sat.saturating_sub(admin_settings.withdrawal_fee_limit_sat(available_sat)))
+}
+
+#[get("/")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    db: Connection<Db>,
+    user: User,
+    admin_user: AdminUser,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(
+        db,
+        flash,
+        user,
+        Some(admin_user),
+        lightning_backend.inner().as_ref(),
+    )
+    .await
+    .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("marketwithdraw", context))
+}
+
+pub fn market_withdraw_stage() -> AdHoc {
+    AdHoc::on_ignite("Market Withdraw Stage", |rocket| async {
+        rocket.mount("/market_withdraw", routes![index, new])
+    })
+}
@@ -0,0 +1,657 @@
+use crate::lightning::{InvoiceStream, LightningBackend, TransactionStream};
+use crate::rocket::futures::stream;
+use crate::rocket::futures::StreamExt;
+use crate::util;
+use rocket::tokio::sync::broadcast;
+use std::sync::Mutex;
+use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
+use tonic_openssl_lnd::lnrpc::payment::PaymentStatus;
+use tonic_openssl_lnd::lnrpc::PaymentFailureReason;
+
+const MOCK_NODE_PUBKEY: &str = "020000000000000000000000000000000000000000000000000000000000000001";
+const MOCK_PAY_REQ_PREFIX: &str = "lnmock";
+const MOCK_SIGNATURE_PREFIX: &str = "mocksig";
+const MOCK_ADDRESS_PREFIX: &str = "bcrt1qmock";
+const SETTLED_INVOICES_CHANNEL_CAPACITY: usize = 1024;
+const TRANSACTIONS_CHANNEL_CAPACITY: usize = 1024;
+
+/// An in-memory lightning backend for tests and staging.
+///
+/// Payment hashes and payment requests are derived from a counter, so a
+/// sequence of calls always produces the same values. Invoices created by
+/// this backend are paid either by calling `receive_payment` or by paying
+/// them with `pay_invoice` or `send_payment`. Paying any other mock payment
+/// request always succeeds. Wallet addresses are also derived from a counter,
+/// and on-chain payments to them are made with `receive_onchain_payment`.
+pub struct MockLightningBackend {
+    state: Mutex<MockLightningState>,
+    settled_invoices_sender: broadcast::Sender<tonic_openssl_lnd::lnrpc::Invoice>,
+    transactions_sender: broadcast::Sender<tonic_openssl_lnd::lnrpc::Transaction>,
+}
+
+#[derive(Default)]
+struct MockLightningState {
+    invoices: Vec<tonic_openssl_lnd::lnrpc::Invoice>,
+    payments: Vec<tonic_openssl_lnd::lnrpc::Payment>,
+    transactions: Vec<tonic_openssl_lnd::lnrpc::Transaction>,
+    next_index: u64,
+    next_settle_index: u64,
+    next_address_index: u64,
+}
+
+impl MockLightningState {
+    /// Cancels open invoices that are past their expiry, as LND does.
+    fn cancel_expired_invoices(&mut self) {
+        let now_s = (util::current_time_millis() / 1000) as i64;
+        for invoice in self.invoices.iter_mut() {
+            if invoice.state == InvoiceState::Open as i32
+                && invoice.expiry > 0
+                && now_s >= invoice.creation_date + invoice.expiry
+            {
+                invoice.state = InvoiceState::Canceled as i32;
+            }
+        }
+    }
+}
+
+impl MockLightningBackend {
+    pub fn new() -> MockLightningBackend {
+        let (settled_invoices_sender, _) = broadcast::channel(SETTLED_INVOICES_CHANNEL_CAPACITY);
+        let (transactions_sender, _) = broadcast::channel(TRANSACTIONS_CHANNEL_CAPACITY);
+        MockLightningBackend {
+            state: Mutex::new(MockLightningState::default()),
+            settled_invoices_sender,
+            transactions_sender,
+        }
+    }
+
+    /// Pays to a wallet address, as if the transaction came from a remote
+    /// wallet. Returns the transaction hash.
+    #[cfg(test)]
+    pub fn receive_onchain_payment(
+        &self,
+        address: &str,
+        amount_sat: u64,
+        num_confirmations: i32,
+    ) -> String {
+        let transaction = {
+            let mut state = self.state.lock().unwrap();
+            let tx_index = state.transactions.len() as u64 + 1;
+            let transaction = tonic_openssl_lnd::lnrpc::Transaction {
+                tx_hash: util::to_hex(&MockLightningBackend::counter_bytes(4, tx_index)),
+                amount: amount_sat as i64,
+                num_confirmations,
+                time_stamp: (util::current_time_millis() / 1000) as i64,
+                output_details: vec![tonic_openssl_lnd::lnrpc::OutputDetail {
+                    address: address.to_string(),
+                    amount: amount_sat as i64,
+                    is_our_address: true,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            state.transactions.push(transaction.clone());
+            transaction
+        };
+        // Sending only fails when there are no subscribers.
+        self.transactions_sender.send(transaction.clone()).ok();
+        transaction.tx_hash
+    }
+
+    /// Pays an open invoice, as if the payment came from a remote node.
+    ///
+    /// Regular invoices are settled right away. Hold invoices are only
+    /// accepted, and are settled later by `settle_hold_invoice`.
+    pub fn receive_payment(&self, payment_hash: &[u8]) -> Result<(), String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.cancel_expired_invoices();
+            let invoice = state
+                .invoices
+                .iter_mut()
+                .find(|invoice| invoice.r_hash == payment_hash)
+                .ok_or("Invoice not found.")?;
+            if invoice.state != InvoiceState::Open as i32 {
+                return Err("Invoice is not open.".to_string());
+            }
+            invoice.amt_paid_msat = invoice.value_msat;
+            invoice.amt_paid_sat = invoice.value;
+            if invoice.r_preimage.is_empty() {
+                invoice.state = InvoiceState::Accepted as i32;
+                return Ok(());
+            }
+        }
+        self.settle(payment_hash, None)
+    }
+
+    #[allow(deprecated)]
+    fn settle(&self, payment_hash: &[u8], preimage: Option<Vec<u8>>) -> Result<(), String> {
+        let settled_invoice = {
+            let mut state = self.state.lock().unwrap();
+            let settle_index = state.next_settle_index + 1;
+            let invoice = state
+                .invoices
+                .iter_mut()
+                .find(|invoice| invoice.r_hash == payment_hash)
+                .ok_or("Invoice not found.")?;
+            if let Some(preimage) = preimage {
+                invoice.r_preimage = preimage;
+            }
+            invoice.settled = true;
+            invoice.state = InvoiceState::Settled as i32;
+            invoice.settle_index = settle_index;
+            invoice.settle_date = (util::current_time_millis() / 1000) as i64;
+            let settled_invoice = invoice.clone();
+            state.next_settle_index = settle_index;
+            settled_invoice
+        };
+        // Sending only fails when there are no subscribers.
+        self.settled_invoices_sender.send(settled_invoice).ok();
+        Ok(())
+    }
+
+    /// Returns all payments sent through this backend, oldest first.
+    #[cfg(test)]
+    pub fn payments(&self) -> Vec<tonic_openssl_lnd::lnrpc::Payment> {
+        self.state.lock().unwrap().payments.clone()
+    }
+
+    /// Pays a payment request, and records the payment as succeeded or failed.
+    ///
+    /// Like LND, an amount is required for a payment request without an
+    /// amount, and is rejected for one with an amount.
+    fn pay(
+        &self,
+        pay_req: &str,
+        amount_sat: Option<u64>,
+    ) -> Result<tonic_openssl_lnd::lnrpc::Payment, String> {
+        let (invoice_amount_msat, payment_hash) = MockLightningBackend::parse_pay_req(pay_req)?;
+        let amount_msat = match (invoice_amount_msat, amount_sat) {
+            (0, Some(amount_sat)) => (amount_sat as i64) * 1000,
+            (0, None) => {
+                return Err("failed to send payment: amount must be specified.".to_string())
+            }
+            (invoice_amount_msat, None) => invoice_amount_msat,
+            (_, Some(_)) => {
+                return Err(
+                    "failed to send payment: amount must not be specified for this invoice."
+                        .to_string(),
+                )
+            }
+        };
+        let payment_hash_hex = util::to_hex(&payment_hash);
+        let (is_own_invoice, is_already_paid) = {
+            let state = self.state.lock().unwrap();
+            let is_own_invoice = state
+                .invoices
+                .iter()
+                .any(|invoice| invoice.r_hash == payment_hash);
+            let is_already_paid = state.payments.iter().any(|payment| {
+                payment.payment_hash == payment_hash_hex
+                    && payment.status == PaymentStatus::Succeeded as i32
+            });
+            (is_own_invoice, is_already_paid)
+        };
+        if is_already_paid {
+            return Err("failed to send payment: invoice is already paid.".to_string());
+        }
+        // Paying one of our own invoices settles it.
+        let payment_succeeded = !is_own_invoice || self.receive_payment(&payment_hash).is_ok();
+        let mut state = self.state.lock().unwrap();
+        let payment_index = state.payments.len() as u64 + 1;
+        let creation_time_ns = (util::current_time_millis() * 1_000_000) as i64;
+        let payment = if payment_succeeded {
+            tonic_openssl_lnd::lnrpc::Payment {
+                payment_hash: payment_hash_hex,
+                payment_preimage: util::to_hex(&MockLightningBackend::counter_bytes(
+                    3,
+                    payment_index,
+                )),
+                value_msat: amount_msat,
+                value_sat: amount_msat / 1000,
+                payment_request: pay_req.to_string(),
+                status: PaymentStatus::Succeeded as i32,
+                creation_time_ns,
+                payment_index,
+                ..Default::default()
+            }
+        } else {
+            tonic_openssl_lnd::lnrpc::Payment {
+                payment_hash: payment_hash_hex,
+                value_msat: amount_msat,
+                value_sat: amount_msat / 1000,
+                payment_request: pay_req.to_string(),
+                status: PaymentStatus::Failed as i32,
+                failure_reason: PaymentFailureReason::FailureReasonIncorrectPaymentDetails as i32,
+                creation_time_ns,
+                payment_index,
+                ..Default::default()
+            }
+        };
+        state.payments.push(payment.clone());
+        Ok(payment)
+    }
+
+    pub fn make_pay_req(amount_msat: i64, payment_hash: &[u8]) -> String {
+        format!(
+            "{}{}x{}",
+            MOCK_PAY_REQ_PREFIX,
+            amount_msat,
+            util::to_hex(&payment_hash.to_vec())
+        )
+    }
+
+    fn parse_pay_req(pay_req: &str) -> Result<(i64, Vec<u8>), String> {
+        let (amount_msat_str, payment_hash_hex) = pay_req
+            .strip_prefix(MOCK_PAY_REQ_PREFIX)
+            .and_then(|rest| rest.split_once('x'))
+            .ok_or("failed to decode payment request string.")?;
+        let amount_msat: i64 = amount_msat_str
+            .parse()
+            .map_err(|_| "failed to decode payment request amount.")?;
+        let payment_hash =
+            hex::decode(payment_hash_hex).map_err(|_| "failed to decode payment hash.")?;
+        Ok((amount_msat, payment_hash))
+    }
+
+    fn counter_bytes(tag: u8, index: u64) -> Vec<u8> {
+        let mut bytes = vec![0u8; 32];
+        bytes[0] = tag;
+        bytes[24..].copy_from_slice(&index.to_be_bytes());
+        bytes
+    }
+}
+
+impl Default for MockLightningBackend {
+    fn default() -> MockLightningBackend {
+        MockLightningBackend::new()
+    }
+}
+
+#[rocket::async_trait]
+impl LightningBackend for MockLightningBackend {
+    async fn create_invoice(
+        &self,
+        amount_sat: u64,
+        expiry_s: u32,
+    ) -> Result<tonic_openssl_lnd::lnrpc::AddInvoiceResponse, String> {
+        let mut state = self.state.lock().unwrap();
+        state.next_index += 1;
+        let add_index = state.next_index;
+        let r_preimage = MockLightningBackend::counter_bytes(1, add_index);
+        let r_hash = MockLightningBackend::counter_bytes(2, add_index);
+        let value_msat = (amount_sat as i64) * 1000;
+        let payment_request = MockLightningBackend::make_pay_req(value_msat, &r_hash);
+        let invoice = tonic_openssl_lnd::lnrpc::Invoice {
+            r_preimage,
+            r_hash: r_hash.clone(),
+            value: amount_sat as i64,
+            value_msat,
+            creation_date: (util::current_time_millis() / 1000) as i64,
+            payment_request: payment_request.clone(),
+            add_index,
+            expiry: expiry_s as i64,
+            state: InvoiceState::Open as i32,
+            ..Default::default()
+        };
+        state.invoices.push(invoice);
+        Ok(tonic_openssl_lnd::lnrpc::AddInvoiceResponse {
+            r_hash,
+            payment_request,
+            add_index,
+            ..Default::default()
+        })
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        amount_sat: u64,
+        payment_hash: Vec<u8>,
+        expiry_s: u32,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::AddHoldInvoiceResp, String> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .invoices
+            .iter()
+            .any(|invoice| invoice.r_hash == payment_hash)
+        {
+            return Err("failed to add hold invoice: invoice already exists.".to_string());
+        }
+        state.next_index += 1;
+        let add_index = state.next_index;
+        let value_msat = (amount_sat as i64) * 1000;
+        let payment_request = MockLightningBackend::make_pay_req(value_msat, &payment_hash);
+        let invoice = tonic_openssl_lnd::lnrpc::Invoice {
+            r_hash: payment_hash,
+            value: amount_sat as i64,
+            value_msat,
+            creation_date: (util::current_time_millis() / 1000) as i64,
+            payment_request: payment_request.clone(),
+            add_index,
+            expiry: expiry_s as i64,
+            state: InvoiceState::Open as i32,
+            ..Default::default()
+        };
+        state.invoices.push(invoice);
+        Ok(tonic_openssl_lnd::invoicesrpc::AddHoldInvoiceResp {
+            payment_request,
+            add_index,
+            ..Default::default()
+        })
+    }
+
+    async fn settle_hold_invoice(
+        &self,
+        preimage: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::SettleInvoiceResp, String> {
+        let payment_hash = util::sha256(&preimage);
+        let is_accepted = self.state.lock().unwrap().invoices.iter().any(|invoice| {
+            invoice.r_hash == payment_hash && invoice.state == InvoiceState::Accepted as i32
+        });
+        if !is_accepted {
+            return Err("failed to settle invoice: invoice is not accepted.".to_string());
+        }
+        self.settle(&payment_hash, Some(preimage))?;
+        Ok(tonic_openssl_lnd::invoicesrpc::SettleInvoiceResp {})
+    }
+
+    async fn cancel_invoice(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String> {
+        let mut state = self.state.lock().unwrap();
+        let invoice = state
+            .invoices
+            .iter_mut()
+            .find(|invoice| invoice.r_hash == payment_hash)
+            .ok_or("failed to cancel invoice: not found.")?;
+        if invoice.state == InvoiceState::Settled as i32 {
+            return Err("failed to cancel invoice: already settled.".to_string());
+        }
+        invoice.state = InvoiceState::Canceled as i32;
+        Ok(tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp {})
+    }
+
+    async fn lookup_invoice(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<tonic_openssl_lnd::lnrpc::Invoice, String> {
+        let mut state = self.state.lock().unwrap();
+        state.cancel_expired_invoices();
+        state
+            .invoices
+            .iter()
+            .find(|invoice| invoice.r_hash == payment_hash)
+            .cloned()
+            .ok_or_else(|| "Failed to lookup invoice: not found.".to_string())
+    }
+
+    async fn list_invoices(
+        &self,
+        index_offset: u64,
+        num_max_invoices: u64,
+        reversed: bool,
+    ) -> Result<tonic_openssl_lnd::lnrpc::ListInvoiceResponse, String> {
+        let state = self.state.lock().unwrap();
+        let num_max_invoices = num_max_invoices as usize;
+        // Invoices are stored in add index order.
+        let invoices = if reversed {
+            let before_offset = state
+                .invoices
+                .iter()
+                .filter(|invoice| index_offset == 0 || invoice.add_index < index_offset)
+                .collect::<Vec<_>>();
+            let start = before_offset.len().saturating_sub(num_max_invoices);
+            before_offset[start..]
+                .iter()
+                .map(|invoice| (*invoice).clone())
+                .collect::<Vec<_>>()
+        } else {
+            state
+                .invoices
+                .iter()
+                .filter(|invoice| invoice.add_index > index_offset)
+                .take(num_max_invoices)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        Ok(tonic_openssl_lnd::lnrpc::ListInvoiceResponse {
+            first_index_offset: invoices
+                .first()
+                .map(|invoice| invoice.add_index)
+                .unwrap_or(0),
+            last_index_offset: invoices
+                .last()
+                .map(|invoice| invoice.add_index)
+                .unwrap_or(0),
+            invoices,
+        })
+    }
+
+    async fn subscribe_invoices(&self, settle_index: u64) -> Result<InvoiceStream, String> {
+        // Subscribe before taking the snapshot, so that no settlement is missed.
+        let receiver = self.settled_invoices_sender.subscribe();
+        let already_settled = {
+            let state = self.state.lock().unwrap();
+            let mut invoices = state
+                .invoices
+                .iter()
+                // Like LND, only replay past settlements for a non-zero index.
+                .filter(|invoice| settle_index > 0 && invoice.settle_index > settle_index)
+                .cloned()
+                .collect::<Vec<_>>();
+            invoices.sort_by_key(|invoice| invoice.settle_index);
+            invoices
+        };
+        let new_settlements = stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(invoice) => return Some((Ok(invoice), receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(stream::iter(already_settled.into_iter().map(Ok))
+            .chain(new_settlements)
+            .boxed())
+    }
+
+    async fn new_address(&self) -> Result<String, String> {
+        let mut state = self.state.lock().unwrap();
+        state.next_address_index += 1;
+        Ok(format!(
+            "{}{:032}",
+            MOCK_ADDRESS_PREFIX, state.next_address_index
+        ))
+    }
+
+    async fn get_transactions(&self) -> Result<Vec<tonic_openssl_lnd::lnrpc::Transaction>, String> {
+        Ok(self.state.lock().unwrap().transactions.clone())
+    }
+
+    async fn subscribe_transactions(&self) -> Result<TransactionStream, String> {
+        let receiver = self.transactions_sender.subscribe();
+        let new_transactions = stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(transaction) => return Some((Ok(transaction), receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(new_transactions.boxed())
+    }
+
+    async fn decode_pay_req(
+        &self,
+        pay_req: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::PayReq, String> {
+        let (amount_msat, payment_hash) = MockLightningBackend::parse_pay_req(pay_req)?;
+        Ok(tonic_openssl_lnd::lnrpc::PayReq {
+            destination: MOCK_NODE_PUBKEY.to_string(),
+            payment_hash: util::to_hex(&payment_hash),
+            num_satoshis: amount_msat / 1000,
+            num_msat: amount_msat,
+            ..Default::default()
+        })
+    }
+
+    async fn pay_invoice(
+        &self,
+        pay_req: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::SendResponse, String> {
+        let payment = self.pay(pay_req, None)?;
+        if payment.status != PaymentStatus::Succeeded as i32 {
+            return Err("Send Payment failure: incorrect payment details.".to_string());
+        }
+        Ok(tonic_openssl_lnd::lnrpc::SendResponse {
+            payment_preimage: util::from_hex(&payment.payment_preimage),
+            payment_hash: util::from_hex(&payment.payment_hash),
+            ..Default::default()
+        })
+    }
+
+    async fn send_payment(
+        &self,
+        pay_req: &str,
+        amount_sat: Option<u64>,
+        _fee_limit_sat: u64,
+    ) -> Result<(), String> {
+        self.pay(pay_req, amount_sat)?;
+        Ok(())
+    }
+
+    /// Keysend payments to any node always succeed.
+    async fn send_keysend_payment(
+        &self,
+        dest_pubkey: Vec<u8>,
+        amount_sat: u64,
+        preimage: Vec<u8>,
+        _fee_limit_sat: u64,
+    ) -> Result<(), String> {
+        if dest_pubkey.len() != 33 {
+            return Err("failed to send keysend payment: invalid destination.".to_string());
+        }
+        let payment_hash_hex = util::to_hex(&util::sha256(&preimage));
+        let mut state = self.state.lock().unwrap();
+        if state
+            .payments
+            .iter()
+            .any(|payment| payment.payment_hash == payment_hash_hex)
+        {
+            return Err("failed to send keysend payment: payment already exists.".to_string());
+        }
+        let payment_index = state.payments.len() as u64 + 1;
+        let amount_msat = (amount_sat as i64) * 1000;
+        state.payments.push(tonic_openssl_lnd::lnrpc::Payment {
+            payment_hash: payment_hash_hex,
+            payment_preimage: util::to_hex(&preimage),
+            value_msat: amount_msat,
+            value_sat: amount_msat / 1000,
+            status: PaymentStatus::Succeeded as i32,
+            creation_time_ns: (util::current_time_millis() * 1_000_000) as i64,
+            payment_index,
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    async fn track_payment(
+        &self,
+        payment_hash: Vec<u8>,
+    ) -> Result<Option<tonic_openssl_lnd::lnrpc::Payment>, String> {
+        let payment_hash_hex = util::to_hex(&payment_hash);
+        let state = self.state.lock().unwrap();
+        // Like LND, report the successful attempt if there is one.
+        let payments = state
+            .payments
+            .iter()
+            .filter(|payment| payment.payment_hash == payment_hash_hex)
+            .collect::<Vec<_>>();
+        let maybe_payment = payments
+            .iter()
+            .find(|payment| payment.status == PaymentStatus::Succeeded as i32)
+            .or_else(|| payments.last())
+            .map(|payment| (*payment).clone());
+        Ok(maybe_payment)
+    }
+
+    async fn get_info(&self) -> Result<tonic_openssl_lnd::lnrpc::GetInfoResponse, String> {
+        Ok(tonic_openssl_lnd::lnrpc::GetInfoResponse {
+            identity_pubkey: MOCK_NODE_PUBKEY.to_string(),
+            alias: "mock".to_string(),
+            synced_to_chain: true,
+            synced_to_graph: true,
+            ..Default::default()
+        })
+    }
+
+    /// Accepts signatures of the form "mocksig<pubkey>x<hex sha256 of msg>".
+    async fn verify_message(
+        &self,
+        msg: &[u8],
+        signature: &str,
+    ) -> Result<tonic_openssl_lnd::lnrpc::VerifyMessageResponse, String> {
+        let maybe_pubkey = signature
+            .strip_prefix(MOCK_SIGNATURE_PREFIX)
+            .and_then(|rest| rest.split_once('x'))
+            .filter(|(_, msg_hash_hex)| *msg_hash_hex == util::to_hex(&util::sha256(msg)))
+            .map(|(pubkey, _)| pubkey.to_string());
+        Ok(match maybe_pubkey {
+            Some(pubkey) => tonic_openssl_lnd::lnrpc::VerifyMessageResponse {
+                valid: true,
+                pubkey,
+            },
+            None => tonic_openssl_lnd::lnrpc::VerifyMessageResponse::default(),
+        })
+    }
+
+    /// Returns the amount received by settled invoices minus the amount sent
+    /// by successful payments. Accepted hold invoices are reported as the
+    /// unsettled remote balance.
+    async fn channel_balance(
+        &self,
+    ) -> Result<tonic_openssl_lnd::lnrpc::ChannelBalanceResponse, String> {
+        let state = self.state.lock().unwrap();
+        let received_msat: i64 = state
+            .invoices
+            .iter()
+            .filter(|invoice| invoice.state == InvoiceState::Settled as i32)
+            .map(|invoice| invoice.amt_paid_msat)
+            .sum();
+        let sent_msat: i64 = state
+            .payments
+            .iter()
+            .filter(|payment| payment.status == PaymentStatus::Succeeded as i32)
+            .map(|payment| payment.value_msat + payment.fee_msat)
+            .sum();
+        let local_balance_msat = (received_msat - sent_msat).max(0) as u64;
+        let held_msat: i64 = state
+            .invoices
+            .iter()
+            .filter(|invoice| invoice.state == InvoiceState::Accepted as i32)
+            .map(|invoice| invoice.value_msat)
+            .sum();
+        let held_msat = held_msat as u64;
+        Ok(tonic_openssl_lnd::lnrpc::ChannelBalanceResponse {
+            local_balance: Some(tonic_openssl_lnd::lnrpc::Amount {
+                sat: local_balance_msat / 1000,
+                msat: local_balance_msat,
+            }),
+            unsettled_remote_balance: Some(tonic_openssl_lnd::lnrpc::Amount {
+                sat: held_msat / 1000,
+                msat: held_msat,
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// The mock node has no on-chain funds.
+    async fn wallet_balance(
+        &self,
+    ) -> Result<tonic_openssl_lnd::lnrpc::WalletBalanceResponse, String> {
+        Ok(tonic_openssl_lnd::lnrpc::WalletBalanceResponse::default())
+    }
+}
@@ -9,6 +9,8 @@ use sqlx::pool::PoolConnection;
 use sqlx::Acquire;
 use sqlx::Row;
 use sqlx::Sqlite;
+use sqlx::SqliteConnection;
+use std::collections::HashMap;
 use std::future::Future;
 use std::result::Result;
 
@@ -28,6 +30,9 @@ pub struct Listing {
     pub deactivated_by_seller: bool,
     pub deactivated_by_admin: bool,
     pub created_time_ms: u64,
+    pub fiat_price_cents: u64,
+    pub quantity: u32,
+    pub digital: bool,
 }
 
 #[derive(Debug, FromForm)]
@@ -35,6 +40,14 @@ pub struct InitialListingInfo {
     pub title: String,
     pub description: String,
     pub price_sat: Option<u64>,
+    pub fiat_price: Option<String>,
+    pub quantity: Option<u32>,
+    pub digital: bool,
+}
+
+#[derive(Debug, FromForm)]
+pub struct ListingQuantityInput {
+    pub quantity: Option<u32>,
 }
 
 #[derive(FromForm)]
@@ -58,6 +71,7 @@ pub struct ListingDisplay {
     pub listing: Listing,
     pub images: Vec<ListingImageDisplay>,
     pub shipping_options: Vec<ShippingOption>,
+    pub variants: Vec<ListingVariant>,
     pub user: Option<RocketAuthUser>,
 }
 
@@ -98,6 +112,66 @@ pub struct ShippingOption {
     pub price_sat: u64,
 }
 
+/// A variant of a listing, such as a size or a weight, with its own price
+/// and stock.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ListingVariant {
+    pub id: Option<i32>,
+    pub public_id: String,
+    pub listing_id: i32,
+    pub title: String,
+    pub price_sat: u64,
+    pub quantity: u32,
+}
+
+#[derive(Debug, FromForm)]
+pub struct ListingVariantInfo {
+    pub title: String,
+    pub price_sat: Option<u64>,
+    pub quantity: Option<u32>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ListingVariantDisplay {
+    pub variant: ListingVariant,
+    pub remaining_quantity: u32,
+}
+
+/// A code or file that is delivered to the buyer of a digital listing. Once
+/// the payload is assigned to a paid order, its plaintext is replaced by a
+/// copy encrypted to the PGP key of the buyer.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct DigitalPayload {
+    pub id: Option<i32>,
+    pub public_id: String,
+    pub listing_id: i32,
+    pub payload_name: String,
+    pub payload_data: Vec<u8>,
+    pub order_id: i32,
+    pub encrypted_payload: String,
+    pub created_time_ms: u64,
+    pub delivered_time_ms: u64,
+}
+
+/// A payload as shown to the seller, without its contents.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct DigitalPayloadDisplay {
+    pub public_id: String,
+    pub payload_name: String,
+    pub payload_size_bytes: u64,
+    pub delivered: bool,
+    pub created_time_ms: u64,
+}
+
+#[derive(Debug, FromForm)]
+pub struct DigitalPayloadCodesInput {
+    pub codes: String,
+}
+
 #[derive(Debug, FromForm)]
 pub struct ShippingOptionInfo {
     pub title: String,
@@ -123,6 +197,15 @@ pub struct AdminSettings {
     pub squeaknode_pubkey: String,
     pub squeaknode_address: String,
     pub max_allowed_users: u64,
+    pub max_withdrawal_fee_sat: u64,
+    pub max_withdrawal_fee_basis_points: u32,
+    pub withdrawal_fee_paid_by_user: bool,
+    pub order_invoice_expiry_s: u32,
+    pub user_account_invoice_expiry_s: u32,
+    pub order_auto_finalize_days: u32,
+    pub early_finalization_min_amount_sold_sat: u64,
+    pub early_finalization_min_rating: u32,
+    pub order_shipping_deadline_days: u32,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -132,6 +215,10 @@ pub struct UserSettings {
     pub pgp_key: String,
     pub squeaknode_pubkey: String,
     pub squeaknode_address: String,
+    pub payout_node_pubkey: String,
+    pub auto_payout_enabled: bool,
+    pub auto_payout_threshold_sat: u64,
+    pub auto_payout_target: String,
 }
 
 #[derive(Debug, FromForm)]
@@ -150,11 +237,48 @@ pub struct SqueaknodeInfoInput {
     pub squeaknode_address: String,
 }
 
+#[derive(Debug, FromForm)]
+pub struct PayoutNodeInput {
+    pub signature: String,
+}
+
+#[derive(Debug, FromForm)]
+pub struct AutoPayoutInput {
+    pub auto_payout_enabled: bool,
+    pub auto_payout_threshold_sat: Option<u64>,
+    pub auto_payout_target: String,
+}
+
 #[derive(Debug, FromForm)]
 pub struct FeeRateInput {
     pub fee_rate_basis_points: Option<i32>,
 }
 
+#[derive(Debug, FromForm)]
+pub struct WithdrawalFeeLimitInput {
+    pub max_withdrawal_fee_sat: Option<u64>,
+    pub max_withdrawal_fee_basis_points: Option<i32>,
+    pub withdrawal_fee_paid_by_user: bool,
+}
+
+#[derive(Debug, FromForm)]
+pub struct InvoiceExpiryInput {
+    pub order_invoice_expiry_s: Option<u32>,
+    pub user_account_invoice_expiry_s: Option<u32>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct FinalizationInput {
+    pub order_auto_finalize_days: Option<u32>,
+    pub early_finalization_min_amount_sold_sat: Option<u64>,
+    pub early_finalization_min_rating: Option<u32>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct ShippingDeadlineInput {
+    pub order_shipping_deadline_days: Option<u32>,
+}
+
 #[derive(Debug, FromForm)]
 pub struct UserBondPriceInput {
     pub user_bond_price_sat: Option<u64>,
@@ -190,13 +314,38 @@ pub struct Order {
     pub created_time_ms: u64,
     pub payment_time_ms: u64,
     pub review_time_ms: u64,
+    pub escrow: bool,
+    #[serde(skip_serializing)]
+    pub invoice_preimage: String,
+    pub paid_from_balance: bool,
+    pub invoice_expiry_time_ms: u64,
+    pub onchain_address: String,
+    pub onchain_pending_sat: u64,
+    pub onchain_received_sat: u64,
+    pub onchain_buyer_credit_sat: u64,
+    pub onchain_expired: bool,
+    pub fiat_currency: String,
+    pub fiat_cents_per_btc: u64,
+    pub listing_fiat_price_cents: u64,
+    pub disputed: bool,
+    pub dispute_opened_time_ms: u64,
+    pub dispute_resolved: bool,
+    pub dispute_resolved_time_ms: u64,
+    pub dispute_buyer_refund_sat: u64,
+    pub dispute_seller_credit_sat: u64,
+    pub shipped_time_ms: u64,
+    pub finalized: bool,
+    pub finalized_time_ms: u64,
+    pub shipping_expired: bool,
 }
 
 #[derive(Debug, FromForm, Clone)]
 pub struct OrderInfo {
     pub quantity: Option<u32>,
     pub shipping_option_id: String,
+    pub variant_id: Option<String>,
     pub shipping_instructions: String,
+    pub onchain: bool,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -208,11 +357,71 @@ pub struct OrderCard {
     pub user: Option<RocketAuthUser>,
 }
 
+/// A line of an order. The unit price, the market fee and the title of the
+/// variant are kept as they were when the order was created.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OrderItem {
+    pub id: Option<i32>,
+    pub order_id: i32,
+    pub listing_id: i32,
+    pub quantity: u32,
+    pub price_sat: u64,
+    pub fee_rate_basis_points: u32,
+    pub market_fee_sat: u64,
+    pub variant_id: i32,
+    pub variant_title: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OrderItemDisplay {
+    pub order_item: OrderItem,
+    pub listing: Option<Listing>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CartItem {
+    pub id: Option<i32>,
+    pub public_id: String,
+    pub user_id: i32,
+    pub listing_id: i32,
+    pub variant_id: i32,
+    pub shipping_option_id: i32,
+    pub quantity: u32,
+    pub created_time_ms: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct CartItemDisplay {
+    pub cart_item: CartItem,
+    pub listing: Listing,
+    pub maybe_variant: Option<ListingVariant>,
+    pub shipping_option: ShippingOption,
+    pub unit_price_sat: u64,
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct CartItemInput {
+    pub quantity: Option<u32>,
+    pub shipping_option_id: String,
+    pub variant_id: Option<String>,
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct CartCheckoutInfo {
+    pub seller_user_id: i32,
+    pub shipping_instructions: String,
+}
+
 #[derive(Serialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct AccountInfo {
     pub account_balance_sat: i64,
     pub num_unshipped_orders: u32,
+    pub num_unread_messages: u32,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -240,11 +449,61 @@ pub struct Withdrawal {
     pub invoice_hash: String,
     pub invoice_payment_request: String,
     pub created_time_ms: u64,
+    pub status: WithdrawalStatus,
+    pub failure_reason: String,
+    pub fee_limit_sat: u64,
+    pub fee_sat: u64,
+    pub fee_paid_by_user: bool,
+    pub from_market_revenue: bool,
+    pub keysend_pubkey: String,
+}
+
+/// The state of the payment that sends a withdrawal to the user.
+///
+/// Failed withdrawals are not counted in the account balance.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum WithdrawalStatus {
+    Pending,
+    InFlight,
+    Succeeded,
+    Failed,
+}
+
+impl WithdrawalStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WithdrawalStatus::Pending => "pending",
+            WithdrawalStatus::InFlight => "in_flight",
+            WithdrawalStatus::Succeeded => "succeeded",
+            WithdrawalStatus::Failed => "failed",
+        }
+    }
+}
+
+impl std::str::FromStr for WithdrawalStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WithdrawalStatus, String> {
+        match s {
+            "pending" => Ok(WithdrawalStatus::Pending),
+            "in_flight" => Ok(WithdrawalStatus::InFlight),
+            "succeeded" => Ok(WithdrawalStatus::Succeeded),
+            "failed" => Ok(WithdrawalStatus::Failed),
+            other => Err(format!("Unknown withdrawal status: {:?}", other)),
+        }
+    }
 }
 
 #[derive(Debug, FromForm, Clone)]
 pub struct WithdrawalInfo {
     pub invoice_payment_request: String,
+    pub amount_sat: Option<u64>,
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct KeysendPayoutInfo {
+    pub amount_sat: Option<u64>,
 }
 
 #[derive(Debug, FromForm, Clone)]
@@ -259,6 +518,7 @@ pub struct SellerInfo {
     pub username: String,
     pub total_amount_sold_sat: u64,
     pub weighted_average_rating: f32,
+    pub num_shipping_strikes: u32,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -274,6 +534,188 @@ pub struct UserAccount {
     pub invoice_payment_request: String,
     pub created_time_ms: u64,
     pub payment_time_ms: u64,
+    pub invoice_expiry_time_ms: u64,
+}
+
+/// An evidence message posted by the buyer, the seller, or an admin on a
+/// disputed order.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct DisputeMessage {
+    pub id: Option<i32>,
+    pub public_id: String,
+    pub order_id: i32,
+    pub author_user_id: i32,
+    pub text: String,
+    pub created_time_ms: u64,
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct DisputeMessageInput {
+    pub text: String,
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct DisputeResolutionInput {
+    pub buyer_refund_sat: Option<u64>,
+}
+
+/// A message in the thread of an order. The buyer and the seller each track
+/// which messages they have read. The admin can only post in the thread of a
+/// disputed order.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OrderMessage {
+    pub id: Option<i32>,
+    pub public_id: String,
+    pub order_id: i32,
+    pub author_user_id: i32,
+    pub text: String,
+    pub read_by_buyer: bool,
+    pub read_by_seller: bool,
+    pub created_time_ms: u64,
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct OrderMessageInput {
+    pub text: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OrderMessageCard {
+    pub order_public_id: String,
+    pub num_unread_messages: u32,
+    pub latest_message_time_ms: u64,
+}
+
+/// The lifecycle state of an order, derived from its flags.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum OrderStatus {
+    Unpaid,
+    Paid,
+    Shipped,
+    Finalized,
+    CanceledBySeller,
+    CanceledByBuyer,
+    Expired,
+    Disputed,
+    DisputeResolved,
+}
+
+/// The smallest routing fee limit of a withdrawal. Most routes charge a base
+/// fee of at least one sat per hop, which a proportional limit rounds away
+/// for small amounts.
+pub const MIN_WITHDRAWAL_FEE_LIMIT_SAT: u64 = 10;
+
+/// The only changes of status an order can go through. Every other
+/// transition is rejected.
+const ORDER_STATUS_TRANSITIONS: &[(OrderStatus, OrderStatus)] = &[
+    (OrderStatus::Unpaid, OrderStatus::Paid),
+    (OrderStatus::Unpaid, OrderStatus::Expired),
+    (OrderStatus::Paid, OrderStatus::Shipped),
+    (OrderStatus::Paid, OrderStatus::CanceledBySeller),
+    (OrderStatus::Paid, OrderStatus::CanceledByBuyer),
+    (OrderStatus::Shipped, OrderStatus::Finalized),
+    (OrderStatus::Shipped, OrderStatus::Disputed),
+    (OrderStatus::Disputed, OrderStatus::DisputeResolved),
+];
+
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Unpaid => "unpaid",
+            OrderStatus::Paid => "paid",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Finalized => "finalized",
+            OrderStatus::CanceledBySeller => "canceled_by_seller",
+            OrderStatus::CanceledByBuyer => "canceled_by_buyer",
+            OrderStatus::Expired => "expired",
+            OrderStatus::Disputed => "disputed",
+            OrderStatus::DisputeResolved => "dispute_resolved",
+        }
+    }
+
+    pub fn can_transition_to(&self, next_status: OrderStatus) -> bool {
+        ORDER_STATUS_TRANSITIONS.contains(&(*self, next_status))
+    }
+}
+
+impl std::str::FromStr for OrderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OrderStatus, String> {
+        match s {
+            "unpaid" => Ok(OrderStatus::Unpaid),
+            "paid" => Ok(OrderStatus::Paid),
+            "shipped" => Ok(OrderStatus::Shipped),
+            "finalized" => Ok(OrderStatus::Finalized),
+            "canceled_by_seller" => Ok(OrderStatus::CanceledBySeller),
+            "canceled_by_buyer" => Ok(OrderStatus::CanceledByBuyer),
+            "expired" => Ok(OrderStatus::Expired),
+            "disputed" => Ok(OrderStatus::Disputed),
+            "dispute_resolved" => Ok(OrderStatus::DisputeResolved),
+            other => Err(format!("Unknown order status: {:?}", other)),
+        }
+    }
+}
+
+/// A change of status of an order. The actor is 0 when the change was made
+/// by the market itself, for example by a background task.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OrderEvent {
+    pub id: Option<i32>,
+    pub order_id: i32,
+    pub status: OrderStatus,
+    pub actor_user_id: i32,
+    pub created_time_ms: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct LnurlWithdrawal {
+    pub id: Option<i32>,
+    pub public_id: String,
+    pub user_id: i32,
+    pub k1: String,
+    pub used: bool,
+    pub withdrawal_public_id: String,
+    pub created_time_ms: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct InvoiceSettleIndex {
+    pub settle_index: u64,
+}
+
+/// The funds held by the node compared with the funds owed by the market, at
+/// a point in time.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SolvencySnapshot {
+    pub id: Option<i32>,
+    pub channel_balance_sat: u64,
+    pub unsettled_balance_sat: u64,
+    pub wallet_balance_sat: u64,
+    pub total_market_liabilities_sat: i64,
+    pub pending_escrow_sat: u64,
+    pub created_time_ms: u64,
+    pub reserves_sat: i64,
+    pub obligations_sat: i64,
+    pub reserve_ratio_basis_points: Option<i64>,
+}
+
+/// The price of a bitcoin in a fiat currency, as last fetched from the
+/// exchange rate provider.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ExchangeRate {
+    pub currency: String,
+    pub fiat_cents_per_btc: u64,
+    pub updated_time_ms: u64,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -294,6 +736,15 @@ impl Default for AdminSettings {
             squeaknode_pubkey: "".to_string(),
             squeaknode_address: "".to_string(),
             max_allowed_users: 10000,
+            max_withdrawal_fee_sat: 1000,
+            max_withdrawal_fee_basis_points: 100,
+            withdrawal_fee_paid_by_user: false,
+            order_invoice_expiry_s: 86400,
+            user_account_invoice_expiry_s: 600,
+            order_auto_finalize_days: 14,
+            early_finalization_min_amount_sold_sat: 0,
+            early_finalization_min_rating: 4,
+            order_shipping_deadline_days: 30,
         }
     }
 }
@@ -305,6 +756,10 @@ impl Default for UserSettings {
             pgp_key: "".to_string(),
             squeaknode_pubkey: "".to_string(),
             squeaknode_address: "".to_string(),
+            payout_node_pubkey: "".to_string(),
+            auto_payout_enabled: false,
+            auto_payout_threshold_sat: 0,
+            auto_payout_target: "".to_string(),
         }
     }
 }
@@ -323,9 +778,10 @@ impl Listing {
 
         let price_sat: i64 = listing.price_sat.try_into().unwrap();
         let created_time_ms: i64 = listing.created_time_ms.try_into().unwrap();
+        let fiat_price_cents: i64 = listing.fiat_price_cents.try_into().unwrap();
 
         let insert_result = sqlx::query!(
-            "INSERT INTO listings (public_id, user_id, title, description, price_sat, fee_rate_basis_points, submitted, reviewed, approved, deactivated_by_seller, deactivated_by_admin, created_time_ms) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            "INSERT INTO listings (public_id, user_id, title, description, price_sat, fee_rate_basis_points, submitted, reviewed, approved, deactivated_by_seller, deactivated_by_admin, created_time_ms, fiat_price_cents, quantity, digital) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
             listing.public_id,
             listing.user_id,
             listing.title,
@@ -338,6 +794,9 @@ impl Listing {
             listing.deactivated_by_seller,
             listing.deactivated_by_admin,
             created_time_ms,
+            fiat_price_cents,
+            listing.quantity,
+            listing.digital,
         )
             .execute(&mut *tx)
             .await
@@ -375,9 +834,9 @@ AND
         Ok(insert_result.last_insert_rowid() as _)
     }
 
-    pub async fn single(db: &mut Connection<Db>, id: i32) -> Result<Listing, sqlx::Error> {
+    pub async fn single(db: &mut SqliteConnection, id: i32) -> Result<Listing, sqlx::Error> {
         let listing = sqlx::query!("select * from listings WHERE id = ?;", id)
-            .fetch_one(&mut **db)
+            .fetch_one(&mut *db)
             .map_ok(|r| Listing {
                 id: Some(r.id.try_into().unwrap()),
                 public_id: r.public_id,
@@ -392,6 +851,9 @@ AND
                 deactivated_by_seller: r.deactivated_by_seller,
                 deactivated_by_admin: r.deactivated_by_admin,
                 created_time_ms: r.created_time_ms.try_into().unwrap(),
+                fiat_price_cents: r.fiat_price_cents.try_into().unwrap(),
+                quantity: r.quantity.try_into().unwrap(),
+                digital: r.digital,
             })
             .await?;
 
@@ -418,12 +880,39 @@ AND
                 deactivated_by_seller: r.deactivated_by_seller,
                 deactivated_by_admin: r.deactivated_by_admin,
                 created_time_ms: r.created_time_ms.try_into().unwrap(),
+                fiat_price_cents: r.fiat_price_cents.try_into().unwrap(),
+                quantity: r.quantity.try_into().unwrap(),
+                digital: r.digital,
             })
             .await?;
 
         Ok(listing)
     }
 
+    /// Converts the price of every fiat-pegged listing to sats at the given rate.
+    pub async fn update_fiat_pegged_prices(
+        db: &mut PoolConnection<Sqlite>,
+        fiat_cents_per_btc: u64,
+    ) -> Result<(), sqlx::Error> {
+        let fiat_cents_per_btc_i64: i64 = fiat_cents_per_btc.try_into().unwrap();
+
+        // Same rounding as util::fiat_cents_to_sat.
+        sqlx::query!(
+            "
+UPDATE listings
+SET price_sat = (fiat_price_cents * 100000000 + ? / 2) / ?
+WHERE
+ fiat_price_cents > 0
+;",
+            fiat_cents_per_btc_i64,
+            fiat_cents_per_btc_i64,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn mark_as_submitted(
         db: &mut Connection<Db>,
         public_id: &str,
@@ -463,6 +952,55 @@ AND
         Ok(())
     }
 
+    /// Returns the number of units that are still available to order. Unpaid
+    /// orders reserve units until they expire, paid orders consume them, and
+    /// canceled or expired orders release them.
+    pub async fn remaining_quantity(
+        db: &mut SqliteConnection,
+        listing_id: i32,
+    ) -> Result<u32, sqlx::Error> {
+        let remaining_quantity = sqlx::query(
+            "
+select
+ listings.quantity - (
+  SELECT COALESCE(SUM(orderitems.quantity), 0)
+  FROM orderitems
+  INNER JOIN orders ON orderitems.order_id = orders.id
+  WHERE orderitems.listing_id = listings.id
+  AND NOT (orders.canceled_by_seller OR orders.canceled_by_buyer OR orders.onchain_expired)
+ ) as remaining_quantity
+from
+ listings
+WHERE
+ listings.id = ?
+;",
+        )
+        .bind(listing_id)
+        .fetch_one(&mut *db)
+        .map_ok(|r| {
+            let remaining_quantity_i64: i64 = r.try_get("remaining_quantity").unwrap();
+            remaining_quantity_i64.max(0).try_into().unwrap()
+        })
+        .await?;
+
+        Ok(remaining_quantity)
+    }
+
+    pub async fn set_quantity(
+        db: &mut Connection<Db>,
+        listing_id: i32,
+        quantity: u32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE listings SET quantity = ? WHERE id = ?",
+            quantity,
+            listing_id,
+        )
+        .execute(&mut **db)
+        .await?;
+        Ok(())
+    }
+
     pub async fn mark_as_deactivated_by_seller(
         db: &mut Connection<Db>,
         public_id: &str,
@@ -555,6 +1093,30 @@ WHERE
         .await
         .map_err(|_| "failed to delete images for listing.")?;
 
+        sqlx::query!(
+            "
+DELETE from cartitems
+WHERE
+ listing_id = ?
+;",
+            listing_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete cart items for listing.")?;
+
+        sqlx::query!(
+            "
+DELETE from listingvariants
+WHERE
+ listing_id = ?
+;",
+            listing_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete variants for listing.")?;
+
         sqlx::query!(
             "
 DELETE from shippingoptions
@@ -567,6 +1129,22 @@ WHERE
         .await
         .map_err(|_| "failed to delete shipping options for listing.")?;
 
+        // Delivered payloads are kept, because buyers still read them on the
+        // order page.
+        sqlx::query!(
+            "
+DELETE from digitalpayloads
+WHERE
+ listing_id = ?
+AND
+ order_id = 0
+;",
+            listing_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete payloads for listing.")?;
+
         tx.commit()
             .await
             .map_err(|_| "failed to commit transaction.")?;
@@ -725,12 +1303,14 @@ impl ListingDisplay {
             .collect::<Vec<_>>();
         let shipping_options =
             ShippingOption::all_for_listing(&mut *db, listing.id.unwrap()).await?;
+        let variants = ListingVariant::all_for_listing(&mut *db, listing.id.unwrap()).await?;
         let rocket_auth_user = RocketAuthUser::single(&mut *db, listing.user_id).await.ok();
 
         let listing_display = ListingDisplay {
             listing,
             images: image_displays,
             shipping_options,
+            variants,
             user: rocket_auth_user,
         };
 
@@ -746,14 +1326,13 @@ impl ListingCard {
     ) -> Result<Vec<ListingCard>, sqlx::Error> {
         // Example query for this kind of join/group by: https://stackoverflow.com/a/63037790/1639564
         // Other example query: https://stackoverflow.com/a/13698334/1639564
-        // TODO: change WHERE condition to use dynamically calculated remaining quantity
-        // based on number of shipped orders.
+        // Sold out listings are hidden until the seller adds more stock.
         let offset = (page_num - 1) * page_size;
         let limit = page_size;
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -776,6 +1355,14 @@ WHERE
  listings.approved
 AND
  not (listings.deactivated_by_seller OR listings.deactivated_by_admin)
+AND
+ listings.quantity > (
+  SELECT COALESCE(SUM(orderitems.quantity), 0)
+  FROM orderitems
+  INNER JOIN orders ON orderitems.order_id = orders.id
+  WHERE orderitems.listing_id = listings.id
+  AND NOT (orders.canceled_by_seller OR orders.canceled_by_buyer OR orders.onchain_expired)
+ )
 GROUP BY
  listings.id
 ORDER BY listings.created_time_ms DESC
@@ -798,6 +1385,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_seller.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -832,7 +1422,7 @@ OFFSET ?
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -871,6 +1461,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_seller.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -909,7 +1502,7 @@ OFFSET ?
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -948,6 +1541,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_seller.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -987,7 +1583,7 @@ OFFSET ?
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -1026,6 +1622,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_seller.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -1065,7 +1664,7 @@ OFFSET ?
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -1106,6 +1705,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_seller.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -1145,7 +1747,7 @@ OFFSET ?
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -1186,6 +1788,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_admin.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -1221,7 +1826,7 @@ OFFSET ?
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -1262,6 +1867,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_admin.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -1301,7 +1909,7 @@ OFFSET ?
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -1344,6 +1952,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_seller.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -1376,8 +1987,7 @@ OFFSET ?
     ) -> Result<Vec<ListingCard>, sqlx::Error> {
         // Example query for this kind of join/group by: https://stackoverflow.com/a/63037790/1639564
         // Other example query: https://stackoverflow.com/a/13698334/1639564
-        // TODO: change WHERE condition to use dynamically calculated remaining quantity
-        // based on number of shipped orders.
+        // Sold out listings are hidden until the seller adds more stock.
         let offset = (page_num - 1) * page_size;
         let limit = page_size;
         // let uppercase_search_term = search_text.to_owned().to_ascii_uppercase();
@@ -1385,7 +1995,7 @@ OFFSET ?
         let listing_cards =
             sqlx::query!("
 select
- listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ listings.id, listings.public_id, listings.user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
 from
  listings
 LEFT JOIN
@@ -1412,6 +2022,14 @@ AND
  NOT (listings.deactivated_by_seller OR listings.deactivated_by_admin)
 AND
  (UPPER(listings.title) like ? OR UPPER(listings.description) like ?)
+AND
+ listings.quantity > (
+  SELECT COALESCE(SUM(orderitems.quantity), 0)
+  FROM orderitems
+  INNER JOIN orders ON orderitems.order_id = orders.id
+  WHERE orderitems.listing_id = listings.id
+  AND NOT (orders.canceled_by_seller OR orders.canceled_by_buyer OR orders.onchain_expired)
+ )
 GROUP BY
  listings.id
 ORDER BY listings.created_time_ms DESC
@@ -1434,6 +2052,9 @@ OFFSET ?
                     deactivated_by_seller: r.deactivated_by_seller.unwrap(),
                     deactivated_by_admin: r.deactivated_by_admin.unwrap(),
                     created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
                 };
                 let i = r.image_id.map(|image_id| ListingImage {
                     id: Some(image_id.try_into().unwrap()),
@@ -1708,543 +2329,626 @@ impl ShippingOption {
     }
 }
 
-impl AdminSettings {
-    pub async fn single(db: &mut Connection<Db>) -> Result<AdminSettings, sqlx::Error> {
-        let maybe_admin_settings = sqlx::query!("select * from adminsettings;")
-            .fetch_optional(&mut **db)
-            .map_ok(|maybe_r| {
-                maybe_r.map(|r| AdminSettings {
-                    id: Some(r.id.try_into().unwrap()),
-                    market_name: r.market_name,
-                    fee_rate_basis_points: r.fee_rate_basis_points.try_into().unwrap(),
-                    user_bond_price_sat: r.user_bond_price_sat.try_into().unwrap(),
-                    pgp_key: r.pgp_key,
-                    squeaknode_pubkey: r.squeaknode_pubkey,
-                    squeaknode_address: r.squeaknode_address,
-                    max_allowed_users: r.max_allowed_users.try_into().unwrap(),
-                })
-            })
-            .await?;
-
-        let admin_settings = maybe_admin_settings.unwrap_or_default();
+impl ListingVariant {
+    pub async fn insert(
+        listing_variant: ListingVariant,
+        db: &mut Connection<Db>,
+    ) -> Result<(), String> {
+        let price_sat: i64 = listing_variant.price_sat.try_into().unwrap();
 
-        Ok(admin_settings)
-    }
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
 
-    async fn insert_if_doesnt_exist(db: &mut Connection<Db>) -> Result<(), sqlx::Error> {
-        let admin_settings = AdminSettings::default();
-        let user_bond_price_sat_i64: i64 = admin_settings.user_bond_price_sat.try_into().unwrap();
-        let max_allowed_users_i64: i64 = admin_settings.max_allowed_users.try_into().unwrap();
         sqlx::query!(
-            "
-INSERT INTO
- adminsettings (market_name, fee_rate_basis_points, user_bond_price_sat, pgp_key, squeaknode_pubkey, squeaknode_address, max_allowed_users)
-SELECT ?, ?, ?, ?, ?, ?, ?
-WHERE NOT EXISTS(SELECT 1 FROM adminsettings)
-;",
-            admin_settings.market_name,
-            admin_settings.fee_rate_basis_points,
-            user_bond_price_sat_i64,
-            admin_settings.pgp_key,
-            admin_settings.squeaknode_pubkey,
-            admin_settings.squeaknode_address,
-            max_allowed_users_i64,
+            "INSERT INTO listingvariants (public_id, listing_id, title, price_sat, quantity) VALUES (?, ?, ?, ?, ?)",
+            listing_variant.public_id,
+            listing_variant.listing_id,
+            listing_variant.title,
+            price_sat,
+            listing_variant.quantity,
         )
-        .execute(&mut **db)
-        .await?;
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to insert variant.")?;
+
+        ListingVariant::update_listing_quantity(&mut tx, listing_variant.listing_id)
+            .await
+            .map_err(|_| "failed to update quantity of listing.")?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
 
         Ok(())
     }
 
-    pub async fn set_market_name(
+    pub async fn all_for_listing(
         db: &mut Connection<Db>,
-        new_market_name: &str,
-    ) -> Result<(), sqlx::Error> {
-        AdminSettings::insert_if_doesnt_exist(db).await?;
+        listing_id: i32,
+    ) -> Result<Vec<ListingVariant>, sqlx::Error> {
+        let listing_variants = sqlx::query!(
+            "select * from listingvariants WHERE listing_id = ? ORDER BY price_sat ASC;",
+            listing_id
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| ListingVariant {
+            id: r.id.map(|n| n.try_into().unwrap()),
+            public_id: r.public_id,
+            listing_id: r.listing_id.try_into().unwrap(),
+            title: r.title,
+            price_sat: r.price_sat.try_into().unwrap(),
+            quantity: r.quantity.try_into().unwrap(),
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
 
-        sqlx::query!("UPDATE adminsettings SET market_name = ?", new_market_name)
-            .execute(&mut **db)
+        Ok(listing_variants)
+    }
+
+    pub async fn single(db: &mut Connection<Db>, id: i32) -> Result<ListingVariant, sqlx::Error> {
+        let listing_variant = sqlx::query!("select * from listingvariants WHERE id = ?;", id)
+            .fetch_one(&mut **db)
+            .map_ok(|r| ListingVariant {
+                id: Some(r.id.try_into().unwrap()),
+                public_id: r.public_id,
+                listing_id: r.listing_id.try_into().unwrap(),
+                title: r.title,
+                price_sat: r.price_sat.try_into().unwrap(),
+                quantity: r.quantity.try_into().unwrap(),
+            })
             .await?;
 
-        Ok(())
+        Ok(listing_variant)
     }
 
-    pub async fn set_fee_rate(
+    pub async fn single_by_public_id(
         db: &mut Connection<Db>,
-        new_fee_rate_basis_points: i32,
-    ) -> Result<(), sqlx::Error> {
-        AdminSettings::insert_if_doesnt_exist(db).await?;
-
-        sqlx::query!(
-            "UPDATE adminsettings SET fee_rate_basis_points = ?",
-            new_fee_rate_basis_points,
+        public_id: &str,
+    ) -> Result<ListingVariant, sqlx::Error> {
+        let listing_variant = sqlx::query!(
+            "select * from listingvariants WHERE public_id = ?;",
+            public_id,
         )
-        .execute(&mut **db)
+        .fetch_one(&mut **db)
+        .map_ok(|r| ListingVariant {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            listing_id: r.listing_id.try_into().unwrap(),
+            title: r.title,
+            price_sat: r.price_sat.try_into().unwrap(),
+            quantity: r.quantity.try_into().unwrap(),
+        })
         .await?;
 
-        Ok(())
+        Ok(listing_variant)
     }
 
-    pub async fn set_user_bond_price(
+    pub async fn delete(
         db: &mut Connection<Db>,
-        new_user_bond_price_sat: u64,
-    ) -> Result<(), sqlx::Error> {
-        let user_bond_price_sat_i64: i64 = new_user_bond_price_sat.try_into().unwrap();
-
-        AdminSettings::insert_if_doesnt_exist(db).await?;
+        listing_variant: &ListingVariant,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
 
         sqlx::query!(
-            "UPDATE adminsettings SET user_bond_price_sat = ?",
-            user_bond_price_sat_i64,
+            "DELETE FROM listingvariants WHERE id = ?",
+            listing_variant.id,
         )
-        .execute(&mut **db)
-        .await?;
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete variant.")?;
 
-        Ok(())
-    }
+        sqlx::query!(
+            "DELETE FROM cartitems WHERE variant_id = ?",
+            listing_variant.id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete cart items for variant.")?;
 
-    pub async fn set_pgp_key(
-        db: &mut Connection<Db>,
-        new_pgp_key: &str,
-    ) -> Result<(), sqlx::Error> {
-        AdminSettings::insert_if_doesnt_exist(db).await?;
+        ListingVariant::update_listing_quantity(&mut tx, listing_variant.listing_id)
+            .await
+            .map_err(|_| "failed to update quantity of listing.")?;
 
-        sqlx::query!("UPDATE adminsettings SET pgp_key = ?", new_pgp_key,)
-            .execute(&mut **db)
-            .await?;
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
 
         Ok(())
     }
 
-    pub async fn set_squeaknode_pubkey(
+    pub async fn set_quantity(
         db: &mut Connection<Db>,
-        new_squeaknode_pubkey: &str,
-    ) -> Result<(), sqlx::Error> {
-        AdminSettings::insert_if_doesnt_exist(db).await?;
+        listing_variant: &ListingVariant,
+        quantity: u32,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
 
         sqlx::query!(
-            "UPDATE adminsettings SET squeaknode_pubkey = ?",
-            new_squeaknode_pubkey,
+            "UPDATE listingvariants SET quantity = ? WHERE id = ?",
+            quantity,
+            listing_variant.id,
         )
-        .execute(&mut **db)
-        .await?;
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to update quantity of variant.")?;
+
+        ListingVariant::update_listing_quantity(&mut tx, listing_variant.listing_id)
+            .await
+            .map_err(|_| "failed to update quantity of listing.")?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
 
         Ok(())
     }
 
-    pub async fn set_squeaknode_address(
-        db: &mut Connection<Db>,
-        new_squeaknode_address: &str,
+    /// Keeps the stock of a listing with variants equal to the total stock of
+    /// its variants.
+    async fn update_listing_quantity(
+        db: &mut SqliteConnection,
+        listing_id: i32,
     ) -> Result<(), sqlx::Error> {
-        AdminSettings::insert_if_doesnt_exist(db).await?;
-
         sqlx::query!(
-            "UPDATE adminsettings SET squeaknode_address = ?",
-            new_squeaknode_address,
+            "
+UPDATE listings
+SET quantity = (SELECT COALESCE(SUM(quantity), 0) FROM listingvariants WHERE listing_id = ?)
+WHERE
+ id = ?
+;",
+            listing_id,
+            listing_id,
         )
-        .execute(&mut **db)
+        .execute(&mut *db)
         .await?;
 
         Ok(())
     }
 
-    pub async fn set_max_allowed_users(
-        db: &mut Connection<Db>,
-        new_max_allowed_users: u64,
-    ) -> Result<(), sqlx::Error> {
-        let max_allowed_users_i64: i64 = new_max_allowed_users.try_into().unwrap();
-
-        AdminSettings::insert_if_doesnt_exist(db).await?;
-
-        sqlx::query!(
-            "UPDATE adminsettings SET max_allowed_users = ?",
-            max_allowed_users_i64,
+    /// Returns the number of units of the variant that are still available
+    /// to order.
+    pub async fn remaining_quantity(
+        db: &mut SqliteConnection,
+        variant_id: i32,
+    ) -> Result<u32, sqlx::Error> {
+        let remaining_quantity = sqlx::query(
+            "
+select
+ listingvariants.quantity - (
+  SELECT COALESCE(SUM(orderitems.quantity), 0)
+  FROM orderitems
+  INNER JOIN orders ON orderitems.order_id = orders.id
+  WHERE orderitems.variant_id = listingvariants.id
+  AND NOT (orders.canceled_by_seller OR orders.canceled_by_buyer OR orders.onchain_expired)
+ ) as remaining_quantity
+from
+ listingvariants
+WHERE
+ listingvariants.id = ?
+;",
         )
-        .execute(&mut **db)
+        .bind(variant_id)
+        .fetch_one(&mut *db)
+        .map_ok(|r| {
+            let remaining_quantity_i64: i64 = r.try_get("remaining_quantity").unwrap();
+            remaining_quantity_i64.max(0).try_into().unwrap()
+        })
         .await?;
 
-        Ok(())
+        Ok(remaining_quantity)
     }
 }
 
-impl UserSettings {
-    pub async fn single(
+impl DigitalPayload {
+    /// Adds a batch of payloads to a digital listing.
+    pub async fn insert_batch(
         db: &mut Connection<Db>,
-        user_id: i32,
-    ) -> Result<UserSettings, sqlx::Error> {
-        let maybe_user_settings =
-            sqlx::query!("select * from usersettings WHERE user_id = ?;", user_id,)
-                .fetch_optional(&mut **db)
-                .map_ok(|maybe_r| {
-                    maybe_r.map(|r| UserSettings {
-                        id: Some(r.id.try_into().unwrap()),
-                        pgp_key: r.pgp_key,
-                        squeaknode_pubkey: r.squeaknode_pubkey,
-                        squeaknode_address: r.squeaknode_address,
-                    })
-                })
-                .await?;
-        let user_settings = maybe_user_settings.unwrap_or_default();
+        listing_id: i32,
+        digital_payloads: Vec<DigitalPayload>,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
 
-        Ok(user_settings)
-    }
+        for digital_payload in digital_payloads {
+            let created_time_ms: i64 = digital_payload.created_time_ms.try_into().unwrap();
+            sqlx::query!(
+                "INSERT INTO digitalpayloads (public_id, listing_id, payload_name, payload_data, order_id, encrypted_payload, created_time_ms, delivered_time_ms) VALUES (?, ?, ?, ?, 0, '', ?, 0)",
+                digital_payload.public_id,
+                listing_id,
+                digital_payload.payload_name,
+                digital_payload.payload_data,
+                created_time_ms,
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| "failed to insert payload.")?;
+        }
 
-    /// Returns the number of affected rows: 1.
-    async fn insert_if_doesnt_exist(
-        db: &mut Connection<Db>,
-        user_id: i32,
-    ) -> Result<(), sqlx::Error> {
-        let user_settings = UserSettings::default();
-        sqlx::query!(
-            "
-INSERT INTO
- usersettings (user_id, pgp_key, squeaknode_pubkey, squeaknode_address)
-SELECT ?, ?, ?, ?
-WHERE NOT EXISTS(SELECT 1 FROM usersettings WHERE user_id = ?)
-;",
-            user_id,
-            user_settings.pgp_key,
-            user_settings.squeaknode_pubkey,
-            user_settings.squeaknode_address,
-            user_id,
-        )
-        .execute(&mut **db)
-        .await?;
+        DigitalPayload::update_listing_quantity(&mut tx, listing_id)
+            .await
+            .map_err(|_| "failed to update quantity of listing.")?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
 
         Ok(())
     }
 
-    pub async fn set_pgp_key(
+    pub async fn all_displays_for_listing(
         db: &mut Connection<Db>,
-        user_id: i32,
-        new_pgp_key: &str,
-    ) -> Result<(), sqlx::Error> {
-        UserSettings::insert_if_doesnt_exist(db, user_id).await?;
-
-        sqlx::query!(
-            "UPDATE usersettings SET pgp_key = ? WHERE user_id = ?;",
-            new_pgp_key,
-            user_id,
+        listing_id: i32,
+    ) -> Result<Vec<DigitalPayloadDisplay>, sqlx::Error> {
+        let digital_payload_displays = sqlx::query(
+            "
+select
+ public_id, payload_name, LENGTH(payload_data) as payload_size_bytes, order_id, created_time_ms
+from
+ digitalpayloads
+WHERE
+ listing_id = ?
+ORDER BY digitalpayloads.id ASC
+;",
         )
-        .execute(&mut **db)
+        .bind(listing_id)
+        .fetch(&mut **db)
+        .map_ok(|r| {
+            let payload_size_bytes_i64: i64 = r.try_get("payload_size_bytes").unwrap();
+            let order_id: i64 = r.try_get("order_id").unwrap();
+            let created_time_ms: i64 = r.try_get("created_time_ms").unwrap();
+            DigitalPayloadDisplay {
+                public_id: r.try_get("public_id").unwrap(),
+                payload_name: r.try_get("payload_name").unwrap(),
+                payload_size_bytes: payload_size_bytes_i64.try_into().unwrap(),
+                delivered: order_id != 0,
+                created_time_ms: created_time_ms.try_into().unwrap(),
+            }
+        })
+        .try_collect::<Vec<_>>()
         .await?;
 
-        Ok(())
+        Ok(digital_payload_displays)
     }
 
-    pub async fn set_squeaknode_pubkey(
+    pub async fn single_by_public_id(
         db: &mut Connection<Db>,
-        user_id: i32,
-        new_squeaknode_pubkey: &str,
-    ) -> Result<(), sqlx::Error> {
-        UserSettings::insert_if_doesnt_exist(db, user_id).await?;
-
-        sqlx::query!(
-            "UPDATE usersettings SET squeaknode_pubkey = ? WHERE user_id = ?;",
-            new_squeaknode_pubkey,
-            user_id,
+        public_id: &str,
+    ) -> Result<DigitalPayload, sqlx::Error> {
+        let digital_payload = sqlx::query!(
+            "select * from digitalpayloads WHERE public_id = ?;",
+            public_id,
         )
-        .execute(&mut **db)
+        .fetch_one(&mut **db)
+        .map_ok(|r| DigitalPayload {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            listing_id: r.listing_id.try_into().unwrap(),
+            payload_name: r.payload_name,
+            payload_data: r.payload_data,
+            order_id: r.order_id.try_into().unwrap(),
+            encrypted_payload: r.encrypted_payload,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            delivered_time_ms: r.delivered_time_ms.try_into().unwrap(),
+        })
         .await?;
 
-        Ok(())
+        Ok(digital_payload)
     }
 
-    pub async fn set_squeaknode_address(
-        db: &mut Connection<Db>,
-        user_id: i32,
-        new_squeaknode_address: &str,
-    ) -> Result<(), sqlx::Error> {
-        UserSettings::insert_if_doesnt_exist(db, user_id).await?;
+    /// Returns the payloads delivered for the order, in the order they were
+    /// assigned.
+    pub async fn all_for_order(
+        db: &mut SqliteConnection,
+        order_id: i32,
+    ) -> Result<Vec<DigitalPayload>, sqlx::Error> {
+        let digital_payloads = sqlx::query!(
+            "select * from digitalpayloads WHERE order_id = ? ORDER BY digitalpayloads.id ASC;",
+            order_id,
+        )
+        .fetch(&mut *db)
+        .map_ok(|r| DigitalPayload {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            listing_id: r.listing_id.try_into().unwrap(),
+            payload_name: r.payload_name,
+            payload_data: r.payload_data,
+            order_id: r.order_id.try_into().unwrap(),
+            encrypted_payload: r.encrypted_payload,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            delivered_time_ms: r.delivered_time_ms.try_into().unwrap(),
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
 
-        sqlx::query!(
-            "UPDATE usersettings SET squeaknode_address = ? WHERE user_id = ?;",
-            new_squeaknode_address,
-            user_id,
+        Ok(digital_payloads)
+    }
+
+    /// Returns up to `limit` payloads of the listing that are not assigned to
+    /// any order yet, oldest first.
+    pub async fn unassigned_for_listing(
+        db: &mut SqliteConnection,
+        listing_id: i32,
+        limit: u32,
+    ) -> Result<Vec<DigitalPayload>, sqlx::Error> {
+        let digital_payloads = sqlx::query!(
+            "select * from digitalpayloads WHERE listing_id = ? AND order_id = 0 ORDER BY digitalpayloads.id ASC LIMIT ?;",
+            listing_id,
+            limit,
         )
-        .execute(&mut **db)
+        .fetch(&mut *db)
+        .map_ok(|r| DigitalPayload {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            listing_id: r.listing_id.try_into().unwrap(),
+            payload_name: r.payload_name,
+            payload_data: r.payload_data,
+            order_id: r.order_id.try_into().unwrap(),
+            encrypted_payload: r.encrypted_payload,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            delivered_time_ms: r.delivered_time_ms.try_into().unwrap(),
+        })
+        .try_collect::<Vec<_>>()
         .await?;
 
-        Ok(())
+        Ok(digital_payloads)
     }
-}
 
-impl Order {
-    /// Returns the id of the inserted row.
-    pub async fn insert(
-        order: Order,
-        max_unpaid_orders: u32,
-        db: &mut Connection<Db>,
-    ) -> Result<i32, String> {
-        let amount_owed_sat: i64 = order.amount_owed_sat.try_into().unwrap();
-        let seller_credit_sat: i64 = order.seller_credit_sat.try_into().unwrap();
-        let created_time_ms: i64 = order.created_time_ms.try_into().unwrap();
-        let payment_time_ms: i64 = order.payment_time_ms.try_into().unwrap();
-        let review_time_ms: i64 = order.review_time_ms.try_into().unwrap();
+    /// Assigns the payloads to the order, keeping only their encrypted copies.
+    ///
+    /// Fails if any of the payloads was already assigned to another order.
+    pub async fn mark_as_delivered(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        encrypted_payloads: Vec<(i32, String)>,
+        delivered_time_ms: u64,
+    ) -> Result<(), String> {
+        let delivered_time_ms_i64: i64 = delivered_time_ms.try_into().unwrap();
 
         let mut tx = db
             .begin()
             .await
             .map_err(|_| "failed to begin transaction.")?;
 
-        let insert_result = sqlx::query!(
-            "INSERT INTO orders (public_id, buyer_user_id, seller_user_id, quantity, listing_id, shipping_option_id, shipping_instructions, amount_owed_sat, seller_credit_sat, paid, shipped, canceled_by_seller, canceled_by_buyer, reviewed, review_text, review_rating, invoice_hash, invoice_payment_request, created_time_ms, payment_time_ms, review_time_ms) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            order.public_id,
-            order.buyer_user_id,
-            order.seller_user_id,
-            order.quantity,
-            order.listing_id,
-            order.shipping_option_id,
-            order.shipping_instructions,
-            amount_owed_sat,
-            seller_credit_sat,
-            order.paid,
-            order.shipped,
-            order.canceled_by_seller,
-            order.canceled_by_buyer,
-            order.reviewed,
-            order.review_text,
-            order.review_rating,
-            order.invoice_hash,
-            order.invoice_payment_request,
-            created_time_ms,
-            payment_time_ms,
-            review_time_ms,
-        )
-            .execute(&mut *tx)
-            .await
-            .map_err(|_| "failed to insert order.")?;
-
-        let num_unpaid_orders = sqlx::query!(
-            "
-select
- COUNT(orders.id) as num_unpaid_orders
-from
- orders
+        for (digital_payload_id, encrypted_payload) in encrypted_payloads {
+            let update_result = sqlx::query!(
+                "
+UPDATE
+ digitalpayloads
+SET
+ order_id = ?, encrypted_payload = ?, payload_data = x'', delivered_time_ms = ?
 WHERE
- orders.buyer_user_id = ?
+ id = ?
 AND
- NOT orders.paid
+ order_id = 0
 ;",
-            order.buyer_user_id,
+                order_id,
+                encrypted_payload,
+                delivered_time_ms_i64,
+                digital_payload_id,
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| "failed to mark payload as delivered.")?;
+
+            if update_result.rows_affected() != 1 {
+                return Err("payload was already delivered.".to_string());
+            }
+        }
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Deletes a payload that was not delivered yet.
+    pub async fn delete(
+        db: &mut Connection<Db>,
+        digital_payload: &DigitalPayload,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        let delete_result = sqlx::query!(
+            "DELETE FROM digitalpayloads WHERE id = ? AND order_id = 0",
+            digital_payload.id,
         )
-        .fetch_one(&mut *tx)
-        .map_ok(|r| r.num_unpaid_orders as u32)
+        .execute(&mut *tx)
         .await
-        .map_err(|_| "failed to get count of unpaid orders for buyer.")?;
+        .map_err(|_| "failed to delete payload.")?;
 
-        if num_unpaid_orders > max_unpaid_orders {
-            return Err(format!(
-                "more than {:?} unpaid orders not allowed.",
-                max_unpaid_orders,
-            ));
+        if delete_result.rows_affected() != 1 {
+            return Err("Payload was already delivered.".to_string());
         }
 
+        DigitalPayload::update_listing_quantity(&mut tx, digital_payload.listing_id)
+            .await
+            .map_err(|_| "failed to update quantity of listing.")?;
+
         tx.commit()
             .await
             .map_err(|_| "failed to commit transaction.")?;
 
-        Ok(insert_result.last_insert_rowid() as _)
+        Ok(())
     }
 
-    /// Sets a new review for a given order.
-    ///
-    /// Sets the "review_time_ms" field to current time if this is the first review.
-    /// Otherwise, keeps the existing value for "review_time_ms"
-    pub async fn set_order_review(
-        db: &mut Connection<Db>,
-        public_id: &str,
-        review_rating: u32,
-        review_text: &str,
-        review_time_ms: u64,
+    /// Keeps the stock of a digital listing equal to the number of its
+    /// payloads, including the ones already delivered, so that the units
+    /// reserved by orders are subtracted only once.
+    async fn update_listing_quantity(
+        db: &mut SqliteConnection,
+        listing_id: i32,
     ) -> Result<(), sqlx::Error> {
-        let review_time_ms_i64: i64 = review_time_ms.try_into().unwrap();
-
         sqlx::query!(
             "
-        UPDATE
-         orders
-        SET
-         reviewed = true,
-         review_rating = ?,
-         review_text = ?,
-         review_time_ms = ?
-        WHERE
-         public_id = ?
-        ;",
-            review_rating,
-            review_text,
-            review_time_ms_i64,
-            public_id,
+UPDATE listings
+SET quantity = (SELECT COUNT(id) FROM digitalpayloads WHERE listing_id = ?)
+WHERE
+ id = ?
+;",
+            listing_id,
+            listing_id,
         )
-        .execute(&mut **db)
+        .execute(&mut *db)
         .await?;
 
         Ok(())
     }
+}
 
-    pub async fn single(db: &mut Connection<Db>, id: i32) -> Result<Order, sqlx::Error> {
-        let order = sqlx::query!("select * from orders WHERE id = ?;", id)
-            .fetch_one(&mut **db)
-            .map_ok(|r| Order {
-                id: Some(r.id.try_into().unwrap()),
-                public_id: r.public_id,
-                quantity: r.quantity.try_into().unwrap(),
-                buyer_user_id: r.buyer_user_id.try_into().unwrap(),
-                seller_user_id: r.seller_user_id.try_into().unwrap(),
-                listing_id: r.listing_id.try_into().unwrap(),
-                shipping_option_id: r.shipping_option_id.try_into().unwrap(),
-                shipping_instructions: r.shipping_instructions,
-                amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
-                seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
-                paid: r.paid,
-                shipped: r.shipped,
-                canceled_by_seller: r.canceled_by_seller,
-                canceled_by_buyer: r.canceled_by_buyer,
-                reviewed: r.reviewed,
-                invoice_hash: r.invoice_hash,
-                invoice_payment_request: r.invoice_payment_request,
-                review_rating: r.review_rating.try_into().unwrap(),
-                review_text: r.review_text,
-                created_time_ms: r.created_time_ms.try_into().unwrap(),
-                payment_time_ms: r.payment_time_ms.try_into().unwrap(),
-                review_time_ms: r.review_time_ms.try_into().unwrap(),
+impl AdminSettings {
+    pub async fn single(db: &mut SqliteConnection) -> Result<AdminSettings, sqlx::Error> {
+        let maybe_admin_settings = sqlx::query!("select * from adminsettings;")
+            .fetch_optional(&mut *db)
+            .map_ok(|maybe_r| {
+                maybe_r.map(|r| AdminSettings {
+                    id: Some(r.id.try_into().unwrap()),
+                    market_name: r.market_name,
+                    fee_rate_basis_points: r.fee_rate_basis_points.try_into().unwrap(),
+                    user_bond_price_sat: r.user_bond_price_sat.try_into().unwrap(),
+                    pgp_key: r.pgp_key,
+                    squeaknode_pubkey: r.squeaknode_pubkey,
+                    squeaknode_address: r.squeaknode_address,
+                    max_allowed_users: r.max_allowed_users.try_into().unwrap(),
+                    max_withdrawal_fee_sat: r.max_withdrawal_fee_sat.try_into().unwrap(),
+                    max_withdrawal_fee_basis_points: r
+                        .max_withdrawal_fee_basis_points
+                        .try_into()
+                        .unwrap(),
+                    withdrawal_fee_paid_by_user: r.withdrawal_fee_paid_by_user,
+                    order_invoice_expiry_s: r.order_invoice_expiry_s.try_into().unwrap(),
+                    user_account_invoice_expiry_s: r
+                        .user_account_invoice_expiry_s
+                        .try_into()
+                        .unwrap(),
+                    order_auto_finalize_days: r.order_auto_finalize_days.try_into().unwrap(),
+                    early_finalization_min_amount_sold_sat: r
+                        .early_finalization_min_amount_sold_sat
+                        .try_into()
+                        .unwrap(),
+                    early_finalization_min_rating: r
+                        .early_finalization_min_rating
+                        .try_into()
+                        .unwrap(),
+                    order_shipping_deadline_days: r
+                        .order_shipping_deadline_days
+                        .try_into()
+                        .unwrap(),
+                })
             })
             .await?;
 
-        Ok(order)
+        let admin_settings = maybe_admin_settings.unwrap_or_default();
+
+        Ok(admin_settings)
     }
 
-    pub async fn single_by_public_id(
-        db: &mut Connection<Db>,
-        public_id: &str,
-    ) -> Result<Order, sqlx::Error> {
-        let order = sqlx::query!("select * from orders WHERE public_id = ?;", public_id)
-            .fetch_one(&mut **db)
-            .map_ok(|r| Order {
-                id: Some(r.id.try_into().unwrap()),
-                public_id: r.public_id,
-                quantity: r.quantity.try_into().unwrap(),
-                buyer_user_id: r.buyer_user_id.try_into().unwrap(),
-                seller_user_id: r.seller_user_id.try_into().unwrap(),
-                listing_id: r.listing_id.try_into().unwrap(),
-                shipping_option_id: r.shipping_option_id.try_into().unwrap(),
-                shipping_instructions: r.shipping_instructions,
-                amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
-                seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
-                paid: r.paid,
-                shipped: r.shipped,
-                canceled_by_seller: r.canceled_by_seller,
-                canceled_by_buyer: r.canceled_by_buyer,
-                reviewed: r.reviewed,
-                invoice_hash: r.invoice_hash,
-                invoice_payment_request: r.invoice_payment_request,
-                review_rating: r.review_rating.try_into().unwrap(),
-                review_text: r.review_text,
-                created_time_ms: r.created_time_ms.try_into().unwrap(),
-                payment_time_ms: r.payment_time_ms.try_into().unwrap(),
-                review_time_ms: r.review_time_ms.try_into().unwrap(),
-            })
-            .await?;
+    async fn insert_if_doesnt_exist(db: &mut Connection<Db>) -> Result<(), sqlx::Error> {
+        let admin_settings = AdminSettings::default();
+        let user_bond_price_sat_i64: i64 = admin_settings.user_bond_price_sat.try_into().unwrap();
+        let max_allowed_users_i64: i64 = admin_settings.max_allowed_users.try_into().unwrap();
+        let max_withdrawal_fee_sat_i64: i64 =
+            admin_settings.max_withdrawal_fee_sat.try_into().unwrap();
+        let early_finalization_min_amount_sold_sat_i64: i64 = admin_settings
+            .early_finalization_min_amount_sold_sat
+            .try_into()
+            .unwrap();
+        sqlx::query!(
+            "
+INSERT INTO
+ adminsettings (market_name, fee_rate_basis_points, user_bond_price_sat, pgp_key, squeaknode_pubkey, squeaknode_address, max_allowed_users, max_withdrawal_fee_sat, max_withdrawal_fee_basis_points, withdrawal_fee_paid_by_user, order_invoice_expiry_s, user_account_invoice_expiry_s, order_auto_finalize_days, early_finalization_min_amount_sold_sat, early_finalization_min_rating, order_shipping_deadline_days)
+SELECT ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
+WHERE NOT EXISTS(SELECT 1 FROM adminsettings)
+;",
+            admin_settings.market_name,
+            admin_settings.fee_rate_basis_points,
+            user_bond_price_sat_i64,
+            admin_settings.pgp_key,
+            admin_settings.squeaknode_pubkey,
+            admin_settings.squeaknode_address,
+            max_allowed_users_i64,
+            max_withdrawal_fee_sat_i64,
+            admin_settings.max_withdrawal_fee_basis_points,
+            admin_settings.withdrawal_fee_paid_by_user,
+            admin_settings.order_invoice_expiry_s,
+            admin_settings.user_account_invoice_expiry_s,
+            admin_settings.order_auto_finalize_days,
+            early_finalization_min_amount_sold_sat_i64,
+            admin_settings.early_finalization_min_rating,
+            admin_settings.order_shipping_deadline_days,
+        )
+        .execute(&mut **db)
+        .await?;
 
-        Ok(order)
+        Ok(())
     }
 
-    pub async fn single_by_invoice_hash(
-        db: &mut PoolConnection<Sqlite>,
-        invoice_hash: &str,
-    ) -> Result<Order, sqlx::Error> {
-        let order = sqlx::query!("select * from orders WHERE invoice_hash = ?;", invoice_hash)
-            .fetch_one(&mut **db)
-            .map_ok(|r| Order {
-                id: Some(r.id.try_into().unwrap()),
-                public_id: r.public_id,
-                quantity: r.quantity.try_into().unwrap(),
-                buyer_user_id: r.buyer_user_id.try_into().unwrap(),
-                seller_user_id: r.seller_user_id.try_into().unwrap(),
-                listing_id: r.listing_id.try_into().unwrap(),
-                shipping_option_id: r.shipping_option_id.try_into().unwrap(),
-                shipping_instructions: r.shipping_instructions,
-                amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
-                seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
-                paid: r.paid,
-                shipped: r.shipped,
-                canceled_by_seller: r.canceled_by_seller,
-                canceled_by_buyer: r.canceled_by_buyer,
-                reviewed: r.reviewed,
-                invoice_hash: r.invoice_hash,
-                invoice_payment_request: r.invoice_payment_request,
-                review_rating: r.review_rating.try_into().unwrap(),
-                review_text: r.review_text,
-                created_time_ms: r.created_time_ms.try_into().unwrap(),
-                payment_time_ms: r.payment_time_ms.try_into().unwrap(),
-                review_time_ms: r.review_time_ms.try_into().unwrap(),
-            })
+    /// Returns the largest routing fee allowed for a withdrawal of the given
+    /// amount: the lower of the absolute and the proportional limits.
+    ///
+    /// The proportional limit is raised to `MIN_WITHDRAWAL_FEE_LIMIT_SAT`,
+    /// so that small withdrawals can still pay for a route.
+    pub fn withdrawal_fee_limit_sat(&self, amount_sat: u64) -> u64 {
+        let proportional_limit_sat =
+            amount_sat * (self.max_withdrawal_fee_basis_points as u64) / 10000;
+        proportional_limit_sat
+            .max(MIN_WITHDRAWAL_FEE_LIMIT_SAT)
+            .min(self.max_withdrawal_fee_sat)
+    }
+
+    pub async fn set_market_name(
+        db: &mut Connection<Db>,
+        new_market_name: &str,
+    ) -> Result<(), sqlx::Error> {
+        AdminSettings::insert_if_doesnt_exist(db).await?;
+
+        sqlx::query!("UPDATE adminsettings SET market_name = ?", new_market_name)
+            .execute(&mut **db)
             .await?;
 
-        Ok(order)
+        Ok(())
     }
 
-    pub async fn all_older_than(
-        db: &mut PoolConnection<Sqlite>,
-        created_time_ms: u64,
-    ) -> Result<Vec<Order>, sqlx::Error> {
-        let created_time_ms_i64: i64 = created_time_ms.try_into().unwrap();
+    pub async fn set_fee_rate(
+        db: &mut Connection<Db>,
+        new_fee_rate_basis_points: i32,
+    ) -> Result<(), sqlx::Error> {
+        AdminSettings::insert_if_doesnt_exist(db).await?;
 
-        let orders = sqlx::query!(
-            "
-select *
-from
- orders
-WHERE
- created_time_ms < ?
-AND
- NOT paid
-;",
-            created_time_ms_i64,
+        sqlx::query!(
+            "UPDATE adminsettings SET fee_rate_basis_points = ?",
+            new_fee_rate_basis_points,
         )
-        .fetch(&mut **db)
-        .map_ok(|r| Order {
-            id: Some(r.id.try_into().unwrap()),
-            public_id: r.public_id,
-            quantity: r.quantity.try_into().unwrap(),
-            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
-            seller_user_id: r.seller_user_id.try_into().unwrap(),
-            listing_id: r.listing_id.try_into().unwrap(),
-            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
-            shipping_instructions: r.shipping_instructions,
-            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
-            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
-            paid: r.paid,
-            shipped: r.shipped,
-            canceled_by_seller: r.canceled_by_seller,
-            canceled_by_buyer: r.canceled_by_buyer,
-            reviewed: r.reviewed,
-            invoice_hash: r.invoice_hash,
-            invoice_payment_request: r.invoice_payment_request,
-            review_rating: r.review_rating.try_into().unwrap(),
-            review_text: r.review_text,
-            created_time_ms: r.created_time_ms.try_into().unwrap(),
-            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
-            review_time_ms: r.review_time_ms.try_into().unwrap(),
-        })
-        .try_collect::<Vec<_>>()
+        .execute(&mut **db)
         .await?;
 
-        Ok(orders)
+        Ok(())
     }
 
-    pub async fn mark_as_paid(
-        db: &mut PoolConnection<Sqlite>,
-        order_id: i32,
-        time_now_ms: u64,
+    pub async fn set_user_bond_price(
+        db: &mut Connection<Db>,
+        new_user_bond_price_sat: u64,
     ) -> Result<(), sqlx::Error> {
-        let time_now_ms_i64: i64 = time_now_ms.try_into().unwrap();
+        let user_bond_price_sat_i64: i64 = new_user_bond_price_sat.try_into().unwrap();
+
+        AdminSettings::insert_if_doesnt_exist(db).await?;
 
         sqlx::query!(
-            "UPDATE orders SET paid = true, payment_time_ms = ? WHERE id = ?",
-            time_now_ms_i64,
-            order_id,
+            "UPDATE adminsettings SET user_bond_price_sat = ?",
+            user_bond_price_sat_i64,
         )
         .execute(&mut **db)
         .await?;
@@ -2252,24 +2956,28 @@ AND
         Ok(())
     }
 
-    pub async fn mark_as_shipped(
-        db: &mut PoolConnection<Sqlite>,
-        order_id: i32,
+    pub async fn set_pgp_key(
+        db: &mut Connection<Db>,
+        new_pgp_key: &str,
+    ) -> Result<(), sqlx::Error> {
+        AdminSettings::insert_if_doesnt_exist(db).await?;
+
+        sqlx::query!("UPDATE adminsettings SET pgp_key = ?", new_pgp_key,)
+            .execute(&mut **db)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_squeaknode_pubkey(
+        db: &mut Connection<Db>,
+        new_squeaknode_pubkey: &str,
     ) -> Result<(), sqlx::Error> {
+        AdminSettings::insert_if_doesnt_exist(db).await?;
+
         sqlx::query!(
-            "
-UPDATE
- orders
-SET
- shipped = true, shipping_instructions = ''
-WHERE
- id = ?
-AND
- paid
-AND
- not (shipped OR canceled_by_seller OR canceled_by_buyer)
-;",
-            order_id,
+            "UPDATE adminsettings SET squeaknode_pubkey = ?",
+            new_squeaknode_pubkey,
         )
         .execute(&mut **db)
         .await?;
@@ -2277,22 +2985,15 @@ AND
         Ok(())
     }
 
-    pub async fn mark_as_canceled_by_seller(
-        db: &mut PoolConnection<Sqlite>,
-        order_id: i32,
+    pub async fn set_squeaknode_address(
+        db: &mut Connection<Db>,
+        new_squeaknode_address: &str,
     ) -> Result<(), sqlx::Error> {
+        AdminSettings::insert_if_doesnt_exist(db).await?;
+
         sqlx::query!(
-            "
-UPDATE
- orders
-SET
- canceled_by_seller = true, shipping_instructions = ''
-WHERE
- id = ?
-AND
- not (shipped OR canceled_by_seller OR canceled_by_buyer)
-;",
-            order_id,
+            "UPDATE adminsettings SET squeaknode_address = ?",
+            new_squeaknode_address,
         )
         .execute(&mut **db)
         .await?;
@@ -2300,22 +3001,17 @@ AND
         Ok(())
     }
 
-    pub async fn mark_as_canceled_by_buyer(
-        db: &mut PoolConnection<Sqlite>,
-        order_id: i32,
+    pub async fn set_max_allowed_users(
+        db: &mut Connection<Db>,
+        new_max_allowed_users: u64,
     ) -> Result<(), sqlx::Error> {
+        let max_allowed_users_i64: i64 = new_max_allowed_users.try_into().unwrap();
+
+        AdminSettings::insert_if_doesnt_exist(db).await?;
+
         sqlx::query!(
-            "
-UPDATE
- orders
-SET
- canceled_by_buyer = true, shipping_instructions = ''
-WHERE
- id = ?
-AND
- not (shipped OR canceled_by_seller OR canceled_by_buyer)
-;",
-            order_id,
+            "UPDATE adminsettings SET max_allowed_users = ?",
+            max_allowed_users_i64,
         )
         .execute(&mut **db)
         .await?;
@@ -2323,1224 +3019,4953 @@ AND
         Ok(())
     }
 
-    pub async fn delete_expired_order(
-        db: &mut PoolConnection<Sqlite>,
-        order_id: i32,
-        cancel_order_invoice_future: impl Future<
-            Output = Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String>,
-        >,
-    ) -> Result<(), String> {
-        let mut tx = db
-            .begin()
-            .await
-            .map_err(|_| "failed to begin transaction.")?;
+    pub async fn set_withdrawal_fee_limit(
+        db: &mut Connection<Db>,
+        new_max_withdrawal_fee_sat: u64,
+        new_max_withdrawal_fee_basis_points: i32,
+        new_withdrawal_fee_paid_by_user: bool,
+    ) -> Result<(), sqlx::Error> {
+        let max_withdrawal_fee_sat_i64: i64 = new_max_withdrawal_fee_sat.try_into().unwrap();
+
+        AdminSettings::insert_if_doesnt_exist(db).await?;
 
         sqlx::query!(
-            "
-DELETE FROM orders
-WHERE
- id = ?
-AND
- NOT paid
-;",
-            order_id,
+            "UPDATE adminsettings SET max_withdrawal_fee_sat = ?, max_withdrawal_fee_basis_points = ?, withdrawal_fee_paid_by_user = ?",
+            max_withdrawal_fee_sat_i64,
+            new_max_withdrawal_fee_basis_points,
+            new_withdrawal_fee_paid_by_user,
         )
-        .execute(&mut *tx)
-        .await
-        .map_err(|_| "failed to delete order from database.")?;
+        .execute(&mut **db)
+        .await?;
 
-        cancel_order_invoice_future
-            .await
-            .map_err(|e| format!("failed to cancel order invoice: {:?}", e))?;
+        Ok(())
+    }
 
-        tx.commit()
-            .await
-            .map_err(|_| "failed to begin transaction.")?;
+    pub async fn set_invoice_expiry(
+        db: &mut Connection<Db>,
+        new_order_invoice_expiry_s: u32,
+        new_user_account_invoice_expiry_s: u32,
+    ) -> Result<(), sqlx::Error> {
+        AdminSettings::insert_if_doesnt_exist(db).await?;
+
+        sqlx::query!(
+            "UPDATE adminsettings SET order_invoice_expiry_s = ?, user_account_invoice_expiry_s = ?",
+            new_order_invoice_expiry_s,
+            new_user_account_invoice_expiry_s,
+        )
+        .execute(&mut **db)
+        .await?;
 
         Ok(())
     }
 
-    pub async fn seller_info_for_user(
+    pub async fn set_finalization(
         db: &mut Connection<Db>,
-        user_id: i32,
-    ) -> Result<SellerInfo, sqlx::Error> {
-        let total_amount_sold_sat = sqlx::query(
-            "
-            select
-             SUM(orders.amount_owed_sat) as total_amount_sold_sat
-            FROM
-             orders
-            WHERE
-             orders.shipped
-            AND
-             orders.seller_user_id = ?
-            GROUP BY
-             orders.seller_user_id
-            ;",
-        )
-        .bind(user_id)
-        .fetch_optional(&mut **db)
-        .map_ok(|maybe_r| {
-            maybe_r.map(|r| {
-                let amount_sat_i64: i64 = r.try_get("total_amount_sold_sat").unwrap();
-                let amount_sat_u64: u64 = amount_sat_i64.try_into().unwrap();
-                amount_sat_u64
-            })
-        })
-        .await?;
+        new_order_auto_finalize_days: u32,
+        new_early_finalization_min_amount_sold_sat: u64,
+        new_early_finalization_min_rating: u32,
+    ) -> Result<(), sqlx::Error> {
+        AdminSettings::insert_if_doesnt_exist(db).await?;
 
-        let weighted_average_rating = sqlx::query(
-            "
-            select
-             SUM(orders.amount_owed_sat * orders.review_rating * 1000) / SUM(orders.amount_owed_sat) as weighted_average
-            FROM
-             orders
-            WHERE
-             orders.reviewed
-            AND
-             orders.seller_user_id = ?
-            GROUP BY
-             orders.seller_user_id
-            ;")
-.bind(user_id)
-            .fetch_optional(&mut **db)
-            .map_ok(|maybe_r| maybe_r.map(|r| {
-                let average_rating_numerator: i64 = r.try_get("weighted_average").unwrap();
-                let average_rating: f32 = (average_rating_numerator as f32) / 1000.0;
-                average_rating
-            }))
+        let new_early_finalization_min_amount_sold_sat_i64: i64 =
+            new_early_finalization_min_amount_sold_sat
+                .try_into()
+                .unwrap();
+        sqlx::query!(
+            "UPDATE adminsettings SET order_auto_finalize_days = ?, early_finalization_min_amount_sold_sat = ?, early_finalization_min_rating = ?",
+            new_order_auto_finalize_days,
+            new_early_finalization_min_amount_sold_sat_i64,
+            new_early_finalization_min_rating,
+        )
+        .execute(&mut **db)
         .await?;
 
-        let seller_info = SellerInfo {
-            username: "".to_string(),
-            total_amount_sold_sat: total_amount_sold_sat.unwrap_or(0),
-            weighted_average_rating: weighted_average_rating.unwrap_or(0.0),
-        };
+        Ok(())
+    }
 
-        // TODO: remove option from return type.
-        Ok(seller_info)
+    /// Returns how long after shipping an order is finalized without
+    /// waiting for the buyer.
+    pub fn order_auto_finalize_period_ms(&self) -> u64 {
+        (self.order_auto_finalize_days as u64) * 86400000
     }
 
-    pub async fn seller_info_for_all_users(
+    pub async fn set_shipping_deadline(
         db: &mut Connection<Db>,
-        page_size: u32,
-        page_num: u32,
-    ) -> Result<Vec<SellerInfo>, sqlx::Error> {
-        let offset = (page_num - 1) * page_size;
-        let limit = page_size;
-        let seller_infos = sqlx::query(
-                    "
-        SELECT weighted_average, total_amount_sold_sat, users.email
-        FROM
-         users
-        LEFT JOIN
-            (select
-             SUM(orders.amount_owed_sat) as total_amount_sold_sat, orders.seller_user_id as shipped_seller_user_id
-            FROM
-             orders
-            WHERE
-             shipped
-            GROUP BY
-             orders.seller_user_id) as seller_infos
-        ON
-         users.id = seller_infos.shipped_seller_user_id
-        LEFT JOIN
-            (select
-             SUM(orders.amount_owed_sat * orders.review_rating * 1000) / SUM(orders.amount_owed_sat) as weighted_average, orders.seller_user_id as reviewed_seller_user_id
-            FROM
-             orders
-            WHERE
-             orders.reviewed
-            AND
-             orders.shipped
-            GROUP BY
-             orders.seller_user_id) as seller_infos
-        ON
-         users.id = seller_infos.reviewed_seller_user_id
-        WHERE
-         total_amount_sold_sat > 0
-        ORDER BY
-         total_amount_sold_sat DESC
-        LIMIT ?
-        OFFSET ?
-            ;")
-            .bind(limit)
-            .bind(offset)
-            .fetch(&mut **db)
-            .map_ok(|r| {
-                SellerInfo {
-                    username: r.try_get("email").unwrap(),
-                    total_amount_sold_sat: {
-                        let amount_sat_i64: i64 = r.try_get("total_amount_sold_sat").unwrap();
-                        let amount_sat_u64: u64 = amount_sat_i64.try_into().unwrap();
-                        amount_sat_u64
-                    },
-                    weighted_average_rating: {
-                        let average_rating_numerator: i64 = r.try_get("weighted_average").unwrap();
-                        let average_rating: f32 = (average_rating_numerator as f32) / 1000.0;
-                        average_rating
-                    },
-                }})
-            .try_collect::<Vec<_>>()
-            .await?;
-
-        Ok(seller_infos)
-    }
+        new_order_shipping_deadline_days: u32,
+    ) -> Result<(), sqlx::Error> {
+        AdminSettings::insert_if_doesnt_exist(db).await?;
 
-    // TODO: implement this.
-    pub async fn most_recent_paid_order(
-        db: &mut PoolConnection<Sqlite>,
-    ) -> Result<Option<String>, sqlx::Error> {
-        let latest_paid_order_invoice_hash = sqlx::query!(
-            "
-SELECT
- invoice_hash
-FROM
-(SELECT invoice_hash, payment_time_ms FROM useraccounts
- UNION ALL
-SELECT invoice_hash, payment_time_ms FROM orders)
-WHERE
- payment_time_ms = (SELECT MAX(payment_time_ms) FROM
-(SELECT invoice_hash, payment_time_ms FROM useraccounts
- UNION ALL
-SELECT invoice_hash, payment_time_ms FROM orders))
-LIMIT 1
-;"
+        sqlx::query!(
+            "UPDATE adminsettings SET order_shipping_deadline_days = ?",
+            new_order_shipping_deadline_days,
         )
-        .fetch_optional(&mut **db)
-        .map_ok(|maybe_r| maybe_r.map(|r| r.invoice_hash))
+        .execute(&mut **db)
         .await?;
 
-        Ok(latest_paid_order_invoice_hash)
+        Ok(())
+    }
+
+    /// Returns how long after payment an order that was not shipped is
+    /// canceled and refunded to the buyer.
+    pub fn order_shipping_deadline_ms(&self) -> u64 {
+        (self.order_shipping_deadline_days as u64) * 86400000
     }
+}
 
-    pub async fn num_processing_for_user(
+impl UserSettings {
+    pub async fn single(
+        db: &mut SqliteConnection,
+        user_id: i32,
+    ) -> Result<UserSettings, sqlx::Error> {
+        let maybe_user_settings =
+            sqlx::query!("select * from usersettings WHERE user_id = ?;", user_id,)
+                .fetch_optional(&mut *db)
+                .map_ok(|maybe_r| {
+                    maybe_r.map(|r| UserSettings {
+                        id: Some(r.id.try_into().unwrap()),
+                        pgp_key: r.pgp_key,
+                        squeaknode_pubkey: r.squeaknode_pubkey,
+                        squeaknode_address: r.squeaknode_address,
+                        payout_node_pubkey: r.payout_node_pubkey,
+                        auto_payout_enabled: r.auto_payout_enabled,
+                        auto_payout_threshold_sat: r.auto_payout_threshold_sat.try_into().unwrap(),
+                        auto_payout_target: r.auto_payout_target,
+                    })
+                })
+                .await?;
+        let user_settings = maybe_user_settings.unwrap_or_default();
+
+        Ok(user_settings)
+    }
+
+    /// Returns the number of affected rows: 1.
+    async fn insert_if_doesnt_exist(
         db: &mut Connection<Db>,
         user_id: i32,
-    ) -> Result<u32, sqlx::Error> {
-        let num_orders = sqlx::query!(
+    ) -> Result<(), sqlx::Error> {
+        let user_settings = UserSettings::default();
+        sqlx::query!(
             "
-select
- COUNT(orders.id) as num_processing_orders
-from
- orders
-WHERE
- orders.paid
-AND
- not (orders.shipped OR orders.canceled_by_seller OR orders.canceled_by_buyer)
-AND
- orders.seller_user_id = ?
+INSERT INTO
+ usersettings (user_id, pgp_key, squeaknode_pubkey, squeaknode_address)
+SELECT ?, ?, ?, ?
+WHERE NOT EXISTS(SELECT 1 FROM usersettings WHERE user_id = ?)
 ;",
             user_id,
+            user_settings.pgp_key,
+            user_settings.squeaknode_pubkey,
+            user_settings.squeaknode_address,
+            user_id,
         )
-        .fetch_one(&mut **db)
-        .map_ok(|r| r.num_processing_orders as u32)
+        .execute(&mut **db)
         .await?;
 
-        Ok(num_orders)
+        Ok(())
     }
-}
 
-impl OrderCard {
-    pub async fn all_unpaid_for_user(
+    pub async fn set_pgp_key(
         db: &mut Connection<Db>,
         user_id: i32,
-        page_size: u32,
-        page_num: u32,
-    ) -> Result<Vec<OrderCard>, sqlx::Error> {
-        let offset = (page_num - 1) * page_size;
-        let limit = page_size;
-        let orders = sqlx::query!(
-            "
-select
- orders.id as order_id, orders.public_id as order_public_id, orders.buyer_user_id as order_buyer_user_id, orders.seller_user_id as order_seller_user_id, orders.quantity as order_quantity, orders.listing_id as order_listing_id, orders.shipping_option_id, orders.shipping_instructions, orders.amount_owed_sat, orders.seller_credit_sat, orders.paid, orders.shipped, orders.canceled_by_seller, orders.canceled_by_buyer, orders.reviewed as order_reviewed, orders.invoice_hash, orders.invoice_payment_request, orders.review_rating, orders.review_text, orders.created_time_ms, orders.payment_time_ms, orders.review_time_ms, listings.id, listings.public_id as listing_public_id, listings.user_id as listing_user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms as listing_created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
-from
- orders
-LEFT JOIN
- listings
-ON
- orders.listing_id = listings.id
-LEFT JOIN
- listingimages
-ON
- listings.id = listingimages.listing_id
-AND
- listingimages.is_primary = (SELECT MAX(is_primary) FROM listingimages WHERE listing_id = listings.id)
-LEFT JOIN
- users
-ON
- listings.user_id = users.id
-WHERE
- not orders.paid
-AND
- order_buyer_user_id = ?
-GROUP BY
- orders.id
-ORDER BY orders.created_time_ms DESC
-LIMIT ?
-OFFSET ?
-;",
+        new_pgp_key: &str,
+    ) -> Result<(), sqlx::Error> {
+        UserSettings::insert_if_doesnt_exist(db, user_id).await?;
+
+        sqlx::query!(
+            "UPDATE usersettings SET pgp_key = ? WHERE user_id = ?;",
+            new_pgp_key,
             user_id,
-            limit,
-            offset,
         )
-            .fetch(&mut **db)
-            .map_ok(|r| {
-                let o = Order {
-                    id: Some(r.order_id.unwrap().try_into().unwrap()),
-                    public_id: r.order_public_id.unwrap(),
-                    quantity: r.order_quantity.unwrap().try_into().unwrap(),
-                    buyer_user_id: r.order_buyer_user_id.unwrap().try_into().unwrap(),
-                    seller_user_id: r.order_seller_user_id.unwrap().try_into().unwrap(),
-                    listing_id: r.order_listing_id.unwrap().try_into().unwrap(),
-                    shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
-                    shipping_instructions: r.shipping_instructions.unwrap(),
-                    amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
-                    seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
-                    paid: r.paid.unwrap(),
-                    shipped: r.shipped.unwrap(),
-                    canceled_by_seller: r.canceled_by_seller.unwrap(),
-                    canceled_by_buyer: r.canceled_by_buyer.unwrap(),
-                    reviewed: r.order_reviewed.unwrap(),
-                    invoice_hash: r.invoice_hash.unwrap(),
-                    invoice_payment_request: r.invoice_payment_request.unwrap(),
-                    review_rating: r.review_rating.unwrap().try_into().unwrap(),
-                    review_text: r.review_text.unwrap(),
-                    created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
-                    payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
-                    review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
-                };
-                let l = r.id.map(|listing_id| Listing {
-                    id: Some(listing_id.try_into().unwrap()),
-                    public_id: r.listing_public_id.unwrap(),
-                    user_id: r.listing_user_id.unwrap().try_into().unwrap(),
-                    title: r.title.unwrap(),
-                    description: r.description.unwrap(),
-                    price_sat: r.price_sat.unwrap().try_into().unwrap(),
-                    fee_rate_basis_points: r.fee_rate_basis_points.unwrap().try_into().unwrap(),
-                    submitted: r.submitted.unwrap(),
-                    reviewed: r.reviewed.unwrap(),
-                    approved: r.approved.unwrap(),
-                    deactivated_by_seller: r.deactivated_by_seller.unwrap(),
-                    deactivated_by_admin: r.deactivated_by_admin.unwrap(),
-                    created_time_ms: r.listing_created_time_ms.unwrap().try_into().unwrap(),
-                });
-                let i = r.image_id.map(|image_id| ListingImage {
-                    id: Some(image_id.try_into().unwrap()),
-                    public_id: r.image_public_id.unwrap(),
-                    listing_id: r.listing_id.unwrap().try_into().unwrap(),
-                    image_data: r.image_data.unwrap(),
-                    is_primary: r.is_primary.unwrap(),
-                });
-                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
-                    id: Some(rocket_auth_user_id.try_into().unwrap()),
-                    username: r.rocket_auth_user_username.unwrap(),
-                });
-                OrderCard {
-                    order: o,
-                    listing: l,
-                    image: i,
-                    user: u,
-                }
-            })
-            .try_collect::<Vec<_>>()
-            .await?;
+        .execute(&mut **db)
+        .await?;
 
-        Ok(orders)
+        Ok(())
     }
 
-    pub async fn all_paid_for_user(
+    pub async fn set_squeaknode_pubkey(
         db: &mut Connection<Db>,
         user_id: i32,
-        page_size: u32,
-        page_num: u32,
-    ) -> Result<Vec<OrderCard>, sqlx::Error> {
-        let offset = (page_num - 1) * page_size;
-        let limit = page_size;
-        let orders = sqlx::query!(
-            "
-select
- orders.id as order_id, orders.public_id as order_public_id, orders.buyer_user_id as order_buyer_user_id, orders.seller_user_id as order_seller_user_id, orders.quantity as order_quantity, orders.listing_id as order_listing_id, orders.shipping_option_id, orders.shipping_instructions, orders.amount_owed_sat, orders.seller_credit_sat, orders.paid, orders.shipped, orders.canceled_by_seller, orders.canceled_by_buyer, orders.reviewed as order_reviewed, orders.invoice_hash, orders.invoice_payment_request, orders.review_rating, orders.review_text, orders.created_time_ms, orders.payment_time_ms, orders.review_time_ms, listings.id, listings.public_id as listing_public_id, listings.user_id as listing_user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms as listing_created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
-from
- orders
-LEFT JOIN
- listings
-ON
- orders.listing_id = listings.id
-LEFT JOIN
- listingimages
-ON
- listings.id = listingimages.listing_id
-AND
- listingimages.is_primary = (SELECT MAX(is_primary) FROM listingimages WHERE listing_id = listings.id)
-LEFT JOIN
- users
-ON
- listings.user_id = users.id
-WHERE
- orders.paid
-AND
- order_buyer_user_id = ?
-GROUP BY
- orders.id
-ORDER BY orders.payment_time_ms DESC
-LIMIT ?
-OFFSET ?
-;",
+        new_squeaknode_pubkey: &str,
+    ) -> Result<(), sqlx::Error> {
+        UserSettings::insert_if_doesnt_exist(db, user_id).await?;
+
+        sqlx::query!(
+            "UPDATE usersettings SET squeaknode_pubkey = ? WHERE user_id = ?;",
+            new_squeaknode_pubkey,
             user_id,
-            limit,
-            offset,
         )
-            .fetch(&mut **db)
-            .map_ok(|r| {
-                let o = Order {
-                    id: Some(r.order_id.unwrap().try_into().unwrap()),
-                    public_id: r.order_public_id.unwrap(),
-                    quantity: r.order_quantity.unwrap().try_into().unwrap(),
-                    buyer_user_id: r.order_buyer_user_id.unwrap().try_into().unwrap(),
-                    seller_user_id: r.order_seller_user_id.unwrap().try_into().unwrap(),
-                    listing_id: r.order_listing_id.unwrap().try_into().unwrap(),
-                    shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
-                    shipping_instructions: r.shipping_instructions.unwrap(),
-                    amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
-                    seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
-                    paid: r.paid.unwrap(),
-                    shipped: r.shipped.unwrap(),
-                    canceled_by_seller: r.canceled_by_seller.unwrap(),
-                    canceled_by_buyer: r.canceled_by_buyer.unwrap(),
-                    reviewed: r.order_reviewed.unwrap(),
-                    invoice_hash: r.invoice_hash.unwrap(),
-                    invoice_payment_request: r.invoice_payment_request.unwrap(),
-                    review_rating: r.review_rating.unwrap().try_into().unwrap(),
-                    review_text: r.review_text.unwrap(),
-                    created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
-                    payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
-                    review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
-                };
-                let l = r.id.map(|listing_id| Listing {
-                    id: Some(listing_id.try_into().unwrap()),
-                    public_id: r.listing_public_id.unwrap(),
-                    user_id: r.listing_user_id.unwrap().try_into().unwrap(),
-                    title: r.title.unwrap(),
-                    description: r.description.unwrap(),
-                    price_sat: r.price_sat.unwrap().try_into().unwrap(),
-                    fee_rate_basis_points: r.fee_rate_basis_points.unwrap().try_into().unwrap(),
-                    submitted: r.submitted.unwrap(),
-                    reviewed: r.reviewed.unwrap(),
-                    approved: r.approved.unwrap(),
-                    deactivated_by_seller: r.deactivated_by_seller.unwrap(),
-                    deactivated_by_admin: r.deactivated_by_admin.unwrap(),
-                    created_time_ms: r.listing_created_time_ms.unwrap().try_into().unwrap(),
-                });
-                let i = r.image_id.map(|image_id| ListingImage {
-                    id: Some(image_id.try_into().unwrap()),
-                    public_id: r.image_public_id.unwrap(),
-                    listing_id: r.listing_id.unwrap().try_into().unwrap(),
-                    image_data: r.image_data.unwrap(),
-                    is_primary: r.is_primary.unwrap(),
-                });
-                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
-                    id: Some(rocket_auth_user_id.try_into().unwrap()),
-                    username: r.rocket_auth_user_username.unwrap(),
-                });
-                OrderCard {
-                    order: o,
-                    listing: l,
-                    image: i,
-                    user: u,
-                }
-            })
-            .try_collect::<Vec<_>>()
-            .await?;
+        .execute(&mut **db)
+        .await?;
 
-        Ok(orders)
+        Ok(())
     }
 
-    pub async fn all_received_for_user(
+    pub async fn set_squeaknode_address(
         db: &mut Connection<Db>,
         user_id: i32,
-        page_size: u32,
-        page_num: u32,
-    ) -> Result<Vec<OrderCard>, sqlx::Error> {
-        let offset = (page_num - 1) * page_size;
-        let limit = page_size;
-        let orders = sqlx::query!(
+        new_squeaknode_address: &str,
+    ) -> Result<(), sqlx::Error> {
+        UserSettings::insert_if_doesnt_exist(db, user_id).await?;
+
+        sqlx::query!(
+            "UPDATE usersettings SET squeaknode_address = ? WHERE user_id = ?;",
+            new_squeaknode_address,
+            user_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_payout_node_pubkey(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        new_payout_node_pubkey: &str,
+    ) -> Result<(), sqlx::Error> {
+        UserSettings::insert_if_doesnt_exist(db, user_id).await?;
+
+        sqlx::query!(
+            "UPDATE usersettings SET payout_node_pubkey = ? WHERE user_id = ?;",
+            new_payout_node_pubkey,
+            user_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_auto_payout(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        new_auto_payout_enabled: bool,
+        new_auto_payout_threshold_sat: u64,
+        new_auto_payout_target: &str,
+    ) -> Result<(), sqlx::Error> {
+        UserSettings::insert_if_doesnt_exist(db, user_id).await?;
+        let new_auto_payout_threshold_sat_i64: i64 =
+            new_auto_payout_threshold_sat.try_into().unwrap();
+
+        sqlx::query!(
+            "UPDATE usersettings SET auto_payout_enabled = ?, auto_payout_threshold_sat = ?, auto_payout_target = ? WHERE user_id = ?;",
+            new_auto_payout_enabled,
+            new_auto_payout_threshold_sat_i64,
+            new_auto_payout_target,
+            user_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the ids of the active users that have automatic payouts
+    /// enabled.
+    pub async fn all_user_ids_with_auto_payout(
+        db: &mut SqliteConnection,
+    ) -> Result<Vec<i32>, sqlx::Error> {
+        let user_ids = sqlx::query!(
             "
-select
- orders.id as order_id, orders.public_id as order_public_id, orders.buyer_user_id as order_buyer_user_id, orders.seller_user_id as order_seller_user_id, orders.quantity as order_quantity, orders.listing_id as order_listing_id, orders.shipping_option_id, orders.shipping_instructions, orders.amount_owed_sat, orders.seller_credit_sat, orders.paid, orders.shipped, orders.canceled_by_seller, orders.canceled_by_buyer, orders.reviewed as order_reviewed, orders.invoice_hash, orders.invoice_payment_request, orders.review_rating, orders.review_text, orders.created_time_ms, orders.payment_time_ms, orders.review_time_ms, listings.id, listings.public_id as listing_public_id, listings.user_id as listing_user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms as listing_created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+select usersettings.user_id
 from
- orders
-LEFT JOIN
- listings
-ON
- orders.listing_id = listings.id
-LEFT JOIN
- listingimages
-ON
- listings.id = listingimages.listing_id
-AND
- listingimages.is_primary = (SELECT MAX(is_primary) FROM listingimages WHERE listing_id = listings.id)
-LEFT JOIN
- users
+ usersettings
+INNER JOIN
+ useraccounts
 ON
- listings.user_id = users.id
+ usersettings.user_id = useraccounts.user_id
 WHERE
- orders.shipped
+ usersettings.auto_payout_enabled
 AND
- order_seller_user_id = ?
-GROUP BY
- orders.id
-ORDER BY orders.payment_time_ms DESC
-LIMIT ?
-OFFSET ?
-;",
-            user_id,
-            limit,
-            offset,
+ useraccounts.paid
+AND
+ NOT useraccounts.disabled
+ORDER BY usersettings.user_id ASC
+;"
         )
-            .fetch(&mut **db)
-            .map_ok(|r| {
-                let o = Order {
-                    id: Some(r.order_id.unwrap().try_into().unwrap()),
-                    public_id: r.order_public_id.unwrap(),
-                    quantity: r.order_quantity.unwrap().try_into().unwrap(),
-                    buyer_user_id: r.order_buyer_user_id.unwrap().try_into().unwrap(),
-                    seller_user_id: r.order_seller_user_id.unwrap().try_into().unwrap(),
-                    listing_id: r.order_listing_id.unwrap().try_into().unwrap(),
-                    shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
-                    shipping_instructions: r.shipping_instructions.unwrap(),
-                    amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
-                    seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
-                    paid: r.paid.unwrap(),
-                    shipped: r.shipped.unwrap(),
-                    canceled_by_seller: r.canceled_by_seller.unwrap(),
-                    canceled_by_buyer: r.canceled_by_buyer.unwrap(),
-                    reviewed: r.order_reviewed.unwrap(),
-                    invoice_hash: r.invoice_hash.unwrap(),
-                    invoice_payment_request: r.invoice_payment_request.unwrap(),
-                    review_rating: r.review_rating.unwrap().try_into().unwrap(),
-                    review_text: r.review_text.unwrap(),
-                    created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
-                    payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
-                    review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
-                };
-                let l = r.id.map(|listing_id| Listing {
-                    id: Some(listing_id.try_into().unwrap()),
-                    public_id: r.listing_public_id.unwrap(),
-                    user_id: r.listing_user_id.unwrap().try_into().unwrap(),
-                    title: r.title.unwrap(),
-                    description: r.description.unwrap(),
-                    price_sat: r.price_sat.unwrap().try_into().unwrap(),
-                    fee_rate_basis_points: r.fee_rate_basis_points.unwrap().try_into().unwrap(),
-                    submitted: r.submitted.unwrap(),
-                    reviewed: r.reviewed.unwrap(),
-                    approved: r.approved.unwrap(),
-                    deactivated_by_seller: r.deactivated_by_seller.unwrap(),
-                    deactivated_by_admin: r.deactivated_by_admin.unwrap(),
-                    created_time_ms: r.listing_created_time_ms.unwrap().try_into().unwrap(),
-                });
-                let i = r.image_id.map(|image_id| ListingImage {
-                    id: Some(image_id.try_into().unwrap()),
-                    public_id: r.image_public_id.unwrap(),
-                    listing_id: r.listing_id.unwrap().try_into().unwrap(),
-                    image_data: r.image_data.unwrap(),
-                    is_primary: r.is_primary.unwrap(),
-                });
-                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
-                    id: Some(rocket_auth_user_id.try_into().unwrap()),
-                    username: r.rocket_auth_user_username.unwrap(),
-                });
-                OrderCard {
-                    order: o,
-                    listing: l,
-                    image: i,
-                    user: u,
-                }
-            })
-            .try_collect::<Vec<_>>()
-            .await?;
+        .fetch(&mut *db)
+        .map_ok(|r| -> i32 { r.user_id.try_into().unwrap() })
+        .try_collect::<Vec<_>>()
+        .await?;
 
-        Ok(orders)
+        Ok(user_ids)
+    }
+}
+
+impl Order {
+    /// Returns the current status of the order.
+    pub fn status(&self) -> OrderStatus {
+        if self.dispute_resolved {
+            OrderStatus::DisputeResolved
+        } else if self.disputed {
+            OrderStatus::Disputed
+        } else if self.finalized {
+            OrderStatus::Finalized
+        } else if self.shipped {
+            OrderStatus::Shipped
+        } else if self.canceled_by_seller {
+            OrderStatus::CanceledBySeller
+        } else if self.canceled_by_buyer {
+            OrderStatus::CanceledByBuyer
+        } else if self.paid {
+            OrderStatus::Paid
+        } else if self.onchain_expired {
+            OrderStatus::Expired
+        } else {
+            OrderStatus::Unpaid
+        }
     }
 
-    pub async fn all_processing_for_user(
+    /// Returns the id of the inserted row.
+    pub async fn insert(
+        order: Order,
+        order_items: Vec<OrderItem>,
+        max_unpaid_orders: u32,
         db: &mut Connection<Db>,
-        user_id: i32,
-        page_size: u32,
-        page_num: u32,
-    ) -> Result<Vec<OrderCard>, sqlx::Error> {
-        let offset = (page_num - 1) * page_size;
-        let limit = page_size;
-        let orders = sqlx::query!(
+    ) -> Result<i32, String> {
+        let amount_owed_sat: i64 = order.amount_owed_sat.try_into().unwrap();
+        let seller_credit_sat: i64 = order.seller_credit_sat.try_into().unwrap();
+        let created_time_ms: i64 = order.created_time_ms.try_into().unwrap();
+        let payment_time_ms: i64 = order.payment_time_ms.try_into().unwrap();
+        let review_time_ms: i64 = order.review_time_ms.try_into().unwrap();
+        let invoice_expiry_time_ms: i64 = order.invoice_expiry_time_ms.try_into().unwrap();
+        let onchain_pending_sat: i64 = order.onchain_pending_sat.try_into().unwrap();
+        let onchain_received_sat: i64 = order.onchain_received_sat.try_into().unwrap();
+        let onchain_buyer_credit_sat: i64 = order.onchain_buyer_credit_sat.try_into().unwrap();
+        let fiat_cents_per_btc: i64 = order.fiat_cents_per_btc.try_into().unwrap();
+        let listing_fiat_price_cents: i64 = order.listing_fiat_price_cents.try_into().unwrap();
+        let dispute_opened_time_ms: i64 = order.dispute_opened_time_ms.try_into().unwrap();
+        let dispute_resolved_time_ms: i64 = order.dispute_resolved_time_ms.try_into().unwrap();
+        let dispute_buyer_refund_sat: i64 = order.dispute_buyer_refund_sat.try_into().unwrap();
+        let dispute_seller_credit_sat: i64 = order.dispute_seller_credit_sat.try_into().unwrap();
+        let shipped_time_ms: i64 = order.shipped_time_ms.try_into().unwrap();
+        let finalized_time_ms: i64 = order.finalized_time_ms.try_into().unwrap();
+
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        // Several variants of the same listing share the stock of the listing.
+        let mut listing_quantities: HashMap<i32, u32> = HashMap::new();
+        for order_item in &order_items {
+            *listing_quantities.entry(order_item.listing_id).or_insert(0) += order_item.quantity;
+        }
+        for (listing_id, quantity) in listing_quantities {
+            let remaining_quantity = Listing::remaining_quantity(&mut tx, listing_id)
+                .await
+                .map_err(|_| "failed to get remaining quantity of listing.")?;
+            if quantity > remaining_quantity {
+                return Err(format!(
+                    "Only {} items remaining in stock.",
+                    remaining_quantity
+                ));
+            }
+        }
+        for order_item in &order_items {
+            if order_item.variant_id != 0 {
+                let remaining_variant_quantity =
+                    ListingVariant::remaining_quantity(&mut tx, order_item.variant_id)
+                        .await
+                        .map_err(|_| "failed to get remaining quantity of variant.")?;
+                if order_item.quantity > remaining_variant_quantity {
+                    return Err(format!(
+                        "Only {} items of {} remaining in stock.",
+                        remaining_variant_quantity, order_item.variant_title
+                    ));
+                }
+            }
+        }
+
+        let insert_result = sqlx::query!(
+            "INSERT INTO orders (public_id, buyer_user_id, seller_user_id, quantity, listing_id, shipping_option_id, shipping_instructions, amount_owed_sat, seller_credit_sat, paid, shipped, canceled_by_seller, canceled_by_buyer, reviewed, review_text, review_rating, invoice_hash, invoice_payment_request, created_time_ms, payment_time_ms, review_time_ms, escrow, invoice_preimage, invoice_expiry_time_ms, onchain_address, onchain_pending_sat, onchain_received_sat, onchain_buyer_credit_sat, onchain_expired, fiat_currency, fiat_cents_per_btc, listing_fiat_price_cents, disputed, dispute_opened_time_ms, dispute_resolved, dispute_resolved_time_ms, dispute_buyer_refund_sat, dispute_seller_credit_sat, shipped_time_ms, finalized, finalized_time_ms, shipping_expired) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            order.public_id,
+            order.buyer_user_id,
+            order.seller_user_id,
+            order.quantity,
+            order.listing_id,
+            order.shipping_option_id,
+            order.shipping_instructions,
+            amount_owed_sat,
+            seller_credit_sat,
+            order.paid,
+            order.shipped,
+            order.canceled_by_seller,
+            order.canceled_by_buyer,
+            order.reviewed,
+            order.review_text,
+            order.review_rating,
+            order.invoice_hash,
+            order.invoice_payment_request,
+            created_time_ms,
+            payment_time_ms,
+            review_time_ms,
+            order.escrow,
+            order.invoice_preimage,
+            invoice_expiry_time_ms,
+            order.onchain_address,
+            onchain_pending_sat,
+            onchain_received_sat,
+            onchain_buyer_credit_sat,
+            order.onchain_expired,
+            order.fiat_currency,
+            fiat_cents_per_btc,
+            listing_fiat_price_cents,
+            order.disputed,
+            dispute_opened_time_ms,
+            order.dispute_resolved,
+            dispute_resolved_time_ms,
+            dispute_buyer_refund_sat,
+            dispute_seller_credit_sat,
+            shipped_time_ms,
+            order.finalized,
+            finalized_time_ms,
+            order.shipping_expired,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| "failed to insert order.")?;
+        let order_id: i32 = insert_result.last_insert_rowid() as _;
+
+        for order_item in order_items {
+            OrderItem::insert(
+                OrderItem {
+                    order_id,
+                    ..order_item
+                },
+                &mut tx,
+            )
+            .await
+            .map_err(|_| "failed to insert order item.")?;
+        }
+
+        let num_unpaid_orders = sqlx::query!(
             "
 select
- orders.id as order_id, orders.public_id as order_public_id, orders.buyer_user_id as order_buyer_user_id, orders.seller_user_id as order_seller_user_id, orders.quantity as order_quantity, orders.listing_id as order_listing_id, orders.shipping_option_id, orders.shipping_instructions, orders.amount_owed_sat, orders.seller_credit_sat, orders.paid, orders.shipped, orders.canceled_by_seller, orders.canceled_by_buyer, orders.reviewed as order_reviewed, orders.invoice_hash, orders.invoice_payment_request, orders.review_rating, orders.review_text, orders.created_time_ms, orders.payment_time_ms, orders.review_time_ms, listings.id, listings.public_id as listing_public_id, listings.user_id as listing_user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.created_time_ms as listing_created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+ COUNT(orders.id) as num_unpaid_orders
 from
  orders
-LEFT JOIN
- listings
-ON
- orders.listing_id = listings.id
-LEFT JOIN
- listingimages
-ON
- listings.id = listingimages.listing_id
-AND
- listingimages.is_primary = (SELECT MAX(is_primary) FROM listingimages WHERE listing_id = listings.id)
-LEFT JOIN
- users
-ON
- listings.user_id = users.id
 WHERE
- orders.paid
-AND
- not (orders.shipped OR orders.canceled_by_seller OR orders.canceled_by_buyer)
+ orders.buyer_user_id = ?
 AND
- orders.seller_user_id = ?
-GROUP BY
- orders.id
-ORDER BY orders.payment_time_ms DESC
-LIMIT ?
-OFFSET ?
+ NOT orders.paid
 ;",
-            user_id,
-            limit,
-            offset,
+            order.buyer_user_id,
         )
-            .fetch(&mut **db)
-            .map_ok(|r| {
+        .fetch_one(&mut *tx)
+        .map_ok(|r| r.num_unpaid_orders as u32)
+        .await
+        .map_err(|_| "failed to get count of unpaid orders for buyer.")?;
 
-                let o = Order {
-                    id: Some(r.order_id.unwrap().try_into().unwrap()),
-                    public_id: r.order_public_id.unwrap(),
-                    quantity: r.order_quantity.unwrap().try_into().unwrap(),
-                    buyer_user_id: r.order_buyer_user_id.unwrap().try_into().unwrap(),
-                    seller_user_id: r.order_seller_user_id.unwrap().try_into().unwrap(),
-                    listing_id: r.order_listing_id.unwrap().try_into().unwrap(),
-                    shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
-                    shipping_instructions: r.shipping_instructions.unwrap(),
-                    amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
-                    seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
-                    paid: r.paid.unwrap(),
-                    shipped: r.shipped.unwrap(),
-                    canceled_by_seller: r.canceled_by_seller.unwrap(),
-                    canceled_by_buyer: r.canceled_by_buyer.unwrap(),
-                    reviewed: r.order_reviewed.unwrap(),
-                    invoice_hash: r.invoice_hash.unwrap(),
-                    invoice_payment_request: r.invoice_payment_request.unwrap(),
-                    review_rating: r.review_rating.unwrap().try_into().unwrap(),
-                    review_text: r.review_text.unwrap(),
-                    created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
-                    payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
-                    review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
-                };
-                let l = r.id.map(|listing_id| Listing {
-                    id: Some(listing_id.try_into().unwrap()),
-                    public_id: r.listing_public_id.unwrap(),
-                    user_id: r.listing_user_id.unwrap().try_into().unwrap(),
-                    title: r.title.unwrap(),
-                    description: r.description.unwrap(),
-                    price_sat: r.price_sat.unwrap().try_into().unwrap(),
-                    fee_rate_basis_points: r.fee_rate_basis_points.unwrap().try_into().unwrap(),
-                    submitted: r.submitted.unwrap(),
-                    reviewed: r.reviewed.unwrap(),
-                    approved: r.approved.unwrap(),
-                    deactivated_by_seller: r.deactivated_by_seller.unwrap(),
-                    deactivated_by_admin: r.deactivated_by_admin.unwrap(),
-                    created_time_ms: r.listing_created_time_ms.unwrap().try_into().unwrap(),
-                });
-                let i = r.image_id.map(|image_id| ListingImage {
-                    id: Some(image_id.try_into().unwrap()),
-                    public_id: r.image_public_id.unwrap(),
-                    listing_id: r.listing_id.unwrap().try_into().unwrap(),
-                    image_data: r.image_data.unwrap(),
-                    is_primary: r.is_primary.unwrap(),
-                });
-                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
-                    id: Some(rocket_auth_user_id.try_into().unwrap()),
-                    username: r.rocket_auth_user_username.unwrap(),
-                });
-                OrderCard {
-                    order: o,
-                    listing: l,
-                    image: i,
-                    user: u,
-                }
+        if num_unpaid_orders > max_unpaid_orders {
+            return Err(format!(
+                "more than {:?} unpaid orders not allowed.",
+                max_unpaid_orders,
+            ));
+        }
 
-            })
-            .try_collect::<Vec<_>>()
-            .await?;
+        let order_event = OrderEvent {
+            id: None,
+            order_id,
+            status: OrderStatus::Unpaid,
+            actor_user_id: order.buyer_user_id,
+            created_time_ms: order.created_time_ms,
+        };
+        OrderEvent::insert(order_event, &mut tx)
+            .await
+            .map_err(|_| "failed to insert order event.")?;
 
-        Ok(orders)
-    }
-}
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
 
-impl AccountInfo {
-    pub async fn account_info_for_user(
-        db: &mut Connection<Db>,
-        user_id: i32,
-    ) -> Result<AccountInfo, sqlx::Error> {
-        let account_balance_sat = AccountInfo::total_account_balance_for_user(db, user_id).await?;
-        let num_unshipped_orders = Order::num_processing_for_user(db, user_id).await?;
-        Ok(AccountInfo {
-            account_balance_sat,
-            num_unshipped_orders,
-        })
+        Ok(order_id)
     }
 
-    pub async fn all_account_balance_changes_for_user(
+    /// Sets a new review for a given order.
+    ///
+    /// Sets the "review_time_ms" field to current time if this is the first review.
+    /// Otherwise, keeps the existing value for "review_time_ms"
+    pub async fn set_order_review(
         db: &mut Connection<Db>,
-        user_id: i32,
-        page_size: u32,
-        page_num: u32,
-    ) -> Result<Vec<AccountBalanceChange>, sqlx::Error> {
-        // TODO: Order by event time in SQL query. When this is fixed: https://github.com/launchbadge/sqlx/issues/1350
-        let offset = (page_num - 1) * page_size;
-        let limit = page_size;
+        public_id: &str,
+        review_rating: u32,
+        review_text: &str,
+        review_time_ms: u64,
+    ) -> Result<(), sqlx::Error> {
+        let review_time_ms_i64: i64 = review_time_ms.try_into().unwrap();
+
+        sqlx::query!(
+            "
+        UPDATE
+         orders
+        SET
+         reviewed = true,
+         review_rating = ?,
+         review_text = ?,
+         review_time_ms = ?
+        WHERE
+         public_id = ?
+        ;",
+            review_rating,
+            review_text,
+            review_time_ms_i64,
+            public_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn single(db: &mut SqliteConnection, id: i32) -> Result<Order, sqlx::Error> {
+        let order = sqlx::query!("select * from orders WHERE id = ?;", id)
+            .fetch_one(&mut *db)
+            .map_ok(|r| Order {
+                id: Some(r.id.try_into().unwrap()),
+                public_id: r.public_id,
+                quantity: r.quantity.try_into().unwrap(),
+                buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+                seller_user_id: r.seller_user_id.try_into().unwrap(),
+                listing_id: r.listing_id.try_into().unwrap(),
+                shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+                shipping_instructions: r.shipping_instructions,
+                amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+                seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+                paid: r.paid,
+                shipped: r.shipped,
+                canceled_by_seller: r.canceled_by_seller,
+                canceled_by_buyer: r.canceled_by_buyer,
+                reviewed: r.reviewed,
+                invoice_hash: r.invoice_hash,
+                invoice_payment_request: r.invoice_payment_request,
+                review_rating: r.review_rating.try_into().unwrap(),
+                review_text: r.review_text,
+                created_time_ms: r.created_time_ms.try_into().unwrap(),
+                payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+                review_time_ms: r.review_time_ms.try_into().unwrap(),
+                escrow: r.escrow,
+                invoice_preimage: r.invoice_preimage,
+                paid_from_balance: r.paid_from_balance,
+                invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+                onchain_address: r.onchain_address,
+                onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+                onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+                onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+                onchain_expired: r.onchain_expired,
+                fiat_currency: r.fiat_currency,
+                fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+                listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+                disputed: r.disputed,
+                dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+                dispute_resolved: r.dispute_resolved,
+                dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+                dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+                dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+                shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+                finalized: r.finalized,
+                finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+                shipping_expired: r.shipping_expired,
+            })
+            .await?;
+
+        Ok(order)
+    }
+
+    pub async fn single_by_public_id(
+        db: &mut Connection<Db>,
+        public_id: &str,
+    ) -> Result<Order, sqlx::Error> {
+        let order = sqlx::query!("select * from orders WHERE public_id = ?;", public_id)
+            .fetch_one(&mut **db)
+            .map_ok(|r| Order {
+                id: Some(r.id.try_into().unwrap()),
+                public_id: r.public_id,
+                quantity: r.quantity.try_into().unwrap(),
+                buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+                seller_user_id: r.seller_user_id.try_into().unwrap(),
+                listing_id: r.listing_id.try_into().unwrap(),
+                shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+                shipping_instructions: r.shipping_instructions,
+                amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+                seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+                paid: r.paid,
+                shipped: r.shipped,
+                canceled_by_seller: r.canceled_by_seller,
+                canceled_by_buyer: r.canceled_by_buyer,
+                reviewed: r.reviewed,
+                invoice_hash: r.invoice_hash,
+                invoice_payment_request: r.invoice_payment_request,
+                review_rating: r.review_rating.try_into().unwrap(),
+                review_text: r.review_text,
+                created_time_ms: r.created_time_ms.try_into().unwrap(),
+                payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+                review_time_ms: r.review_time_ms.try_into().unwrap(),
+                escrow: r.escrow,
+                invoice_preimage: r.invoice_preimage,
+                paid_from_balance: r.paid_from_balance,
+                invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+                onchain_address: r.onchain_address,
+                onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+                onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+                onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+                onchain_expired: r.onchain_expired,
+                fiat_currency: r.fiat_currency,
+                fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+                listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+                disputed: r.disputed,
+                dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+                dispute_resolved: r.dispute_resolved,
+                dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+                dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+                dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+                shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+                finalized: r.finalized,
+                finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+                shipping_expired: r.shipping_expired,
+            })
+            .await?;
+
+        Ok(order)
+    }
+
+    pub async fn single_by_invoice_hash(
+        db: &mut PoolConnection<Sqlite>,
+        invoice_hash: &str,
+    ) -> Result<Order, sqlx::Error> {
+        let order = sqlx::query!("select * from orders WHERE invoice_hash = ?;", invoice_hash)
+            .fetch_one(&mut **db)
+            .map_ok(|r| Order {
+                id: Some(r.id.try_into().unwrap()),
+                public_id: r.public_id,
+                quantity: r.quantity.try_into().unwrap(),
+                buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+                seller_user_id: r.seller_user_id.try_into().unwrap(),
+                listing_id: r.listing_id.try_into().unwrap(),
+                shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+                shipping_instructions: r.shipping_instructions,
+                amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+                seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+                paid: r.paid,
+                shipped: r.shipped,
+                canceled_by_seller: r.canceled_by_seller,
+                canceled_by_buyer: r.canceled_by_buyer,
+                reviewed: r.reviewed,
+                invoice_hash: r.invoice_hash,
+                invoice_payment_request: r.invoice_payment_request,
+                review_rating: r.review_rating.try_into().unwrap(),
+                review_text: r.review_text,
+                created_time_ms: r.created_time_ms.try_into().unwrap(),
+                payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+                review_time_ms: r.review_time_ms.try_into().unwrap(),
+                escrow: r.escrow,
+                invoice_preimage: r.invoice_preimage,
+                paid_from_balance: r.paid_from_balance,
+                invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+                onchain_address: r.onchain_address,
+                onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+                onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+                onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+                onchain_expired: r.onchain_expired,
+                fiat_currency: r.fiat_currency,
+                fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+                listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+                disputed: r.disputed,
+                dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+                dispute_resolved: r.dispute_resolved,
+                dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+                dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+                dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+                shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+                finalized: r.finalized,
+                finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+                shipping_expired: r.shipping_expired,
+            })
+            .await?;
+
+        Ok(order)
+    }
+
+    pub async fn all_older_than(
+        db: &mut PoolConnection<Sqlite>,
+        created_time_ms: u64,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let created_time_ms_i64: i64 = created_time_ms.try_into().unwrap();
+
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ created_time_ms < ?
+AND
+ NOT paid
+;",
+            created_time_ms_i64,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            quantity: r.quantity.try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+            seller_user_id: r.seller_user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions,
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+            paid: r.paid,
+            shipped: r.shipped,
+            canceled_by_seller: r.canceled_by_seller,
+            canceled_by_buyer: r.canceled_by_buyer,
+            reviewed: r.reviewed,
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            review_rating: r.review_rating.try_into().unwrap(),
+            review_text: r.review_text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            review_time_ms: r.review_time_ms.try_into().unwrap(),
+            escrow: r.escrow,
+            invoice_preimage: r.invoice_preimage,
+            paid_from_balance: r.paid_from_balance,
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            onchain_address: r.onchain_address,
+            onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+            onchain_expired: r.onchain_expired,
+            fiat_currency: r.fiat_currency,
+            fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+            disputed: r.disputed,
+            dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved,
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+            finalized: r.finalized,
+            finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+            shipping_expired: r.shipping_expired,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    /// Returns all unpaid orders with an invoice that expired before the given time.
+    pub async fn all_unpaid_with_invoice_expired_before(
+        db: &mut PoolConnection<Sqlite>,
+        invoice_expiry_time_ms: u64,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let invoice_expiry_time_ms_i64: i64 = invoice_expiry_time_ms.try_into().unwrap();
+
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ invoice_expiry_time_ms < ?
+AND
+ NOT paid
+AND
+ onchain_address = ''
+;",
+            invoice_expiry_time_ms_i64,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            quantity: r.quantity.try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+            seller_user_id: r.seller_user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions,
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+            paid: r.paid,
+            shipped: r.shipped,
+            canceled_by_seller: r.canceled_by_seller,
+            canceled_by_buyer: r.canceled_by_buyer,
+            reviewed: r.reviewed,
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            review_rating: r.review_rating.try_into().unwrap(),
+            review_text: r.review_text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            review_time_ms: r.review_time_ms.try_into().unwrap(),
+            escrow: r.escrow,
+            invoice_preimage: r.invoice_preimage,
+            paid_from_balance: r.paid_from_balance,
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            onchain_address: r.onchain_address,
+            onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+            onchain_expired: r.onchain_expired,
+            fiat_currency: r.fiat_currency,
+            fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+            disputed: r.disputed,
+            dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved,
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+            finalized: r.finalized,
+            finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+            shipping_expired: r.shipping_expired,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    /// Replaces the invoice of an unpaid order.
+    ///
+    /// Returns false if the order was paid in the meantime.
+    pub async fn update_invoice(
+        db: &mut Connection<Db>,
+        order_id: i32,
+        invoice_hash: &str,
+        invoice_payment_request: &str,
+        invoice_preimage: &str,
+        invoice_expiry_time_ms: u64,
+    ) -> Result<bool, sqlx::Error> {
+        let invoice_expiry_time_ms_i64: i64 = invoice_expiry_time_ms.try_into().unwrap();
+
+        let update_result = sqlx::query!(
+            "UPDATE orders SET invoice_hash = ?, invoice_payment_request = ?, invoice_preimage = ?, invoice_expiry_time_ms = ? WHERE id = ? AND NOT paid",
+            invoice_hash,
+            invoice_payment_request,
+            invoice_preimage,
+            invoice_expiry_time_ms_i64,
+            order_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(update_result.rows_affected() == 1)
+    }
+
+    /// Returns all escrow orders that are waiting for their hold invoice to be paid.
+    pub async fn all_unpaid_with_escrow(
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ escrow
+AND
+ NOT paid
+;",
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            quantity: r.quantity.try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+            seller_user_id: r.seller_user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions,
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+            paid: r.paid,
+            shipped: r.shipped,
+            canceled_by_seller: r.canceled_by_seller,
+            canceled_by_buyer: r.canceled_by_buyer,
+            reviewed: r.reviewed,
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            review_rating: r.review_rating.try_into().unwrap(),
+            review_text: r.review_text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            review_time_ms: r.review_time_ms.try_into().unwrap(),
+            escrow: r.escrow,
+            invoice_preimage: r.invoice_preimage,
+            paid_from_balance: r.paid_from_balance,
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            onchain_address: r.onchain_address,
+            onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+            onchain_expired: r.onchain_expired,
+            fiat_currency: r.fiat_currency,
+            fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+            disputed: r.disputed,
+            dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved,
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+            finalized: r.finalized,
+            finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+            shipping_expired: r.shipping_expired,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    /// Returns all escrow orders paid before the given time that are not yet shipped or canceled.
+    pub async fn all_with_escrow_paid_before(
+        db: &mut PoolConnection<Sqlite>,
+        payment_time_ms: u64,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let payment_time_ms_i64: i64 = payment_time_ms.try_into().unwrap();
+
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ escrow
+AND
+ paid
+AND
+ NOT (shipped OR canceled_by_seller OR canceled_by_buyer)
+AND
+ payment_time_ms < ?
+;",
+            payment_time_ms_i64,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            quantity: r.quantity.try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+            seller_user_id: r.seller_user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions,
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+            paid: r.paid,
+            shipped: r.shipped,
+            canceled_by_seller: r.canceled_by_seller,
+            canceled_by_buyer: r.canceled_by_buyer,
+            reviewed: r.reviewed,
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            review_rating: r.review_rating.try_into().unwrap(),
+            review_text: r.review_text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            review_time_ms: r.review_time_ms.try_into().unwrap(),
+            escrow: r.escrow,
+            invoice_preimage: r.invoice_preimage,
+            paid_from_balance: r.paid_from_balance,
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            onchain_address: r.onchain_address,
+            onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+            onchain_expired: r.onchain_expired,
+            fiat_currency: r.fiat_currency,
+            fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+            disputed: r.disputed,
+            dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved,
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+            finalized: r.finalized,
+            finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+            shipping_expired: r.shipping_expired,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    /// Returns all orders without escrow paid before the given time that are not yet shipped or canceled.
+    pub async fn all_without_escrow_paid_before(
+        db: &mut PoolConnection<Sqlite>,
+        payment_time_ms: u64,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let payment_time_ms_i64: i64 = payment_time_ms.try_into().unwrap();
+
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ NOT escrow
+AND
+ paid
+AND
+ NOT (shipped OR canceled_by_seller OR canceled_by_buyer)
+AND
+ payment_time_ms < ?
+;",
+            payment_time_ms_i64,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            quantity: r.quantity.try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+            seller_user_id: r.seller_user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions,
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+            paid: r.paid,
+            shipped: r.shipped,
+            canceled_by_seller: r.canceled_by_seller,
+            canceled_by_buyer: r.canceled_by_buyer,
+            reviewed: r.reviewed,
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            review_rating: r.review_rating.try_into().unwrap(),
+            review_text: r.review_text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            review_time_ms: r.review_time_ms.try_into().unwrap(),
+            escrow: r.escrow,
+            invoice_preimage: r.invoice_preimage,
+            paid_from_balance: r.paid_from_balance,
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            onchain_address: r.onchain_address,
+            onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+            onchain_expired: r.onchain_expired,
+            fiat_currency: r.fiat_currency,
+            fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+            disputed: r.disputed,
+            dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved,
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+            finalized: r.finalized,
+            finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+            shipping_expired: r.shipping_expired,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    /// Returns the shipped orders still waiting for the buyer to finalize
+    /// that were shipped before the given time.
+    pub async fn all_unfinalized_shipped_before(
+        db: &mut PoolConnection<Sqlite>,
+        shipped_time_ms: u64,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let shipped_time_ms_i64: i64 = shipped_time_ms.try_into().unwrap();
+
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ paid
+AND
+ shipped
+AND
+ NOT (finalized OR disputed)
+AND
+ shipped_time_ms < ?
+;",
+            shipped_time_ms_i64,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            quantity: r.quantity.try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+            seller_user_id: r.seller_user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions,
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+            paid: r.paid,
+            shipped: r.shipped,
+            canceled_by_seller: r.canceled_by_seller,
+            canceled_by_buyer: r.canceled_by_buyer,
+            reviewed: r.reviewed,
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            review_rating: r.review_rating.try_into().unwrap(),
+            review_text: r.review_text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            review_time_ms: r.review_time_ms.try_into().unwrap(),
+            escrow: r.escrow,
+            invoice_preimage: r.invoice_preimage,
+            paid_from_balance: r.paid_from_balance,
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            onchain_address: r.onchain_address,
+            onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+            onchain_expired: r.onchain_expired,
+            fiat_currency: r.fiat_currency,
+            fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+            disputed: r.disputed,
+            dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved,
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+            finalized: r.finalized,
+            finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+            shipping_expired: r.shipping_expired,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    /// Returns the total amount of the paid escrow orders that are not yet
+    /// shipped or canceled. These funds are held in unsettled hold invoices.
+    pub async fn total_pending_escrow_sat(db: &mut SqliteConnection) -> Result<u64, sqlx::Error> {
+        let pending_escrow_sat = sqlx::query(
+            "
+SELECT COALESCE(SUM(amount_owed_sat), 0) as total_pending_escrow_sat
+from
+ orders
+WHERE
+ escrow
+AND
+ paid
+AND
+ NOT (shipped OR canceled_by_seller OR canceled_by_buyer)
+;",
+        )
+        .fetch_one(&mut *db)
+        .map_ok(|r| {
+            let pending_escrow_sat_i64: i64 = r.try_get("total_pending_escrow_sat").unwrap();
+            pending_escrow_sat_i64.try_into().unwrap()
+        })
+        .await?;
+
+        Ok(pending_escrow_sat)
+    }
+
+    pub async fn mark_as_paid(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        time_now_ms: u64,
+    ) -> Result<(), String> {
+        let time_now_ms_i64: i64 = time_now_ms.try_into().unwrap();
+
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(&mut tx, order_id, OrderStatus::Paid, 0, time_now_ms).await?;
+
+        sqlx::query!(
+            "UPDATE orders SET paid = true, payment_time_ms = ? WHERE id = ?",
+            time_now_ms_i64,
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as paid.")?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Marks an unpaid order as paid by debiting the buyer's account balance.
+    ///
+    /// The order invoice is canceled, so that it cannot also be paid over
+    /// Lightning. Fails without changing anything if the balance is too low.
+    pub async fn mark_as_paid_from_balance(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        buyer_user_id: i32,
+        time_now_ms: u64,
+        cancel_invoice_future: impl Future<
+            Output = Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String>,
+        >,
+    ) -> Result<(), String> {
+        let time_now_ms_i64: i64 = time_now_ms.try_into().unwrap();
+
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::Paid,
+            buyer_user_id,
+            time_now_ms,
+        )
+        .await?;
+
+        let update_result = sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ paid = true, paid_from_balance = true, escrow = false, payment_time_ms = ?
+WHERE
+ id = ?
+AND
+ buyer_user_id = ?
+AND
+ NOT paid
+;",
+            time_now_ms_i64,
+            order_id,
+            buyer_user_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as paid.")?;
+
+        if update_result.rows_affected() != 1 {
+            return Err("order cannot be paid.".to_string());
+        }
+
+        let account_balance_sat =
+            AccountInfo::total_account_balance_for_user(&mut tx, buyer_user_id)
+                .await
+                .map_err(|_| "failed to get account balance.")?;
+
+        if account_balance_sat < 0 {
+            return Err("Insufficient funds in account balance.".to_string());
+        }
+
+        cancel_invoice_future
+            .await
+            .map_err(|e| format!("failed to cancel order invoice: {:?}", e))?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Marks an order as shipped. Orders of sellers eligible for early
+    /// finalization are finalized at the same time.
+    pub async fn mark_as_shipped(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        seller_user_id: i32,
+        shipped_time_ms: u64,
+        finalized: bool,
+    ) -> Result<(), String> {
+        let shipped_time_ms_i64: i64 = shipped_time_ms.try_into().unwrap();
+
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::Shipped,
+            seller_user_id,
+            shipped_time_ms,
+        )
+        .await?;
+
+        sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ shipped = true, shipping_instructions = '', shipped_time_ms = ?
+WHERE
+ id = ?
+AND
+ paid
+AND
+ not (shipped OR canceled_by_seller OR canceled_by_buyer)
+;",
+            shipped_time_ms_i64,
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as shipped.")?;
+
+        if finalized {
+            Order::finalize(&mut tx, order_id, 0, shipped_time_ms).await?;
+        }
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Finalizes a shipped order, which credits the seller.
+    pub async fn mark_as_finalized(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        actor_user_id: i32,
+        finalized_time_ms: u64,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        Order::finalize(&mut tx, order_id, actor_user_id, finalized_time_ms).await?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    async fn finalize(
+        db: &mut SqliteConnection,
+        order_id: i32,
+        actor_user_id: i32,
+        finalized_time_ms: u64,
+    ) -> Result<(), String> {
+        let finalized_time_ms_i64: i64 = finalized_time_ms.try_into().unwrap();
+
+        OrderEvent::record_transition(
+            db,
+            order_id,
+            OrderStatus::Finalized,
+            actor_user_id,
+            finalized_time_ms,
+        )
+        .await?;
+
+        let update_result = sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ finalized = true, finalized_time_ms = ?
+WHERE
+ id = ?
+AND
+ paid
+AND
+ shipped
+AND
+ NOT (finalized OR disputed)
+;",
+            finalized_time_ms_i64,
+            order_id,
+        )
+        .execute(&mut *db)
+        .await
+        .map_err(|_| "failed to mark order as finalized.")?;
+
+        if update_result.rows_affected() != 1 {
+            return Err("order cannot be finalized.".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub async fn mark_as_canceled_by_seller(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        actor_user_id: i32,
+        canceled_time_ms: u64,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::CanceledBySeller,
+            actor_user_id,
+            canceled_time_ms,
+        )
+        .await?;
+
+        sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ canceled_by_seller = true, shipping_instructions = ''
+WHERE
+ id = ?
+AND
+ not (shipped OR canceled_by_seller OR canceled_by_buyer)
+;",
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as canceled by seller.")?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Cancels a paid order that the seller did not ship before the shipping
+    /// deadline, which refunds the buyer's account balance and counts as a
+    /// strike against the seller.
+    pub async fn mark_as_shipping_expired(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        canceled_time_ms: u64,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::CanceledBySeller,
+            0,
+            canceled_time_ms,
+        )
+        .await?;
+
+        let update_result = sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ canceled_by_seller = true, shipping_expired = true, shipping_instructions = ''
+WHERE
+ id = ?
+AND
+ paid
+AND
+ NOT escrow
+AND
+ not (shipped OR canceled_by_seller OR canceled_by_buyer)
+;",
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as shipping expired.")?;
+
+        if update_result.rows_affected() != 1 {
+            return Err("order cannot be marked as canceled.".to_string());
+        }
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    pub async fn mark_as_canceled_by_buyer(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        actor_user_id: i32,
+        canceled_time_ms: u64,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::CanceledByBuyer,
+            actor_user_id,
+            canceled_time_ms,
+        )
+        .await?;
+
+        sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ canceled_by_buyer = true, shipping_instructions = ''
+WHERE
+ id = ?
+AND
+ not (shipped OR canceled_by_seller OR canceled_by_buyer)
+;",
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as canceled by buyer.")?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Marks an escrow order as shipped, and settles its hold invoice in the
+    /// same transaction.
+    pub async fn mark_as_shipped_with_escrow(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        seller_user_id: i32,
+        shipped_time_ms: u64,
+        finalized: bool,
+        settle_hold_invoice_future: impl Future<
+            Output = Result<tonic_openssl_lnd::invoicesrpc::SettleInvoiceResp, String>,
+        >,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::Shipped,
+            seller_user_id,
+            shipped_time_ms,
+        )
+        .await?;
+
+        let shipped_time_ms_i64: i64 = shipped_time_ms.try_into().unwrap();
+        let update_result = sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ shipped = true, shipping_instructions = '', shipped_time_ms = ?
+WHERE
+ id = ?
+AND
+ paid
+AND
+ escrow
+AND
+ not (shipped OR canceled_by_seller OR canceled_by_buyer)
+;",
+            shipped_time_ms_i64,
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as shipped.")?;
+
+        if update_result.rows_affected() != 1 {
+            return Err("order cannot be marked as shipped.".to_string());
+        }
+
+        if finalized {
+            Order::finalize(&mut tx, order_id, 0, shipped_time_ms).await?;
+        }
+
+        settle_hold_invoice_future
+            .await
+            .map_err(|e| format!("failed to settle order hold invoice: {:?}", e))?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Marks an escrow order as canceled by the seller, and cancels its hold
+    /// invoice in the same transaction, which returns the payment to the buyer.
+    pub async fn mark_as_canceled_by_seller_with_escrow(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        actor_user_id: i32,
+        canceled_time_ms: u64,
+        shipping_expired: bool,
+        cancel_hold_invoice_future: impl Future<
+            Output = Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String>,
+        >,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::CanceledBySeller,
+            actor_user_id,
+            canceled_time_ms,
+        )
+        .await?;
+
+        let update_result = sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ canceled_by_seller = true, shipping_expired = ?, shipping_instructions = ''
+WHERE
+ id = ?
+AND
+ escrow
+AND
+ not (shipped OR canceled_by_seller OR canceled_by_buyer)
+;",
+            shipping_expired,
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as canceled by seller.")?;
+
+        if update_result.rows_affected() != 1 {
+            return Err("order cannot be marked as canceled.".to_string());
+        }
+
+        cancel_hold_invoice_future
+            .await
+            .map_err(|e| format!("failed to cancel order hold invoice: {:?}", e))?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Marks an escrow order as canceled by the buyer, and cancels its hold
+    /// invoice in the same transaction, which returns the payment to the buyer.
+    pub async fn mark_as_canceled_by_buyer_with_escrow(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        actor_user_id: i32,
+        canceled_time_ms: u64,
+        cancel_hold_invoice_future: impl Future<
+            Output = Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String>,
+        >,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::CanceledByBuyer,
+            actor_user_id,
+            canceled_time_ms,
+        )
+        .await?;
+
+        let update_result = sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ canceled_by_buyer = true, shipping_instructions = ''
+WHERE
+ id = ?
+AND
+ escrow
+AND
+ not (shipped OR canceled_by_seller OR canceled_by_buyer)
+;",
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as canceled by buyer.")?;
+
+        if update_result.rows_affected() != 1 {
+            return Err("order cannot be marked as canceled.".to_string());
+        }
+
+        cancel_hold_invoice_future
+            .await
+            .map_err(|e| format!("failed to cancel order hold invoice: {:?}", e))?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    pub async fn delete_expired_order(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        cancel_order_invoice_future: impl Future<
+            Output = Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String>,
+        >,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        let delete_result = sqlx::query!(
+            "
+DELETE FROM orders
+WHERE
+ id = ?
+AND
+ NOT paid
+;",
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete order from database.")?;
+
+        if delete_result.rows_affected() == 1 {
+            sqlx::query!("DELETE FROM orderevents WHERE order_id = ?", order_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| "failed to delete order events from database.")?;
+            sqlx::query!("DELETE FROM orderitems WHERE order_id = ?", order_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| "failed to delete order items from database.")?;
+        }
+
+        cancel_order_invoice_future
+            .await
+            .map_err(|e| format!("failed to cancel order invoice: {:?}", e))?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        Ok(())
+    }
+
+    /// Returns all unpaid on-chain orders that are still waiting for a payment.
+    pub async fn all_unpaid_onchain(
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ onchain_address != ''
+AND
+ NOT paid
+AND
+ NOT onchain_expired
+;",
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            quantity: r.quantity.try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+            seller_user_id: r.seller_user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions,
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+            paid: r.paid,
+            shipped: r.shipped,
+            canceled_by_seller: r.canceled_by_seller,
+            canceled_by_buyer: r.canceled_by_buyer,
+            reviewed: r.reviewed,
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            review_rating: r.review_rating.try_into().unwrap(),
+            review_text: r.review_text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            review_time_ms: r.review_time_ms.try_into().unwrap(),
+            escrow: r.escrow,
+            invoice_preimage: r.invoice_preimage,
+            paid_from_balance: r.paid_from_balance,
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            onchain_address: r.onchain_address,
+            onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+            onchain_expired: r.onchain_expired,
+            fiat_currency: r.fiat_currency,
+            fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+            disputed: r.disputed,
+            dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved,
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+            finalized: r.finalized,
+            finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+            shipping_expired: r.shipping_expired,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    /// Returns all unpaid on-chain orders whose payment window ended before the given time.
+    pub async fn all_unpaid_onchain_with_payment_expired_before(
+        db: &mut PoolConnection<Sqlite>,
+        invoice_expiry_time_ms: u64,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let invoice_expiry_time_ms_i64: i64 = invoice_expiry_time_ms.try_into().unwrap();
+
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ invoice_expiry_time_ms < ?
+AND
+ onchain_address != ''
+AND
+ NOT paid
+AND
+ NOT onchain_expired
+;",
+            invoice_expiry_time_ms_i64,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            quantity: r.quantity.try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.try_into().unwrap(),
+            seller_user_id: r.seller_user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions,
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.try_into().unwrap(),
+            paid: r.paid,
+            shipped: r.shipped,
+            canceled_by_seller: r.canceled_by_seller,
+            canceled_by_buyer: r.canceled_by_buyer,
+            reviewed: r.reviewed,
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            review_rating: r.review_rating.try_into().unwrap(),
+            review_text: r.review_text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            review_time_ms: r.review_time_ms.try_into().unwrap(),
+            escrow: r.escrow,
+            invoice_preimage: r.invoice_preimage,
+            paid_from_balance: r.paid_from_balance,
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            onchain_address: r.onchain_address,
+            onchain_pending_sat: r.onchain_pending_sat.try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.try_into().unwrap(),
+            onchain_expired: r.onchain_expired,
+            fiat_currency: r.fiat_currency,
+            fiat_cents_per_btc: r.fiat_cents_per_btc.try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.try_into().unwrap(),
+            disputed: r.disputed,
+            dispute_opened_time_ms: r.dispute_opened_time_ms.try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved,
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.try_into().unwrap(),
+            finalized: r.finalized,
+            finalized_time_ms: r.finalized_time_ms.try_into().unwrap(),
+            shipping_expired: r.shipping_expired,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    pub async fn update_onchain_amounts(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        onchain_pending_sat: u64,
+        onchain_received_sat: u64,
+    ) -> Result<(), sqlx::Error> {
+        let onchain_pending_sat_i64: i64 = onchain_pending_sat.try_into().unwrap();
+        let onchain_received_sat_i64: i64 = onchain_received_sat.try_into().unwrap();
+
+        sqlx::query!(
+            "
+UPDATE orders
+SET onchain_pending_sat = ?, onchain_received_sat = ?
+WHERE
+ id = ?
+AND
+ NOT paid
+AND
+ NOT onchain_expired
+;",
+            onchain_pending_sat_i64,
+            onchain_received_sat_i64,
+            order_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records the overpaid amount to credit back to the buyer before the order is marked as paid.
+    pub async fn set_onchain_buyer_credit(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        onchain_buyer_credit_sat: u64,
+    ) -> Result<(), sqlx::Error> {
+        let onchain_buyer_credit_sat_i64: i64 = onchain_buyer_credit_sat.try_into().unwrap();
+
+        sqlx::query!(
+            "
+UPDATE orders
+SET onchain_buyer_credit_sat = ?
+WHERE
+ id = ?
+AND
+ NOT paid
+AND
+ NOT onchain_expired
+;",
+            onchain_buyer_credit_sat_i64,
+            order_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Closes an underpaid on-chain order and credits the received amount back to the buyer.
+    pub async fn mark_onchain_as_expired(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+        onchain_buyer_credit_sat: u64,
+        expired_time_ms: u64,
+    ) -> Result<(), String> {
+        let onchain_buyer_credit_sat_i64: i64 = onchain_buyer_credit_sat.try_into().unwrap();
+
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(&mut tx, order_id, OrderStatus::Expired, 0, expired_time_ms)
+            .await?;
+
+        sqlx::query!(
+            "
+UPDATE orders
+SET onchain_expired = true, onchain_buyer_credit_sat = ?
+WHERE
+ id = ?
+AND
+ NOT paid
+AND
+ NOT onchain_expired
+;",
+            onchain_buyer_credit_sat_i64,
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark on-chain order as expired.")?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Deletes an expired on-chain order that never received any funds.
+    pub async fn delete_expired_onchain_order(
+        db: &mut PoolConnection<Sqlite>,
+        order_id: i32,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        let delete_result = sqlx::query!(
+            "
+DELETE FROM orders
+WHERE
+ id = ?
+AND
+ NOT paid
+AND
+ onchain_pending_sat = 0
+AND
+ onchain_received_sat = 0
+;",
+            order_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete order from database.")?;
+
+        if delete_result.rows_affected() == 1 {
+            sqlx::query!("DELETE FROM orderevents WHERE order_id = ?", order_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| "failed to delete order events from database.")?;
+            sqlx::query!("DELETE FROM orderitems WHERE order_id = ?", order_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| "failed to delete order items from database.")?;
+        }
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Opens a dispute on a shipped order that was paid after the given time,
+    /// together with the first evidence message.
+    pub async fn open_dispute(
+        db: &mut Connection<Db>,
+        order_id: i32,
+        dispute_message: DisputeMessage,
+        min_payment_time_ms: u64,
+        dispute_opened_time_ms: u64,
+    ) -> Result<(), String> {
+        let min_payment_time_ms_i64: i64 = min_payment_time_ms.try_into().unwrap();
+        let dispute_opened_time_ms_i64: i64 = dispute_opened_time_ms.try_into().unwrap();
+        let created_time_ms: i64 = dispute_message.created_time_ms.try_into().unwrap();
+
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::Disputed,
+            dispute_message.author_user_id,
+            dispute_opened_time_ms,
+        )
+        .await?;
+
+        let update_result = sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ disputed = true, dispute_opened_time_ms = ?
+WHERE
+ id = ?
+AND
+ paid
+AND
+ shipped
+AND
+ NOT (finalized OR disputed)
+AND
+ payment_time_ms > ?
+;",
+            dispute_opened_time_ms_i64,
+            order_id,
+            min_payment_time_ms_i64,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to mark order as disputed.")?;
+
+        if update_result.rows_affected() != 1 {
+            return Err("order cannot be disputed.".to_string());
+        }
+
+        sqlx::query!(
+            "INSERT INTO disputemessages (public_id, order_id, author_user_id, text, created_time_ms) VALUES (?, ?, ?, ?, ?)",
+            dispute_message.public_id,
+            dispute_message.order_id,
+            dispute_message.author_user_id,
+            dispute_message.text,
+            created_time_ms,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to insert dispute message.")?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Splits the amount of a disputed order between a refund to the buyer
+    /// and a credit to the seller. The market keeps the rest.
+    pub async fn resolve_dispute(
+        db: &mut Connection<Db>,
+        order_id: i32,
+        admin_user_id: i32,
+        dispute_buyer_refund_sat: u64,
+        dispute_seller_credit_sat: u64,
+        dispute_resolved_time_ms: u64,
+    ) -> Result<(), String> {
+        let dispute_buyer_refund_sat_i64: i64 = dispute_buyer_refund_sat.try_into().unwrap();
+        let dispute_seller_credit_sat_i64: i64 = dispute_seller_credit_sat.try_into().unwrap();
+        let dispute_resolved_time_ms_i64: i64 = dispute_resolved_time_ms.try_into().unwrap();
+
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        OrderEvent::record_transition(
+            &mut tx,
+            order_id,
+            OrderStatus::DisputeResolved,
+            admin_user_id,
+            dispute_resolved_time_ms,
+        )
+        .await?;
+
+        let update_result = sqlx::query!(
+            "
+UPDATE
+ orders
+SET
+ dispute_resolved = true, dispute_buyer_refund_sat = ?, dispute_seller_credit_sat = ?, dispute_resolved_time_ms = ?
+WHERE
+ id = ?
+AND
+ disputed
+AND
+ NOT dispute_resolved
+AND
+ ? + ? <= amount_owed_sat
+;",
+            dispute_buyer_refund_sat_i64,
+            dispute_seller_credit_sat_i64,
+            dispute_resolved_time_ms_i64,
+            order_id,
+            dispute_buyer_refund_sat_i64,
+            dispute_seller_credit_sat_i64,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to resolve dispute.")?;
+
+        if update_result.rows_affected() != 1 {
+            return Err("dispute cannot be resolved.".to_string());
+        }
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    /// Returns the orders with an unresolved dispute, oldest dispute first.
+    pub async fn all_with_open_dispute(
+        db: &mut Connection<Db>,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<Order>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+
+        let orders = sqlx::query!(
+            "
+select *
+from
+ orders
+WHERE
+ disputed
+AND
+ NOT dispute_resolved
+ORDER BY orders.dispute_opened_time_ms ASC
+LIMIT ?
+OFFSET ?
+;",
+            page_size,
+            offset,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Order {
+            id: r.id.map(|n| n.try_into().unwrap()),
+            public_id: r.public_id.unwrap(),
+            quantity: r.quantity.unwrap().try_into().unwrap(),
+            buyer_user_id: r.buyer_user_id.unwrap().try_into().unwrap(),
+            seller_user_id: r.seller_user_id.unwrap().try_into().unwrap(),
+            listing_id: r.listing_id.unwrap().try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
+            shipping_instructions: r.shipping_instructions.unwrap(),
+            amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
+            seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
+            paid: r.paid.unwrap(),
+            shipped: r.shipped.unwrap(),
+            canceled_by_seller: r.canceled_by_seller.unwrap(),
+            canceled_by_buyer: r.canceled_by_buyer.unwrap(),
+            reviewed: r.reviewed.unwrap(),
+            invoice_hash: r.invoice_hash.unwrap(),
+            invoice_payment_request: r.invoice_payment_request.unwrap(),
+            review_rating: r.review_rating.unwrap().try_into().unwrap(),
+            review_text: r.review_text.unwrap(),
+            created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
+            review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
+            escrow: r.escrow.unwrap(),
+            invoice_preimage: r.invoice_preimage.unwrap(),
+            paid_from_balance: r.paid_from_balance.unwrap(),
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.unwrap().try_into().unwrap(),
+            onchain_address: r.onchain_address.unwrap(),
+            onchain_pending_sat: r.onchain_pending_sat.unwrap().try_into().unwrap(),
+            onchain_received_sat: r.onchain_received_sat.unwrap().try_into().unwrap(),
+            onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.unwrap().try_into().unwrap(),
+            onchain_expired: r.onchain_expired.unwrap(),
+            fiat_currency: r.fiat_currency.unwrap(),
+            fiat_cents_per_btc: r.fiat_cents_per_btc.unwrap().try_into().unwrap(),
+            listing_fiat_price_cents: r.listing_fiat_price_cents.unwrap().try_into().unwrap(),
+            disputed: r.disputed.unwrap(),
+            dispute_opened_time_ms: r.dispute_opened_time_ms.unwrap().try_into().unwrap(),
+            dispute_resolved: r.dispute_resolved.unwrap(),
+            dispute_resolved_time_ms: r.dispute_resolved_time_ms.unwrap().try_into().unwrap(),
+            dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.unwrap().try_into().unwrap(),
+            dispute_seller_credit_sat: r.dispute_seller_credit_sat.unwrap().try_into().unwrap(),
+            shipped_time_ms: r.shipped_time_ms.unwrap().try_into().unwrap(),
+            finalized: r.finalized.unwrap(),
+            finalized_time_ms: r.finalized_time_ms.unwrap().try_into().unwrap(),
+            shipping_expired: r.shipping_expired.unwrap(),
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(orders)
+    }
+
+    pub async fn seller_info_for_user(
+        db: &mut SqliteConnection,
+        user_id: i32,
+    ) -> Result<SellerInfo, sqlx::Error> {
+        let total_amount_sold_sat = sqlx::query(
+            "
+            select
+             SUM(orders.amount_owed_sat) as total_amount_sold_sat
+            FROM
+             orders
+            WHERE
+             orders.finalized
+            AND
+             orders.seller_user_id = ?
+            GROUP BY
+             orders.seller_user_id
+            ;",
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *db)
+        .map_ok(|maybe_r| {
+            maybe_r.map(|r| {
+                let amount_sat_i64: i64 = r.try_get("total_amount_sold_sat").unwrap();
+                let amount_sat_u64: u64 = amount_sat_i64.try_into().unwrap();
+                amount_sat_u64
+            })
+        })
+        .await?;
+
+        let weighted_average_rating = sqlx::query(
+            "
+            select
+             SUM(orders.amount_owed_sat * orders.review_rating * 1000) / SUM(orders.amount_owed_sat) as weighted_average
+            FROM
+             orders
+            WHERE
+             orders.reviewed
+            AND
+             orders.seller_user_id = ?
+            GROUP BY
+             orders.seller_user_id
+            ;")
+.bind(user_id)
+            .fetch_optional(&mut *db)
+            .map_ok(|maybe_r| maybe_r.map(|r| {
+                let average_rating_numerator: i64 = r.try_get("weighted_average").unwrap();
+                let average_rating: f32 = (average_rating_numerator as f32) / 1000.0;
+                average_rating
+            }))
+        .await?;
+
+        let num_shipping_strikes = sqlx::query(
+            "
+            select
+             COUNT(orders.id) as num_shipping_strikes
+            FROM
+             orders
+            WHERE
+             orders.shipping_expired
+            AND
+             orders.seller_user_id = ?
+            ;",
+        )
+        .bind(user_id)
+        .fetch_one(&mut *db)
+        .map_ok(|r| {
+            let num_shipping_strikes_i64: i64 = r.try_get("num_shipping_strikes").unwrap();
+            num_shipping_strikes_i64.try_into().unwrap()
+        })
+        .await?;
+
+        let seller_info = SellerInfo {
+            username: "".to_string(),
+            total_amount_sold_sat: total_amount_sold_sat.unwrap_or(0),
+            weighted_average_rating: weighted_average_rating.unwrap_or(0.0),
+            num_shipping_strikes,
+        };
+
+        // TODO: remove option from return type.
+        Ok(seller_info)
+    }
+
+    pub async fn seller_info_for_all_users(
+        db: &mut Connection<Db>,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<SellerInfo>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let seller_infos = sqlx::query(
+                    "
+        SELECT weighted_average, total_amount_sold_sat, COALESCE(num_shipping_strikes, 0) as num_shipping_strikes, users.email
+        FROM
+         users
+        LEFT JOIN
+            (select
+             SUM(orders.amount_owed_sat) as total_amount_sold_sat, orders.seller_user_id as shipped_seller_user_id
+            FROM
+             orders
+            WHERE
+             shipped
+            GROUP BY
+             orders.seller_user_id) as seller_infos
+        ON
+         users.id = seller_infos.shipped_seller_user_id
+        LEFT JOIN
+            (select
+             SUM(orders.amount_owed_sat * orders.review_rating * 1000) / SUM(orders.amount_owed_sat) as weighted_average, orders.seller_user_id as reviewed_seller_user_id
+            FROM
+             orders
+            WHERE
+             orders.reviewed
+            AND
+             orders.shipped
+            GROUP BY
+             orders.seller_user_id) as seller_infos
+        ON
+         users.id = seller_infos.reviewed_seller_user_id
+        LEFT JOIN
+            (select
+             COUNT(orders.id) as num_shipping_strikes, orders.seller_user_id as struck_seller_user_id
+            FROM
+             orders
+            WHERE
+             orders.shipping_expired
+            GROUP BY
+             orders.seller_user_id) as seller_strikes
+        ON
+         users.id = seller_strikes.struck_seller_user_id
+        WHERE
+         total_amount_sold_sat > 0
+        ORDER BY
+         total_amount_sold_sat DESC
+        LIMIT ?
+        OFFSET ?
+            ;")
+            .bind(limit)
+            .bind(offset)
+            .fetch(&mut **db)
+            .map_ok(|r| {
+                SellerInfo {
+                    username: r.try_get("email").unwrap(),
+                    total_amount_sold_sat: {
+                        let amount_sat_i64: i64 = r.try_get("total_amount_sold_sat").unwrap();
+                        let amount_sat_u64: u64 = amount_sat_i64.try_into().unwrap();
+                        amount_sat_u64
+                    },
+                    weighted_average_rating: {
+                        let average_rating_numerator: i64 = r.try_get("weighted_average").unwrap();
+                        let average_rating: f32 = (average_rating_numerator as f32) / 1000.0;
+                        average_rating
+                    },
+                    num_shipping_strikes: {
+                        let num_shipping_strikes_i64: i64 = r.try_get("num_shipping_strikes").unwrap();
+                        num_shipping_strikes_i64.try_into().unwrap()
+                    },
+                }})
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(seller_infos)
+    }
+
+    pub async fn num_processing_for_user(
+        db: &mut Connection<Db>,
+        user_id: i32,
+    ) -> Result<u32, sqlx::Error> {
+        let num_orders = sqlx::query!(
+            "
+select
+ COUNT(orders.id) as num_processing_orders
+from
+ orders
+WHERE
+ orders.paid
+AND
+ not (orders.shipped OR orders.canceled_by_seller OR orders.canceled_by_buyer)
+AND
+ orders.seller_user_id = ?
+;",
+            user_id,
+        )
+        .fetch_one(&mut **db)
+        .map_ok(|r| r.num_processing_orders as u32)
+        .await?;
+
+        Ok(num_orders)
+    }
+}
+
+impl OrderCard {
+    pub async fn all_unpaid_for_user(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<OrderCard>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let orders = sqlx::query!(
+            "
+select
+ orders.id as order_id, orders.public_id as order_public_id, orders.buyer_user_id as order_buyer_user_id, orders.seller_user_id as order_seller_user_id, orders.quantity as order_quantity, orders.listing_id as order_listing_id, orders.shipping_option_id, orders.shipping_instructions, orders.amount_owed_sat, orders.seller_credit_sat, orders.paid, orders.shipped, orders.canceled_by_seller, orders.canceled_by_buyer, orders.reviewed as order_reviewed, orders.invoice_hash, orders.invoice_payment_request, orders.review_rating, orders.review_text, orders.created_time_ms, orders.payment_time_ms, orders.review_time_ms, orders.escrow, orders.invoice_preimage, orders.paid_from_balance, orders.invoice_expiry_time_ms, orders.onchain_address, orders.onchain_pending_sat, orders.onchain_received_sat, orders.onchain_buyer_credit_sat, orders.onchain_expired, orders.fiat_currency, orders.fiat_cents_per_btc, orders.listing_fiat_price_cents, orders.disputed, orders.dispute_opened_time_ms, orders.dispute_resolved, orders.dispute_resolved_time_ms, orders.dispute_buyer_refund_sat, orders.dispute_seller_credit_sat, orders.shipped_time_ms, orders.finalized, orders.finalized_time_ms, orders.shipping_expired, listings.id, listings.public_id as listing_public_id, listings.user_id as listing_user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms as listing_created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+from
+ orders
+LEFT JOIN
+ listings
+ON
+ orders.listing_id = listings.id
+LEFT JOIN
+ listingimages
+ON
+ listings.id = listingimages.listing_id
+AND
+ listingimages.is_primary = (SELECT MAX(is_primary) FROM listingimages WHERE listing_id = listings.id)
+LEFT JOIN
+ users
+ON
+ listings.user_id = users.id
+WHERE
+ not orders.paid
+AND
+ order_buyer_user_id = ?
+GROUP BY
+ orders.id
+ORDER BY orders.created_time_ms DESC
+LIMIT ?
+OFFSET ?
+;",
+            user_id,
+            limit,
+            offset,
+        )
+            .fetch(&mut **db)
+            .map_ok(|r| {
+                let o = Order {
+                    id: Some(r.order_id.unwrap().try_into().unwrap()),
+                    public_id: r.order_public_id.unwrap(),
+                    quantity: r.order_quantity.unwrap().try_into().unwrap(),
+                    buyer_user_id: r.order_buyer_user_id.unwrap().try_into().unwrap(),
+                    seller_user_id: r.order_seller_user_id.unwrap().try_into().unwrap(),
+                    listing_id: r.order_listing_id.unwrap().try_into().unwrap(),
+                    shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
+                    shipping_instructions: r.shipping_instructions.unwrap(),
+                    amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
+                    seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
+                    paid: r.paid.unwrap(),
+                    shipped: r.shipped.unwrap(),
+                    canceled_by_seller: r.canceled_by_seller.unwrap(),
+                    canceled_by_buyer: r.canceled_by_buyer.unwrap(),
+                    reviewed: r.order_reviewed.unwrap(),
+                    invoice_hash: r.invoice_hash.unwrap(),
+                    invoice_payment_request: r.invoice_payment_request.unwrap(),
+                    review_rating: r.review_rating.unwrap().try_into().unwrap(),
+                    review_text: r.review_text.unwrap(),
+                    created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
+                    review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
+                    escrow: r.escrow.unwrap(),
+                    invoice_preimage: r.invoice_preimage.unwrap(),
+                    paid_from_balance: r.paid_from_balance.unwrap(),
+                    invoice_expiry_time_ms: r.invoice_expiry_time_ms.unwrap().try_into().unwrap(),
+                    onchain_address: r.onchain_address.unwrap(),
+                    onchain_pending_sat: r.onchain_pending_sat.unwrap().try_into().unwrap(),
+                    onchain_received_sat: r.onchain_received_sat.unwrap().try_into().unwrap(),
+                    onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.unwrap().try_into().unwrap(),
+                    onchain_expired: r.onchain_expired.unwrap(),
+                    fiat_currency: r.fiat_currency.unwrap(),
+                    fiat_cents_per_btc: r.fiat_cents_per_btc.unwrap().try_into().unwrap(),
+                    listing_fiat_price_cents: r.listing_fiat_price_cents.unwrap().try_into().unwrap(),
+                    disputed: r.disputed.unwrap(),
+                    dispute_opened_time_ms: r.dispute_opened_time_ms.unwrap().try_into().unwrap(),
+                    dispute_resolved: r.dispute_resolved.unwrap(),
+                    dispute_resolved_time_ms: r.dispute_resolved_time_ms.unwrap().try_into().unwrap(),
+                    dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.unwrap().try_into().unwrap(),
+                    dispute_seller_credit_sat: r.dispute_seller_credit_sat.unwrap().try_into().unwrap(),
+                    shipped_time_ms: r.shipped_time_ms.unwrap().try_into().unwrap(),
+                    finalized: r.finalized.unwrap(),
+                    finalized_time_ms: r.finalized_time_ms.unwrap().try_into().unwrap(),
+                    shipping_expired: r.shipping_expired.unwrap(),
+                };
+                let l = r.id.map(|listing_id| Listing {
+                    id: Some(listing_id.try_into().unwrap()),
+                    public_id: r.listing_public_id.unwrap(),
+                    user_id: r.listing_user_id.unwrap().try_into().unwrap(),
+                    title: r.title.unwrap(),
+                    description: r.description.unwrap(),
+                    price_sat: r.price_sat.unwrap().try_into().unwrap(),
+                    fee_rate_basis_points: r.fee_rate_basis_points.unwrap().try_into().unwrap(),
+                    submitted: r.submitted.unwrap(),
+                    reviewed: r.reviewed.unwrap(),
+                    approved: r.approved.unwrap(),
+                    deactivated_by_seller: r.deactivated_by_seller.unwrap(),
+                    deactivated_by_admin: r.deactivated_by_admin.unwrap(),
+                    created_time_ms: r.listing_created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
+                });
+                let i = r.image_id.map(|image_id| ListingImage {
+                    id: Some(image_id.try_into().unwrap()),
+                    public_id: r.image_public_id.unwrap(),
+                    listing_id: r.listing_id.unwrap().try_into().unwrap(),
+                    image_data: r.image_data.unwrap(),
+                    is_primary: r.is_primary.unwrap(),
+                });
+                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
+                    id: Some(rocket_auth_user_id.try_into().unwrap()),
+                    username: r.rocket_auth_user_username.unwrap(),
+                });
+                OrderCard {
+                    order: o,
+                    listing: l,
+                    image: i,
+                    user: u,
+                }
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(orders)
+    }
+
+    pub async fn all_paid_for_user(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<OrderCard>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let orders = sqlx::query!(
+            "
+select
+ orders.id as order_id, orders.public_id as order_public_id, orders.buyer_user_id as order_buyer_user_id, orders.seller_user_id as order_seller_user_id, orders.quantity as order_quantity, orders.listing_id as order_listing_id, orders.shipping_option_id, orders.shipping_instructions, orders.amount_owed_sat, orders.seller_credit_sat, orders.paid, orders.shipped, orders.canceled_by_seller, orders.canceled_by_buyer, orders.reviewed as order_reviewed, orders.invoice_hash, orders.invoice_payment_request, orders.review_rating, orders.review_text, orders.created_time_ms, orders.payment_time_ms, orders.review_time_ms, orders.escrow, orders.invoice_preimage, orders.paid_from_balance, orders.invoice_expiry_time_ms, orders.onchain_address, orders.onchain_pending_sat, orders.onchain_received_sat, orders.onchain_buyer_credit_sat, orders.onchain_expired, orders.fiat_currency, orders.fiat_cents_per_btc, orders.listing_fiat_price_cents, orders.disputed, orders.dispute_opened_time_ms, orders.dispute_resolved, orders.dispute_resolved_time_ms, orders.dispute_buyer_refund_sat, orders.dispute_seller_credit_sat, orders.shipped_time_ms, orders.finalized, orders.finalized_time_ms, orders.shipping_expired, listings.id, listings.public_id as listing_public_id, listings.user_id as listing_user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms as listing_created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+from
+ orders
+LEFT JOIN
+ listings
+ON
+ orders.listing_id = listings.id
+LEFT JOIN
+ listingimages
+ON
+ listings.id = listingimages.listing_id
+AND
+ listingimages.is_primary = (SELECT MAX(is_primary) FROM listingimages WHERE listing_id = listings.id)
+LEFT JOIN
+ users
+ON
+ listings.user_id = users.id
+WHERE
+ orders.paid
+AND
+ order_buyer_user_id = ?
+GROUP BY
+ orders.id
+ORDER BY orders.payment_time_ms DESC
+LIMIT ?
+OFFSET ?
+;",
+            user_id,
+            limit,
+            offset,
+        )
+            .fetch(&mut **db)
+            .map_ok(|r| {
+                let o = Order {
+                    id: Some(r.order_id.unwrap().try_into().unwrap()),
+                    public_id: r.order_public_id.unwrap(),
+                    quantity: r.order_quantity.unwrap().try_into().unwrap(),
+                    buyer_user_id: r.order_buyer_user_id.unwrap().try_into().unwrap(),
+                    seller_user_id: r.order_seller_user_id.unwrap().try_into().unwrap(),
+                    listing_id: r.order_listing_id.unwrap().try_into().unwrap(),
+                    shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
+                    shipping_instructions: r.shipping_instructions.unwrap(),
+                    amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
+                    seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
+                    paid: r.paid.unwrap(),
+                    shipped: r.shipped.unwrap(),
+                    canceled_by_seller: r.canceled_by_seller.unwrap(),
+                    canceled_by_buyer: r.canceled_by_buyer.unwrap(),
+                    reviewed: r.order_reviewed.unwrap(),
+                    invoice_hash: r.invoice_hash.unwrap(),
+                    invoice_payment_request: r.invoice_payment_request.unwrap(),
+                    review_rating: r.review_rating.unwrap().try_into().unwrap(),
+                    review_text: r.review_text.unwrap(),
+                    created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
+                    review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
+                    escrow: r.escrow.unwrap(),
+                    invoice_preimage: r.invoice_preimage.unwrap(),
+                    paid_from_balance: r.paid_from_balance.unwrap(),
+                    invoice_expiry_time_ms: r.invoice_expiry_time_ms.unwrap().try_into().unwrap(),
+                    onchain_address: r.onchain_address.unwrap(),
+                    onchain_pending_sat: r.onchain_pending_sat.unwrap().try_into().unwrap(),
+                    onchain_received_sat: r.onchain_received_sat.unwrap().try_into().unwrap(),
+                    onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.unwrap().try_into().unwrap(),
+                    onchain_expired: r.onchain_expired.unwrap(),
+                    fiat_currency: r.fiat_currency.unwrap(),
+                    fiat_cents_per_btc: r.fiat_cents_per_btc.unwrap().try_into().unwrap(),
+                    listing_fiat_price_cents: r.listing_fiat_price_cents.unwrap().try_into().unwrap(),
+                    disputed: r.disputed.unwrap(),
+                    dispute_opened_time_ms: r.dispute_opened_time_ms.unwrap().try_into().unwrap(),
+                    dispute_resolved: r.dispute_resolved.unwrap(),
+                    dispute_resolved_time_ms: r.dispute_resolved_time_ms.unwrap().try_into().unwrap(),
+                    dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.unwrap().try_into().unwrap(),
+                    dispute_seller_credit_sat: r.dispute_seller_credit_sat.unwrap().try_into().unwrap(),
+                    shipped_time_ms: r.shipped_time_ms.unwrap().try_into().unwrap(),
+                    finalized: r.finalized.unwrap(),
+                    finalized_time_ms: r.finalized_time_ms.unwrap().try_into().unwrap(),
+                    shipping_expired: r.shipping_expired.unwrap(),
+                };
+                let l = r.id.map(|listing_id| Listing {
+                    id: Some(listing_id.try_into().unwrap()),
+                    public_id: r.listing_public_id.unwrap(),
+                    user_id: r.listing_user_id.unwrap().try_into().unwrap(),
+                    title: r.title.unwrap(),
+                    description: r.description.unwrap(),
+                    price_sat: r.price_sat.unwrap().try_into().unwrap(),
+                    fee_rate_basis_points: r.fee_rate_basis_points.unwrap().try_into().unwrap(),
+                    submitted: r.submitted.unwrap(),
+                    reviewed: r.reviewed.unwrap(),
+                    approved: r.approved.unwrap(),
+                    deactivated_by_seller: r.deactivated_by_seller.unwrap(),
+                    deactivated_by_admin: r.deactivated_by_admin.unwrap(),
+                    created_time_ms: r.listing_created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
+                });
+                let i = r.image_id.map(|image_id| ListingImage {
+                    id: Some(image_id.try_into().unwrap()),
+                    public_id: r.image_public_id.unwrap(),
+                    listing_id: r.listing_id.unwrap().try_into().unwrap(),
+                    image_data: r.image_data.unwrap(),
+                    is_primary: r.is_primary.unwrap(),
+                });
+                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
+                    id: Some(rocket_auth_user_id.try_into().unwrap()),
+                    username: r.rocket_auth_user_username.unwrap(),
+                });
+                OrderCard {
+                    order: o,
+                    listing: l,
+                    image: i,
+                    user: u,
+                }
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(orders)
+    }
+
+    pub async fn all_received_for_user(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<OrderCard>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let orders = sqlx::query!(
+            "
+select
+ orders.id as order_id, orders.public_id as order_public_id, orders.buyer_user_id as order_buyer_user_id, orders.seller_user_id as order_seller_user_id, orders.quantity as order_quantity, orders.listing_id as order_listing_id, orders.shipping_option_id, orders.shipping_instructions, orders.amount_owed_sat, orders.seller_credit_sat, orders.paid, orders.shipped, orders.canceled_by_seller, orders.canceled_by_buyer, orders.reviewed as order_reviewed, orders.invoice_hash, orders.invoice_payment_request, orders.review_rating, orders.review_text, orders.created_time_ms, orders.payment_time_ms, orders.review_time_ms, orders.escrow, orders.invoice_preimage, orders.paid_from_balance, orders.invoice_expiry_time_ms, orders.onchain_address, orders.onchain_pending_sat, orders.onchain_received_sat, orders.onchain_buyer_credit_sat, orders.onchain_expired, orders.fiat_currency, orders.fiat_cents_per_btc, orders.listing_fiat_price_cents, orders.disputed, orders.dispute_opened_time_ms, orders.dispute_resolved, orders.dispute_resolved_time_ms, orders.dispute_buyer_refund_sat, orders.dispute_seller_credit_sat, orders.shipped_time_ms, orders.finalized, orders.finalized_time_ms, orders.shipping_expired, listings.id, listings.public_id as listing_public_id, listings.user_id as listing_user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms as listing_created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+from
+ orders
+LEFT JOIN
+ listings
+ON
+ orders.listing_id = listings.id
+LEFT JOIN
+ listingimages
+ON
+ listings.id = listingimages.listing_id
+AND
+ listingimages.is_primary = (SELECT MAX(is_primary) FROM listingimages WHERE listing_id = listings.id)
+LEFT JOIN
+ users
+ON
+ listings.user_id = users.id
+WHERE
+ orders.shipped
+AND
+ order_seller_user_id = ?
+GROUP BY
+ orders.id
+ORDER BY orders.payment_time_ms DESC
+LIMIT ?
+OFFSET ?
+;",
+            user_id,
+            limit,
+            offset,
+        )
+            .fetch(&mut **db)
+            .map_ok(|r| {
+                let o = Order {
+                    id: Some(r.order_id.unwrap().try_into().unwrap()),
+                    public_id: r.order_public_id.unwrap(),
+                    quantity: r.order_quantity.unwrap().try_into().unwrap(),
+                    buyer_user_id: r.order_buyer_user_id.unwrap().try_into().unwrap(),
+                    seller_user_id: r.order_seller_user_id.unwrap().try_into().unwrap(),
+                    listing_id: r.order_listing_id.unwrap().try_into().unwrap(),
+                    shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
+                    shipping_instructions: r.shipping_instructions.unwrap(),
+                    amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
+                    seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
+                    paid: r.paid.unwrap(),
+                    shipped: r.shipped.unwrap(),
+                    canceled_by_seller: r.canceled_by_seller.unwrap(),
+                    canceled_by_buyer: r.canceled_by_buyer.unwrap(),
+                    reviewed: r.order_reviewed.unwrap(),
+                    invoice_hash: r.invoice_hash.unwrap(),
+                    invoice_payment_request: r.invoice_payment_request.unwrap(),
+                    review_rating: r.review_rating.unwrap().try_into().unwrap(),
+                    review_text: r.review_text.unwrap(),
+                    created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
+                    review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
+                    escrow: r.escrow.unwrap(),
+                    invoice_preimage: r.invoice_preimage.unwrap(),
+                    paid_from_balance: r.paid_from_balance.unwrap(),
+                    invoice_expiry_time_ms: r.invoice_expiry_time_ms.unwrap().try_into().unwrap(),
+                    onchain_address: r.onchain_address.unwrap(),
+                    onchain_pending_sat: r.onchain_pending_sat.unwrap().try_into().unwrap(),
+                    onchain_received_sat: r.onchain_received_sat.unwrap().try_into().unwrap(),
+                    onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.unwrap().try_into().unwrap(),
+                    onchain_expired: r.onchain_expired.unwrap(),
+                    fiat_currency: r.fiat_currency.unwrap(),
+                    fiat_cents_per_btc: r.fiat_cents_per_btc.unwrap().try_into().unwrap(),
+                    listing_fiat_price_cents: r.listing_fiat_price_cents.unwrap().try_into().unwrap(),
+                    disputed: r.disputed.unwrap(),
+                    dispute_opened_time_ms: r.dispute_opened_time_ms.unwrap().try_into().unwrap(),
+                    dispute_resolved: r.dispute_resolved.unwrap(),
+                    dispute_resolved_time_ms: r.dispute_resolved_time_ms.unwrap().try_into().unwrap(),
+                    dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.unwrap().try_into().unwrap(),
+                    dispute_seller_credit_sat: r.dispute_seller_credit_sat.unwrap().try_into().unwrap(),
+                    shipped_time_ms: r.shipped_time_ms.unwrap().try_into().unwrap(),
+                    finalized: r.finalized.unwrap(),
+                    finalized_time_ms: r.finalized_time_ms.unwrap().try_into().unwrap(),
+                    shipping_expired: r.shipping_expired.unwrap(),
+                };
+                let l = r.id.map(|listing_id| Listing {
+                    id: Some(listing_id.try_into().unwrap()),
+                    public_id: r.listing_public_id.unwrap(),
+                    user_id: r.listing_user_id.unwrap().try_into().unwrap(),
+                    title: r.title.unwrap(),
+                    description: r.description.unwrap(),
+                    price_sat: r.price_sat.unwrap().try_into().unwrap(),
+                    fee_rate_basis_points: r.fee_rate_basis_points.unwrap().try_into().unwrap(),
+                    submitted: r.submitted.unwrap(),
+                    reviewed: r.reviewed.unwrap(),
+                    approved: r.approved.unwrap(),
+                    deactivated_by_seller: r.deactivated_by_seller.unwrap(),
+                    deactivated_by_admin: r.deactivated_by_admin.unwrap(),
+                    created_time_ms: r.listing_created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
+                });
+                let i = r.image_id.map(|image_id| ListingImage {
+                    id: Some(image_id.try_into().unwrap()),
+                    public_id: r.image_public_id.unwrap(),
+                    listing_id: r.listing_id.unwrap().try_into().unwrap(),
+                    image_data: r.image_data.unwrap(),
+                    is_primary: r.is_primary.unwrap(),
+                });
+                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
+                    id: Some(rocket_auth_user_id.try_into().unwrap()),
+                    username: r.rocket_auth_user_username.unwrap(),
+                });
+                OrderCard {
+                    order: o,
+                    listing: l,
+                    image: i,
+                    user: u,
+                }
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(orders)
+    }
+
+    pub async fn all_processing_for_user(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<OrderCard>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let orders = sqlx::query!(
+            "
+select
+ orders.id as order_id, orders.public_id as order_public_id, orders.buyer_user_id as order_buyer_user_id, orders.seller_user_id as order_seller_user_id, orders.quantity as order_quantity, orders.listing_id as order_listing_id, orders.shipping_option_id, orders.shipping_instructions, orders.amount_owed_sat, orders.seller_credit_sat, orders.paid, orders.shipped, orders.canceled_by_seller, orders.canceled_by_buyer, orders.reviewed as order_reviewed, orders.invoice_hash, orders.invoice_payment_request, orders.review_rating, orders.review_text, orders.created_time_ms, orders.payment_time_ms, orders.review_time_ms, orders.escrow, orders.invoice_preimage, orders.paid_from_balance, orders.invoice_expiry_time_ms, orders.onchain_address, orders.onchain_pending_sat, orders.onchain_received_sat, orders.onchain_buyer_credit_sat, orders.onchain_expired, orders.fiat_currency, orders.fiat_cents_per_btc, orders.listing_fiat_price_cents, orders.disputed, orders.dispute_opened_time_ms, orders.dispute_resolved, orders.dispute_resolved_time_ms, orders.dispute_buyer_refund_sat, orders.dispute_seller_credit_sat, orders.shipped_time_ms, orders.finalized, orders.finalized_time_ms, orders.shipping_expired, listings.id, listings.public_id as listing_public_id, listings.user_id as listing_user_id, listings.title, listings.description, listings.price_sat, listings.fee_rate_basis_points, listings.submitted, listings.reviewed, listings.approved, listings.deactivated_by_seller, listings.deactivated_by_admin, listings.fiat_price_cents, listings.quantity, listings.digital, listings.created_time_ms as listing_created_time_ms, listingimages.id as image_id, listingimages.public_id as image_public_id, listingimages.listing_id, listingimages.image_data, listingimages.is_primary, users.id as rocket_auth_user_id, users.email as rocket_auth_user_username
+from
+ orders
+LEFT JOIN
+ listings
+ON
+ orders.listing_id = listings.id
+LEFT JOIN
+ listingimages
+ON
+ listings.id = listingimages.listing_id
+AND
+ listingimages.is_primary = (SELECT MAX(is_primary) FROM listingimages WHERE listing_id = listings.id)
+LEFT JOIN
+ users
+ON
+ listings.user_id = users.id
+WHERE
+ orders.paid
+AND
+ not (orders.shipped OR orders.canceled_by_seller OR orders.canceled_by_buyer)
+AND
+ orders.seller_user_id = ?
+GROUP BY
+ orders.id
+ORDER BY orders.payment_time_ms DESC
+LIMIT ?
+OFFSET ?
+;",
+            user_id,
+            limit,
+            offset,
+        )
+            .fetch(&mut **db)
+            .map_ok(|r| {
+
+                let o = Order {
+                    id: Some(r.order_id.unwrap().try_into().unwrap()),
+                    public_id: r.order_public_id.unwrap(),
+                    quantity: r.order_quantity.unwrap().try_into().unwrap(),
+                    buyer_user_id: r.order_buyer_user_id.unwrap().try_into().unwrap(),
+                    seller_user_id: r.order_seller_user_id.unwrap().try_into().unwrap(),
+                    listing_id: r.order_listing_id.unwrap().try_into().unwrap(),
+                    shipping_option_id: r.shipping_option_id.unwrap().try_into().unwrap(),
+                    shipping_instructions: r.shipping_instructions.unwrap(),
+                    amount_owed_sat: r.amount_owed_sat.unwrap().try_into().unwrap(),
+                    seller_credit_sat: r.seller_credit_sat.unwrap().try_into().unwrap(),
+                    paid: r.paid.unwrap(),
+                    shipped: r.shipped.unwrap(),
+                    canceled_by_seller: r.canceled_by_seller.unwrap(),
+                    canceled_by_buyer: r.canceled_by_buyer.unwrap(),
+                    reviewed: r.order_reviewed.unwrap(),
+                    invoice_hash: r.invoice_hash.unwrap(),
+                    invoice_payment_request: r.invoice_payment_request.unwrap(),
+                    review_rating: r.review_rating.unwrap().try_into().unwrap(),
+                    review_text: r.review_text.unwrap(),
+                    created_time_ms: r.created_time_ms.unwrap().try_into().unwrap(),
+                    payment_time_ms: r.payment_time_ms.unwrap().try_into().unwrap(),
+                    review_time_ms: r.review_time_ms.unwrap().try_into().unwrap(),
+                    escrow: r.escrow.unwrap(),
+                    invoice_preimage: r.invoice_preimage.unwrap(),
+                    paid_from_balance: r.paid_from_balance.unwrap(),
+                    invoice_expiry_time_ms: r.invoice_expiry_time_ms.unwrap().try_into().unwrap(),
+                    onchain_address: r.onchain_address.unwrap(),
+                    onchain_pending_sat: r.onchain_pending_sat.unwrap().try_into().unwrap(),
+                    onchain_received_sat: r.onchain_received_sat.unwrap().try_into().unwrap(),
+                    onchain_buyer_credit_sat: r.onchain_buyer_credit_sat.unwrap().try_into().unwrap(),
+                    onchain_expired: r.onchain_expired.unwrap(),
+                    fiat_currency: r.fiat_currency.unwrap(),
+                    fiat_cents_per_btc: r.fiat_cents_per_btc.unwrap().try_into().unwrap(),
+                    listing_fiat_price_cents: r.listing_fiat_price_cents.unwrap().try_into().unwrap(),
+                    disputed: r.disputed.unwrap(),
+                    dispute_opened_time_ms: r.dispute_opened_time_ms.unwrap().try_into().unwrap(),
+                    dispute_resolved: r.dispute_resolved.unwrap(),
+                    dispute_resolved_time_ms: r.dispute_resolved_time_ms.unwrap().try_into().unwrap(),
+                    dispute_buyer_refund_sat: r.dispute_buyer_refund_sat.unwrap().try_into().unwrap(),
+                    dispute_seller_credit_sat: r.dispute_seller_credit_sat.unwrap().try_into().unwrap(),
+                    shipped_time_ms: r.shipped_time_ms.unwrap().try_into().unwrap(),
+                    finalized: r.finalized.unwrap(),
+                    finalized_time_ms: r.finalized_time_ms.unwrap().try_into().unwrap(),
+                    shipping_expired: r.shipping_expired.unwrap(),
+                };
+                let l = r.id.map(|listing_id| Listing {
+                    id: Some(listing_id.try_into().unwrap()),
+                    public_id: r.listing_public_id.unwrap(),
+                    user_id: r.listing_user_id.unwrap().try_into().unwrap(),
+                    title: r.title.unwrap(),
+                    description: r.description.unwrap(),
+                    price_sat: r.price_sat.unwrap().try_into().unwrap(),
+                    fee_rate_basis_points: r.fee_rate_basis_points.unwrap().try_into().unwrap(),
+                    submitted: r.submitted.unwrap(),
+                    reviewed: r.reviewed.unwrap(),
+                    approved: r.approved.unwrap(),
+                    deactivated_by_seller: r.deactivated_by_seller.unwrap(),
+                    deactivated_by_admin: r.deactivated_by_admin.unwrap(),
+                    created_time_ms: r.listing_created_time_ms.unwrap().try_into().unwrap(),
+                    fiat_price_cents: r.fiat_price_cents.unwrap().try_into().unwrap(),
+                    quantity: r.quantity.unwrap().try_into().unwrap(),
+                    digital: r.digital.unwrap(),
+                });
+                let i = r.image_id.map(|image_id| ListingImage {
+                    id: Some(image_id.try_into().unwrap()),
+                    public_id: r.image_public_id.unwrap(),
+                    listing_id: r.listing_id.unwrap().try_into().unwrap(),
+                    image_data: r.image_data.unwrap(),
+                    is_primary: r.is_primary.unwrap(),
+                });
+                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
+                    id: Some(rocket_auth_user_id.try_into().unwrap()),
+                    username: r.rocket_auth_user_username.unwrap(),
+                });
+                OrderCard {
+                    order: o,
+                    listing: l,
+                    image: i,
+                    user: u,
+                }
+
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(orders)
+    }
+}
+
+impl AccountInfo {
+    pub async fn account_info_for_user(
+        db: &mut Connection<Db>,
+        user_id: i32,
+    ) -> Result<AccountInfo, sqlx::Error> {
+        let account_balance_sat = AccountInfo::total_account_balance_for_user(db, user_id).await?;
+        let num_unshipped_orders = Order::num_processing_for_user(db, user_id).await?;
+        let num_unread_messages = OrderMessage::num_unread_for_user(db, user_id).await?;
+        Ok(AccountInfo {
+            account_balance_sat,
+            num_unshipped_orders,
+            num_unread_messages,
+        })
+    }
+
+    pub async fn all_account_balance_changes_for_user(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<AccountBalanceChange>, sqlx::Error> {
+        // TODO: Order by event time in SQL query. When this is fixed: https://github.com/launchbadge/sqlx/issues/1350
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let account_balance_changes = sqlx::query("
+SELECT * FROM
+(select orders.seller_user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.shipped
+AND
+ orders.finalized
+AND
+ NOT orders.disputed
+AND
+ orders.seller_user_id = ?
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ (orders.canceled_by_seller OR orders.canceled_by_buyer)
+AND
+ NOT orders.escrow
+AND
+ orders.buyer_user_id = ?
+UNION ALL
+select orders.buyer_user_id as user_id, (0 - orders.amount_owed_sat) as amount_change_sat, 'order_payment' as event_type, orders.public_id as event_id, orders.payment_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid_from_balance
+AND
+ orders.buyer_user_id = ?
+UNION ALL
+select orders.buyer_user_id as user_id, orders.onchain_buyer_credit_sat as amount_change_sat, 'onchain_credit' as event_type, orders.public_id as event_id, CASE WHEN orders.paid THEN orders.payment_time_ms ELSE orders.invoice_expiry_time_ms END as event_time_ms
+from
+ orders
+WHERE
+ orders.onchain_buyer_credit_sat > 0
+AND
+ (orders.paid OR orders.onchain_expired)
+AND
+ orders.buyer_user_id = ?
+UNION ALL
+select orders.buyer_user_id as user_id, orders.dispute_buyer_refund_sat as amount_change_sat, 'dispute_refund' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+AND
+ orders.dispute_buyer_refund_sat > 0
+AND
+ orders.buyer_user_id = ?
+UNION ALL
+select orders.seller_user_id as user_id, orders.dispute_seller_credit_sat as amount_change_sat, 'dispute_seller_credit' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+AND
+ orders.dispute_seller_credit_sat > 0
+AND
+ orders.seller_user_id = ?
+UNION ALL
+select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.user_id = ?
+AND
+ withdrawals.status != 'failed'
+AND
+ NOT withdrawals.from_market_revenue
+UNION ALL
+select withdrawals.user_id as user_id, (0 - CASE WHEN withdrawals.status = 'succeeded' THEN withdrawals.fee_sat ELSE withdrawals.fee_limit_sat END) as amount_change_sat, 'withdrawal_fee' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.user_id = ?
+AND
+ withdrawals.fee_paid_by_user
+AND
+ withdrawals.status != 'failed')
+ORDER BY event_time_ms DESC
+LIMIT ?
+OFFSET ?
+;")
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(limit)
+            .bind(offset)
+            .fetch(&mut **db)
+            .map_ok(|r| AccountBalanceChange {
+                amount_change_sat: r.try_get("amount_change_sat").unwrap(),
+                event_type: r.try_get("event_type").unwrap(),
+                event_id: r.try_get("event_id").unwrap(),
+                event_time_ms: {
+                    let time_ms_i64: i64 = r.try_get("event_time_ms").unwrap();
+                    time_ms_i64 as u64
+                },
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(account_balance_changes)
+    }
+
+    pub async fn total_account_balance_for_user(
+        db: &mut SqliteConnection,
+        user_id: i32,
+    ) -> Result<i64, sqlx::Error> {
+        let account_balance_sat = sqlx::query("
+SELECT SUM(amount_change_sat) as total_account_balance_sat FROM
+(select orders.seller_user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.shipped
+AND
+ orders.finalized
+AND
+ NOT orders.disputed
+AND
+ orders.seller_user_id = ?
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ (orders.canceled_by_seller OR orders.canceled_by_buyer)
+AND
+ NOT orders.escrow
+AND
+ orders.buyer_user_id = ?
+UNION ALL
+select orders.buyer_user_id as user_id, (0 - orders.amount_owed_sat) as amount_change_sat, 'order_payment' as event_type, orders.public_id as event_id, orders.payment_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid_from_balance
+AND
+ orders.buyer_user_id = ?
+UNION ALL
+select orders.buyer_user_id as user_id, orders.onchain_buyer_credit_sat as amount_change_sat, 'onchain_credit' as event_type, orders.public_id as event_id, CASE WHEN orders.paid THEN orders.payment_time_ms ELSE orders.invoice_expiry_time_ms END as event_time_ms
+from
+ orders
+WHERE
+ orders.onchain_buyer_credit_sat > 0
+AND
+ (orders.paid OR orders.onchain_expired)
+AND
+ orders.buyer_user_id = ?
+UNION ALL
+select orders.buyer_user_id as user_id, orders.dispute_buyer_refund_sat as amount_change_sat, 'dispute_refund' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+AND
+ orders.dispute_buyer_refund_sat > 0
+AND
+ orders.buyer_user_id = ?
+UNION ALL
+select orders.seller_user_id as user_id, orders.dispute_seller_credit_sat as amount_change_sat, 'dispute_seller_credit' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+AND
+ orders.dispute_seller_credit_sat > 0
+AND
+ orders.seller_user_id = ?
+UNION ALL
+select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.user_id = ?
+AND
+ withdrawals.status != 'failed'
+AND
+ NOT withdrawals.from_market_revenue
+UNION ALL
+select withdrawals.user_id as user_id, (0 - CASE WHEN withdrawals.status = 'succeeded' THEN withdrawals.fee_sat ELSE withdrawals.fee_limit_sat END) as amount_change_sat, 'withdrawal_fee' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.user_id = ?
+AND
+ withdrawals.fee_paid_by_user
+AND
+ withdrawals.status != 'failed')
+;")
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_one(&mut *db)
+            .map_ok(|r|  {
+                let balance_sat_i64: i64 = r.try_get("total_account_balance_sat").unwrap();
+                balance_sat_i64
+            })
+            .await?;
+
+        Ok(account_balance_sat)
+    }
+
+    pub async fn all_account_balance_changes(
+        db: &mut Connection<Db>,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<AccountBalanceChange>, sqlx::Error> {
+        // TODO: Order by event time in SQL query. When this is fixed: https://github.com/launchbadge/sqlx/issues/1350
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
         let account_balance_changes = sqlx::query("
 SELECT * FROM
 (select orders.seller_user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
 from
- orders
+ orders
+WHERE
+ orders.paid
+AND
+ orders.shipped
+AND
+ orders.finalized
+AND
+ NOT orders.disputed
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ (orders.canceled_by_seller OR orders.canceled_by_buyer)
+AND
+ NOT orders.escrow
+UNION ALL
+select orders.buyer_user_id as user_id, (0 - orders.amount_owed_sat) as amount_change_sat, 'order_payment' as event_type, orders.public_id as event_id, orders.payment_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid_from_balance
+UNION ALL
+select orders.buyer_user_id as user_id, orders.onchain_buyer_credit_sat as amount_change_sat, 'onchain_credit' as event_type, orders.public_id as event_id, CASE WHEN orders.paid THEN orders.payment_time_ms ELSE orders.invoice_expiry_time_ms END as event_time_ms
+from
+ orders
+WHERE
+ orders.onchain_buyer_credit_sat > 0
+AND
+ (orders.paid OR orders.onchain_expired)
+UNION ALL
+select orders.buyer_user_id as user_id, orders.dispute_buyer_refund_sat as amount_change_sat, 'dispute_refund' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+AND
+ orders.dispute_buyer_refund_sat > 0
+UNION ALL
+select orders.seller_user_id as user_id, orders.dispute_seller_credit_sat as amount_change_sat, 'dispute_seller_credit' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+AND
+ orders.dispute_seller_credit_sat > 0
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'processing_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ NOT (orders.shipped OR orders.canceled_by_seller OR orders.canceled_by_buyer)
+AND
+ NOT orders.escrow
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'unfinalized_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.shipped
+AND
+ NOT (orders.finalized OR orders.disputed)
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'disputed_order' as event_type, orders.public_id as event_id, orders.dispute_opened_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.disputed
+AND
+ NOT orders.dispute_resolved
+UNION ALL
+select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.status != 'failed'
+AND
+ NOT withdrawals.from_market_revenue
+UNION ALL
+select withdrawals.user_id as user_id, (0 - CASE WHEN withdrawals.status = 'succeeded' THEN withdrawals.fee_sat ELSE withdrawals.fee_limit_sat END) as amount_change_sat, 'withdrawal_fee' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.fee_paid_by_user
+AND
+ withdrawals.status != 'failed'
+UNION ALL
+select useraccounts.user_id as user_id, useraccounts.amount_owed_sat as amount_change_sat, 'user_activation' as event_type, useraccounts.public_id as event_id, useraccounts.created_time_ms as event_time_ms
+from
+ useraccounts
+WHERE
+ useraccounts.paid
+AND
+ NOT useraccounts.disabled)
+ORDER BY event_time_ms DESC
+LIMIT ?
+OFFSET ?
+;")
+            .bind(limit)
+            .bind(offset)
+            .fetch(&mut **db)
+            .map_ok(|r| AccountBalanceChange {
+                amount_change_sat: r.try_get("amount_change_sat").unwrap(),
+                event_type: r.try_get("event_type").unwrap(),
+                event_id: r.try_get("event_id").unwrap(),
+                event_time_ms: {
+                    let time_ms_i64: i64 = r.try_get("event_time_ms").unwrap();
+                    time_ms_i64 as u64
+                },
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(account_balance_changes)
+    }
+
+    pub async fn total_market_liabilities_sat(
+        db: &mut SqliteConnection,
+    ) -> Result<i64, sqlx::Error> {
+        let market_liabilities_sat = sqlx::query("
+SELECT SUM(amount_change_sat) as total_market_liabilities_sat FROM
+(select orders.seller_user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.shipped
+AND
+ orders.finalized
+AND
+ NOT orders.disputed
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ (orders.canceled_by_seller OR orders.canceled_by_buyer)
+AND
+ NOT orders.escrow
+UNION ALL
+select orders.buyer_user_id as user_id, (0 - orders.amount_owed_sat) as amount_change_sat, 'order_payment' as event_type, orders.public_id as event_id, orders.payment_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid_from_balance
+UNION ALL
+select orders.buyer_user_id as user_id, orders.onchain_buyer_credit_sat as amount_change_sat, 'onchain_credit' as event_type, orders.public_id as event_id, CASE WHEN orders.paid THEN orders.payment_time_ms ELSE orders.invoice_expiry_time_ms END as event_time_ms
+from
+ orders
+WHERE
+ orders.onchain_buyer_credit_sat > 0
+AND
+ (orders.paid OR orders.onchain_expired)
+UNION ALL
+select orders.buyer_user_id as user_id, orders.dispute_buyer_refund_sat as amount_change_sat, 'dispute_refund' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+AND
+ orders.dispute_buyer_refund_sat > 0
+UNION ALL
+select orders.seller_user_id as user_id, orders.dispute_seller_credit_sat as amount_change_sat, 'dispute_seller_credit' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+AND
+ orders.dispute_seller_credit_sat > 0
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'processing_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ NOT (orders.shipped OR orders.canceled_by_seller OR orders.canceled_by_buyer)
+AND
+ NOT orders.escrow
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'unfinalized_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.shipped
+AND
+ NOT (orders.finalized OR orders.disputed)
+UNION ALL
+select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'disputed_order' as event_type, orders.public_id as event_id, orders.dispute_opened_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.disputed
+AND
+ NOT orders.dispute_resolved
+UNION ALL
+select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.status != 'failed'
+AND
+ NOT withdrawals.from_market_revenue
+UNION ALL
+select withdrawals.user_id as user_id, (0 - CASE WHEN withdrawals.status = 'succeeded' THEN withdrawals.fee_sat ELSE withdrawals.fee_limit_sat END) as amount_change_sat, 'withdrawal_fee' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.fee_paid_by_user
+AND
+ withdrawals.status != 'failed'
+UNION ALL
+select useraccounts.user_id as user_id, useraccounts.amount_owed_sat as amount_change_sat, 'user_activation' as event_type, useraccounts.public_id as event_id, useraccounts.created_time_ms as event_time_ms
+from
+ useraccounts
+WHERE
+ useraccounts.paid
+AND
+ NOT useraccounts.disabled)
+;")
+            .fetch_one(&mut *db)
+            .map_ok(|r|  {
+                let balance_sat_i64: i64 = r.try_get("total_market_liabilities_sat").unwrap();
+                balance_sat_i64
+            })
+            .await?;
+
+        Ok(market_liabilities_sat)
+    }
+
+    /// Returns the changes to the market revenue: the fees earned on shipped
+    /// orders and the forfeited bonds of disabled users, minus the market
+    /// withdrawals and the routing fees paid by the market.
+    ///
+    /// Canceled orders are refunded in full, so they earn no fee. Disputed
+    /// orders earn what is left after the buyer refund and the seller credit.
+    pub async fn all_market_revenue_changes(
+        db: &mut Connection<Db>,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<AccountBalanceChange>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let market_revenue_changes = sqlx::query("
+SELECT * FROM
+(select (orders.amount_owed_sat - orders.seller_credit_sat) as amount_change_sat, 'market_fee' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.shipped
+AND
+ orders.finalized
+AND
+ NOT orders.disputed
+UNION ALL
+select (orders.amount_owed_sat - orders.dispute_buyer_refund_sat - orders.dispute_seller_credit_sat) as amount_change_sat, 'dispute_market_fee' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+UNION ALL
+select (orders.onchain_received_sat - orders.amount_owed_sat - orders.onchain_buyer_credit_sat) as amount_change_sat, 'onchain_payment_difference' as event_type, orders.public_id as event_id, orders.payment_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.onchain_address != ''
+UNION ALL
+select useraccounts.amount_owed_sat as amount_change_sat, 'forfeited_bond' as event_type, useraccounts.public_id as event_id, useraccounts.created_time_ms as event_time_ms
+from
+ useraccounts
+WHERE
+ useraccounts.paid
+AND
+ useraccounts.disabled
+UNION ALL
+select (0 - withdrawals.amount_sat) as amount_change_sat, 'market_withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.from_market_revenue
+AND
+ withdrawals.status != 'failed'
+UNION ALL
+select (0 - CASE WHEN withdrawals.status = 'succeeded' THEN withdrawals.fee_sat ELSE withdrawals.fee_limit_sat END) as amount_change_sat, 'market_withdrawal_fee' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ NOT withdrawals.fee_paid_by_user
+AND
+ withdrawals.status != 'failed')
+ORDER BY event_time_ms DESC
+LIMIT ?
+OFFSET ?
+;")
+            .bind(limit)
+            .bind(offset)
+            .fetch(&mut **db)
+            .map_ok(|r| AccountBalanceChange {
+                amount_change_sat: r.try_get("amount_change_sat").unwrap(),
+                event_type: r.try_get("event_type").unwrap(),
+                event_id: r.try_get("event_id").unwrap(),
+                event_time_ms: {
+                    let time_ms_i64: i64 = r.try_get("event_time_ms").unwrap();
+                    time_ms_i64 as u64
+                },
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(market_revenue_changes)
+    }
+
+    pub async fn total_market_revenue_sat(db: &mut SqliteConnection) -> Result<i64, sqlx::Error> {
+        let market_revenue_sat = sqlx::query("
+SELECT COALESCE(SUM(amount_change_sat), 0) as total_market_revenue_sat FROM
+(select (orders.amount_owed_sat - orders.seller_credit_sat) as amount_change_sat, 'market_fee' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.shipped
+AND
+ orders.finalized
+AND
+ NOT orders.disputed
+UNION ALL
+select (orders.amount_owed_sat - orders.dispute_buyer_refund_sat - orders.dispute_seller_credit_sat) as amount_change_sat, 'dispute_market_fee' as event_type, orders.public_id as event_id, orders.dispute_resolved_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.dispute_resolved
+UNION ALL
+select (orders.onchain_received_sat - orders.amount_owed_sat - orders.onchain_buyer_credit_sat) as amount_change_sat, 'onchain_payment_difference' as event_type, orders.public_id as event_id, orders.payment_time_ms as event_time_ms
+from
+ orders
+WHERE
+ orders.paid
+AND
+ orders.onchain_address != ''
+UNION ALL
+select useraccounts.amount_owed_sat as amount_change_sat, 'forfeited_bond' as event_type, useraccounts.public_id as event_id, useraccounts.created_time_ms as event_time_ms
+from
+ useraccounts
+WHERE
+ useraccounts.paid
+AND
+ useraccounts.disabled
+UNION ALL
+select (0 - withdrawals.amount_sat) as amount_change_sat, 'market_withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ withdrawals.from_market_revenue
+AND
+ withdrawals.status != 'failed'
+UNION ALL
+select (0 - CASE WHEN withdrawals.status = 'succeeded' THEN withdrawals.fee_sat ELSE withdrawals.fee_limit_sat END) as amount_change_sat, 'market_withdrawal_fee' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+from
+ withdrawals
+WHERE
+ NOT withdrawals.fee_paid_by_user
+AND
+ withdrawals.status != 'failed')
+;")
+            .fetch_one(&mut *db)
+            .map_ok(|r|  {
+                let revenue_sat_i64: i64 = r.try_get("total_market_revenue_sat").unwrap();
+                revenue_sat_i64
+            })
+            .await?;
+
+        Ok(market_revenue_sat)
+    }
+
+    // TODO: Use when sqlx is fixed.
+    //     pub async fn account_balance(
+    //         db: &mut Connection<Db>,
+    //         user_id: i32,
+    //     ) -> Result<u64, sqlx::Error> {
+    //         let account_balance_result = sqlx::query!("
+    // SELECT SUM(data.amount_change_sat) as account_balance, data.user_id as user_id
+    // FROM
+    // (select listings.user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+    // from
+    //  orders
+    // LEFT JOIN
+    //  listings
+    // ON
+    //  orders.listing_id = listings.id
+    // WHERE
+    //  orders.paid
+    // AND
+    //  orders.shipped
+    // AND
+    //  listings.user_id = ?
+    // UNION ALL
+    // select orders.user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+    // from
+    //  orders
+    // WHERE
+    //  orders.paid
+    // AND
+    //  not orders.shipped
+    // AND
+    //  orders.user_id = ?
+    // ) data
+    // GROUP BY user_id
+    // ;",
+    //         user_id, user_id)
+    //         .fetch_optional(&mut **db)
+    //         .await?;
+
+    //         let account_balance = match account_balance_result {
+    //             Some(r) => r.account_balance,
+    //             None => 0,
+    //         };
+
+    //         Ok(account_balance)
+    //     }
+}
+
+impl Withdrawal {
+    /// Records a new pending withdrawal, before its payment is sent.
+    ///
+    /// Fails without inserting anything if the user has too many recent
+    /// withdrawals, or if the withdrawal is larger than the account balance.
+    ///
+    /// A withdrawal of market revenue must also be covered by the channel
+    /// balance of the node, after setting aside the funds owed to users and
+    /// the other market withdrawals that are not sent yet.
+    pub async fn insert_pending(
+        withdrawal: Withdrawal,
+        db: &mut SqliteConnection,
+        max_withdrawals_per_interval: u32,
+        interval_start_time_ms: u64,
+        maybe_channel_balance_sat: Option<u64>,
+    ) -> Result<i32, String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        // Insert the new withdrawal.
+        let amount_sat: i64 = withdrawal.amount_sat.try_into().unwrap();
+        let created_time_ms: i64 = withdrawal.created_time_ms.try_into().unwrap();
+        let status = WithdrawalStatus::Pending.as_str();
+        let fee_limit_sat: i64 = withdrawal.fee_limit_sat.try_into().unwrap();
+        let insert_result = sqlx::query!(
+            "INSERT INTO withdrawals (public_id, user_id, amount_sat, invoice_hash, invoice_payment_request, created_time_ms, status, fee_limit_sat, fee_paid_by_user, from_market_revenue, keysend_pubkey) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            withdrawal.public_id,
+            withdrawal.user_id,
+            amount_sat,
+            withdrawal.invoice_hash,
+            withdrawal.invoice_payment_request,
+            created_time_ms,
+            status,
+            fee_limit_sat,
+            withdrawal.fee_paid_by_user,
+            withdrawal.from_market_revenue,
+            withdrawal.keysend_pubkey,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| "failed to insert new withdrawal.")?;
+        let new_withdrawal_id = insert_result.last_insert_rowid();
+
+        // Check if any constraints are violated.
+        let user_id = withdrawal.user_id;
+
+        let start_time_ms_i64: i64 = interval_start_time_ms.try_into().unwrap();
+        let withdrawal_count = sqlx::query!(
+            "
+select count(id) as withdrawal_count from withdrawals
+WHERE
+ user_id = ?
+AND
+ created_time_ms > ?
+ORDER BY withdrawals.created_time_ms ASC;",
+            user_id,
+            start_time_ms_i64,
+        )
+        .fetch_one(&mut *tx)
+        .map_ok(|r| r.withdrawal_count as u32)
+        .await
+        .map_err(|_| "failed to get withdrawal count.")?;
+
+        if withdrawal_count > max_withdrawals_per_interval {
+            return Err(format!(
+                "More than {:?} withdrawals in a single day not allowed.",
+                max_withdrawals_per_interval,
+            ));
+        }
+
+        if withdrawal.from_market_revenue {
+            let market_revenue_sat = AccountInfo::total_market_revenue_sat(&mut tx)
+                .await
+                .map_err(|_| "failed to get market revenue.")?;
+            if market_revenue_sat < 0 {
+                return Err("Insufficient market revenue for withdrawal.".to_string());
+            }
+            if let Some(channel_balance_sat) = maybe_channel_balance_sat {
+                let total_market_liabilities_sat =
+                    AccountInfo::total_market_liabilities_sat(&mut tx)
+                        .await
+                        .map_err(|_| "failed to get total market liabilities.")?;
+                let pending_status = WithdrawalStatus::Pending.as_str();
+                let unsent_market_withdrawals_sat = sqlx::query!(
+                    "
+select COALESCE(SUM(amount_sat + fee_limit_sat), 0) as unsent_sat from withdrawals
+WHERE
+ from_market_revenue
+AND
+ status = ?;",
+                    pending_status,
+                )
+                .fetch_one(&mut *tx)
+                .map_ok(|r| i64::from(r.unsent_sat.unwrap_or(0)))
+                .await
+                .map_err(|_| "failed to get unsent market withdrawals.")?;
+                let channel_balance_sat: i64 = channel_balance_sat.try_into().unwrap();
+                if channel_balance_sat
+                    - total_market_liabilities_sat
+                    - unsent_market_withdrawals_sat
+                    < 0
+                {
+                    return Err("Insufficient node balance for withdrawal.".to_string());
+                }
+            }
+        } else {
+            let account_balance_sat = AccountInfo::total_account_balance_for_user(&mut tx, user_id)
+                .await
+                .map_err(|_| "failed to get account balance.")?;
+
+            if account_balance_sat < 0 {
+                return Err("Insufficient funds for withdrawal.".to_string());
+            }
+        }
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(new_withdrawal_id as _)
+    }
+
+    /// Marks a pending withdrawal as in flight, once its payment was sent.
+    pub async fn mark_as_in_flight(
+        db: &mut SqliteConnection,
+        withdrawal_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "
+UPDATE withdrawals
+SET status = 'in_flight'
+WHERE
+ id = ?
+AND
+ status = 'pending'
+;",
+            withdrawal_id,
+        )
+        .execute(&mut *db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks an unresolved withdrawal as succeeded, and records the routing
+    /// fee that was paid for it.
+    pub async fn mark_as_succeeded(
+        db: &mut PoolConnection<Sqlite>,
+        withdrawal_id: i32,
+        fee_sat: u64,
+    ) -> Result<(), sqlx::Error> {
+        let fee_sat_i64: i64 = fee_sat.try_into().unwrap();
+        sqlx::query!(
+            "
+UPDATE withdrawals
+SET status = 'succeeded', fee_sat = ?
+WHERE
+ id = ?
+AND
+ status IN ('pending', 'in_flight')
+;",
+            fee_sat_i64,
+            withdrawal_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks an unresolved withdrawal as failed, which returns its amount to
+    /// the account balance.
+    pub async fn mark_as_failed(
+        db: &mut SqliteConnection,
+        withdrawal_id: i32,
+        failure_reason: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "
+UPDATE withdrawals
+SET status = 'failed', failure_reason = ?
+WHERE
+ id = ?
+AND
+ status IN ('pending', 'in_flight')
+;",
+            failure_reason,
+            withdrawal_id,
+        )
+        .execute(&mut *db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns all withdrawals whose payment is pending or in flight.
+    pub async fn all_unresolved(
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<Vec<Withdrawal>, sqlx::Error> {
+        let withdrawals = sqlx::query!(
+            "
+select * from withdrawals
+WHERE
+ status IN ('pending', 'in_flight')
+ORDER BY withdrawals.created_time_ms ASC;"
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| Withdrawal {
+            id: r.id.map(|n| n.try_into().unwrap()),
+            public_id: r.public_id,
+            user_id: r.user_id.try_into().unwrap(),
+            amount_sat: r.amount_sat.try_into().unwrap(),
+            invoice_hash: r.invoice_hash,
+            invoice_payment_request: r.invoice_payment_request,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            status: r.status.parse().unwrap(),
+            failure_reason: r.failure_reason,
+            fee_limit_sat: r.fee_limit_sat.try_into().unwrap(),
+            fee_sat: r.fee_sat.try_into().unwrap(),
+            fee_paid_by_user: r.fee_paid_by_user,
+            from_market_revenue: r.from_market_revenue,
+            keysend_pubkey: r.keysend_pubkey,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(withdrawals)
+    }
+
+    pub async fn single_by_public_id(
+        db: &mut Connection<Db>,
+        public_id: &str,
+    ) -> Result<Withdrawal, sqlx::Error> {
+        let withdrawal = sqlx::query!("select * from withdrawals WHERE public_id = ?;", public_id)
+            .fetch_one(&mut **db)
+            .map_ok(|r| Withdrawal {
+                id: Some(r.id.try_into().unwrap()),
+                public_id: r.public_id,
+                user_id: r.user_id.try_into().unwrap(),
+                amount_sat: r.amount_sat.try_into().unwrap(),
+                invoice_hash: r.invoice_hash,
+                invoice_payment_request: r.invoice_payment_request,
+                created_time_ms: r.created_time_ms.try_into().unwrap(),
+                status: r.status.parse().unwrap(),
+                failure_reason: r.failure_reason,
+                fee_limit_sat: r.fee_limit_sat.try_into().unwrap(),
+                fee_sat: r.fee_sat.try_into().unwrap(),
+                fee_paid_by_user: r.fee_paid_by_user,
+                from_market_revenue: r.from_market_revenue,
+                keysend_pubkey: r.keysend_pubkey,
+            })
+            .await?;
+
+        Ok(withdrawal)
+    }
+
+    pub async fn count_for_user_since_time_ms(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        start_time_ms: u64,
+    ) -> Result<u32, sqlx::Error> {
+        let start_time_ms_i64: i64 = start_time_ms.try_into().unwrap();
+
+        let withdrawal_count = sqlx::query!(
+            "
+select count(id) as withdrawal_count from withdrawals
+WHERE
+ user_id = ?
+AND
+ created_time_ms > ?
+ORDER BY withdrawals.created_time_ms ASC;",
+            user_id,
+            start_time_ms_i64,
+        )
+        .fetch_one(&mut **db)
+        .map_ok(|r| r.withdrawal_count)
+        .await?;
+
+        Ok(withdrawal_count.try_into().unwrap())
+    }
+}
+
+impl AdminInfo {
+    pub async fn admin_info(db: &mut Connection<Db>) -> Result<AdminInfo, sqlx::Error> {
+        let num_pending_listings = Listing::num_pending(db).await?;
+        Ok(AdminInfo {
+            num_pending_listings,
+        })
+    }
+}
+
+impl UserAccount {
+    /// Returns the id of the inserted row.
+    pub async fn insert(
+        user_account: UserAccount,
+        db: &mut Connection<Db>,
+    ) -> Result<i32, sqlx::Error> {
+        let amount_owed_sat: i64 = user_account.amount_owed_sat.try_into().unwrap();
+        let created_time_ms: i64 = user_account.created_time_ms.try_into().unwrap();
+        let payment_time_ms: i64 = user_account.payment_time_ms.try_into().unwrap();
+        let invoice_expiry_time_ms: i64 = user_account.invoice_expiry_time_ms.try_into().unwrap();
+
+        let insert_result = sqlx::query!(
+            "INSERT INTO useraccounts (public_id, user_id, amount_owed_sat, paid, disabled, invoice_payment_request, invoice_hash, created_time_ms, payment_time_ms, invoice_expiry_time_ms) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            user_account.public_id,
+            user_account.user_id,
+            amount_owed_sat,
+            user_account.paid,
+            user_account.disabled,
+            user_account.invoice_payment_request,
+            user_account.invoice_hash,
+            created_time_ms,
+            payment_time_ms,
+            invoice_expiry_time_ms,
+        )
+            .execute(&mut **db)
+            .await?;
+
+        Ok(insert_result.last_insert_rowid() as _)
+    }
+
+    pub async fn single(db: &mut Connection<Db>, id: i32) -> Result<UserAccount, sqlx::Error> {
+        let user_account = sqlx::query!("select * from useraccounts WHERE user_id = ?;", id)
+            .fetch_one(&mut **db)
+            .map_ok(|r| UserAccount {
+                id: Some(r.id.try_into().unwrap()),
+                public_id: r.public_id,
+                user_id: r.user_id.try_into().unwrap(),
+                amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+                paid: r.paid,
+                disabled: r.disabled,
+                invoice_payment_request: r.invoice_payment_request,
+                invoice_hash: r.invoice_hash,
+                created_time_ms: r.created_time_ms.try_into().unwrap(),
+                payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+                invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+            })
+            .await?;
+
+        Ok(user_account)
+    }
+
+    pub async fn single_by_public_id(
+        db: &mut Connection<Db>,
+        public_id: &str,
+    ) -> Result<UserAccount, sqlx::Error> {
+        let user_account =
+            sqlx::query!("select * from useraccounts WHERE public_id = ?;", public_id)
+                .fetch_one(&mut **db)
+                .map_ok(|r| UserAccount {
+                    id: Some(r.id.try_into().unwrap()),
+                    public_id: r.public_id,
+                    user_id: r.user_id.try_into().unwrap(),
+                    amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+                    paid: r.paid,
+                    disabled: r.disabled,
+                    invoice_payment_request: r.invoice_payment_request,
+                    invoice_hash: r.invoice_hash,
+                    created_time_ms: r.created_time_ms.try_into().unwrap(),
+                    payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+                    invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+                })
+                .await?;
+
+        Ok(user_account)
+    }
+
+    pub async fn single_by_invoice_hash(
+        db: &mut PoolConnection<Sqlite>,
+        invoice_hash: &str,
+    ) -> Result<UserAccount, sqlx::Error> {
+        let user_account = sqlx::query!(
+            "select * from useraccounts WHERE invoice_hash = ?;",
+            invoice_hash
+        )
+        .fetch_one(&mut **db)
+        .map_ok(|r| UserAccount {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            user_id: r.user_id.try_into().unwrap(),
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            paid: r.paid,
+            disabled: r.disabled,
+            invoice_payment_request: r.invoice_payment_request,
+            invoice_hash: r.invoice_hash,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+        })
+        .await?;
+
+        Ok(user_account)
+    }
+
+    pub async fn mark_as_paid(
+        db: &mut PoolConnection<Sqlite>,
+        user_account_id: i32,
+        time_now_ms: u64,
+    ) -> Result<(), sqlx::Error> {
+        let time_now_ms_i64: i64 = time_now_ms.try_into().unwrap();
+
+        sqlx::query!(
+            "UPDATE useraccounts SET paid = true, payment_time_ms = ? WHERE id = ?",
+            time_now_ms_i64,
+            user_account_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn mark_as_disabled(
+        db: &mut PoolConnection<Sqlite>,
+        user_id: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE useraccounts SET disabled = true WHERE user_id = ?",
+            user_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Enables a disabled user account, returning its forfeited bond to the
+    /// market liabilities. Fails if the bond was already withdrawn as market
+    /// revenue.
+    pub async fn mark_as_enabled(
+        db: &mut PoolConnection<Sqlite>,
+        user_id: i32,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        sqlx::query!(
+            "UPDATE useraccounts SET disabled = false WHERE user_id = ?",
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to enable user account.")?;
+
+        let market_revenue_sat = AccountInfo::total_market_revenue_sat(&mut tx)
+            .await
+            .map_err(|_| "failed to get market revenue.")?;
+        if market_revenue_sat < 0 {
+            return Err("Insufficient market revenue to return the user bond.".to_string());
+        }
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+
+    pub async fn number_of_users(db: &mut Connection<Db>) -> Result<u64, sqlx::Error> {
+        let num_users = sqlx::query!(
+            "
+select
+ COUNT(users.id) as num_users
+from
+ users
+WHERE
+ NOT users.is_admin
+;",
+        )
+        .fetch_one(&mut **db)
+        .map_ok(|r| r.num_users as u64)
+        .await?;
+
+        Ok(num_users)
+    }
+
+    pub async fn all_older_than(
+        db: &mut PoolConnection<Sqlite>,
+        created_time_ms: u64,
+    ) -> Result<Vec<UserAccount>, sqlx::Error> {
+        let created_time_ms_i64: i64 = created_time_ms.try_into().unwrap();
+
+        let user_accounts = sqlx::query!(
+            "
+select *
+from
+ useraccounts
+WHERE
+ created_time_ms < ?
+AND
+ NOT paid
+;",
+            created_time_ms_i64,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| UserAccount {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            user_id: r.user_id.try_into().unwrap(),
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            paid: r.paid,
+            disabled: r.disabled,
+            invoice_payment_request: r.invoice_payment_request,
+            invoice_hash: r.invoice_hash,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(user_accounts)
+    }
+
+    /// Returns all unpaid user accounts with an invoice that expired before the given time.
+    pub async fn all_unpaid_with_invoice_expired_before(
+        db: &mut PoolConnection<Sqlite>,
+        invoice_expiry_time_ms: u64,
+    ) -> Result<Vec<UserAccount>, sqlx::Error> {
+        let invoice_expiry_time_ms_i64: i64 = invoice_expiry_time_ms.try_into().unwrap();
+
+        let user_accounts = sqlx::query!(
+            "
+select *
+from
+ useraccounts
 WHERE
- orders.paid
-AND
- orders.shipped
+ invoice_expiry_time_ms < ?
 AND
- orders.seller_user_id = ?
-UNION ALL
-select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
+ NOT paid
+;",
+            invoice_expiry_time_ms_i64,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| UserAccount {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            user_id: r.user_id.try_into().unwrap(),
+            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
+            paid: r.paid,
+            disabled: r.disabled,
+            invoice_payment_request: r.invoice_payment_request,
+            invoice_hash: r.invoice_hash,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            invoice_expiry_time_ms: r.invoice_expiry_time_ms.try_into().unwrap(),
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(user_accounts)
+    }
+
+    /// Replaces the invoice of an unpaid user account.
+    ///
+    /// Returns false if the user account was paid in the meantime.
+    pub async fn update_invoice(
+        db: &mut Connection<Db>,
+        user_account_id: i32,
+        invoice_hash: &str,
+        invoice_payment_request: &str,
+        invoice_expiry_time_ms: u64,
+    ) -> Result<bool, sqlx::Error> {
+        let invoice_expiry_time_ms_i64: i64 = invoice_expiry_time_ms.try_into().unwrap();
+
+        let update_result = sqlx::query!(
+            "UPDATE useraccounts SET invoice_hash = ?, invoice_payment_request = ?, invoice_expiry_time_ms = ? WHERE id = ? AND NOT paid",
+            invoice_hash,
+            invoice_payment_request,
+            invoice_expiry_time_ms_i64,
+            user_account_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(update_result.rows_affected() == 1)
+    }
+
+    pub async fn delete_expired_user_account(
+        db: &mut PoolConnection<Sqlite>,
+        user_account_id: i32,
+        cancel_user_account_invoice_future: impl Future<
+            Output = Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String>,
+        >,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        sqlx::query!(
+            "
+DELETE FROM useraccounts
 WHERE
- orders.paid
+ user_id = ?
 AND
- (orders.canceled_by_seller OR orders.canceled_by_buyer)
+ NOT paid
+;",
+            user_account_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete user account from database.")?;
+
+        sqlx::query!(
+            "
+DELETE FROM users
+WHERE
+ id = ?
+;",
+            user_account_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete user from database.")?;
+
+        cancel_user_account_invoice_future
+            .await
+            .map_err(|e| format!("failed to cancel user account invoice: {:?}", e))?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        Ok(())
+    }
+
+    pub async fn delete_users_with_no_account(
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<(), String> {
+        sqlx::query!(
+            "
+DELETE FROM users
+WHERE
+ NOT is_admin
 AND
- orders.buyer_user_id = ?
-UNION ALL
-select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
-from
- withdrawals
+ id IN
+(SELECT users.id FROM users
+LEFT JOIN
+ useraccounts
+ON
+ users.id=useraccounts.user_id
 WHERE
- withdrawals.user_id = ?)
-ORDER BY event_time_ms DESC
-LIMIT ?
-OFFSET ?
-;")
-            .bind(user_id)
-            .bind(user_id)
-            .bind(user_id)
-            .bind(limit)
-            .bind(offset)
-            .fetch(&mut **db)
-            .map_ok(|r| AccountBalanceChange {
-                amount_change_sat: r.try_get("amount_change_sat").unwrap(),
-                event_type: r.try_get("event_type").unwrap(),
-                event_id: r.try_get("event_id").unwrap(),
-                event_time_ms: {
-                    let time_ms_i64: i64 = r.try_get("event_time_ms").unwrap();
-                    time_ms_i64 as u64
-                },
-            })
-            .try_collect::<Vec<_>>()
-            .await?;
+ useraccounts.user_id IS NULL);
+;"
+        )
+        .execute(&mut **db)
+        .await
+        .map_err(|_| "failed to delete user account from database.")?;
 
-        Ok(account_balance_changes)
+        Ok(())
     }
 
-    pub async fn total_account_balance_for_user(
+    pub async fn do_deactivation(
+        amount_sat: u64,
+        user_account: UserAccount,
         db: &mut Connection<Db>,
-        user_id: i32,
-    ) -> Result<i64, sqlx::Error> {
-        let account_balance_sat = sqlx::query("
-SELECT SUM(amount_change_sat) as total_account_balance_sat FROM
-(select orders.seller_user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
+        send_deactivation_funds_future: impl Future<
+            Output = Result<tonic_openssl_lnd::lnrpc::SendResponse, String>,
+        >,
+    ) -> Result<(), String> {
+        let mut tx = db
+            .begin()
+            .await
+            .map_err(|_| "failed to begin transaction.")?;
+
+        // Insert the new withdrawal.
+        let activation_bond_amount_sat: i64 = user_account.amount_owed_sat.try_into().unwrap();
+        let amount_sat: i64 = amount_sat.try_into().unwrap();
+        let delete_user_account_result = sqlx::query!(
+            "
+DELETE FROM useraccounts
 WHERE
- orders.paid
-AND
- orders.shipped
+ user_id = ?
 AND
- orders.seller_user_id = ?
-UNION ALL
-select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
+ paid = true
+;",
+            user_account.user_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete user account.")?;
+
+        // Validate that at least one paid user account was deleted
+        if delete_user_account_result.rows_affected() < 1 {
+            return Err("No user bond found.".to_string());
+        }
+
+        sqlx::query!(
+            "
+DELETE FROM users
+WHERE id = ?
+;",
+            user_account.user_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete user.")?;
+
+        sqlx::query!(
+            "
+DELETE FROM listingimages
+WHERE
+ listing_id IN
+(SELECT listings.id FROM listings
+WHERE user_id = ?);
+;",
+            user_account.user_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete user listing images.")?;
+
+        sqlx::query!(
+            "
+DELETE FROM shippingoptions
+WHERE
+ listing_id IN
+(SELECT listings.id FROM listings
+WHERE user_id = ?);
+;",
+            user_account.user_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete user shipping options.")?;
+
+        sqlx::query!(
+            "
+DELETE FROM listings
+WHERE user_id = ?
+;",
+            user_account.user_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "failed to delete user listings.")?;
+
+        if activation_bond_amount_sat - amount_sat < 0 {
+            return Err("Insufficient funds for deactivation.".to_string());
+        }
+
+        send_deactivation_funds_future
+            .await
+            .map_err(|e| format!("failed to send deactivation payment: {:?}", e))?;
+
+        tx.commit()
+            .await
+            .map_err(|_| "failed to commit transaction.")?;
+
+        Ok(())
+    }
+}
+
+impl UserCard {
+    pub async fn all_active(
+        db: &mut Connection<Db>,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<UserCard>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let user_cards =
+            sqlx::query!("
+select
+ users.id as rocket_auth_user_id, users.email as rocket_auth_user_username, useraccounts.id as useraccounts_id, useraccounts.public_id as useraccounts_public_id, useraccounts.user_id as useraccounts_user_id, useraccounts.amount_owed_sat as useraccounts_amount_owed_sat, useraccounts.paid as useraccounts_paid, useraccounts.disabled as useraccounts_disabled, useraccounts.invoice_payment_request as useraccounts_invoice_payment_request, useraccounts.invoice_hash as useraccounts_invoice_hash, useraccounts.created_time_ms as useraccounts_created_time_ms, useraccounts.payment_time_ms as useraccounts_payment_time_ms, useraccounts.invoice_expiry_time_ms as useraccounts_invoice_expiry_time_ms
 from
- orders
+ users
+INNER JOIN
+ useraccounts
+ON
+ users.id = useraccounts.user_id
 WHERE
- orders.paid
-AND
- (orders.canceled_by_seller OR orders.canceled_by_buyer)
+ useraccounts.paid
 AND
- orders.buyer_user_id = ?
-UNION ALL
-select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
-from
- withdrawals
-WHERE
- withdrawals.user_id = ?)
-;")
-            .bind(user_id)
-            .bind(user_id)
-            .bind(user_id)
-            .fetch_one(&mut **db)
-            .map_ok(|r|  {
-                let balance_sat_i64: i64 = r.try_get("total_account_balance_sat").unwrap();
-                balance_sat_i64
+ NOT useraccounts.disabled
+GROUP BY
+ users.id
+ORDER BY useraccounts.created_time_ms DESC
+LIMIT ?
+OFFSET ?
+;", limit, offset)
+            .fetch(&mut **db)
+            .map_ok(|r| {
+                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
+                    id: Some(rocket_auth_user_id.try_into().unwrap()),
+                    username: r.rocket_auth_user_username.unwrap(),
+                });
+                let ua = r.useraccounts_id.map(|user_account_id| UserAccount {
+                    id: Some(user_account_id.try_into().unwrap()),
+                    public_id: r.useraccounts_public_id.unwrap(),
+                    user_id: r.useraccounts_user_id.unwrap().try_into().unwrap(),
+                    amount_owed_sat: r.useraccounts_amount_owed_sat.unwrap().try_into().unwrap(),
+                    paid: r.useraccounts_paid.unwrap(),
+                    disabled: r.useraccounts_disabled.unwrap(),
+                    invoice_payment_request: r.useraccounts_invoice_payment_request.unwrap(),
+                    invoice_hash: r.useraccounts_invoice_hash.unwrap(),
+                    created_time_ms: r.useraccounts_created_time_ms.unwrap().try_into().unwrap(),
+                    payment_time_ms: r.useraccounts_payment_time_ms.unwrap().try_into().unwrap(),
+                    invoice_expiry_time_ms: r.useraccounts_invoice_expiry_time_ms.unwrap().try_into().unwrap(),
+                });
+                UserCard {
+                    user: u.unwrap(),
+                    user_account: ua.unwrap(),
+                }
             })
-            .await?;
+                .try_collect::<Vec<_>>()
+                .await?;
 
-        Ok(account_balance_sat)
+        Ok(user_cards)
     }
 
-    pub async fn all_account_balance_changes(
+    pub async fn all_disabled(
         db: &mut Connection<Db>,
         page_size: u32,
         page_num: u32,
-    ) -> Result<Vec<AccountBalanceChange>, sqlx::Error> {
-        // TODO: Order by event time in SQL query. When this is fixed: https://github.com/launchbadge/sqlx/issues/1350
+    ) -> Result<Vec<UserCard>, sqlx::Error> {
         let offset = (page_num - 1) * page_size;
         let limit = page_size;
-        let account_balance_changes = sqlx::query("
-SELECT * FROM
-(select orders.seller_user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
-WHERE
- orders.paid
-AND
- orders.shipped
-UNION ALL
-select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
-WHERE
- orders.paid
-AND
- (orders.canceled_by_seller OR orders.canceled_by_buyer)
-UNION ALL
-select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'processing_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
-WHERE
- orders.paid
-AND
- NOT (orders.shipped OR orders.canceled_by_seller OR orders.canceled_by_buyer)
-UNION ALL
-select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
-from
- withdrawals
-UNION ALL
-select useraccounts.user_id as user_id, useraccounts.amount_owed_sat as amount_change_sat, 'user_activation' as event_type, useraccounts.public_id as event_id, useraccounts.created_time_ms as event_time_ms
+        let user_cards =
+            sqlx::query!("
+select
+ users.id as rocket_auth_user_id, users.email as rocket_auth_user_username, useraccounts.id as useraccounts_id, useraccounts.public_id as useraccounts_public_id, useraccounts.user_id as useraccounts_user_id, useraccounts.amount_owed_sat as useraccounts_amount_owed_sat, useraccounts.paid as useraccounts_paid, useraccounts.disabled as useraccounts_disabled, useraccounts.invoice_payment_request as useraccounts_invoice_payment_request, useraccounts.invoice_hash as useraccounts_invoice_hash, useraccounts.created_time_ms as useraccounts_created_time_ms, useraccounts.payment_time_ms as useraccounts_payment_time_ms, useraccounts.invoice_expiry_time_ms as useraccounts_invoice_expiry_time_ms
 from
+ users
+INNER JOIN
  useraccounts
+ON
+ users.id = useraccounts.user_id
 WHERE
- useraccounts.paid)
-ORDER BY event_time_ms DESC
+ useraccounts.paid
+AND
+ useraccounts.disabled
+GROUP BY
+ users.id
+ORDER BY useraccounts.created_time_ms DESC
 LIMIT ?
 OFFSET ?
-;")
-            .bind(limit)
-            .bind(offset)
+;", limit, offset)
             .fetch(&mut **db)
-            .map_ok(|r| AccountBalanceChange {
-                amount_change_sat: r.try_get("amount_change_sat").unwrap(),
-                event_type: r.try_get("event_type").unwrap(),
-                event_id: r.try_get("event_id").unwrap(),
-                event_time_ms: {
-                    let time_ms_i64: i64 = r.try_get("event_time_ms").unwrap();
-                    time_ms_i64 as u64
-                },
+            .map_ok(|r| {
+                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
+                    id: Some(rocket_auth_user_id.try_into().unwrap()),
+                    username: r.rocket_auth_user_username.unwrap(),
+                });
+                let ua = r.useraccounts_id.map(|user_account_id| UserAccount {
+                    id: Some(user_account_id.try_into().unwrap()),
+                    public_id: r.useraccounts_public_id.unwrap(),
+                    user_id: r.useraccounts_user_id.unwrap().try_into().unwrap(),
+                    amount_owed_sat: r.useraccounts_amount_owed_sat.unwrap().try_into().unwrap(),
+                    paid: r.useraccounts_paid.unwrap(),
+                    disabled: r.useraccounts_disabled.unwrap(),
+                    invoice_payment_request: r.useraccounts_invoice_payment_request.unwrap(),
+                    invoice_hash: r.useraccounts_invoice_hash.unwrap(),
+                    created_time_ms: r.useraccounts_created_time_ms.unwrap().try_into().unwrap(),
+                    payment_time_ms: r.useraccounts_payment_time_ms.unwrap().try_into().unwrap(),
+                    invoice_expiry_time_ms: r.useraccounts_invoice_expiry_time_ms.unwrap().try_into().unwrap(),
+                });
+                UserCard {
+                    user: u.unwrap(),
+                    user_account: ua.unwrap(),
+                }
             })
-            .try_collect::<Vec<_>>()
-            .await?;
+                .try_collect::<Vec<_>>()
+                .await?;
 
-        Ok(account_balance_changes)
+        Ok(user_cards)
     }
+}
 
-    pub async fn total_market_liabilities_sat(db: &mut Connection<Db>) -> Result<i64, sqlx::Error> {
-        let market_liabilities_sat = sqlx::query("
-SELECT SUM(amount_change_sat) as total_market_liabilities_sat FROM
-(select orders.seller_user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
-WHERE
- orders.paid
-AND
- orders.shipped
-UNION ALL
-select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
-WHERE
- orders.paid
-AND
- (orders.canceled_by_seller OR orders.canceled_by_buyer)
-UNION ALL
-select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'processing_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
-WHERE
- orders.paid
-AND
- NOT (orders.shipped OR orders.canceled_by_seller OR orders.canceled_by_buyer)
-UNION ALL
-select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
-from
- withdrawals
-UNION ALL
-select useraccounts.user_id as user_id, useraccounts.amount_owed_sat as amount_change_sat, 'user_activation' as event_type, useraccounts.public_id as event_id, useraccounts.created_time_ms as event_time_ms
+impl ExchangeRate {
+    /// Returns the most recently updated exchange rate, unless it was last
+    /// updated before the given time.
+    pub async fn latest_updated_after(
+        db: &mut SqliteConnection,
+        min_updated_time_ms: u64,
+    ) -> Result<Option<ExchangeRate>, sqlx::Error> {
+        let min_updated_time_ms_i64: i64 = min_updated_time_ms.try_into().unwrap();
+
+        let maybe_exchange_rate = sqlx::query!(
+            "
+select *
 from
- useraccounts
+ exchangerates
 WHERE
- useraccounts.paid)
-;")
-            .fetch_one(&mut **db)
-            .map_ok(|r|  {
-                let balance_sat_i64: i64 = r.try_get("total_market_liabilities_sat").unwrap();
-                balance_sat_i64
+ exchangerates.updated_time_ms > ?
+ORDER BY exchangerates.updated_time_ms DESC
+LIMIT 1
+;",
+            min_updated_time_ms_i64,
+        )
+        .fetch_optional(&mut *db)
+        .map_ok(|maybe_r| {
+            maybe_r.map(|r| ExchangeRate {
+                currency: r.currency.unwrap(),
+                fiat_cents_per_btc: r.fiat_cents_per_btc.unwrap().try_into().unwrap(),
+                updated_time_ms: r.updated_time_ms.unwrap().try_into().unwrap(),
             })
-            .await?;
+        })
+        .await?;
 
-        Ok(market_liabilities_sat)
+        Ok(maybe_exchange_rate)
     }
 
-    // TODO: Use when sqlx is fixed.
-    //     pub async fn account_balance(
-    //         db: &mut Connection<Db>,
-    //         user_id: i32,
-    //     ) -> Result<u64, sqlx::Error> {
-    //         let account_balance_result = sqlx::query!("
-    // SELECT SUM(data.amount_change_sat) as account_balance, data.user_id as user_id
-    // FROM
-    // (select listings.user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-    // from
-    //  orders
-    // LEFT JOIN
-    //  listings
-    // ON
-    //  orders.listing_id = listings.id
-    // WHERE
-    //  orders.paid
-    // AND
-    //  orders.shipped
-    // AND
-    //  listings.user_id = ?
-    // UNION ALL
-    // select orders.user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-    // from
-    //  orders
-    // WHERE
-    //  orders.paid
-    // AND
-    //  not orders.shipped
-    // AND
-    //  orders.user_id = ?
-    // ) data
-    // GROUP BY user_id
-    // ;",
-    //         user_id, user_id)
-    //         .fetch_optional(&mut **db)
-    //         .await?;
+    /// Stores the exchange rate, replacing the previous rate of the same currency.
+    pub async fn update(
+        exchange_rate: ExchangeRate,
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        let fiat_cents_per_btc: i64 = exchange_rate.fiat_cents_per_btc.try_into().unwrap();
+        let updated_time_ms: i64 = exchange_rate.updated_time_ms.try_into().unwrap();
+
+        let mut tx = db.begin().await?;
+
+        sqlx::query!(
+            "
+INSERT INTO
+ exchangerates (currency, fiat_cents_per_btc, updated_time_ms)
+SELECT ?, 0, 0
+WHERE NOT EXISTS(SELECT 1 FROM exchangerates WHERE currency = ?)
+;",
+            exchange_rate.currency,
+            exchange_rate.currency,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE exchangerates SET fiat_cents_per_btc = ?, updated_time_ms = ? WHERE currency = ?",
+            fiat_cents_per_btc,
+            updated_time_ms,
+            exchange_rate.currency,
+        )
+        .execute(&mut *tx)
+        .await?;
 
-    //         let account_balance = match account_balance_result {
-    //             Some(r) => r.account_balance,
-    //             None => 0,
-    //         };
+        tx.commit().await?;
 
-    //         Ok(account_balance)
-    //     }
+        Ok(())
+    }
 }
 
-impl Withdrawal {
-    pub async fn do_withdrawal(
-        withdrawal: Withdrawal,
-        db: &mut Connection<Db>,
-        send_withdrawal_funds_future: impl Future<
-            Output = Result<tonic_openssl_lnd::lnrpc::SendResponse, String>,
-        >,
-        max_withdrawals_per_interval: u32,
-        interval_start_time_ms: u64,
-    ) -> Result<i32, String> {
-        let mut tx = db
-            .begin()
-            .await
-            .map_err(|_| "failed to begin transaction.")?;
+impl SolvencySnapshot {
+    /// Builds a snapshot, deriving the reserves, obligations, and reserve
+    /// ratio from the balances.
+    pub fn new(
+        channel_balance_sat: u64,
+        unsettled_balance_sat: u64,
+        wallet_balance_sat: u64,
+        total_market_liabilities_sat: i64,
+        pending_escrow_sat: u64,
+        created_time_ms: u64,
+    ) -> SolvencySnapshot {
+        let channel_balance_sat_i64: i64 = channel_balance_sat.try_into().unwrap();
+        let unsettled_balance_sat_i64: i64 = unsettled_balance_sat.try_into().unwrap();
+        let wallet_balance_sat_i64: i64 = wallet_balance_sat.try_into().unwrap();
+        let pending_escrow_sat_i64: i64 = pending_escrow_sat.try_into().unwrap();
+        let reserves_sat =
+            channel_balance_sat_i64 + unsettled_balance_sat_i64 + wallet_balance_sat_i64;
+        let obligations_sat = total_market_liabilities_sat + pending_escrow_sat_i64;
+        let reserve_ratio_basis_points = if obligations_sat > 0 {
+            Some(reserves_sat * 10000 / obligations_sat)
+        } else {
+            None
+        };
+        SolvencySnapshot {
+            id: None,
+            channel_balance_sat,
+            unsettled_balance_sat,
+            wallet_balance_sat,
+            total_market_liabilities_sat,
+            pending_escrow_sat,
+            created_time_ms,
+            reserves_sat,
+            obligations_sat,
+            reserve_ratio_basis_points,
+        }
+    }
+
+    /// Returns true if the node holds less than the market owes.
+    pub fn is_under_reserved(&self) -> bool {
+        self.reserves_sat < self.obligations_sat
+    }
+
+    pub async fn insert(
+        solvency_snapshot: SolvencySnapshot,
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<i32, sqlx::Error> {
+        let channel_balance_sat: i64 = solvency_snapshot.channel_balance_sat.try_into().unwrap();
+        let unsettled_balance_sat: i64 =
+            solvency_snapshot.unsettled_balance_sat.try_into().unwrap();
+        let wallet_balance_sat: i64 = solvency_snapshot.wallet_balance_sat.try_into().unwrap();
+        let pending_escrow_sat: i64 = solvency_snapshot.pending_escrow_sat.try_into().unwrap();
+        let created_time_ms: i64 = solvency_snapshot.created_time_ms.try_into().unwrap();
 
-        // Insert the new withdrawal.
-        let amount_sat: i64 = withdrawal.amount_sat.try_into().unwrap();
-        let created_time_ms: i64 = withdrawal.created_time_ms.try_into().unwrap();
         let insert_result = sqlx::query!(
-            "INSERT INTO withdrawals (public_id, user_id, amount_sat, invoice_hash, invoice_payment_request, created_time_ms) VALUES (?, ?, ?, ?, ?, ?)",
-            withdrawal.public_id,
-            withdrawal.user_id,
-            amount_sat,
-            withdrawal.invoice_hash,
-            withdrawal.invoice_payment_request,
+            "INSERT INTO solvencysnapshots (channel_balance_sat, unsettled_balance_sat, wallet_balance_sat, total_market_liabilities_sat, pending_escrow_sat, created_time_ms) VALUES (?, ?, ?, ?, ?, ?)",
+            channel_balance_sat,
+            unsettled_balance_sat,
+            wallet_balance_sat,
+            solvency_snapshot.total_market_liabilities_sat,
+            pending_escrow_sat,
             created_time_ms,
         )
-            .execute(&mut *tx)
-            .await
-            .map_err(|_| "failed to insert new withdrawal.")?;
-        let new_withdrawal_id = insert_result.last_insert_rowid();
+        .execute(&mut **db)
+        .await?;
 
-        // Check if any constraints are violated.
-        let user_id = withdrawal.user_id;
+        Ok(insert_result.last_insert_rowid() as _)
+    }
 
-        let start_time_ms_i64: i64 = interval_start_time_ms.try_into().unwrap();
-        let withdrawal_count = sqlx::query!(
+    /// Returns the most recent snapshot, if any has been taken.
+    pub async fn latest(db: &mut Connection<Db>) -> Result<Option<SolvencySnapshot>, sqlx::Error> {
+        let maybe_solvency_snapshot = sqlx::query!(
             "
-select count(id) as withdrawal_count from withdrawals
-WHERE
- user_id = ?
-AND
- created_time_ms > ?
-ORDER BY withdrawals.created_time_ms ASC;",
-            user_id,
-            start_time_ms_i64,
-        )
-        .fetch_one(&mut *tx)
-        .map_ok(|r| r.withdrawal_count as u32)
-        .await
-        .map_err(|_| "failed to get withdrawal count.")?;
-
-        if withdrawal_count > max_withdrawals_per_interval {
-            return Err(format!(
-                "More than {:?} withdrawals in a single day not allowed.",
-                max_withdrawals_per_interval,
-            ));
-        }
-
-        let account_balance_sat = sqlx::query("
-SELECT SUM(amount_change_sat) as total_account_balance_sat FROM
-(select orders.seller_user_id as user_id, orders.seller_credit_sat as amount_change_sat, 'received_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
-WHERE
- orders.paid
-AND
- orders.shipped
-AND
- orders.seller_user_id = ?
-UNION ALL
-select orders.buyer_user_id as user_id, orders.amount_owed_sat as amount_change_sat, 'refunded_order' as event_type, orders.public_id as event_id, orders.created_time_ms as event_time_ms
-from
- orders
-WHERE
- orders.paid
-AND
- (orders.canceled_by_seller OR orders.canceled_by_buyer)
-AND
- orders.buyer_user_id = ?
-UNION ALL
-select withdrawals.user_id as user_id, (0 - withdrawals.amount_sat) as amount_change_sat, 'withdrawal' as event_type, withdrawals.public_id as event_id, withdrawals.created_time_ms as event_time_ms
+select *
 from
- withdrawals
-WHERE
- withdrawals.user_id = ?)
-;")
-            .bind(user_id)
-            .bind(user_id)
-            .bind(user_id)
-            .fetch_one(&mut *tx)
-            .map_ok(|r|  {
-                let balance_sat_i64: i64 = r.try_get("total_account_balance_sat").unwrap();
-                balance_sat_i64
+ solvencysnapshots
+ORDER BY solvencysnapshots.created_time_ms DESC
+LIMIT 1
+;"
+        )
+        .fetch_optional(&mut **db)
+        .map_ok(|maybe_r| {
+            maybe_r.map(|r| {
+                let mut solvency_snapshot = SolvencySnapshot::new(
+                    r.channel_balance_sat.unwrap().try_into().unwrap(),
+                    r.unsettled_balance_sat.unwrap().try_into().unwrap(),
+                    r.wallet_balance_sat.unwrap().try_into().unwrap(),
+                    r.total_market_liabilities_sat.unwrap(),
+                    r.pending_escrow_sat.unwrap().try_into().unwrap(),
+                    r.created_time_ms.unwrap().try_into().unwrap(),
+                );
+                solvency_snapshot.id = r.id.map(|n| n.try_into().unwrap());
+                solvency_snapshot
             })
-            .await
-            .map_err(|_| "failed to insert get account balance changes.")?;
-
-        if account_balance_sat < 0 {
-            return Err("Insufficient funds for withdrawal.".to_string());
-        }
+        })
+        .await?;
 
-        let send_response = send_withdrawal_funds_future
-            .await
-            .map_err(|e| format!("failed to send withdrawal payment: {:?}", e))?;
+        Ok(maybe_solvency_snapshot)
+    }
 
-        // Update the withdrawal row with the payment invoice hash.
-        let payment_hash_hex = util::to_hex(&send_response.payment_hash);
-        sqlx::query!(
-            "UPDATE withdrawals SET invoice_hash = ? WHERE id = ?",
-            payment_hash_hex,
-            new_withdrawal_id,
+    /// Returns the snapshots, most recent first.
+    pub async fn all(
+        db: &mut Connection<Db>,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<SolvencySnapshot>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let solvency_snapshots = sqlx::query!(
+            "
+select *
+from
+ solvencysnapshots
+ORDER BY solvencysnapshots.created_time_ms DESC
+LIMIT ?
+OFFSET ?
+;",
+            page_size,
+            offset,
         )
-        .execute(&mut *tx)
-        .await
-        .map_err(|_| "failed to update new withdrawal payment hash.")?;
-
-        tx.commit()
-            .await
-            .map_err(|_| "failed to begin transaction.")?;
+        .fetch(&mut **db)
+        .map_ok(|r| {
+            let mut solvency_snapshot = SolvencySnapshot::new(
+                r.channel_balance_sat.unwrap().try_into().unwrap(),
+                r.unsettled_balance_sat.unwrap().try_into().unwrap(),
+                r.wallet_balance_sat.unwrap().try_into().unwrap(),
+                r.total_market_liabilities_sat.unwrap(),
+                r.pending_escrow_sat.unwrap().try_into().unwrap(),
+                r.created_time_ms.unwrap().try_into().unwrap(),
+            );
+            solvency_snapshot.id = r.id.map(|n| n.try_into().unwrap());
+            solvency_snapshot
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
 
-        Ok(new_withdrawal_id as _)
+        Ok(solvency_snapshots)
     }
+}
 
-    pub async fn single_by_public_id(
-        db: &mut Connection<Db>,
-        public_id: &str,
-    ) -> Result<Withdrawal, sqlx::Error> {
-        let withdrawal = sqlx::query!("select * from withdrawals WHERE public_id = ?;", public_id)
-            .fetch_one(&mut **db)
-            .map_ok(|r| Withdrawal {
-                id: Some(r.id.try_into().unwrap()),
-                public_id: r.public_id,
-                user_id: r.user_id.try_into().unwrap(),
-                amount_sat: r.amount_sat.try_into().unwrap(),
-                invoice_hash: r.invoice_hash,
-                invoice_payment_request: r.invoice_payment_request,
-                created_time_ms: r.created_time_ms.try_into().unwrap(),
+impl InvoiceSettleIndex {
+    /// Returns the settle index of the last invoice handled by the payment
+    /// processor, or zero if no invoice has been handled yet.
+    pub async fn single(
+        db: &mut PoolConnection<Sqlite>,
+    ) -> Result<InvoiceSettleIndex, sqlx::Error> {
+        let maybe_settle_index = sqlx::query!("select settle_index from invoicesettleindex;")
+            .fetch_optional(&mut **db)
+            .map_ok(|maybe_r| {
+                maybe_r.map(|r| InvoiceSettleIndex {
+                    settle_index: r.settle_index.try_into().unwrap(),
+                })
             })
             .await?;
 
-        Ok(withdrawal)
+        Ok(maybe_settle_index.unwrap_or(InvoiceSettleIndex { settle_index: 0 }))
     }
 
-    //     pub async fn count_for_user_since_time_ms(
-    //         db: &mut Connection<Db>,
-    //         user_id: i32,
-    //         start_time_ms: u64,
-    //     ) -> Result<u32, sqlx::Error> {
-    //         let start_time_ms_i64: i64 = start_time_ms.try_into().unwrap();
+    /// Stores a new settle index. The stored value never decreases.
+    pub async fn update(
+        db: &mut PoolConnection<Sqlite>,
+        new_settle_index: u64,
+    ) -> Result<(), sqlx::Error> {
+        let new_settle_index_i64: i64 = new_settle_index.try_into().unwrap();
 
-    //         let withdrawal_count = sqlx::query!(
-    //             "
-    // select count(id) as withdrawal_count from withdrawals
-    // WHERE
-    //  user_id = ?
-    // AND
-    //  created_time_ms > ?
-    // ORDER BY withdrawals.created_time_ms ASC;",
-    //             user_id,
-    //             start_time_ms_i64,
-    //         )
-    //         .fetch_one(&mut **db)
-    //         .map_ok(|r| r.withdrawal_count)
-    //         .await?;
+        sqlx::query!(
+            "
+INSERT INTO
+ invoicesettleindex (settle_index)
+SELECT 0
+WHERE NOT EXISTS(SELECT 1 FROM invoicesettleindex)
+;",
+        )
+        .execute(&mut **db)
+        .await?;
 
-    //         Ok(withdrawal_count.try_into().unwrap())
-    //     }
-}
+        sqlx::query!(
+            "UPDATE invoicesettleindex SET settle_index = ? WHERE settle_index < ?",
+            new_settle_index_i64,
+            new_settle_index_i64,
+        )
+        .execute(&mut **db)
+        .await?;
 
-impl AdminInfo {
-    pub async fn admin_info(db: &mut Connection<Db>) -> Result<AdminInfo, sqlx::Error> {
-        let num_pending_listings = Listing::num_pending(db).await?;
-        Ok(AdminInfo {
-            num_pending_listings,
-        })
+        Ok(())
     }
 }
 
-impl UserAccount {
+impl DisputeMessage {
     /// Returns the id of the inserted row.
     pub async fn insert(
-        user_account: UserAccount,
+        dispute_message: DisputeMessage,
         db: &mut Connection<Db>,
     ) -> Result<i32, sqlx::Error> {
-        let amount_owed_sat: i64 = user_account.amount_owed_sat.try_into().unwrap();
-        let created_time_ms: i64 = user_account.created_time_ms.try_into().unwrap();
-        let payment_time_ms: i64 = user_account.payment_time_ms.try_into().unwrap();
+        let created_time_ms: i64 = dispute_message.created_time_ms.try_into().unwrap();
 
         let insert_result = sqlx::query!(
-            "INSERT INTO useraccounts (public_id, user_id, amount_owed_sat, paid, disabled, invoice_payment_request, invoice_hash, created_time_ms, payment_time_ms) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            user_account.public_id,
-            user_account.user_id,
-            amount_owed_sat,
-            user_account.paid,
-            user_account.disabled,
-            user_account.invoice_payment_request,
-            user_account.invoice_hash,
+            "INSERT INTO disputemessages (public_id, order_id, author_user_id, text, created_time_ms) VALUES (?, ?, ?, ?, ?)",
+            dispute_message.public_id,
+            dispute_message.order_id,
+            dispute_message.author_user_id,
+            dispute_message.text,
             created_time_ms,
-            payment_time_ms,
         )
-            .execute(&mut **db)
-            .await?;
+        .execute(&mut **db)
+        .await?;
 
         Ok(insert_result.last_insert_rowid() as _)
     }
 
-    pub async fn single(db: &mut Connection<Db>, id: i32) -> Result<UserAccount, sqlx::Error> {
-        let user_account = sqlx::query!("select * from useraccounts WHERE user_id = ?;", id)
-            .fetch_one(&mut **db)
-            .map_ok(|r| UserAccount {
-                id: Some(r.id.try_into().unwrap()),
-                public_id: r.public_id,
-                user_id: r.user_id.try_into().unwrap(),
-                amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
-                paid: r.paid,
-                disabled: r.disabled,
-                invoice_payment_request: r.invoice_payment_request,
-                invoice_hash: r.invoice_hash,
-                created_time_ms: r.created_time_ms.try_into().unwrap(),
-                payment_time_ms: r.payment_time_ms.try_into().unwrap(),
-            })
-            .await?;
-
-        Ok(user_account)
-    }
-
-    pub async fn single_by_public_id(
+    /// Returns the evidence messages of an order, oldest first.
+    pub async fn all_for_order(
         db: &mut Connection<Db>,
-        public_id: &str,
-    ) -> Result<UserAccount, sqlx::Error> {
-        let user_account =
-            sqlx::query!("select * from useraccounts WHERE public_id = ?;", public_id)
-                .fetch_one(&mut **db)
-                .map_ok(|r| UserAccount {
-                    id: Some(r.id.try_into().unwrap()),
-                    public_id: r.public_id,
-                    user_id: r.user_id.try_into().unwrap(),
-                    amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
-                    paid: r.paid,
-                    disabled: r.disabled,
-                    invoice_payment_request: r.invoice_payment_request,
-                    invoice_hash: r.invoice_hash,
-                    created_time_ms: r.created_time_ms.try_into().unwrap(),
-                    payment_time_ms: r.payment_time_ms.try_into().unwrap(),
-                })
-                .await?;
+        order_id: i32,
+    ) -> Result<Vec<DisputeMessage>, sqlx::Error> {
+        let dispute_messages = sqlx::query!(
+            "
+select *
+from
+ disputemessages
+WHERE
+ order_id = ?
+ORDER BY disputemessages.created_time_ms ASC
+;",
+            order_id,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| DisputeMessage {
+            id: r.id.map(|n| n.try_into().unwrap()),
+            public_id: r.public_id,
+            order_id: r.order_id.try_into().unwrap(),
+            author_user_id: r.author_user_id.try_into().unwrap(),
+            text: r.text,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
 
-        Ok(user_account)
+        Ok(dispute_messages)
     }
+}
 
-    pub async fn single_by_invoice_hash(
-        db: &mut PoolConnection<Sqlite>,
-        invoice_hash: &str,
-    ) -> Result<UserAccount, sqlx::Error> {
-        let user_account = sqlx::query!(
-            "select * from useraccounts WHERE invoice_hash = ?;",
-            invoice_hash
+impl OrderMessage {
+    /// Returns the id of the inserted row.
+    pub async fn insert(
+        order_message: OrderMessage,
+        db: &mut Connection<Db>,
+    ) -> Result<i32, sqlx::Error> {
+        let created_time_ms: i64 = order_message.created_time_ms.try_into().unwrap();
+
+        let insert_result = sqlx::query!(
+            "INSERT INTO ordermessages (public_id, order_id, author_user_id, text, read_by_buyer, read_by_seller, created_time_ms) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            order_message.public_id,
+            order_message.order_id,
+            order_message.author_user_id,
+            order_message.text,
+            order_message.read_by_buyer,
+            order_message.read_by_seller,
+            created_time_ms,
         )
-        .fetch_one(&mut **db)
-        .map_ok(|r| UserAccount {
-            id: Some(r.id.try_into().unwrap()),
+        .execute(&mut **db)
+        .await?;
+
+        Ok(insert_result.last_insert_rowid() as _)
+    }
+
+    /// Returns the messages of an order, oldest first.
+    pub async fn all_for_order(
+        db: &mut Connection<Db>,
+        order_id: i32,
+    ) -> Result<Vec<OrderMessage>, sqlx::Error> {
+        let order_messages = sqlx::query!(
+            "
+select *
+from
+ ordermessages
+WHERE
+ order_id = ?
+ORDER BY ordermessages.created_time_ms ASC
+;",
+            order_id,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| OrderMessage {
+            id: r.id.map(|n| n.try_into().unwrap()),
             public_id: r.public_id,
-            user_id: r.user_id.try_into().unwrap(),
-            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
-            paid: r.paid,
-            disabled: r.disabled,
-            invoice_payment_request: r.invoice_payment_request,
-            invoice_hash: r.invoice_hash,
+            order_id: r.order_id.try_into().unwrap(),
+            author_user_id: r.author_user_id.try_into().unwrap(),
+            text: r.text,
+            read_by_buyer: r.read_by_buyer,
+            read_by_seller: r.read_by_seller,
             created_time_ms: r.created_time_ms.try_into().unwrap(),
-            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
         })
+        .try_collect::<Vec<_>>()
         .await?;
 
-        Ok(user_account)
+        Ok(order_messages)
     }
 
-    pub async fn mark_as_paid(
-        db: &mut PoolConnection<Sqlite>,
-        user_account_id: i32,
-        time_now_ms: u64,
+    /// Marks all messages of an order as read by the buyer.
+    pub async fn mark_all_as_read_by_buyer(
+        db: &mut Connection<Db>,
+        order_id: i32,
     ) -> Result<(), sqlx::Error> {
-        let time_now_ms_i64: i64 = time_now_ms.try_into().unwrap();
-
         sqlx::query!(
-            "UPDATE useraccounts SET paid = true, payment_time_ms = ? WHERE id = ?",
-            time_now_ms_i64,
-            user_account_id,
+            "UPDATE ordermessages SET read_by_buyer = true WHERE order_id = ? AND NOT read_by_buyer",
+            order_id,
         )
         .execute(&mut **db)
         .await?;
@@ -3548,13 +7973,14 @@ impl UserAccount {
         Ok(())
     }
 
-    pub async fn mark_as_disabled(
-        db: &mut PoolConnection<Sqlite>,
-        user_id: i32,
+    /// Marks all messages of an order as read by the seller.
+    pub async fn mark_all_as_read_by_seller(
+        db: &mut Connection<Db>,
+        order_id: i32,
     ) -> Result<(), sqlx::Error> {
         sqlx::query!(
-            "UPDATE useraccounts SET disabled = true WHERE user_id = ?",
-            user_id,
+            "UPDATE ordermessages SET read_by_seller = true WHERE order_id = ? AND NOT read_by_seller",
+            order_id,
         )
         .execute(&mut **db)
         .await?;
@@ -3562,82 +7988,156 @@ impl UserAccount {
         Ok(())
     }
 
-    pub async fn mark_as_enabled(
-        db: &mut PoolConnection<Sqlite>,
+    pub async fn num_unread_for_user(
+        db: &mut Connection<Db>,
         user_id: i32,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query!(
-            "UPDATE useraccounts SET disabled = false WHERE user_id = ?",
+    ) -> Result<u32, sqlx::Error> {
+        let num_messages = sqlx::query!(
+            "
+select
+ COUNT(ordermessages.id) as num_unread_messages
+from
+ ordermessages
+LEFT JOIN
+ orders
+ON
+ ordermessages.order_id = orders.id
+WHERE
+ (orders.buyer_user_id = ? AND NOT ordermessages.read_by_buyer)
+OR
+ (orders.seller_user_id = ? AND NOT ordermessages.read_by_seller)
+;",
+            user_id,
             user_id,
         )
-        .execute(&mut **db)
+        .fetch_one(&mut **db)
+        .map_ok(|r| r.num_unread_messages as u32)
         .await?;
 
-        Ok(())
+        Ok(num_messages)
     }
+}
 
-    pub async fn number_of_users(db: &mut Connection<Db>) -> Result<u64, sqlx::Error> {
-        let num_users = sqlx::query!(
+impl OrderMessageCard {
+    /// Returns the orders with messages the user has not read, most recent
+    /// message first.
+    pub async fn all_unread_for_user(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        page_size: u32,
+        page_num: u32,
+    ) -> Result<Vec<OrderMessageCard>, sqlx::Error> {
+        let offset = (page_num - 1) * page_size;
+        let limit = page_size;
+        let order_message_cards = sqlx::query(
             "
 select
- COUNT(users.id) as num_users
+ orders.public_id as order_public_id, COUNT(ordermessages.id) as num_unread_messages, MAX(ordermessages.created_time_ms) as latest_message_time_ms
 from
- users
+ ordermessages
+LEFT JOIN
+ orders
+ON
+ ordermessages.order_id = orders.id
 WHERE
- NOT users.is_admin
+ (orders.buyer_user_id = ? AND NOT ordermessages.read_by_buyer)
+OR
+ (orders.seller_user_id = ? AND NOT ordermessages.read_by_seller)
+GROUP BY
+ orders.id
+ORDER BY latest_message_time_ms DESC
+LIMIT ?
+OFFSET ?
 ;",
         )
-        .fetch_one(&mut **db)
-        .map_ok(|r| r.num_users as u64)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch(&mut **db)
+        .map_ok(|r| {
+            let num_unread_messages: i64 = r.try_get("num_unread_messages").unwrap();
+            let latest_message_time_ms: i64 = r.try_get("latest_message_time_ms").unwrap();
+            OrderMessageCard {
+                order_public_id: r.try_get("order_public_id").unwrap(),
+                num_unread_messages: num_unread_messages.try_into().unwrap(),
+                latest_message_time_ms: latest_message_time_ms.try_into().unwrap(),
+            }
+        })
+        .try_collect::<Vec<_>>()
         .await?;
 
-        Ok(num_users)
+        Ok(order_message_cards)
     }
+}
 
-    pub async fn all_older_than(
-        db: &mut PoolConnection<Sqlite>,
-        created_time_ms: u64,
-    ) -> Result<Vec<UserAccount>, sqlx::Error> {
-        let created_time_ms_i64: i64 = created_time_ms.try_into().unwrap();
+impl OrderItem {
+    /// Returns the id of the inserted row.
+    async fn insert(order_item: OrderItem, db: &mut SqliteConnection) -> Result<i32, sqlx::Error> {
+        let price_sat: i64 = order_item.price_sat.try_into().unwrap();
+        let market_fee_sat: i64 = order_item.market_fee_sat.try_into().unwrap();
 
-        let user_accounts = sqlx::query!(
+        let insert_result = sqlx::query!(
+            "INSERT INTO orderitems (order_id, listing_id, quantity, price_sat, fee_rate_basis_points, market_fee_sat, variant_id, variant_title) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            order_item.order_id,
+            order_item.listing_id,
+            order_item.quantity,
+            price_sat,
+            order_item.fee_rate_basis_points,
+            market_fee_sat,
+            order_item.variant_id,
+            order_item.variant_title,
+        )
+        .execute(&mut *db)
+        .await?;
+
+        Ok(insert_result.last_insert_rowid() as _)
+    }
+
+    pub async fn all_for_order(
+        db: &mut SqliteConnection,
+        order_id: i32,
+    ) -> Result<Vec<OrderItem>, sqlx::Error> {
+        let order_items = sqlx::query!(
             "
 select *
 from
- useraccounts
+ orderitems
 WHERE
- created_time_ms < ?
-AND
- NOT paid
+ order_id = ?
+ORDER BY orderitems.id ASC
 ;",
-            created_time_ms_i64,
+            order_id,
         )
-        .fetch(&mut **db)
-        .map_ok(|r| UserAccount {
+        .fetch(&mut *db)
+        .map_ok(|r| OrderItem {
             id: Some(r.id.try_into().unwrap()),
-            public_id: r.public_id,
-            user_id: r.user_id.try_into().unwrap(),
-            amount_owed_sat: r.amount_owed_sat.try_into().unwrap(),
-            paid: r.paid,
-            disabled: r.disabled,
-            invoice_payment_request: r.invoice_payment_request,
-            invoice_hash: r.invoice_hash,
-            created_time_ms: r.created_time_ms.try_into().unwrap(),
-            payment_time_ms: r.payment_time_ms.try_into().unwrap(),
+            order_id: r.order_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            quantity: r.quantity.try_into().unwrap(),
+            price_sat: r.price_sat.try_into().unwrap(),
+            fee_rate_basis_points: r.fee_rate_basis_points.try_into().unwrap(),
+            market_fee_sat: r.market_fee_sat.try_into().unwrap(),
+            variant_id: r.variant_id.try_into().unwrap(),
+            variant_title: r.variant_title,
         })
         .try_collect::<Vec<_>>()
         .await?;
 
-        Ok(user_accounts)
+        Ok(order_items)
     }
+}
 
-    pub async fn delete_expired_user_account(
-        db: &mut PoolConnection<Sqlite>,
-        user_account_id: i32,
-        cancel_user_account_invoice_future: impl Future<
-            Output = Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String>,
-        >,
+impl CartItem {
+    /// Adds a listing to the cart of a user, or replaces the quantity and
+    /// shipping option if the listing variant is already in the cart.
+    pub async fn insert_or_update(
+        cart_item: CartItem,
+        max_cart_items: u32,
+        db: &mut Connection<Db>,
     ) -> Result<(), String> {
+        let created_time_ms: i64 = cart_item.created_time_ms.try_into().unwrap();
+
         let mut tx = db
             .begin()
             .await
@@ -3645,278 +8145,323 @@ AND
 
         sqlx::query!(
             "
-DELETE FROM useraccounts
-WHERE
- user_id = ?
-AND
- NOT paid
+INSERT INTO cartitems (public_id, user_id, listing_id, variant_id, shipping_option_id, quantity, created_time_ms)
+VALUES (?, ?, ?, ?, ?, ?, ?)
+ON CONFLICT (user_id, listing_id, variant_id)
+DO UPDATE SET shipping_option_id = excluded.shipping_option_id, quantity = excluded.quantity
 ;",
-            user_account_id,
+            cart_item.public_id,
+            cart_item.user_id,
+            cart_item.listing_id,
+            cart_item.variant_id,
+            cart_item.shipping_option_id,
+            cart_item.quantity,
+            created_time_ms,
         )
         .execute(&mut *tx)
         .await
-        .map_err(|_| "failed to delete user account from database.")?;
+        .map_err(|_| "failed to add item to cart.")?;
 
-        sqlx::query!(
+        let num_cart_items = sqlx::query!(
             "
-DELETE FROM users
+select
+ COUNT(cartitems.id) as num_cart_items
+from
+ cartitems
 WHERE
- id = ?
+ cartitems.user_id = ?
 ;",
-            user_account_id,
+            cart_item.user_id,
         )
-        .execute(&mut *tx)
+        .fetch_one(&mut *tx)
+        .map_ok(|r| r.num_cart_items as u32)
         .await
-        .map_err(|_| "failed to delete user from database.")?;
+        .map_err(|_| "failed to get count of cart items.")?;
 
-        cancel_user_account_invoice_future
-            .await
-            .map_err(|e| format!("failed to cancel user account invoice: {:?}", e))?;
+        if num_cart_items > max_cart_items {
+            return Err(format!(
+                "more than {:?} items in cart not allowed.",
+                max_cart_items
+            ));
+        }
 
         tx.commit()
             .await
-            .map_err(|_| "failed to begin transaction.")?;
-
-        Ok(())
-    }
-
-    pub async fn delete_users_with_no_account(
-        db: &mut PoolConnection<Sqlite>,
-    ) -> Result<(), String> {
-        sqlx::query!(
-            "
-DELETE FROM users
-WHERE
- NOT is_admin
-AND
- id IN
-(SELECT users.id FROM users
-LEFT JOIN
- useraccounts
-ON
- users.id=useraccounts.user_id
-WHERE
- useraccounts.user_id IS NULL);
-;"
-        )
-        .execute(&mut **db)
-        .await
-        .map_err(|_| "failed to delete user account from database.")?;
+            .map_err(|_| "failed to commit transaction.")?;
 
         Ok(())
     }
 
-    pub async fn do_deactivation(
-        amount_sat: u64,
-        user_account: UserAccount,
+    /// Returns the cart items of a user, oldest first.
+    pub async fn all_for_user(
         db: &mut Connection<Db>,
-        send_deactivation_funds_future: impl Future<
-            Output = Result<tonic_openssl_lnd::lnrpc::SendResponse, String>,
-        >,
-    ) -> Result<(), String> {
-        let mut tx = db
-            .begin()
-            .await
-            .map_err(|_| "failed to begin transaction.")?;
-
-        // Insert the new withdrawal.
-        let activation_bond_amount_sat: i64 = user_account.amount_owed_sat.try_into().unwrap();
-        let amount_sat: i64 = amount_sat.try_into().unwrap();
-        let delete_user_account_result = sqlx::query!(
+        user_id: i32,
+    ) -> Result<Vec<CartItem>, sqlx::Error> {
+        let cart_items = sqlx::query!(
             "
-DELETE FROM useraccounts
+select *
+from
+ cartitems
 WHERE
  user_id = ?
-AND
- paid = true
+ORDER BY cartitems.created_time_ms ASC
 ;",
-            user_account.user_id,
+            user_id,
         )
-        .execute(&mut *tx)
-        .await
-        .map_err(|_| "failed to delete user account.")?;
-
-        // Validate that at least one paid user account was deleted
-        if delete_user_account_result.rows_affected() < 1 {
-            return Err("No user bond found.".to_string());
-        }
+        .fetch(&mut **db)
+        .map_ok(|r| CartItem {
+            id: r.id.map(|n| n.try_into().unwrap()),
+            public_id: r.public_id,
+            user_id: r.user_id.try_into().unwrap(),
+            listing_id: r.listing_id.try_into().unwrap(),
+            variant_id: r.variant_id.try_into().unwrap(),
+            shipping_option_id: r.shipping_option_id.try_into().unwrap(),
+            quantity: r.quantity.try_into().unwrap(),
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
 
-        sqlx::query!(
-            "
-DELETE FROM users
-WHERE id = ?
-;",
-            user_account.user_id,
-        )
-        .execute(&mut *tx)
-        .await
-        .map_err(|_| "failed to delete user.")?;
+        Ok(cart_items)
+    }
 
+    pub async fn delete_with_public_id(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        public_id: &str,
+    ) -> Result<(), sqlx::Error> {
         sqlx::query!(
-            "
-DELETE FROM listingimages
-WHERE
- listing_id IN
-(SELECT listings.id FROM listings
-WHERE user_id = ?);
-;",
-            user_account.user_id,
+            "DELETE FROM cartitems WHERE user_id = ? AND public_id = ?",
+            user_id,
+            public_id,
         )
-        .execute(&mut *tx)
-        .await
-        .map_err(|_| "failed to delete user listing images.")?;
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
+    }
 
+    /// Removes the items of a seller from the cart of a user after checkout.
+    pub async fn delete_all_for_seller(
+        db: &mut Connection<Db>,
+        user_id: i32,
+        seller_user_id: i32,
+    ) -> Result<(), sqlx::Error> {
         sqlx::query!(
             "
-DELETE FROM shippingoptions
+DELETE FROM cartitems
 WHERE
- listing_id IN
-(SELECT listings.id FROM listings
-WHERE user_id = ?);
+ user_id = ?
+AND
+ listing_id IN (SELECT id FROM listings WHERE user_id = ?)
 ;",
-            user_account.user_id,
+            user_id,
+            seller_user_id,
         )
-        .execute(&mut *tx)
-        .await
-        .map_err(|_| "failed to delete user shipping options.")?;
+        .execute(&mut **db)
+        .await?;
 
-        sqlx::query!(
-            "
-DELETE FROM listings
-WHERE user_id = ?
-;",
-            user_account.user_id,
+        Ok(())
+    }
+}
+
+impl OrderEvent {
+    /// Returns the id of the inserted row.
+    async fn insert(
+        order_event: OrderEvent,
+        db: &mut SqliteConnection,
+    ) -> Result<i32, sqlx::Error> {
+        let status = order_event.status.as_str();
+        let created_time_ms: i64 = order_event.created_time_ms.try_into().unwrap();
+
+        let insert_result = sqlx::query!(
+            "INSERT INTO orderevents (order_id, status, actor_user_id, created_time_ms) VALUES (?, ?, ?, ?)",
+            order_event.order_id,
+            status,
+            order_event.actor_user_id,
+            created_time_ms,
         )
-        .execute(&mut *tx)
-        .await
-        .map_err(|_| "failed to delete user listings.")?;
+        .execute(&mut *db)
+        .await?;
 
-        if activation_bond_amount_sat - amount_sat < 0 {
-            return Err("Insufficient funds for deactivation.".to_string());
-        }
+        Ok(insert_result.last_insert_rowid() as _)
+    }
 
-        send_deactivation_funds_future
+    /// Checks that the order is allowed to move to the given status, and
+    /// records the change.
+    ///
+    /// Must run in the same transaction that updates the order.
+    async fn record_transition(
+        db: &mut SqliteConnection,
+        order_id: i32,
+        next_status: OrderStatus,
+        actor_user_id: i32,
+        time_ms: u64,
+    ) -> Result<(), String> {
+        let order = Order::single(db, order_id)
             .await
-            .map_err(|e| format!("failed to send deactivation payment: {:?}", e))?;
+            .map_err(|_| "failed to get order.")?;
+        let status = order.status();
+        if !status.can_transition_to(next_status) {
+            return Err(format!(
+                "order cannot change from {} to {}.",
+                status.as_str(),
+                next_status.as_str()
+            ));
+        }
 
-        tx.commit()
+        let order_event = OrderEvent {
+            id: None,
+            order_id,
+            status: next_status,
+            actor_user_id,
+            created_time_ms: time_ms,
+        };
+        OrderEvent::insert(order_event, db)
             .await
-            .map_err(|_| "failed to commit transaction.")?;
+            .map_err(|_| "failed to insert order event.")?;
 
         Ok(())
     }
-}
 
-impl UserCard {
-    pub async fn all_active(
+    /// Returns the status changes of an order, oldest first.
+    pub async fn all_for_order(
         db: &mut Connection<Db>,
-        page_size: u32,
-        page_num: u32,
-    ) -> Result<Vec<UserCard>, sqlx::Error> {
-        let offset = (page_num - 1) * page_size;
-        let limit = page_size;
-        let user_cards =
-            sqlx::query!("
-select
- users.id as rocket_auth_user_id, users.email as rocket_auth_user_username, useraccounts.id as useraccounts_id, useraccounts.public_id as useraccounts_public_id, useraccounts.user_id as useraccounts_user_id, useraccounts.amount_owed_sat as useraccounts_amount_owed_sat, useraccounts.paid as useraccounts_paid, useraccounts.disabled as useraccounts_disabled, useraccounts.invoice_payment_request as useraccounts_invoice_payment_request, useraccounts.invoice_hash as useraccounts_invoice_hash, useraccounts.created_time_ms as useraccounts_created_time_ms, useraccounts.payment_time_ms as useraccounts_payment_time_ms
+        order_id: i32,
+    ) -> Result<Vec<OrderEvent>, sqlx::Error> {
+        let order_events = sqlx::query!(
+            "
+select *
 from
- users
-INNER JOIN
- useraccounts
-ON
- users.id = useraccounts.user_id
+ orderevents
 WHERE
- useraccounts.paid
-AND
- NOT useraccounts.disabled
-GROUP BY
- users.id
-ORDER BY useraccounts.created_time_ms DESC
-LIMIT ?
-OFFSET ?
-;", limit, offset)
-            .fetch(&mut **db)
-            .map_ok(|r| {
-                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
-                    id: Some(rocket_auth_user_id.try_into().unwrap()),
-                    username: r.rocket_auth_user_username.unwrap(),
-                });
-                let ua = r.useraccounts_id.map(|user_account_id| UserAccount {
-                    id: Some(user_account_id.try_into().unwrap()),
-                    public_id: r.useraccounts_public_id.unwrap(),
-                    user_id: r.useraccounts_user_id.unwrap().try_into().unwrap(),
-                    amount_owed_sat: r.useraccounts_amount_owed_sat.unwrap().try_into().unwrap(),
-                    paid: r.useraccounts_paid.unwrap(),
-                    disabled: r.useraccounts_disabled.unwrap(),
-                    invoice_payment_request: r.useraccounts_invoice_payment_request.unwrap(),
-                    invoice_hash: r.useraccounts_invoice_hash.unwrap(),
-                    created_time_ms: r.useraccounts_created_time_ms.unwrap().try_into().unwrap(),
-                    payment_time_ms: r.useraccounts_payment_time_ms.unwrap().try_into().unwrap(),
-                });
-                UserCard {
-                    user: u.unwrap(),
-                    user_account: ua.unwrap(),
-                }
+ order_id = ?
+ORDER BY orderevents.created_time_ms ASC, orderevents.id ASC
+;",
+            order_id,
+        )
+        .fetch(&mut **db)
+        .map_ok(|r| OrderEvent {
+            id: r.id.map(|n| n.try_into().unwrap()),
+            order_id: r.order_id.try_into().unwrap(),
+            status: r.status.parse().unwrap(),
+            actor_user_id: r.actor_user_id.try_into().unwrap(),
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(order_events)
+    }
+}
+
+impl LnurlWithdrawal {
+    pub async fn insert(
+        lnurl_withdrawal: LnurlWithdrawal,
+        db: &mut Connection<Db>,
+    ) -> Result<i32, sqlx::Error> {
+        let created_time_ms: i64 = lnurl_withdrawal.created_time_ms.try_into().unwrap();
+        let insert_result = sqlx::query!(
+            "INSERT INTO lnurlwithdrawals (public_id, user_id, k1, used, withdrawal_public_id, created_time_ms) VALUES (?, ?, ?, ?, ?, ?)",
+            lnurl_withdrawal.public_id,
+            lnurl_withdrawal.user_id,
+            lnurl_withdrawal.k1,
+            lnurl_withdrawal.used,
+            lnurl_withdrawal.withdrawal_public_id,
+            created_time_ms,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(insert_result.last_insert_rowid() as _)
+    }
+
+    pub async fn single_by_public_id(
+        db: &mut Connection<Db>,
+        public_id: &str,
+    ) -> Result<LnurlWithdrawal, sqlx::Error> {
+        let lnurl_withdrawal = sqlx::query!(
+            "select * from lnurlwithdrawals WHERE public_id = ?;",
+            public_id,
+        )
+        .fetch_one(&mut **db)
+        .map_ok(|r| LnurlWithdrawal {
+            id: Some(r.id.try_into().unwrap()),
+            public_id: r.public_id,
+            user_id: r.user_id.try_into().unwrap(),
+            k1: r.k1,
+            used: r.used,
+            withdrawal_public_id: r.withdrawal_public_id,
+            created_time_ms: r.created_time_ms.try_into().unwrap(),
+        })
+        .await?;
+
+        Ok(lnurl_withdrawal)
+    }
+
+    pub async fn single_by_k1(
+        db: &mut Connection<Db>,
+        k1: &str,
+    ) -> Result<LnurlWithdrawal, sqlx::Error> {
+        let lnurl_withdrawal = sqlx::query!("select * from lnurlwithdrawals WHERE k1 = ?;", k1)
+            .fetch_one(&mut **db)
+            .map_ok(|r| LnurlWithdrawal {
+                id: r.id.map(|n| n.try_into().unwrap()),
+                public_id: r.public_id,
+                user_id: r.user_id.try_into().unwrap(),
+                k1: r.k1,
+                used: r.used,
+                withdrawal_public_id: r.withdrawal_public_id,
+                created_time_ms: r.created_time_ms.try_into().unwrap(),
             })
-                .try_collect::<Vec<_>>()
-                .await?;
+            .await?;
 
-        Ok(user_cards)
+        Ok(lnurl_withdrawal)
     }
 
-    pub async fn all_disabled(
+    /// Marks an unused request created after the given time as used.
+    ///
+    /// Returns false if the request was already used or is expired, so that
+    /// each k1 can only be redeemed once.
+    pub async fn mark_as_used(
         db: &mut Connection<Db>,
-        page_size: u32,
-        page_num: u32,
-    ) -> Result<Vec<UserCard>, sqlx::Error> {
-        let offset = (page_num - 1) * page_size;
-        let limit = page_size;
-        let user_cards =
-            sqlx::query!("
-select
- users.id as rocket_auth_user_id, users.email as rocket_auth_user_username, useraccounts.id as useraccounts_id, useraccounts.public_id as useraccounts_public_id, useraccounts.user_id as useraccounts_user_id, useraccounts.amount_owed_sat as useraccounts_amount_owed_sat, useraccounts.paid as useraccounts_paid, useraccounts.disabled as useraccounts_disabled, useraccounts.invoice_payment_request as useraccounts_invoice_payment_request, useraccounts.invoice_hash as useraccounts_invoice_hash, useraccounts.created_time_ms as useraccounts_created_time_ms, useraccounts.payment_time_ms as useraccounts_payment_time_ms
-from
- users
-INNER JOIN
- useraccounts
-ON
- users.id = useraccounts.user_id
+        lnurl_withdrawal_id: i32,
+        created_after_time_ms: u64,
+    ) -> Result<bool, sqlx::Error> {
+        let created_after_time_ms_i64: i64 = created_after_time_ms.try_into().unwrap();
+        let update_result = sqlx::query!(
+            "
+UPDATE lnurlwithdrawals
+SET used = true
 WHERE
- useraccounts.paid
+ id = ?
 AND
- useraccounts.disabled
-GROUP BY
- users.id
-ORDER BY useraccounts.created_time_ms DESC
-LIMIT ?
-OFFSET ?
-;", limit, offset)
-            .fetch(&mut **db)
-            .map_ok(|r| {
-                let u = r.rocket_auth_user_id.map(|rocket_auth_user_id| RocketAuthUser {
-                    id: Some(rocket_auth_user_id.try_into().unwrap()),
-                    username: r.rocket_auth_user_username.unwrap(),
-                });
-                let ua = r.useraccounts_id.map(|user_account_id| UserAccount {
-                    id: Some(user_account_id.try_into().unwrap()),
-                    public_id: r.useraccounts_public_id.unwrap(),
-                    user_id: r.useraccounts_user_id.unwrap().try_into().unwrap(),
-                    amount_owed_sat: r.useraccounts_amount_owed_sat.unwrap().try_into().unwrap(),
-                    paid: r.useraccounts_paid.unwrap(),
-                    disabled: r.useraccounts_disabled.unwrap(),
-                    invoice_payment_request: r.useraccounts_invoice_payment_request.unwrap(),
-                    invoice_hash: r.useraccounts_invoice_hash.unwrap(),
-                    created_time_ms: r.useraccounts_created_time_ms.unwrap().try_into().unwrap(),
-                    payment_time_ms: r.useraccounts_payment_time_ms.unwrap().try_into().unwrap(),
-                });
-                UserCard {
-                    user: u.unwrap(),
-                    user_account: ua.unwrap(),
-                }
-            })
-                .try_collect::<Vec<_>>()
-                .await?;
+ NOT used
+AND
+ created_time_ms > ?
+;",
+            lnurl_withdrawal_id,
+            created_after_time_ms_i64,
+        )
+        .execute(&mut **db)
+        .await?;
 
-        Ok(user_cards)
+        Ok(update_result.rows_affected() == 1)
+    }
+
+    pub async fn set_withdrawal_public_id(
+        db: &mut Connection<Db>,
+        lnurl_withdrawal_id: i32,
+        withdrawal_public_id: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE lnurlwithdrawals SET withdrawal_public_id = ? WHERE id = ?;",
+            withdrawal_public_id,
+            lnurl_withdrawal_id,
+        )
+        .execute(&mut **db)
+        .await?;
+
+        Ok(())
     }
 }
@@ -1,7 +1,8 @@
 use crate::base::BaseContext;
 use crate::db::Db;
+use crate::exchange_rate_update::MAX_EXCHANGE_RATE_AGE_MS;
 use crate::models::AdminSettings;
-use crate::models::{InitialListingInfo, Listing};
+use crate::models::{ExchangeRate, InitialListingInfo, Listing};
 use crate::user_account::ActiveUser;
 use crate::util;
 use rocket::fairing::AdHoc;
@@ -14,6 +15,8 @@ use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
 
 const MAX_UNAPPROVED_LISTINGS: u32 = 5;
+const MAX_FIAT_PRICE_CENTS: u64 = 10_000_000_000;
+pub const MAX_LISTING_QUANTITY: u32 = 1_000_000;
 
 #[derive(Debug, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -74,7 +77,24 @@ async fn create_listing(
         .map_err(|_| "failed to update market name.")?;
     let now = util::current_time_millis();
 
-    let price_sat = listing_info.price_sat.unwrap_or(0);
+    let fiat_price_cents = match listing_info.fiat_price.as_deref().map(str::trim) {
+        Some(fiat_price) if !fiat_price.is_empty() => util::parse_fiat_cents(fiat_price)?,
+        _ => 0,
+    };
+    if fiat_price_cents > MAX_FIAT_PRICE_CENTS {
+        return Err("Fiat price is too high.".to_string());
+    };
+    // A fiat-pegged listing is converted to sats again on every exchange rate update.
+    let price_sat = if fiat_price_cents > 0 {
+        let exchange_rate =
+            ExchangeRate::latest_updated_after(db, now.saturating_sub(MAX_EXCHANGE_RATE_AGE_MS))
+                .await
+                .map_err(|_| "failed to get exchange rate.")?
+                .ok_or("No exchange rate is available for fiat prices.")?;
+        util::fiat_cents_to_sat(fiat_price_cents, exchange_rate.fiat_cents_per_btc)
+    } else {
+        listing_info.price_sat.unwrap_or(0)
+    };
 
     if listing_info.title.is_empty() {
         return Err("Title cannot be empty.".to_string());
@@ -91,6 +111,18 @@ async fn create_listing(
     if price_sat == 0 {
         return Err("Price must be a positive number.".to_string());
     };
+    // The stock of a digital listing is the number of its uploaded payloads.
+    let quantity = if listing_info.digital {
+        0
+    } else {
+        listing_info.quantity.unwrap_or(0)
+    };
+    if quantity == 0 && !listing_info.digital {
+        return Err("Quantity must be a positive number.".to_string());
+    };
+    if quantity > MAX_LISTING_QUANTITY {
+        return Err("Quantity is too high.".to_string());
+    };
     if user.is_admin {
         return Err("Admin user cannot create a listing.".to_string());
     };
@@ -109,6 +141,9 @@ async fn create_listing(
         deactivated_by_seller: false,
         deactivated_by_admin: false,
         created_time_ms: now,
+        fiat_price_cents,
+        quantity,
+        digital: listing_info.digital,
     };
     match Listing::insert(listing, MAX_UNAPPROVED_LISTINGS, db).await {
         Ok(listing_id) => match Listing::single(db, listing_id).await {
@@ -1,10 +1,19 @@
 use crate::base::BaseContext;
-use crate::config::Config;
 use crate::db::Db;
+use crate::digital_delivery;
 use crate::lightning;
-use crate::models::{Listing, Order, ReviewInput, RocketAuthUser, ShippingOption};
+use crate::lightning::LightningBackend;
+use crate::models::{
+    AccountInfo, AdminSettings, DigitalPayload, Listing, Order, OrderEvent, OrderItem,
+    OrderItemDisplay, OrderMessage, OrderMessageInput, OrderStatus, ReviewInput, RocketAuthUser,
+    ShippingOption, UserSettings,
+};
+use crate::order_expiry;
+use crate::order_finalization;
+use crate::prepare_order;
 use crate::user_account::ActiveUser;
 use crate::util;
+use pgp::composed::{Deserializable, Message};
 use rocket::fairing::AdHoc;
 use rocket::form::Form;
 use rocket::request::FlashMessage;
@@ -16,6 +25,9 @@ use rocket_auth::AdminUser;
 use rocket_auth::User;
 use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
+use std::sync::Arc;
+
+const MAX_ORDER_MESSAGE_LENGTH: usize = 4096;
 
 #[derive(Debug, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -23,13 +35,26 @@ struct Context {
     base_context: BaseContext,
     flash: Option<(String, String)>,
     order: Order,
+    order_status: OrderStatus,
+    order_events: Vec<OrderEvent>,
     maybe_listing: Option<Listing>,
+    order_item_displays: Vec<OrderItemDisplay>,
+    digital_payloads: Vec<DigitalPayload>,
     maybe_shipping_option: Option<ShippingOption>,
     maybe_seller_user: Option<RocketAuthUser>,
     user: Option<User>,
     admin_user: Option<AdminUser>,
     qr_svg_base64: String,
     lightning_node_pubkey: String,
+    maybe_buyer_account_balance_sat: Option<i64>,
+    invoice_expired: bool,
+    invoice_expiry_minutes_remaining: u64,
+    auto_finalize_time_ms: u64,
+    shipping_deadline_time_ms: u64,
+    order_messages: Vec<OrderMessage>,
+    can_message: bool,
+    buyer_pgp_key: String,
+    seller_pgp_key: String,
 }
 
 impl Context {
@@ -39,20 +64,59 @@ impl Context {
         flash: Option<(String, String)>,
         user: Option<User>,
         admin_user: Option<AdminUser>,
-        config: &Config,
+        lightning_backend: &dyn LightningBackend,
     ) -> Result<Context, String> {
+        let order = Order::single_by_public_id(&mut db, order_id)
+            .await
+            .map_err(|_| "failed to get order.")?;
+        // Viewing the order marks its messages as read, so this must happen
+        // before the unread counter in the base context is computed.
+        match &user {
+            Some(user) if user.id() == order.buyer_user_id => {
+                OrderMessage::mark_all_as_read_by_buyer(&mut db, order.id.unwrap())
+                    .await
+                    .map_err(|_| "failed to mark messages as read.")?
+            }
+            Some(user) if user.id() == order.seller_user_id => {
+                OrderMessage::mark_all_as_read_by_seller(&mut db, order.id.unwrap())
+                    .await
+                    .map_err(|_| "failed to mark messages as read.")?
+            }
+            _ => (),
+        };
         let base_context = BaseContext::raw(&mut db, user.clone(), admin_user.clone())
             .await
             .map_err(|_| "failed to get base template.")?;
-        let order = Order::single_by_public_id(&mut db, order_id)
+        let order_status = order.status();
+        let order_events = OrderEvent::all_for_order(&mut db, order.id.unwrap())
             .await
-            .map_err(|_| "failed to get order.")?;
+            .map_err(|_| "failed to get order events.")?;
         let maybe_listing = Listing::single(&mut db, order.listing_id).await.ok();
         // .map_err(|_| "failed to get listing.")?;
         // {
         //     Ok(listing) => Some(listing),
         //     Err(_) => None
         // };
+        let order_items = OrderItem::all_for_order(&mut db, order.id.unwrap())
+            .await
+            .map_err(|_| "failed to get order items.")?;
+        let mut order_item_displays = vec![];
+        for order_item in order_items {
+            let listing = Listing::single(&mut db, order_item.listing_id).await.ok();
+            order_item_displays.push(OrderItemDisplay {
+                order_item,
+                listing,
+            });
+        }
+        // Delivered payloads are encrypted to the buyer, so only the buyer sees them.
+        let digital_payloads = match &user {
+            Some(user) if user.id() == order.buyer_user_id => {
+                DigitalPayload::all_for_order(&mut db, order.id.unwrap())
+                    .await
+                    .map_err(|_| "failed to get delivered payloads.")?
+            }
+            _ => vec![],
+        };
         let maybe_shipping_option = ShippingOption::single(&mut db, order.shipping_option_id)
             .await
             .ok();
@@ -60,42 +124,267 @@ impl Context {
         let maybe_seller_user = RocketAuthUser::single(&mut db, order.seller_user_id)
             .await
             .ok();
-        let qr_svg_bytes = util::generate_qr(&order.invoice_payment_request);
+        let qr_svg_bytes = if order.onchain_address.is_empty() {
+            util::generate_qr(&order.invoice_payment_request)
+        } else {
+            util::generate_qr(&onchain_payment_uri(
+                &order.onchain_address,
+                order.amount_owed_sat,
+            ))
+        };
         let qr_svg_base64 = util::to_base64(&qr_svg_bytes);
-        let lightning_node_pubkey = get_lightning_node_pubkey(config)
+        let lightning_node_pubkey = get_lightning_node_pubkey(lightning_backend)
             .await
             .unwrap_or_else(|_| "".to_string());
+        let maybe_buyer_account_balance_sat = match &user {
+            Some(user) if user.id() == order.buyer_user_id && !order.paid => Some(
+                AccountInfo::total_account_balance_for_user(&mut db, user.id())
+                    .await
+                    .map_err(|_| "failed to get account balance.")?,
+            ),
+            _ => None,
+        };
+        let now = util::current_time_millis();
+        let invoice_expired = now >= order.invoice_expiry_time_ms;
+        let invoice_expiry_minutes_remaining =
+            util::millis_to_minutes_rounded_up(order.invoice_expiry_time_ms.saturating_sub(now));
+        let admin_settings = AdminSettings::single(&mut db)
+            .await
+            .map_err(|_| "failed to get admin settings.")?;
+        let auto_finalize_time_ms =
+            order.shipped_time_ms + admin_settings.order_auto_finalize_period_ms();
+        let shipping_deadline_time_ms = order.payment_time_ms
+            + order_expiry::shipping_deadline_period_ms(&admin_settings, order.escrow);
+        let can_message = match &user {
+            Some(user) => can_message(&order, user, admin_user.is_some()),
+            None => false,
+        };
+        let (order_messages, buyer_pgp_key, seller_pgp_key) = if can_message {
+            let order_messages = OrderMessage::all_for_order(&mut db, order.id.unwrap())
+                .await
+                .map_err(|_| "failed to get order messages.")?;
+            let buyer_user_settings = UserSettings::single(&mut db, order.buyer_user_id)
+                .await
+                .map_err(|_| "failed to get buyer user settings.")?;
+            let seller_user_settings = UserSettings::single(&mut db, order.seller_user_id)
+                .await
+                .map_err(|_| "failed to get seller user settings.")?;
+            (
+                order_messages,
+                buyer_user_settings.pgp_key,
+                seller_user_settings.pgp_key,
+            )
+        } else {
+            (vec![], "".to_string(), "".to_string())
+        };
         Ok(Context {
             base_context,
             flash,
             order,
+            order_status,
+            order_events,
             maybe_listing,
+            order_item_displays,
+            digital_payloads,
             maybe_shipping_option,
             maybe_seller_user,
             user,
             admin_user,
             qr_svg_base64,
             lightning_node_pubkey,
+            maybe_buyer_account_balance_sat,
+            invoice_expired,
+            invoice_expiry_minutes_remaining,
+            auto_finalize_time_ms,
+            shipping_deadline_time_ms,
+            order_messages,
+            can_message,
+            buyer_pgp_key,
+            seller_pgp_key,
         })
     }
 }
 
-async fn get_lightning_node_pubkey(config: &Config) -> Result<String, String> {
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
+/// Returns true if the user can read and post messages on the order. The
+/// admin can only join the conversation of a disputed order.
+fn can_message(order: &Order, user: &User, is_admin: bool) -> bool {
+    order.buyer_user_id == user.id()
+        || order.seller_user_id == user.id()
+        || (is_admin && order.status() == OrderStatus::Disputed)
+}
+
+/// Returns a BIP 21 URI requesting the given amount on the given address.
+fn onchain_payment_uri(address: &str, amount_sat: u64) -> String {
+    format!(
+        "bitcoin:{}?amount={}.{:08}",
+        address,
+        amount_sat / 100_000_000,
+        amount_sat % 100_000_000
+    )
+}
+
+async fn get_lightning_node_pubkey(
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
+    let get_info_resp = lightning_backend.get_info().await?;
+    Ok(get_info_resp.identity_pubkey)
+}
+
+#[put("/<id>/pay_from_balance")]
+async fn pay_from_balance(
+    id: &str,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match pay_order_from_balance(
+        id,
+        &mut db,
+        active_user.user.clone(),
+        lightning_backend.inner().as_ref(),
     )
     .await
-    .expect("failed to get lightning client");
-    let get_info_resp = lightning_client
-        // All calls require at least empty parameter
-        .get_info(tonic_openssl_lnd::lnrpc::GetInfoRequest {})
+    {
+        Ok(_) => Ok(Flash::success(
+            Redirect::to(format!("/{}/{}", "order", id)),
+            "Order paid from account balance.",
+        )),
+        Err(e) => {
+            error_!("DB update({}) error: {}", id, e);
+            Err(Flash::error(
+                Redirect::to(format!("/{}/{}", "order", id)),
+                e,
+            ))
+        }
+    }
+}
+
+async fn pay_order_from_balance(
+    order_id: &str,
+    db: &mut Connection<Db>,
+    user: User,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let order = Order::single_by_public_id(db, order_id)
         .await
-        .expect("failed to get lightning node info")
-        .into_inner();
-    Ok(get_info_resp.identity_pubkey)
+        .map_err(|_| "failed to get order.")?;
+
+    if order.buyer_user_id != user.id() {
+        return Err("User is not the order buyer.".to_string());
+    };
+    if !order.status().can_transition_to(OrderStatus::Paid) {
+        return Err("order cannot be paid.".to_string());
+    };
+    if !order.onchain_address.is_empty() {
+        return Err("On-chain orders must be paid on-chain.".to_string());
+    };
+
+    let cancel_invoice_ret = lightning_backend.cancel_invoice(util::from_hex(&order.invoice_hash));
+    Order::mark_as_paid_from_balance(
+        &mut *db,
+        order.id.unwrap(),
+        user.id(),
+        now,
+        cancel_invoice_ret,
+    )
+    .await?;
+
+    // A failed delivery leaves the order paid, to be shipped by the seller.
+    if let Err(e) =
+        digital_delivery::deliver_order(&mut *db, lightning_backend, order.id.unwrap()).await
+    {
+        error_!("Failed to deliver digital order {}: {}", order_id, e);
+    }
+    Ok(())
+}
+
+#[put("/<id>/regenerate_invoice")]
+async fn regenerate_invoice(
+    id: &str,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match regenerate_order_invoice(
+        id,
+        &mut db,
+        active_user.user.clone(),
+        lightning_backend.inner().as_ref(),
+    )
+    .await
+    {
+        Ok(_) => Ok(Flash::success(
+            Redirect::to(format!("/{}/{}", "order", id)),
+            "New invoice created.",
+        )),
+        Err(e) => {
+            error_!("DB update({}) error: {}", id, e);
+            Err(Flash::error(
+                Redirect::to(format!("/{}/{}", "order", id)),
+                e,
+            ))
+        }
+    }
+}
+
+async fn regenerate_order_invoice(
+    order_id: &str,
+    db: &mut Connection<Db>,
+    user: User,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let order = Order::single_by_public_id(db, order_id)
+        .await
+        .map_err(|_| "failed to get order.")?;
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+
+    if order.buyer_user_id != user.id() {
+        return Err("User is not the order buyer.".to_string());
+    };
+    if order.status() != OrderStatus::Unpaid {
+        return Err("order is already paid.".to_string());
+    };
+    if !order.onchain_address.is_empty() {
+        return Err("On-chain orders do not have an invoice.".to_string());
+    };
+
+    lightning::cancel_expired_invoice(
+        lightning_backend,
+        &order.invoice_hash,
+        order.invoice_expiry_time_ms,
+    )
+    .await?;
+
+    let invoice_expiry_s = admin_settings.order_invoice_expiry_s;
+    let (invoice_hash, invoice_payment_request, invoice_preimage) =
+        prepare_order::create_order_invoice(
+            lightning_backend,
+            order.amount_owed_sat,
+            order.escrow,
+            invoice_expiry_s,
+        )
+        .await?;
+    let is_updated = Order::update_invoice(
+        db,
+        order.id.unwrap(),
+        &invoice_hash,
+        &invoice_payment_request,
+        &invoice_preimage,
+        now + (invoice_expiry_s as u64) * 1000,
+    )
+    .await
+    .map_err(|_| "failed to update order invoice.")?;
+    if !is_updated {
+        return Err("order is already paid.".to_string());
+    }
+
+    Ok(())
 }
 
 #[put("/<id>/ship")]
@@ -104,8 +393,17 @@ async fn ship(
     mut db: Connection<Db>,
     active_user: ActiveUser,
     admin_user: Option<AdminUser>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
 ) -> Result<Flash<Redirect>, Flash<Redirect>> {
-    match mark_order_as_shipped(id, &mut db, active_user.user.clone(), admin_user.clone()).await {
+    match mark_order_as_shipped(
+        id,
+        &mut db,
+        active_user.user.clone(),
+        admin_user.clone(),
+        lightning_backend.inner().as_ref(),
+    )
+    .await
+    {
         Ok(_) => Ok(Flash::success(
             Redirect::to(format!("/{}/{}", "order", id)),
             "Order marked as shipped.",
@@ -125,6 +423,7 @@ async fn mark_order_as_shipped(
     db: &mut Connection<Db>,
     user: User,
     _admin_user: Option<AdminUser>,
+    lightning_backend: &dyn LightningBackend,
 ) -> Result<(), String> {
     let order = Order::single_by_public_id(db, order_id)
         .await
@@ -133,16 +432,71 @@ async fn mark_order_as_shipped(
     if order.seller_user_id != user.id() {
         return Err("User is not the order seller.".to_string());
     };
-    if order.shipped {
-        return Err("order is already shipped.".to_string());
-    };
-    if order.canceled_by_seller || order.canceled_by_buyer {
-        return Err("order is already canceled.".to_string());
+    if !order.status().can_transition_to(OrderStatus::Shipped) {
+        return Err("order cannot be marked as shipped.".to_string());
+    }
+
+    let now = util::current_time_millis();
+    let finalized =
+        order_finalization::is_eligible_for_early_finalization(db, order.seller_user_id).await?;
+
+    if order.escrow {
+        let settle_hold_invoice_ret =
+            lightning_backend.settle_hold_invoice(util::from_hex(&order.invoice_preimage));
+        return Order::mark_as_shipped_with_escrow(
+            &mut *db,
+            order.id.unwrap(),
+            user.id(),
+            now,
+            finalized,
+            settle_hold_invoice_ret,
+        )
+        .await;
+    }
+
+    Order::mark_as_shipped(&mut *db, order.id.unwrap(), user.id(), now, finalized).await
+}
+
+#[put("/<id>/finalize")]
+async fn finalize(
+    id: &str,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match mark_order_as_finalized(id, &mut db, active_user.user.clone()).await {
+        Ok(_) => Ok(Flash::success(
+            Redirect::to(format!("/{}/{}", "order", id)),
+            "Order finalized.",
+        )),
+        Err(e) => {
+            error_!("DB update({}) error: {}", id, e);
+            Err(Flash::error(
+                Redirect::to(format!("/{}/{}", "order", id)),
+                "Failed to finalize order.",
+            ))
+        }
     }
+}
 
-    Order::mark_as_shipped(&mut *db, order.id.unwrap())
+async fn mark_order_as_finalized(
+    order_id: &str,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let order = Order::single_by_public_id(db, order_id)
         .await
-        .map_err(|_| "failed to mark order as shipped.".to_string())
+        .map_err(|_| "failed to get order.")?;
+
+    if order.buyer_user_id != user.id() {
+        return Err("User is not the order buyer.".to_string());
+    };
+    if !order.status().can_transition_to(OrderStatus::Finalized) {
+        return Err("order cannot be finalized.".to_string());
+    };
+
+    let now = util::current_time_millis();
+    Order::mark_as_finalized(&mut *db, order.id.unwrap(), user.id(), now).await
 }
 
 #[put("/<id>/seller_cancel")]
@@ -151,12 +505,14 @@ async fn seller_cancel(
     mut db: Connection<Db>,
     active_user: ActiveUser,
     admin_user: Option<AdminUser>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
 ) -> Result<Flash<Redirect>, Flash<Redirect>> {
     match mark_order_as_canceled_by_seller(
         id,
         &mut db,
         active_user.user.clone(),
         admin_user.clone(),
+        lightning_backend.inner().as_ref(),
     )
     .await
     {
@@ -179,6 +535,7 @@ async fn mark_order_as_canceled_by_seller(
     db: &mut Connection<Db>,
     user: User,
     _admin_user: Option<AdminUser>,
+    lightning_backend: &dyn LightningBackend,
 ) -> Result<(), String> {
     let order = Order::single_by_public_id(db, order_id)
         .await
@@ -187,16 +544,30 @@ async fn mark_order_as_canceled_by_seller(
     if order.seller_user_id != user.id() {
         return Err("User is not the order seller.".to_string());
     };
-    if order.shipped {
-        return Err("order is already shipped.".to_string());
+    if !order
+        .status()
+        .can_transition_to(OrderStatus::CanceledBySeller)
+    {
+        return Err("order cannot be marked as canceled.".to_string());
     };
-    if order.canceled_by_seller || order.canceled_by_buyer {
-        return Err("order is already canceled.".to_string());
+
+    let now = util::current_time_millis();
+
+    if order.escrow {
+        let cancel_hold_invoice_ret =
+            lightning_backend.cancel_invoice(util::from_hex(&order.invoice_hash));
+        return Order::mark_as_canceled_by_seller_with_escrow(
+            &mut *db,
+            order.id.unwrap(),
+            user.id(),
+            now,
+            false,
+            cancel_hold_invoice_ret,
+        )
+        .await;
     }
 
-    Order::mark_as_canceled_by_seller(&mut *db, order.id.unwrap())
-        .await
-        .map_err(|_| "failed to mark order as canceled by seller.".to_string())
+    Order::mark_as_canceled_by_seller(&mut *db, order.id.unwrap(), user.id(), now).await
 }
 
 #[put("/<id>/buyer_cancel")]
@@ -205,9 +576,16 @@ async fn buyer_cancel(
     mut db: Connection<Db>,
     active_user: ActiveUser,
     admin_user: Option<AdminUser>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
 ) -> Result<Flash<Redirect>, Flash<Redirect>> {
-    match mark_order_as_canceled_by_buyer(id, &mut db, active_user.user.clone(), admin_user.clone())
-        .await
+    match mark_order_as_canceled_by_buyer(
+        id,
+        &mut db,
+        active_user.user.clone(),
+        admin_user.clone(),
+        lightning_backend.inner().as_ref(),
+    )
+    .await
     {
         Ok(_) => Ok(Flash::success(
             Redirect::to(format!("/{}/{}", "order", id)),
@@ -228,6 +606,7 @@ async fn mark_order_as_canceled_by_buyer(
     db: &mut Connection<Db>,
     user: User,
     _admin_user: Option<AdminUser>,
+    lightning_backend: &dyn LightningBackend,
 ) -> Result<(), String> {
     let order = Order::single_by_public_id(db, order_id)
         .await
@@ -236,16 +615,29 @@ async fn mark_order_as_canceled_by_buyer(
     if order.buyer_user_id != user.id() {
         return Err("User is not the order buyer.".to_string());
     };
-    if order.shipped {
-        return Err("order is already shipped.".to_string());
-    };
-    if order.canceled_by_seller || order.canceled_by_buyer {
-        return Err("order is already canceled.".to_string());
+    if !order
+        .status()
+        .can_transition_to(OrderStatus::CanceledByBuyer)
+    {
+        return Err("order cannot be marked as canceled.".to_string());
     };
 
-    Order::mark_as_canceled_by_buyer(&mut *db, order.id.unwrap())
-        .await
-        .map_err(|_| "failed to mark order as canceled by buyer.".to_string())
+    let now = util::current_time_millis();
+
+    if order.escrow {
+        let cancel_hold_invoice_ret =
+            lightning_backend.cancel_invoice(util::from_hex(&order.invoice_hash));
+        return Order::mark_as_canceled_by_buyer_with_escrow(
+            &mut *db,
+            order.id.unwrap(),
+            user.id(),
+            now,
+            cancel_hold_invoice_ret,
+        )
+        .await;
+    }
+
+    Order::mark_as_canceled_by_buyer(&mut *db, order.id.unwrap(), user.id(), now).await
 }
 
 #[post("/<id>/new_review", data = "<order_review_form>")]
@@ -318,6 +710,66 @@ async fn create_order_review(
     })
 }
 
+#[post("/<id>/new_message", data = "<order_message_form>")]
+async fn new_message(
+    id: &str,
+    order_message_form: Form<OrderMessageInput>,
+    mut db: Connection<Db>,
+    user: User,
+    admin_user: Option<AdminUser>,
+) -> Flash<Redirect> {
+    let order_message_info = order_message_form.into_inner();
+
+    match add_order_message(id, order_message_info, &mut db, user, admin_user).await {
+        Ok(_) => Flash::success(
+            Redirect::to(format!("/{}/{}", "order", id)),
+            "Message sent.",
+        ),
+        Err(e) => Flash::error(Redirect::to(format!("/{}/{}", "order", id)), e),
+    }
+}
+
+async fn add_order_message(
+    order_id: &str,
+    order_message_info: OrderMessageInput,
+    db: &mut Connection<Db>,
+    user: User,
+    admin_user: Option<AdminUser>,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let order = Order::single_by_public_id(db, order_id)
+        .await
+        .map_err(|_| "failed to get order.")?;
+    let text = order_message_info.text;
+
+    if !can_message(&order, &user, admin_user.is_some()) {
+        return Err("User cannot send messages on this order.".to_string());
+    };
+    if text.is_empty() {
+        return Err("Message cannot be empty.".to_string());
+    };
+    if text.len() > MAX_ORDER_MESSAGE_LENGTH {
+        return Err("Message length is too long.".to_string());
+    };
+    Message::from_string(&text).map_err(|_| "Message must be PGP encrypted.")?;
+
+    let order_message = OrderMessage {
+        id: None,
+        public_id: util::create_uuid(),
+        order_id: order.id.unwrap(),
+        author_user_id: user.id(),
+        text,
+        read_by_buyer: order.buyer_user_id == user.id(),
+        read_by_seller: order.seller_user_id == user.id(),
+        created_time_ms: now,
+    };
+    OrderMessage::insert(order_message, db)
+        .await
+        .map_err(|_| "failed to insert order message.")?;
+
+    Ok(())
+}
+
 #[get("/<id>")]
 async fn index(
     flash: Option<FlashMessage<'_>>,
@@ -325,12 +777,19 @@ async fn index(
     db: Connection<Db>,
     user: Option<User>,
     admin_user: Option<AdminUser>,
-    config: &State<Config>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
 ) -> Result<Template, String> {
     let flash = flash.map(FlashMessage::into_inner);
-    let context = Context::raw(db, id, flash, user, admin_user, config)
-        .await
-        .map_err(|_| "failed to get template context.")?;
+    let context = Context::raw(
+        db,
+        id,
+        flash,
+        user,
+        admin_user,
+        lightning_backend.inner().as_ref(),
+    )
+    .await
+    .map_err(|_| "failed to get template context.")?;
     Ok(Template::render("order", context))
 }
 
@@ -338,7 +797,17 @@ pub fn order_stage() -> AdHoc {
     AdHoc::on_ignite("Order Stage", |rocket| async {
         rocket.mount(
             "/order",
-            routes![index, ship, seller_cancel, buyer_cancel, new_review],
+            routes![
+                index,
+                pay_from_balance,
+                regenerate_invoice,
+                ship,
+                finalize,
+                seller_cancel,
+                buyer_cancel,
+                new_review,
+                new_message
+            ],
         )
     })
 }
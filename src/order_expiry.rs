@@ -1,65 +1,154 @@
-use crate::config::Config;
-use crate::lightning::get_lnd_invoices_client;
-use crate::models::Order;
+use crate::lightning::LightningBackend;
+use crate::models::{AdminSettings, Order};
 use crate::util;
 use sqlx::pool::PoolConnection;
 use sqlx::Sqlite;
-use tonic_openssl_lnd::LndInvoicesClient;
+use std::sync::Arc;
 
-const ORDER_EXPIRY_INTERVAL_MS: u64 = 86400000;
+// How long an unpaid order is kept after its invoice expires, so that the
+// buyer can still get a new invoice.
+const EXPIRED_ORDER_RETENTION_MS: u64 = 86400000;
+// Must be shorter than the hold invoice CLTV expiry in lightning.rs.
+const ESCROW_SHIPPING_DEADLINE_MS: u64 = 172800000;
+// How long an on-chain order keeps waiting for unconfirmed funds after its
+// payment window ends.
+const ONCHAIN_UNCONFIRMED_GRACE_PERIOD_MS: u64 = 604800000;
 
 pub async fn remove_expired_orders(
-    config: Config,
+    lightning_backend: Arc<dyn LightningBackend>,
     mut conn: PoolConnection<Sqlite>,
 ) -> Result<(), String> {
-    let mut lightning_invoices_client = get_lnd_invoices_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
-    )
-    .await
-    .map_err(|e| format!("failed to get lightning client: {:?}", e))?;
-
-    // Get all orders older than expiry time limit.
+    // Get all unpaid orders with an invoice that expired before the retention period.
     let now = util::current_time_millis();
-    let expiry_cutoff = now - ORDER_EXPIRY_INTERVAL_MS;
-    let expired_orders = Order::all_older_than(&mut conn, expiry_cutoff)
+    let expiry_cutoff = now - EXPIRED_ORDER_RETENTION_MS;
+    let expired_orders = Order::all_unpaid_with_invoice_expired_before(&mut conn, expiry_cutoff)
         .await
         .map_err(|_| "failed to expired orders.")?;
 
     for order in expired_orders {
-        remove_order(&mut conn, &order, &mut lightning_invoices_client)
+        remove_order(&mut conn, &order, &*lightning_backend)
+            .await
+            .ok();
+    }
+
+    // On-chain orders have no invoice to cancel, and may have received funds.
+    let onchain_expired_orders =
+        Order::all_unpaid_onchain_with_payment_expired_before(&mut conn, now)
+            .await
+            .map_err(|_| "failed to get expired on-chain orders.")?;
+
+    for order in onchain_expired_orders {
+        expire_onchain_order(&mut conn, &order, now).await.ok();
+    }
+
+    // Cancel paid orders that were not shipped in time, refunding the buyer.
+    let admin_settings = AdminSettings::single(&mut conn)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    let shipping_cutoff = now.saturating_sub(shipping_deadline_period_ms(&admin_settings, false));
+    let unshipped_orders = Order::all_without_escrow_paid_before(&mut conn, shipping_cutoff)
+        .await
+        .map_err(|_| "failed to get unshipped orders.")?;
+
+    for order in unshipped_orders {
+        cancel_unshipped_order(&mut conn, &order, now).await.ok();
+    }
+
+    let escrow_shipping_cutoff =
+        now.saturating_sub(shipping_deadline_period_ms(&admin_settings, true));
+    let unshipped_escrow_orders =
+        Order::all_with_escrow_paid_before(&mut conn, escrow_shipping_cutoff)
+            .await
+            .map_err(|_| "failed to get unshipped escrow orders.")?;
+
+    for order in unshipped_escrow_orders {
+        cancel_unshipped_escrow_order(&mut conn, &order, &*lightning_backend)
             .await
             .ok();
     }
     Ok(())
 }
 
+/// Returns how long after payment an order that was not shipped is canceled.
+/// Escrow orders cannot wait longer than their hold invoices.
+pub fn shipping_deadline_period_ms(admin_settings: &AdminSettings, escrow: bool) -> u64 {
+    let shipping_deadline_ms = admin_settings.order_shipping_deadline_ms();
+    if escrow {
+        shipping_deadline_ms.min(ESCROW_SHIPPING_DEADLINE_MS)
+    } else {
+        shipping_deadline_ms
+    }
+}
+
 async fn remove_order(
     conn: &mut PoolConnection<Sqlite>,
     order: &Order,
-    lightning_invoices_client: &mut LndInvoicesClient,
+    lightning_backend: &dyn LightningBackend,
 ) -> Result<(), String> {
     println!("deleting expired order: {:?}", order);
-    let cancel_order_invoice_ret = cancel_order_invoice(
-        lightning_invoices_client,
-        util::from_hex(&order.invoice_hash),
-    );
-    Order::delete_expired_order(conn, order.id.unwrap(), cancel_order_invoice_ret)
-        .await
-        .expect("failed to delete expired user account.");
+    let cancel_order_invoice_ret =
+        lightning_backend.cancel_invoice(util::from_hex(&order.invoice_hash));
+    Order::delete_expired_order(conn, order.id.unwrap(), cancel_order_invoice_ret).await
+}
+
+/// Deletes an expired on-chain order that received nothing, or closes it and
+/// credits the buyer with whatever was received.
+///
+/// Orders with unconfirmed funds are left open until the grace period ends.
+async fn expire_onchain_order(
+    conn: &mut PoolConnection<Sqlite>,
+    order: &Order,
+    now: u64,
+) -> Result<(), String> {
+    if order.onchain_pending_sat == 0 && order.onchain_received_sat == 0 {
+        println!("deleting expired on-chain order: {:?}", order);
+        Order::delete_expired_onchain_order(conn, order.id.unwrap()).await?;
+    } else if order.onchain_pending_sat == 0
+        || order.invoice_expiry_time_ms + ONCHAIN_UNCONFIRMED_GRACE_PERIOD_MS < now
+    {
+        println!("closing underpaid on-chain order: {:?}", order);
+        Order::mark_onchain_as_expired(conn, order.id.unwrap(), order.onchain_received_sat, now)
+            .await?;
+    }
     Ok(())
 }
 
-async fn cancel_order_invoice(
-    lightning_invoices_client: &mut LndInvoicesClient,
-    payment_hash: Vec<u8>,
-) -> Result<tonic_openssl_lnd::invoicesrpc::CancelInvoiceResp, String> {
-    let cancel_response = lightning_invoices_client
-        .cancel_invoice(tonic_openssl_lnd::invoicesrpc::CancelInvoiceMsg { payment_hash })
+/// Cancels a paid order that was not shipped in time. The payment is
+/// credited back to the buyer's account balance.
+async fn cancel_unshipped_order(
+    conn: &mut PoolConnection<Sqlite>,
+    order: &Order,
+    now: u64,
+) -> Result<(), String> {
+    println!("canceling unshipped order: {:?}", order);
+    Order::mark_as_shipping_expired(conn, order.id.unwrap(), now)
         .await
-        .expect("failed to cancel invoice")
-        .into_inner();
-    Ok(cancel_response)
+        .map_err(|e| {
+            println!("failed to cancel unshipped order: {:?}", e);
+            e
+        })
+}
+
+async fn cancel_unshipped_escrow_order(
+    conn: &mut PoolConnection<Sqlite>,
+    order: &Order,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<(), String> {
+    println!("canceling unshipped escrow order: {:?}", order);
+    let cancel_hold_invoice_ret =
+        lightning_backend.cancel_invoice(util::from_hex(&order.invoice_hash));
+    let now = util::current_time_millis();
+    Order::mark_as_canceled_by_seller_with_escrow(
+        conn,
+        order.id.unwrap(),
+        0,
+        now,
+        true,
+        cancel_hold_invoice_ret,
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to cancel unshipped escrow order: {:?}", e);
+        e
+    })
 }
@@ -0,0 +1,50 @@
+use crate::models::{AdminSettings, Order};
+use crate::util;
+use sqlx::pool::PoolConnection;
+use sqlx::{Sqlite, SqliteConnection};
+
+/// Finalizes the shipped orders that the buyer did not finalize before the
+/// auto-finalize period ended.
+pub async fn finalize_expired_orders(mut conn: PoolConnection<Sqlite>) -> Result<(), String> {
+    let admin_settings = AdminSettings::single(&mut conn)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    let now = util::current_time_millis();
+    let shipping_cutoff = now.saturating_sub(admin_settings.order_auto_finalize_period_ms());
+    let unfinalized_orders = Order::all_unfinalized_shipped_before(&mut conn, shipping_cutoff)
+        .await
+        .map_err(|_| "failed to get unfinalized orders.")?;
+
+    for order in unfinalized_orders {
+        println!("auto-finalizing order: {:?}", order.public_id);
+        Order::mark_as_finalized(&mut conn, order.id.unwrap(), 0, now)
+            .await
+            .ok();
+    }
+    Ok(())
+}
+
+/// Returns true if the seller has enough reputation for their orders to be
+/// finalized as soon as they are shipped.
+pub async fn is_eligible_for_early_finalization(
+    db: &mut SqliteConnection,
+    seller_user_id: i32,
+) -> Result<bool, String> {
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    // Early finalization is disabled when no minimum amount sold is set.
+    if admin_settings.early_finalization_min_amount_sold_sat == 0 {
+        return Ok(false);
+    }
+    let seller_info = Order::seller_info_for_user(db, seller_user_id)
+        .await
+        .map_err(|_| "failed to get seller info.")?;
+    // Any order canceled for not being shipped in time disqualifies the seller.
+    Ok(
+        seller_info.total_amount_sold_sat >= admin_settings.early_finalization_min_amount_sold_sat
+            && seller_info.weighted_average_rating
+                >= admin_settings.early_finalization_min_rating as f32
+            && seller_info.num_shipping_strikes == 0,
+    )
+}
@@ -1,97 +1,275 @@
 use crate::config::Config;
-use crate::lightning::get_lnd_lightning_client;
-use crate::models::{Order, UserAccount};
+use crate::digital_delivery;
+use crate::lightning::LightningBackend;
+use crate::models::{InvoiceSettleIndex, Order, UserAccount};
+use crate::rocket::futures::StreamExt;
 use crate::util;
 use sqlx::pool::PoolConnection;
 use sqlx::Sqlite;
-use tonic_openssl_lnd::LndLightningClient;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
+
+pub const LIST_INVOICES_PAGE_SIZE: u64 = 1000;
 
 pub async fn handle_received_payments(
-    config: Config,
+    lightning_backend: Arc<dyn LightningBackend>,
     mut conn: PoolConnection<Sqlite>,
 ) -> Result<(), String> {
-    let mut lightning_client = get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
-    )
-    .await
-    .map_err(|e| format!("failed to get lightning client: {:?}", e))?;
-
-    let latest_settle_index = get_latest_settle_index(&mut conn, &mut lightning_client).await?;
+    let latest_settle_index = InvoiceSettleIndex::single(&mut conn)
+        .await
+        .map_err(|_| "failed to get latest settle index.")?
+        .settle_index;
 
     println!(
         "Starting subscribe invoices with latest settle index: {:?}",
         latest_settle_index
     );
-    let invoice_subscription = tonic_openssl_lnd::lnrpc::InvoiceSubscription {
-        settle_index: latest_settle_index,
-        ..Default::default()
-    };
-    let mut update_stream = lightning_client
-        .subscribe_invoices(invoice_subscription)
-        .await
-        .map_err(|_| "Failed to call subscribe invoices.")?
-        .into_inner();
-    while let Ok(Some(invoice)) = update_stream.message().await {
+    let mut update_stream = lightning_backend
+        .subscribe_invoices(latest_settle_index)
+        .await?;
+
+    // Subscribe before reconciling, so that no settlement is missed in between.
+    reconcile_unpaid_invoices(&mut conn, &*lightning_backend).await?;
+
+    while let Some(Ok(invoice)) = update_stream.next().await {
         #[allow(deprecated)]
         if invoice.settled {
             println!("Handling settled invoice: {:?}", invoice);
             let invoice_hash = util::to_hex(&invoice.r_hash);
-            handle_payment(&mut conn, &invoice_hash).await?;
+            handle_payment(&mut conn, &*lightning_backend, &invoice_hash).await?;
+            InvoiceSettleIndex::update(&mut conn, invoice.settle_index)
+                .await
+                .map_err(|_| "failed to update latest settle index.")?;
+        }
+    }
+    Ok(())
+}
+
+/// Marks escrow orders as paid once their hold invoices are accepted.
+///
+/// Hold invoices are not settled when they are paid, so they do not show up
+/// in the invoice subscription until the order is shipped.
+pub async fn handle_accepted_hold_invoices(
+    lightning_backend: Arc<dyn LightningBackend>,
+    mut conn: PoolConnection<Sqlite>,
+) -> Result<(), String> {
+    let unpaid_escrow_orders = Order::all_unpaid_with_escrow(&mut conn)
+        .await
+        .map_err(|_| "failed to get unpaid escrow orders.")?;
+
+    for order in unpaid_escrow_orders {
+        let invoice = lightning_backend
+            .lookup_invoice(util::from_hex(&order.invoice_hash))
+            .await?;
+        if invoice.state == InvoiceState::Accepted as i32 {
+            println!("Handling accepted hold invoice: {:?}", invoice);
+            handle_payment(&mut conn, &*lightning_backend, &order.invoice_hash).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Marks on-chain orders as paid once enough funds have been confirmed on
+/// their addresses.
+///
+/// The transaction subscription only reports the first confirmation, so the
+/// confirmation threshold is also checked periodically by
+/// `reconcile_onchain_payments`.
+pub async fn handle_onchain_payments(
+    lightning_backend: Arc<dyn LightningBackend>,
+    mut conn: PoolConnection<Sqlite>,
+    config: Config,
+) -> Result<(), String> {
+    let mut update_stream = lightning_backend.subscribe_transactions().await?;
+
+    // Subscribe before reconciling, so that no transaction is missed in between.
+    reconcile_onchain_payments(&mut conn, &*lightning_backend, &config).await?;
+
+    while let Some(Ok(transaction)) = update_stream.next().await {
+        println!("Handling on-chain transaction: {:?}", transaction.tx_hash);
+        reconcile_onchain_payments(&mut conn, &*lightning_backend, &config).await?;
+    }
+    Ok(())
+}
+
+/// Updates the amounts received by every unpaid on-chain order, and marks as
+/// paid the orders that received enough confirmed funds.
+///
+/// Any overpayment beyond the tolerance is credited back to the buyer's
+/// account balance.
+pub async fn reconcile_onchain_payments(
+    conn: &mut PoolConnection<Sqlite>,
+    lightning_backend: &dyn LightningBackend,
+    config: &Config,
+) -> Result<(), String> {
+    let unpaid_orders = Order::all_unpaid_onchain(conn)
+        .await
+        .map_err(|_| "failed to get unpaid on-chain orders.")?;
+    if unpaid_orders.is_empty() {
+        return Ok(());
+    }
+    let transactions = lightning_backend.get_transactions().await?;
+
+    for order in unpaid_orders {
+        let (pending_sat, received_sat) = onchain_amounts_for_address(
+            &transactions,
+            &order.onchain_address,
+            config.onchain_min_confirmations,
+        );
+        if pending_sat != order.onchain_pending_sat || received_sat != order.onchain_received_sat {
+            Order::update_onchain_amounts(conn, order.id.unwrap(), pending_sat, received_sat)
+                .await
+                .map_err(|_| "failed to update on-chain amounts.")?;
+        }
+
+        let underpayment_tolerance_sat =
+            order.amount_owed_sat * config.onchain_underpayment_tolerance_basis_points / 10000;
+        let overpayment_tolerance_sat =
+            order.amount_owed_sat * config.onchain_overpayment_tolerance_basis_points / 10000;
+        if received_sat + underpayment_tolerance_sat >= order.amount_owed_sat {
+            let buyer_credit_sat =
+                if received_sat > order.amount_owed_sat + overpayment_tolerance_sat {
+                    received_sat - order.amount_owed_sat
+                } else {
+                    0
+                };
+            Order::set_onchain_buyer_credit(conn, order.id.unwrap(), buyer_credit_sat)
+                .await
+                .map_err(|_| "failed to set on-chain buyer credit.")?;
+            println!("Handling on-chain payment for order: {:?}", order.public_id);
+            handle_payment(conn, lightning_backend, &order.invoice_hash).await?;
         }
     }
     Ok(())
 }
 
-async fn handle_payment(
+/// Returns the unconfirmed and confirmed amounts received on the given address.
+fn onchain_amounts_for_address(
+    transactions: &[tonic_openssl_lnd::lnrpc::Transaction],
+    address: &str,
+    min_confirmations: u32,
+) -> (u64, u64) {
+    transactions
+        .iter()
+        .fold((0, 0), |(pending_sat, received_sat), transaction| {
+            let amount_sat: u64 = transaction
+                .output_details
+                .iter()
+                .filter(|output| output.is_our_address && output.address == address)
+                .map(|output| output.amount as u64)
+                .sum();
+            if transaction.num_confirmations >= min_confirmations as i32 {
+                (pending_sat, received_sat + amount_sat)
+            } else {
+                (pending_sat + amount_sat, received_sat)
+            }
+        })
+}
+
+pub async fn handle_payment(
     conn: &mut PoolConnection<Sqlite>,
+    lightning_backend: &dyn LightningBackend,
     invoice_hash: &str,
 ) -> Result<(), String> {
     let now = util::current_time_millis();
 
     let maybe_order = Order::single_by_invoice_hash(conn, invoice_hash).await.ok();
     if let Some(order) = maybe_order {
-        Order::mark_as_paid(conn, order.id.unwrap(), now)
-            .await
-            .map_err(|_| "failed to mark order as paid.")?;
+        if !order.paid {
+            Order::mark_as_paid(conn, order.id.unwrap(), now).await?;
+            // A failed delivery leaves the order paid, to be shipped by the seller.
+            if let Err(e) =
+                digital_delivery::deliver_order(conn, lightning_backend, order.id.unwrap()).await
+            {
+                println!(
+                    "Failed to deliver digital order {:?}: {}",
+                    order.public_id, e
+                );
+            }
+        }
     }
 
     let maybe_user_account = UserAccount::single_by_invoice_hash(conn, invoice_hash)
         .await
         .ok();
     if let Some(user_account) = maybe_user_account {
-        UserAccount::mark_as_paid(conn, user_account.id.unwrap(), now)
-            .await
-            .map_err(|_| "failed to mark user account as paid.")?;
+        if !user_account.paid {
+            UserAccount::mark_as_paid(conn, user_account.id.unwrap(), now)
+                .await
+                .map_err(|_| "failed to mark user account as paid.")?;
+        }
     }
 
     Ok(())
 }
 
-async fn get_latest_settle_index(
+/// Marks as paid every unpaid order and user account whose invoice was
+/// settled while the invoice subscription was not running.
+async fn reconcile_unpaid_invoices(
     conn: &mut PoolConnection<Sqlite>,
-    lightning_client: &mut LndLightningClient,
-) -> Result<u64, String> {
-    // Get latest paid invoice if exists.
-    let latest_paid_order = Order::most_recent_paid_order(conn)
+    lightning_backend: &dyn LightningBackend,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let unpaid_orders = Order::all_older_than(conn, now)
+        .await
+        .map_err(|_| "failed to get unpaid orders.")?;
+    let unpaid_user_accounts = UserAccount::all_older_than(conn, now)
         .await
-        .map_err(|_| "failed to latest paid order.")?;
-
-    let settle_index: u64 = if let Some(latest_invoice_hash) = latest_paid_order {
-        let latest_paid_order_invoice = lightning_client
-            .lookup_invoice(tonic_openssl_lnd::lnrpc::PaymentHash {
-                r_hash: util::from_hex(&latest_invoice_hash),
-                ..Default::default()
-            })
-            .await
-            .map_err(|e| format!("Failed to lookup invoice: {:?}", e))?
-            .into_inner();
-        latest_paid_order_invoice.settle_index
-    } else {
-        0
+        .map_err(|_| "failed to get unpaid user accounts.")?;
+
+    let oldest_created_time_ms = unpaid_orders
+        .iter()
+        .map(|order| order.created_time_ms)
+        .chain(
+            unpaid_user_accounts
+                .iter()
+                .map(|user_account| user_account.created_time_ms),
+        )
+        .min();
+    let oldest_creation_date = match oldest_created_time_ms {
+        Some(created_time_ms) => (created_time_ms / 1000) as i64,
+        None => return Ok(()),
     };
+    let mut unpaid_invoice_hashes = unpaid_orders
+        .into_iter()
+        .map(|order| order.invoice_hash)
+        .chain(
+            unpaid_user_accounts
+                .into_iter()
+                .map(|user_account| user_account.invoice_hash),
+        )
+        .collect::<HashSet<_>>();
 
-    Ok(settle_index)
+    // Page backwards from the newest invoice until reaching invoices that are
+    // older than every unpaid order and user account.
+    let mut index_offset = 0;
+    loop {
+        let list_invoices_response = lightning_backend
+            .list_invoices(index_offset, LIST_INVOICES_PAGE_SIZE, true)
+            .await?;
+        let invoices = list_invoices_response.invoices;
+        for invoice in &invoices {
+            let invoice_hash = util::to_hex(&invoice.r_hash);
+            if invoice.state == InvoiceState::Settled as i32
+                && unpaid_invoice_hashes.remove(&invoice_hash)
+            {
+                println!("Reconciling settled invoice: {:?}", invoice);
+                handle_payment(conn, lightning_backend, &invoice_hash).await?;
+            }
+        }
+        let reached_oldest_invoice = invoices
+            .iter()
+            .any(|invoice| invoice.creation_date < oldest_creation_date);
+        if invoices.is_empty()
+            || reached_oldest_invoice
+            || unpaid_invoice_hashes.is_empty()
+            || list_invoices_response.first_index_offset <= 1
+        {
+            break;
+        }
+        index_offset = list_invoices_response.first_index_offset;
+    }
+
+    Ok(())
 }
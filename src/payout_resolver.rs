@@ -0,0 +1,254 @@
+use crate::config::Config;
+use crate::lightning::LightningBackend;
+use crate::mock_lightning::MockLightningBackend;
+use crate::util;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{redirect, Url};
+use rocket::serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// Turns a payout target (a Lightning address or an LNURL-pay string) into
+/// an invoice for a given amount.
+///
+/// Managed as Rocket state as an `Arc<dyn PayoutResolver>`, like the
+/// lightning backend.
+#[rocket::async_trait]
+pub trait PayoutResolver: Send + Sync {
+    /// Returns an invoice for exactly the given amount, checked with the
+    /// lightning backend before it is returned.
+    async fn resolve_invoice(
+        &self,
+        target: &str,
+        amount_sat: u64,
+        lightning_backend: &dyn LightningBackend,
+    ) -> Result<String, String>;
+}
+
+/// Creates the payout resolver selected by the "payout_resolver" config value.
+pub fn new_payout_resolver(config: &Config) -> Result<Arc<dyn PayoutResolver>, String> {
+    match config.payout_resolver.as_str() {
+        "lnurl" => Ok(Arc::new(LnurlPayoutResolver::new()?)),
+        "mock" => Ok(Arc::new(MockPayoutResolver {})),
+        other => Err(format!("Unknown payout resolver: {:?}", other)),
+    }
+}
+
+/// Returns the LNURL-pay URL of a Lightning address or an LNURL string.
+pub fn lnurl_pay_url(target: &str) -> Result<String, String> {
+    let url = match target.split_once('@') {
+        Some((name, domain)) => {
+            if name.is_empty() || domain.is_empty() || domain.contains('/') {
+                return Err("Invalid Lightning address.".to_string());
+            }
+            format!(
+                "https://{}/.well-known/lnurlp/{}",
+                domain,
+                name.to_lowercase()
+            )
+        }
+        None => util::decode_lnurl(target)?,
+    };
+    check_public_url(&url)?;
+    Ok(url)
+}
+
+/// Checks that a URL given by a payout target can be requested by the
+/// market: it must use https, and must not point at a private address.
+///
+/// Hosts given by name are checked again when they are resolved, by
+/// `PublicDnsResolver`.
+fn check_public_url(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|_| "Invalid payout URL.")?;
+    if url.scheme() != "https" {
+        return Err("Payout URL must use https.".to_string());
+    }
+    let host = url.host_str().ok_or("Invalid payout URL.")?;
+    // IPv6 hosts are written in brackets.
+    let is_public = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_public_ip(&ip),
+        Err(_) => host != "localhost",
+    };
+    if !is_public {
+        return Err("Payout URL host must be public.".to_string());
+    }
+    Ok(())
+}
+
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            // Shared address space (100.64.0.0/10) and the "this network"
+            // block (0.0.0.0/8) are not covered by the std methods.
+            let is_shared = octets[0] == 100 && (octets[1] & 0xc0) == 64;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || is_shared
+                || octets[0] == 0)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // IPv4-mapped addresses (::ffff:a.b.c.d) are checked as IPv4.
+            // Ipv6Addr::to_ipv4_mapped is too recent for the Dockerfile.
+            if segments[..5] == [0; 5] && segments[5] == 0xffff {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public_ip(&IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+            }
+            let is_unique_local = (segments[0] & 0xfe00) == 0xfc00;
+            let is_link_local = (segments[0] & 0xffc0) == 0xfe80;
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || is_unique_local
+                || is_link_local)
+        }
+    }
+}
+
+/// Resolves host names only to public addresses, so that a payout target
+/// cannot make the market send requests into its own network.
+struct PublicDnsResolver;
+
+impl Resolve for PublicDnsResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = rocket::tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(&addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err("Payout URL host must be public.".into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct LnurlPayParams {
+    callback: String,
+    min_sendable: u64,
+    max_sendable: u64,
+    metadata: String,
+    tag: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct LnurlPayInvoice {
+    pr: String,
+}
+
+/// Resolves payout targets with the LNURL-pay protocol.
+pub struct LnurlPayoutResolver {
+    client: reqwest::Client,
+}
+
+impl LnurlPayoutResolver {
+    pub fn new() -> Result<LnurlPayoutResolver, String> {
+        // Redirects are not followed, because they could lead to a host
+        // that was not checked.
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS))
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+            .redirect(redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicDnsResolver))
+            .build()
+            .map_err(|_| "failed to build http client.")?;
+        Ok(LnurlPayoutResolver { client })
+    }
+}
+
+#[rocket::async_trait]
+impl PayoutResolver for LnurlPayoutResolver {
+    async fn resolve_invoice(
+        &self,
+        target: &str,
+        amount_sat: u64,
+        lightning_backend: &dyn LightningBackend,
+    ) -> Result<String, String> {
+        let url = lnurl_pay_url(target)?;
+        let pay_params: LnurlPayParams = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| format!("failed to get pay request: {:?}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("failed to decode pay request: {:?}", e))?;
+        if pay_params.tag != "payRequest" {
+            return Err("Payout target is not a pay request.".to_string());
+        }
+        let amount_msat = amount_sat * 1000;
+        if amount_msat < pay_params.min_sendable || amount_msat > pay_params.max_sendable {
+            return Err(format!(
+                "Amount must be between {} and {} msats.",
+                pay_params.min_sendable, pay_params.max_sendable
+            ));
+        }
+        let separator = if pay_params.callback.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let callback_url = format!("{}{}amount={}", pay_params.callback, separator, amount_msat);
+        check_public_url(&callback_url)?;
+        let pay_invoice: LnurlPayInvoice = self
+            .client
+            .get(&callback_url)
+            .send()
+            .await
+            .map_err(|e| format!("failed to get invoice: {:?}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("failed to decode invoice: {:?}", e))?;
+
+        // The invoice must commit to the metadata of the pay request (LUD-06),
+        // and be for the requested amount.
+        let decoded_pay_req = lightning_backend.decode_pay_req(&pay_invoice.pr).await?;
+        let metadata_hash = util::to_hex(&util::sha256(pay_params.metadata.as_bytes()));
+        if decoded_pay_req.description_hash != metadata_hash {
+            return Err("Invoice description hash does not match the metadata.".to_string());
+        }
+        if decoded_pay_req.num_msat != amount_msat as i64 {
+            return Err("Invoice amount does not match the payout amount.".to_string());
+        }
+        Ok(pay_invoice.pr)
+    }
+}
+
+/// Resolves any valid payout target to a mock payment request, for use with
+/// the mock lightning backend.
+pub struct MockPayoutResolver {}
+
+#[rocket::async_trait]
+impl PayoutResolver for MockPayoutResolver {
+    async fn resolve_invoice(
+        &self,
+        target: &str,
+        amount_sat: u64,
+        _lightning_backend: &dyn LightningBackend,
+    ) -> Result<String, String> {
+        lnurl_pay_url(target)?;
+        let payment_hash = util::sha256(&util::create_preimage());
+        Ok(MockLightningBackend::make_pay_req(
+            (amount_sat as i64) * 1000,
+            &payment_hash,
+        ))
+    }
+}
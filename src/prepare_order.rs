@@ -1,8 +1,13 @@
 use crate::base::BaseContext;
 use crate::config::Config;
 use crate::db::Db;
-use crate::lightning;
-use crate::models::{Listing, ListingDisplay, Order, OrderInfo, ShippingOption, UserSettings};
+use crate::digital_delivery;
+use crate::exchange_rate_update::MAX_EXCHANGE_RATE_AGE_MS;
+use crate::lightning::LightningBackend;
+use crate::models::{
+    AdminSettings, ExchangeRate, Listing, ListingDisplay, ListingVariant, Order, OrderInfo,
+    OrderItem, ShippingOption, UserSettings,
+};
 use crate::user_account::ActiveUser;
 use crate::util;
 use pgp::composed::{Deserializable, Message};
@@ -17,8 +22,10 @@ use rocket_auth::AdminUser;
 use rocket_auth::User;
 use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
+use std::sync::Arc;
 
-const MAX_UNPAID_ORDERS: u32 = 100;
+pub const MAX_UNPAID_ORDERS: u32 = 100;
+pub const ONCHAIN_PAYMENT_EXPIRY_S: u64 = 86400;
 
 #[derive(Debug, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -27,19 +34,25 @@ struct Context {
     flash: Option<(String, String)>,
     listing_display: Option<ListingDisplay>,
     selected_shipping_option: ShippingOption,
+    maybe_selected_variant: Option<ListingVariant>,
+    unit_price_sat: u64,
     quantity: i32,
     seller_user_settings: UserSettings,
+    onchain_payments_enabled: bool,
 }
 
 impl Context {
+    #[allow(clippy::too_many_arguments)]
     pub async fn raw(
         mut db: Connection<Db>,
         listing_id: &str,
         shipping_option_id: &str,
+        maybe_variant_id: Option<&str>,
         quantity: i32,
         flash: Option<(String, String)>,
         user: User,
         admin_user: Option<AdminUser>,
+        onchain_payments_enabled: bool,
     ) -> Result<Context, String> {
         let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
             .await
@@ -50,6 +63,18 @@ impl Context {
         let shipping_option = ShippingOption::single_by_public_id(&mut db, shipping_option_id)
             .await
             .map_err(|_| "failed to get shipping option.")?;
+        let maybe_selected_variant = match maybe_variant_id {
+            Some(variant_id) => Some(
+                ListingVariant::single_by_public_id(&mut db, variant_id)
+                    .await
+                    .map_err(|_| "failed to get variant.")?,
+            ),
+            None => None,
+        };
+        let unit_price_sat = match &maybe_selected_variant {
+            Some(variant) => variant.price_sat,
+            None => listing_display.listing.price_sat,
+        };
         let seller_user_settings = UserSettings::single(&mut db, listing_display.listing.user_id)
             .await
             .map_err(|_| "failed to get visited user settings.")?;
@@ -58,8 +83,11 @@ impl Context {
             flash,
             listing_display: Some(listing_display),
             selected_shipping_option: shipping_option,
+            maybe_selected_variant,
+            unit_price_sat,
             quantity,
             seller_user_settings,
+            onchain_payments_enabled,
         })
     }
 }
@@ -71,6 +99,7 @@ async fn new(
     mut db: Connection<Db>,
     active_user: ActiveUser,
     _admin_user: Option<AdminUser>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
     config: &State<Config>,
 ) -> Result<Flash<Redirect>, Flash<Redirect>> {
     let order_info = order_form.into_inner();
@@ -80,7 +109,9 @@ async fn new(
         order_info.clone(),
         &mut db,
         active_user.user.clone(),
-        config.inner().clone(),
+        lightning_backend.inner().as_ref(),
+        config.hold_invoice_escrow,
+        config.onchain_payments,
     )
     .await
     {
@@ -93,7 +124,12 @@ async fn new(
             Err(Flash::error(
                 Redirect::to(uri!(
                     "/prepare_order",
-                    index(id, order_info.shipping_option_id, 1)
+                    index(
+                        id,
+                        order_info.shipping_option_id,
+                        order_info.variant_id.as_deref(),
+                        1
+                    )
                 )),
                 e,
             ))
@@ -106,7 +142,9 @@ async fn create_order(
     order_info: OrderInfo,
     db: &mut Connection<Db>,
     user: User,
-    config: Config,
+    lightning_backend: &dyn LightningBackend,
+    escrow: bool,
+    onchain_payments_enabled: bool,
 ) -> Result<String, String> {
     let listing = Listing::single_by_public_id(db, listing_id)
         .await
@@ -118,14 +156,34 @@ async fn create_order(
     let shipping_instructions = order_info.shipping_instructions;
     let quantity = order_info.quantity.unwrap_or(0);
 
-    let price_per_unit_with_shipping_sat: u64 = listing.price_sat + shipping_option.price_sat;
-    let amount_owed_sat: u64 = (quantity as u64) * price_per_unit_with_shipping_sat;
-    // let market_fee_sat: u64 = (amount_owed_sat * (listing.fee_rate_basis_points as u64)) / 10000;
-    let market_fee_sat: u64 = divide_round_up(
-        amount_owed_sat * (listing.fee_rate_basis_points as u64),
-        10000,
-    );
-    let seller_credit_sat: u64 = amount_owed_sat - market_fee_sat;
+    // Fiat-pegged listings are converted to sats at the current exchange rate,
+    // which is kept on the order.
+    let maybe_exchange_rate =
+        ExchangeRate::latest_updated_after(db, now.saturating_sub(MAX_EXCHANGE_RATE_AGE_MS))
+            .await
+            .map_err(|_| "failed to get exchange rate.")?;
+    let variants = ListingVariant::all_for_listing(db, listing.id.unwrap())
+        .await
+        .map_err(|_| "failed to get variants for listing.")?;
+    let maybe_variant = match order_info.variant_id.as_deref() {
+        Some(variant_id) if !variant_id.is_empty() => Some(
+            ListingVariant::single_by_public_id(db, variant_id)
+                .await
+                .map_err(|_| "failed to get variant.")?,
+        ),
+        _ => None,
+    };
+    let listing_price_sat = match &maybe_variant {
+        Some(variant) => variant.price_sat,
+        None => listing_price_sat(&listing, maybe_exchange_rate.as_ref())?,
+    };
+    let items_amount_sat: u64 = (quantity as u64) * listing_price_sat;
+    let shipping_amount_sat: u64 = (quantity as u64) * shipping_option.price_sat;
+    let amount_owed_sat: u64 = items_amount_sat + shipping_amount_sat;
+    let item_market_fee_sat = market_fee_sat(items_amount_sat, listing.fee_rate_basis_points);
+    let shipping_market_fee_sat =
+        market_fee_sat(shipping_amount_sat, listing.fee_rate_basis_points);
+    let seller_credit_sat: u64 = amount_owed_sat - item_market_fee_sat - shipping_market_fee_sat;
 
     let (message, _) =
         Message::from_string(&shipping_instructions).map_err(|_| "Invalid PGP message.")?;
@@ -155,23 +213,79 @@ async fn create_order(
     if quantity == 0 {
         return Err("Quantity must be postive.".to_string());
     };
+    if !variants.is_empty() && maybe_variant.is_none() {
+        return Err("A variant must be chosen.".to_string());
+    };
+    if listing.digital {
+        digital_delivery::check_buyer_pgp_key(db, user.id()).await?;
+    };
+    if let Some(variant) = &maybe_variant {
+        if variant.listing_id != listing.id.unwrap() {
+            return Err("Variant not associated with listing.".to_string());
+        };
+        let remaining_variant_quantity =
+            ListingVariant::remaining_quantity(db, variant.id.unwrap())
+                .await
+                .map_err(|_| "failed to get remaining quantity of variant.")?;
+        if quantity > remaining_variant_quantity {
+            return Err(format!(
+                "Only {} items of {} remaining in stock.",
+                remaining_variant_quantity, variant.title
+            ));
+        };
+    };
+    let remaining_quantity = Listing::remaining_quantity(db, listing.id.unwrap())
+        .await
+        .map_err(|_| "failed to get remaining quantity of listing.")?;
+    if quantity > remaining_quantity {
+        return Err(format!(
+            "Only {} items remaining in stock.",
+            remaining_quantity
+        ));
+    };
+    if order_info.onchain && !onchain_payments_enabled {
+        return Err("On-chain payments are not enabled.".to_string());
+    };
 
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
-    )
-    .await
-    .expect("failed to get lightning client");
-    let invoice = lightning_client
-        .add_invoice(tonic_openssl_lnd::lnrpc::Invoice {
-            value_msat: (amount_owed_sat as i64) * 1000,
-            ..Default::default()
-        })
+    let admin_settings = AdminSettings::single(db)
         .await
-        .expect("failed to get new invoice")
-        .into_inner();
+        .map_err(|_| "failed to get admin settings.")?;
+    let invoice_expiry_s = admin_settings.order_invoice_expiry_s;
+    let (
+        invoice_hash,
+        invoice_payment_request,
+        invoice_preimage,
+        invoice_expiry_time_ms,
+        onchain_address,
+        escrow,
+    ) = if order_info.onchain {
+        // On-chain orders have no invoice, so a random unique hash is used
+        // to mark the order as paid through the same path as invoices.
+        let onchain_address = lightning_backend
+            .new_address()
+            .await
+            .map_err(|_| "failed to get new on-chain address.")?;
+        (
+            util::to_hex(&util::create_preimage()),
+            "".to_string(),
+            "".to_string(),
+            now + ONCHAIN_PAYMENT_EXPIRY_S * 1000,
+            onchain_address,
+            false,
+        )
+    } else {
+        let (invoice_hash, invoice_payment_request, invoice_preimage) =
+            create_order_invoice(lightning_backend, amount_owed_sat, escrow, invoice_expiry_s)
+                .await?;
+        (
+            invoice_hash,
+            invoice_payment_request,
+            invoice_preimage,
+            now + (invoice_expiry_s as u64) * 1000,
+            "".to_string(),
+            escrow,
+        )
+    };
 
     let order = Order {
         id: None,
@@ -189,16 +303,62 @@ async fn create_order(
         canceled_by_seller: false,
         canceled_by_buyer: false,
         reviewed: false,
-        invoice_hash: util::to_hex(&invoice.r_hash),
-        invoice_payment_request: invoice.payment_request,
+        invoice_hash,
+        invoice_payment_request,
         review_rating: 0,
         review_text: "".to_string(),
         created_time_ms: now,
         payment_time_ms: 0,
         review_time_ms: 0,
+        escrow,
+        invoice_preimage,
+        paid_from_balance: false,
+        invoice_expiry_time_ms,
+        onchain_address,
+        onchain_pending_sat: 0,
+        onchain_received_sat: 0,
+        onchain_buyer_credit_sat: 0,
+        onchain_expired: false,
+        fiat_currency: maybe_exchange_rate
+            .as_ref()
+            .map(|exchange_rate| exchange_rate.currency.clone())
+            .unwrap_or_default(),
+        fiat_cents_per_btc: maybe_exchange_rate
+            .as_ref()
+            .map(|exchange_rate| exchange_rate.fiat_cents_per_btc)
+            .unwrap_or(0),
+        listing_fiat_price_cents: listing.fiat_price_cents,
+        disputed: false,
+        dispute_opened_time_ms: 0,
+        dispute_resolved: false,
+        dispute_resolved_time_ms: 0,
+        dispute_buyer_refund_sat: 0,
+        dispute_seller_credit_sat: 0,
+        shipped_time_ms: 0,
+        finalized: false,
+        finalized_time_ms: 0,
+        shipping_expired: false,
     };
 
-    match Order::insert(order, MAX_UNPAID_ORDERS, db).await {
+    let order_item = OrderItem {
+        id: None,
+        order_id: 0,
+        listing_id: listing.id.unwrap(),
+        quantity,
+        price_sat: listing_price_sat,
+        fee_rate_basis_points: listing.fee_rate_basis_points,
+        market_fee_sat: item_market_fee_sat,
+        variant_id: maybe_variant
+            .as_ref()
+            .map(|variant| variant.id.unwrap())
+            .unwrap_or(0),
+        variant_title: maybe_variant
+            .as_ref()
+            .map(|variant| variant.title.clone())
+            .unwrap_or_default(),
+    };
+
+    match Order::insert(order, vec![order_item], MAX_UNPAID_ORDERS, db).await {
         Ok(order_id) => match Order::single(db, order_id).await {
             Ok(new_order) => Ok(new_order.public_id),
             Err(e) => {
@@ -213,29 +373,91 @@ async fn create_order(
     }
 }
 
+/// Returns the hash, payment request, and preimage of a new order invoice.
+///
+/// In escrow mode, the payment is held until the order is shipped or canceled.
+pub async fn create_order_invoice(
+    lightning_backend: &dyn LightningBackend,
+    amount_owed_sat: u64,
+    escrow: bool,
+    expiry_s: u32,
+) -> Result<(String, String, String), String> {
+    if escrow {
+        let preimage = util::create_preimage();
+        let payment_hash = util::sha256(&preimage);
+        let hold_invoice = lightning_backend
+            .create_hold_invoice(amount_owed_sat, payment_hash.clone(), expiry_s)
+            .await
+            .map_err(|_| "failed to get new hold invoice.")?;
+        Ok((
+            util::to_hex(&payment_hash),
+            hold_invoice.payment_request,
+            util::to_hex(&preimage),
+        ))
+    } else {
+        let invoice = lightning_backend
+            .create_invoice(amount_owed_sat, expiry_s)
+            .await
+            .map_err(|_| "failed to get new invoice.")?;
+        Ok((
+            util::to_hex(&invoice.r_hash),
+            invoice.payment_request,
+            "".to_string(),
+        ))
+    }
+}
+
+/// Returns the price of one unit of the listing in sats. Fiat-pegged listings
+/// are converted at the given exchange rate.
+pub fn listing_price_sat(
+    listing: &Listing,
+    maybe_exchange_rate: Option<&ExchangeRate>,
+) -> Result<u64, String> {
+    if listing.fiat_price_cents > 0 {
+        let exchange_rate = maybe_exchange_rate
+            .ok_or("No exchange rate is available for the fiat-pegged listing.")?;
+        Ok(util::fiat_cents_to_sat(
+            listing.fiat_price_cents,
+            exchange_rate.fiat_cents_per_btc,
+        ))
+    } else {
+        Ok(listing.price_sat)
+    }
+}
+
+/// Returns the market fee for an amount, rounded up to the next sat.
+pub fn market_fee_sat(amount_sat: u64, fee_rate_basis_points: u32) -> u64 {
+    divide_round_up(amount_sat * (fee_rate_basis_points as u64), 10000)
+}
+
 fn divide_round_up(dividend: u64, divisor: u64) -> u64 {
     (dividend + divisor - 1) / divisor
 }
 
-#[get("/<id>?<shipping_option_id>&<quantity>")]
+#[allow(clippy::too_many_arguments)]
+#[get("/<id>?<shipping_option_id>&<variant_id>&<quantity>")]
 async fn index(
     flash: Option<FlashMessage<'_>>,
     id: &str,
     shipping_option_id: &str,
+    variant_id: Option<&str>,
     quantity: usize,
     db: Connection<Db>,
     active_user: ActiveUser,
     admin_user: Option<AdminUser>,
+    config: &State<Config>,
 ) -> Result<Template, String> {
     let flash = flash.map(FlashMessage::into_inner);
     let context = Context::raw(
         db,
         id,
         shipping_option_id,
+        variant_id,
         quantity.try_into().unwrap(),
         flash,
         active_user.user,
         admin_user,
+        config.onchain_payments,
     )
     .await
     .map_err(|_| "failed to get template context.")?;
@@ -1,18 +1,35 @@
+use crate::auto_payout;
 use crate::config::Config;
 use crate::db::Db;
+use crate::exchange_rate_provider::{self, ExchangeRateProvider};
+use crate::exchange_rate_update;
+use crate::lightning::{self, LightningBackend};
 use crate::order_expiry;
+use crate::order_finalization;
 use crate::payment_processor;
+use crate::payout_resolver::{self, PayoutResolver};
+use crate::solvency_check;
 use crate::user_account_expiry;
+use crate::withdrawal_processor;
 use rocket::fairing::{self, AdHoc};
 use rocket::fs::{relative, FileServer};
-use rocket::{Build, Rocket};
+use rocket::{Build, Orbit, Rocket};
 use rocket_auth::Error::SqlxError;
 use rocket_auth::Users;
 use rocket_db_pools::{sqlx, Database};
 use rocket_dyn_templates::Template;
+use std::sync::Arc;
 
 const PAYMENT_PROCESSOR_TASK_INTERVAL_S: u64 = 10;
 const ORDER_EXPIRY_TASK_INTERVAL_S: u64 = 600;
+const HOLD_INVOICE_PROCESSOR_TASK_INTERVAL_S: u64 = 10;
+const LIGHTNING_HEALTH_CHECK_TASK_INTERVAL_S: u64 = 30;
+const WITHDRAWAL_PROCESSOR_TASK_INTERVAL_S: u64 = 10;
+const SOLVENCY_CHECK_TASK_INTERVAL_S: u64 = 3600;
+const AUTO_PAYOUT_TASK_INTERVAL_S: u64 = 3600;
+const ONCHAIN_PAYMENT_PROCESSOR_TASK_INTERVAL_S: u64 = 60;
+const EXCHANGE_RATE_UPDATE_TASK_INTERVAL_S: u64 = 600;
+const ORDER_FINALIZATION_TASK_INTERVAL_S: u64 = 3600;
 
 async fn run_migrations(rocket: Rocket<Build>) -> fairing::Result {
     match Db::fetch(&rocket) {
@@ -65,6 +82,64 @@ async fn create_admin_user(rocket: Rocket<Build>, config: Config) -> fairing::Re
     }
 }
 
+async fn manage_lightning_backend(rocket: Rocket<Build>, config: Config) -> fairing::Result {
+    match lightning::new_lightning_backend(&config) {
+        Ok(lightning_backend) => Ok(rocket.manage(lightning_backend)),
+        Err(e) => {
+            error!("Failed to create lightning backend: {}", e);
+            Err(rocket)
+        }
+    }
+}
+
+async fn manage_payout_resolver(rocket: Rocket<Build>, config: Config) -> fairing::Result {
+    match payout_resolver::new_payout_resolver(&config) {
+        Ok(payout_resolver) => Ok(rocket.manage(payout_resolver)),
+        Err(e) => {
+            error!("Failed to create payout resolver: {}", e);
+            Err(rocket)
+        }
+    }
+}
+
+async fn manage_exchange_rate_provider(rocket: Rocket<Build>, config: Config) -> fairing::Result {
+    match exchange_rate_provider::new_exchange_rate_provider(&config) {
+        Ok(exchange_rate_provider) => Ok(rocket.manage(exchange_rate_provider)),
+        Err(e) => {
+            error!("Failed to create exchange rate provider: {}", e);
+            Err(rocket)
+        }
+    }
+}
+
+fn get_exchange_rate_provider(rocket: &Rocket<Orbit>) -> Arc<dyn ExchangeRateProvider> {
+    match rocket.state::<Arc<dyn ExchangeRateProvider>>() {
+        Some(exchange_rate_provider) => exchange_rate_provider.clone(),
+        None => panic!("failed to get exchange rate provider for background task."),
+    }
+}
+
+fn get_payout_resolver(rocket: &Rocket<Orbit>) -> Arc<dyn PayoutResolver> {
+    match rocket.state::<Arc<dyn PayoutResolver>>() {
+        Some(payout_resolver) => payout_resolver.clone(),
+        None => panic!("failed to get payout resolver for background task."),
+    }
+}
+
+fn get_lightning_backend(rocket: &Rocket<Orbit>) -> Arc<dyn LightningBackend> {
+    match rocket.state::<Arc<dyn LightningBackend>>() {
+        Some(lightning_backend) => lightning_backend.clone(),
+        None => panic!("failed to get lightning backend for background task."),
+    }
+}
+
+fn get_config(rocket: &Rocket<Orbit>) -> Config {
+    match rocket.state::<Config>() {
+        Some(config) => config.clone(),
+        None => panic!("failed to get config for background task."),
+    }
+}
+
 pub fn stage(config: Config) -> AdHoc {
     let config_clone = config.clone();
     let config_clone_2 = config.clone();
@@ -76,6 +151,15 @@ pub fn stage(config: Config) -> AdHoc {
             .attach(AdHoc::try_on_ignite("Manage config", |rocket| {
                 Box::pin(async move { Ok(rocket.manage(config)) })
             }))
+            .attach(AdHoc::try_on_ignite("Manage lightning backend", |r| {
+                manage_lightning_backend(r, config_clone)
+            }))
+            .attach(AdHoc::try_on_ignite("Manage payout resolver", |r| {
+                manage_payout_resolver(r, config_clone_3)
+            }))
+            .attach(AdHoc::try_on_ignite("Manage exchange rate provider", |r| {
+                manage_exchange_rate_provider(r, config_clone_4)
+            }))
             .attach(Db::init())
             .attach(AdHoc::try_on_ignite("SQLx Migrations", run_migrations))
             .attach(AdHoc::try_on_ignite(
@@ -92,11 +176,12 @@ pub fn stage(config: Config) -> AdHoc {
                         Some(pool) => pool.0.clone(), // clone the wrapped pool
                         None => panic!("failed to get db for background task."),
                     };
+                    let lightning_backend = get_lightning_backend(rocket);
                     rocket::tokio::spawn(async move {
                         loop {
                             if let Ok(conn) = pool.acquire().await {
                                 match payment_processor::handle_received_payments(
-                                    config_clone.clone(),
+                                    lightning_backend.clone(),
                                     conn,
                                 )
                                 .await
@@ -121,12 +206,305 @@ pub fn stage(config: Config) -> AdHoc {
                     });
                 })
             }))
+            .attach(AdHoc::on_liftoff(
+                "Process hold invoice payments",
+                |rocket| {
+                    Box::pin(async move {
+                        let pool = match Db::fetch(rocket) {
+                            Some(pool) => pool.0.clone(), // clone the wrapped pool
+                            None => panic!("failed to get db for background task."),
+                        };
+                        let lightning_backend = get_lightning_backend(rocket);
+                        rocket::tokio::spawn(async move {
+                            let mut interval = rocket::tokio::time::interval(
+                                rocket::tokio::time::Duration::from_secs(
+                                    HOLD_INVOICE_PROCESSOR_TASK_INTERVAL_S,
+                                ),
+                            );
+                            loop {
+                                if let Ok(conn) = pool.acquire().await {
+                                    match payment_processor::handle_accepted_hold_invoices(
+                                        lightning_backend.clone(),
+                                        conn,
+                                    )
+                                    .await
+                                    {
+                                        Ok(_) => (),
+                                        Err(e) => {
+                                            println!("hold invoice processor task failed: {:?}", e)
+                                        }
+                                    }
+                                }
+                                interval.tick().await;
+                            }
+                        });
+                    })
+                },
+            ))
+            .attach(AdHoc::on_liftoff("Process on-chain payments", |rocket| {
+                Box::pin(async move {
+                    let pool = match Db::fetch(rocket) {
+                        Some(pool) => pool.0.clone(), // clone the wrapped pool
+                        None => panic!("failed to get db for background task."),
+                    };
+                    let lightning_backend = get_lightning_backend(rocket);
+                    let config = get_config(rocket);
+                    if !config.onchain_payments {
+                        return;
+                    }
+                    rocket::tokio::spawn(async move {
+                        loop {
+                            if let Ok(conn) = pool.acquire().await {
+                                match payment_processor::handle_onchain_payments(
+                                    lightning_backend.clone(),
+                                    conn,
+                                    config.clone(),
+                                )
+                                .await
+                                {
+                                    Ok(_) => println!(
+                                        "on-chain payment processor task `completed` (shouldn't happen)."
+                                    ),
+                                    Err(e) => {
+                                        println!("on-chain payment processor task failed: {:?}", e)
+                                    }
+                                }
+                                println!(
+                                    "Subscription failed. Trying again in {:?} seconds.",
+                                    ONCHAIN_PAYMENT_PROCESSOR_TASK_INTERVAL_S
+                                );
+                                rocket::tokio::time::sleep(
+                                    rocket::tokio::time::Duration::from_secs(
+                                        ONCHAIN_PAYMENT_PROCESSOR_TASK_INTERVAL_S,
+                                    ),
+                                )
+                                .await;
+                            }
+                        }
+                    });
+                })
+            }))
+            .attach(AdHoc::on_liftoff(
+                "Check on-chain payment confirmations",
+                |rocket| {
+                    Box::pin(async move {
+                        let pool = match Db::fetch(rocket) {
+                            Some(pool) => pool.0.clone(), // clone the wrapped pool
+                            None => panic!("failed to get db for background task."),
+                        };
+                        let lightning_backend = get_lightning_backend(rocket);
+                        let config = get_config(rocket);
+                        if !config.onchain_payments {
+                            return;
+                        }
+                        rocket::tokio::spawn(async move {
+                            let mut interval = rocket::tokio::time::interval(
+                                rocket::tokio::time::Duration::from_secs(
+                                    ONCHAIN_PAYMENT_PROCESSOR_TASK_INTERVAL_S,
+                                ),
+                            );
+                            loop {
+                                if let Ok(mut conn) = pool.acquire().await {
+                                    match payment_processor::reconcile_onchain_payments(
+                                        &mut conn,
+                                        &*lightning_backend,
+                                        &config,
+                                    )
+                                    .await
+                                    {
+                                        Ok(_) => (),
+                                        Err(e) => println!(
+                                            "on-chain confirmation check task failed: {:?}",
+                                            e
+                                        ),
+                                    }
+                                }
+                                interval.tick().await;
+                            }
+                        });
+                    })
+                },
+            ))
+            .attach(AdHoc::on_liftoff("Track withdrawal payments", |rocket| {
+                Box::pin(async move {
+                    let pool = match Db::fetch(rocket) {
+                        Some(pool) => pool.0.clone(), // clone the wrapped pool
+                        None => panic!("failed to get db for background task."),
+                    };
+                    let lightning_backend = get_lightning_backend(rocket);
+                    rocket::tokio::spawn(async move {
+                        let mut interval = rocket::tokio::time::interval(
+                            rocket::tokio::time::Duration::from_secs(
+                                WITHDRAWAL_PROCESSOR_TASK_INTERVAL_S,
+                            ),
+                        );
+                        loop {
+                            if let Ok(conn) = pool.acquire().await {
+                                match withdrawal_processor::track_withdrawal_payments(
+                                    lightning_backend.clone(),
+                                    conn,
+                                )
+                                .await
+                                {
+                                    Ok(_) => (),
+                                    Err(e) => {
+                                        println!("withdrawal processor task failed: {:?}", e)
+                                    }
+                                }
+                            }
+                            interval.tick().await;
+                        }
+                    });
+                })
+            }))
+            .attach(AdHoc::on_liftoff("Update exchange rate", |rocket| {
+                Box::pin(async move {
+                    let pool = match Db::fetch(rocket) {
+                        Some(pool) => pool.0.clone(), // clone the wrapped pool
+                        None => panic!("failed to get db for background task."),
+                    };
+                    let exchange_rate_provider = get_exchange_rate_provider(rocket);
+                    let config = get_config(rocket);
+                    if config.fiat_currency.is_empty() {
+                        return;
+                    }
+                    rocket::tokio::spawn(async move {
+                        let mut interval = rocket::tokio::time::interval(
+                            rocket::tokio::time::Duration::from_secs(
+                                EXCHANGE_RATE_UPDATE_TASK_INTERVAL_S,
+                            ),
+                        );
+                        loop {
+                            if let Ok(conn) = pool.acquire().await {
+                                match exchange_rate_update::update_exchange_rate(
+                                    exchange_rate_provider.clone(),
+                                    conn,
+                                    &config.fiat_currency,
+                                )
+                                .await
+                                {
+                                    Ok(_) => (),
+                                    Err(e) => println!("exchange rate update task failed: {:?}", e),
+                                }
+                            }
+                            interval.tick().await;
+                        }
+                    });
+                })
+            }))
+            .attach(AdHoc::on_liftoff("Run auto payouts", |rocket| {
+                Box::pin(async move {
+                    let pool = match Db::fetch(rocket) {
+                        Some(pool) => pool.0.clone(), // clone the wrapped pool
+                        None => panic!("failed to get db for background task."),
+                    };
+                    let lightning_backend = get_lightning_backend(rocket);
+                    let payout_resolver = get_payout_resolver(rocket);
+                    rocket::tokio::spawn(async move {
+                        let mut interval = rocket::tokio::time::interval(
+                            rocket::tokio::time::Duration::from_secs(AUTO_PAYOUT_TASK_INTERVAL_S),
+                        );
+                        loop {
+                            if let Ok(conn) = pool.acquire().await {
+                                match auto_payout::run_auto_payouts(
+                                    lightning_backend.clone(),
+                                    payout_resolver.clone(),
+                                    conn,
+                                )
+                                .await
+                                {
+                                    Ok(_) => (),
+                                    Err(e) => println!("auto payout task failed: {:?}", e),
+                                }
+                            }
+                            interval.tick().await;
+                        }
+                    });
+                })
+            }))
+            .attach(AdHoc::on_liftoff("Finalize shipped orders", |rocket| {
+                Box::pin(async move {
+                    let pool = match Db::fetch(rocket) {
+                        Some(pool) => pool.0.clone(), // clone the wrapped pool
+                        None => panic!("failed to get db for background task."),
+                    };
+                    rocket::tokio::spawn(async move {
+                        let mut interval = rocket::tokio::time::interval(
+                            rocket::tokio::time::Duration::from_secs(
+                                ORDER_FINALIZATION_TASK_INTERVAL_S,
+                            ),
+                        );
+                        loop {
+                            if let Ok(conn) = pool.acquire().await {
+                                match order_finalization::finalize_expired_orders(conn).await {
+                                    Ok(_) => (),
+                                    Err(e) => println!("order finalization task failed: {:?}", e),
+                                }
+                            }
+                            interval.tick().await;
+                        }
+                    });
+                })
+            }))
+            .attach(AdHoc::on_liftoff("Check market solvency", |rocket| {
+                Box::pin(async move {
+                    let pool = match Db::fetch(rocket) {
+                        Some(pool) => pool.0.clone(), // clone the wrapped pool
+                        None => panic!("failed to get db for background task."),
+                    };
+                    let lightning_backend = get_lightning_backend(rocket);
+                    rocket::tokio::spawn(async move {
+                        let mut interval = rocket::tokio::time::interval(
+                            rocket::tokio::time::Duration::from_secs(
+                                SOLVENCY_CHECK_TASK_INTERVAL_S,
+                            ),
+                        );
+                        loop {
+                            if let Ok(conn) = pool.acquire().await {
+                                match solvency_check::check_solvency(
+                                    lightning_backend.clone(),
+                                    conn,
+                                )
+                                .await
+                                {
+                                    Ok(_) => (),
+                                    Err(e) => println!("solvency check task failed: {:?}", e),
+                                }
+                            }
+                            interval.tick().await;
+                        }
+                    });
+                })
+            }))
+            .attach(AdHoc::on_liftoff(
+                "Check lightning backend health",
+                |rocket| {
+                    Box::pin(async move {
+                        let lightning_backend = get_lightning_backend(rocket);
+                        rocket::tokio::spawn(async move {
+                            let mut interval = rocket::tokio::time::interval(
+                                rocket::tokio::time::Duration::from_secs(
+                                    LIGHTNING_HEALTH_CHECK_TASK_INTERVAL_S,
+                                ),
+                            );
+                            loop {
+                                interval.tick().await;
+                                match lightning_backend.check_health().await {
+                                    Ok(_) => (),
+                                    Err(e) => println!("lightning health check failed: {:?}", e),
+                                }
+                            }
+                        });
+                    })
+                },
+            ))
             .attach(AdHoc::on_liftoff("Remove expired orders", |rocket| {
                 Box::pin(async move {
                     let pool = match Db::fetch(rocket) {
                         Some(pool) => pool.0.clone(), // clone the wrapped pool
                         None => panic!("failed to get db for background task."),
                     };
+                    let lightning_backend = get_lightning_backend(rocket);
                     rocket::tokio::spawn(async move {
                         let mut interval = rocket::tokio::time::interval(
                             rocket::tokio::time::Duration::from_secs(ORDER_EXPIRY_TASK_INTERVAL_S),
@@ -135,7 +513,7 @@ pub fn stage(config: Config) -> AdHoc {
                             if let Ok(conn) = pool.acquire().await {
                                 // Remove expired orders
                                 match order_expiry::remove_expired_orders(
-                                    config_clone_3.clone(),
+                                    lightning_backend.clone(),
                                     conn,
                                 )
                                 .await
@@ -157,6 +535,7 @@ pub fn stage(config: Config) -> AdHoc {
                             Some(pool) => pool.0.clone(), // clone the wrapped pool
                             None => panic!("failed to get db for background task."),
                         };
+                        let lightning_backend = get_lightning_backend(rocket);
                         rocket::tokio::spawn(async move {
                             let mut interval = rocket::tokio::time::interval(
                                 rocket::tokio::time::Duration::from_secs(
@@ -167,7 +546,7 @@ pub fn stage(config: Config) -> AdHoc {
                                 if let Ok(conn) = pool.acquire().await {
                                     // Remove expired orders
                                     match user_account_expiry::remove_expired_user_accounts(
-                                        config_clone_4.clone(),
+                                        lightning_backend.clone(),
                                         conn,
                                     )
                                     .await
@@ -195,11 +574,18 @@ pub fn stage(config: Config) -> AdHoc {
             .attach(crate::account_activation::account_activation_stage())
             .attach(crate::deactivate_account::deactivate_account_stage())
             .attach(crate::market_liabilities::market_liabilities_stage())
+            .attach(crate::market_revenue::market_revenue_stage())
+            .attach(crate::market_withdraw::market_withdraw_stage())
+            .attach(crate::solvency::solvency_stage())
+            .attach(crate::disputes::disputes_stage())
+            .attach(crate::dispute::dispute_stage())
             .attach(crate::listings::listings_stage())
             .attach(crate::deactivated_listings::deactivated_listings_stage())
             .attach(crate::listing::listing_stage())
             .attach(crate::new_listing::new_listing_stage())
             .attach(crate::update_listing_images::update_listing_images_stage())
+            .attach(crate::update_listing_variants::update_listing_variants_stage())
+            .attach(crate::update_digital_payloads::update_digital_payloads_stage())
             .attach(crate::update_shipping_options::update_shipping_options_stage())
             .attach(crate::user::user_stage())
             .attach(crate::user_profile::user_profile_stage())
@@ -209,8 +595,14 @@ pub fn stage(config: Config) -> AdHoc {
             .attach(crate::update_squeaknode_info::update_squeaknode_info_stage())
             .attach(crate::update_pgp_info::update_pgp_info_stage())
             .attach(crate::update_max_allowed_users::update_max_allowed_users_stage())
+            .attach(crate::update_withdrawal_fee_limit::update_withdrawal_fee_limit_stage())
+            .attach(crate::update_invoice_expiry::update_invoice_expiry_stage())
+            .attach(crate::update_finalization::update_finalization_stage())
+            .attach(crate::update_shipping_deadline::update_shipping_deadline_stage())
             .attach(crate::update_user_squeaknode_info::update_user_squeaknode_info_stage())
             .attach(crate::update_user_pgp_info::update_user_pgp_info_stage())
+            .attach(crate::update_payout_node::update_payout_node_stage())
+            .attach(crate::update_auto_payout::update_auto_payout_stage())
             .attach(crate::review_pending_listings::review_pending_listings_stage())
             .attach(crate::delete_listing::delete_listing_stage())
             .attach(crate::account::account_stage())
@@ -226,9 +618,12 @@ pub fn stage(config: Config) -> AdHoc {
             .attach(crate::prepare_order::prepare_order_stage())
             .attach(crate::order::order_stage())
             .attach(crate::withdraw::withdraw_stage())
+            .attach(crate::lnurl_withdraw::lnurl_withdraw_stage())
             .attach(crate::withdrawal::withdrawal_stage())
             .attach(crate::seller_history::seller_history_stage())
             .attach(crate::top_sellers::top_sellers_stage())
+            .attach(crate::unread_messages::unread_messages_stage())
+            .attach(crate::cart::cart_stage())
             .attach(crate::search::search_stage())
     })
 }
@@ -18,6 +18,7 @@ struct Context {
     visited_user: RocketAuthUser,
     amount_sold_sat: u64,
     weighted_average_rating: f32,
+    num_shipping_strikes: u32,
     order_cards: Vec<OrderCard>,
     page_num: u32,
 }
@@ -52,12 +53,14 @@ impl Context {
             .map_err(|_| "failed to get weighted average rating for user.")?;
         let weighted_average_rating = seller_info.weighted_average_rating;
         let amount_sold_sat = seller_info.total_amount_sold_sat;
+        let num_shipping_strikes = seller_info.num_shipping_strikes;
         Ok(Context {
             base_context,
             flash,
             visited_user,
             amount_sold_sat,
             weighted_average_rating,
+            num_shipping_strikes,
             order_cards,
             page_num,
         })
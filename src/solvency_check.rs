@@ -0,0 +1,55 @@
+use crate::lightning::LightningBackend;
+use crate::models::{AccountInfo, Order, SolvencySnapshot};
+use crate::util;
+use sqlx::pool::PoolConnection;
+use sqlx::Sqlite;
+use std::sync::Arc;
+
+/// Compares the funds held by the node with the funds owed by the market,
+/// and stores the result as a new solvency snapshot.
+pub async fn check_solvency(
+    lightning_backend: Arc<dyn LightningBackend>,
+    mut conn: PoolConnection<Sqlite>,
+) -> Result<(), String> {
+    let channel_balance_resp = lightning_backend.channel_balance().await?;
+    let wallet_balance_resp = lightning_backend.wallet_balance().await?;
+    let channel_balance_sat = channel_balance_resp
+        .local_balance
+        .map(|local_balance| local_balance.sat)
+        .unwrap_or(0);
+    let unsettled_balance_sat = channel_balance_resp
+        .unsettled_remote_balance
+        .map(|unsettled_remote_balance| unsettled_remote_balance.sat)
+        .unwrap_or(0);
+    let wallet_balance_sat = wallet_balance_resp
+        .confirmed_balance
+        .max(0)
+        .try_into()
+        .unwrap();
+    let total_market_liabilities_sat = AccountInfo::total_market_liabilities_sat(&mut conn)
+        .await
+        .map_err(|_| "failed to get total market liabilities.")?;
+    let pending_escrow_sat = Order::total_pending_escrow_sat(&mut conn)
+        .await
+        .map_err(|_| "failed to get total pending escrow.")?;
+
+    let solvency_snapshot = SolvencySnapshot::new(
+        channel_balance_sat,
+        unsettled_balance_sat,
+        wallet_balance_sat,
+        total_market_liabilities_sat,
+        pending_escrow_sat,
+        util::current_time_millis(),
+    );
+    if solvency_snapshot.is_under_reserved() {
+        println!(
+            "Market is under-reserved: reserves {} sat, obligations {} sat",
+            solvency_snapshot.reserves_sat, solvency_snapshot.obligations_sat,
+        );
+    }
+    SolvencySnapshot::insert(solvency_snapshot, &mut conn)
+        .await
+        .map_err(|_| "failed to insert solvency snapshot.")?;
+
+    Ok(())
+}
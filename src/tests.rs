@@ -1,10 +1,37 @@
+use crate::auto_payout;
 use crate::config::Config;
+use crate::db::Db;
+use crate::lightning::LightningBackend;
+use crate::mock_lightning::MockLightningBackend;
+use crate::models::{
+    AccountInfo, AdminSettings, DigitalPayload, Listing, Order, OrderItem, Withdrawal,
+    WithdrawalStatus,
+};
+use crate::order_expiry;
+use crate::order_finalization;
+use crate::payment_processor;
+use crate::payout_resolver;
+use crate::rocket::futures::StreamExt;
+use crate::withdraw;
+use crate::withdrawal_processor;
+use pgp::composed::Message;
 use rocket::fairing::AdHoc;
 use rocket::http::uri::fmt::{Query, UriDisplay};
-use rocket::http::ContentType;
+use rocket::http::{ContentType, RawStr};
+use rocket::local::asynchronous;
 use rocket::local::blocking::Client;
 use rocket::serde::{Deserialize, Serialize};
 use rocket::{Build, Rocket};
+use rocket_auth::Users;
+use rocket_db_pools::Database;
+use sqlx::pool::PoolConnection;
+use sqlx::Sqlite;
+use std::future::Future;
+use std::sync::Arc;
+use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
+
+const TEST_USER_PASSWORD: &str = "Test-password-1";
+const WAIT_TIMEOUT_MS: u64 = 5000;
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize, UriDisplayQuery)]
 #[serde(crate = "rocket::serde")]
@@ -72,10 +99,1717 @@ fn test_admin_settings(_base: &str, stage: AdHoc, config: Config) {
     assert!(index_page_string.contains("test-market-name"));
 }
 
+fn test_config() -> Config {
+    let config_figment = Config::get_config()
+        .merge(("db_url", "sqlite://:memory:".to_string()))
+        .merge(("lightning_backend", "mock".to_string()))
+        .merge(("payout_resolver", "mock".to_string()));
+    config_figment.extract().unwrap()
+}
+
+/// Returns a client for a market that uses the mock lightning backend, with
+/// all of its background tasks running.
+async fn test_client(config: Config) -> asynchronous::Client {
+    let rocket = rocket_build(config.clone()).attach(crate::routes::stage(config));
+    asynchronous::Client::tracked(rocket).await.unwrap()
+}
+
+fn form_body(fields: &[(&str, &str)]) -> String {
+    fields
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, RawStr::new(value).percent_encode()))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Posts a form, and returns the redirect location.
+async fn post_form(client: &asynchronous::Client, uri: &str, fields: &[(&str, &str)]) -> String {
+    let response = client
+        .post(uri.to_string())
+        .header(ContentType::Form)
+        .body(form_body(fields))
+        .dispatch()
+        .await;
+    response
+        .headers()
+        .get_one("Location")
+        .unwrap_or("")
+        .to_string()
+}
+
+async fn put(client: &asynchronous::Client, uri: &str) -> String {
+    let response = client.put(uri.to_string()).dispatch().await;
+    response
+        .headers()
+        .get_one("Location")
+        .unwrap_or("")
+        .to_string()
+}
+
+async fn db_conn(client: &asynchronous::Client) -> PoolConnection<Sqlite> {
+    Db::fetch(client.rocket())
+        .unwrap()
+        .0
+        .acquire()
+        .await
+        .unwrap()
+}
+
+fn lightning_backend(client: &asynchronous::Client) -> Arc<dyn LightningBackend> {
+    client
+        .rocket()
+        .state::<Arc<dyn LightningBackend>>()
+        .unwrap()
+        .clone()
+}
+
+/// Waits until a background task makes the condition true.
+async fn wait_until<F, Fut>(mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let start_time_ms = crate::util::current_time_millis();
+    while !condition().await {
+        assert!(
+            crate::util::current_time_millis() < start_time_ms + WAIT_TIMEOUT_MS,
+            "condition not met before timeout."
+        );
+        rocket::tokio::time::sleep(rocket::tokio::time::Duration::from_millis(20)).await;
+    }
+}
+
+async fn login(client: &asynchronous::Client, email: &str, password: &str) {
+    post_form(
+        client,
+        "/login",
+        &[("email", email), ("password", password)],
+    )
+    .await;
+}
+
+async fn login_as_admin(client: &asynchronous::Client) {
+    let config = client.rocket().state::<Config>().unwrap().clone();
+    login(client, &config.admin_username, &config.admin_password).await;
+}
+
+/// Signs up a new user and pays the account bond. Returns the user id. The
+/// new user stays logged in.
+/// Signs up a user, without paying for the account.
+async fn signup_user(client: &asynchronous::Client, email: &str) -> i32 {
+    post_form(
+        client,
+        "/signup",
+        &[("email", email), ("password", TEST_USER_PASSWORD)],
+    )
+    .await;
+    let users = client.rocket().state::<Users>().unwrap();
+    users.get_by_email(email).await.unwrap().id()
+}
+
+async fn user_account_paid(client: &asynchronous::Client, user_id: i32) -> bool {
+    sqlx::query_scalar("select paid from useraccounts where user_id = ?")
+        .bind(user_id)
+        .fetch_one(&mut *db_conn(client).await)
+        .await
+        .unwrap()
+}
+
+async fn signup_active_user(client: &asynchronous::Client, email: &str) -> i32 {
+    let user_id = signup_user(client, email).await;
+    let invoice_payment_request: String =
+        sqlx::query_scalar("select invoice_payment_request from useraccounts where user_id = ?")
+            .bind(user_id)
+            .fetch_one(&mut *db_conn(client).await)
+            .await
+            .unwrap();
+    lightning_backend(client)
+        .pay_invoice(&invoice_payment_request)
+        .await
+        .unwrap();
+    wait_until(|| user_account_paid(client, user_id)).await;
+    user_id
+}
+
+async fn account_balance_sat(client: &asynchronous::Client, user_id: i32) -> i64 {
+    AccountInfo::total_account_balance_for_user(&mut *db_conn(client).await, user_id)
+        .await
+        .unwrap()
+}
+
+/// Returns the public id at the end of a redirect location.
+fn public_id_from_location(location: &str) -> String {
+    location.rsplit('/').next().unwrap().to_string()
+}
+
+async fn id_by_public_id(client: &asynchronous::Client, table: &str, public_id: &str) -> i32 {
+    sqlx::query_scalar(&format!("select id from {} where public_id = ?", table))
+        .bind(public_id)
+        .fetch_one(&mut *db_conn(client).await)
+        .await
+        .unwrap()
+}
+
+/// Creates a listing with a single free shipping option. Returns the public
+/// ids of the listing and the shipping option. The seller stays logged in.
+async fn create_listing(
+    client: &asynchronous::Client,
+    seller_email: &str,
+    price_sat: u64,
+    quantity: u32,
+    digital: bool,
+) -> (String, String) {
+    login(client, seller_email, TEST_USER_PASSWORD).await;
+    let location = post_form(
+        client,
+        "/new_listing",
+        &[
+            ("title", "Test listing"),
+            ("description", "A test listing."),
+            ("price_sat", &price_sat.to_string()),
+            ("quantity", &quantity.to_string()),
+            ("digital", &digital.to_string()),
+        ],
+    )
+    .await;
+    let listing_id = public_id_from_location(&location);
+    post_form(
+        client,
+        &format!(
+            "/update_shipping_options/{}/add_shipping_option",
+            listing_id
+        ),
+        &[
+            ("title", "Mail"),
+            ("description", "Regular mail."),
+            ("price_sat", "0"),
+        ],
+    )
+    .await;
+    let shipping_option_id: String = sqlx::query_scalar(
+        "select shippingoptions.public_id from shippingoptions INNER JOIN listings ON shippingoptions.listing_id = listings.id WHERE listings.public_id = ?",
+    )
+    .bind(&listing_id)
+    .fetch_one(&mut *db_conn(client).await)
+    .await
+    .unwrap();
+    (listing_id, shipping_option_id)
+}
+
+/// Submits a listing as its seller, and approves it as the admin.
+async fn approve_listing(client: &asynchronous::Client, listing_id: &str) {
+    put(client, &format!("/listing/{}/submit", listing_id)).await;
+    login_as_admin(client).await;
+    put(client, &format!("/listing/{}/approve", listing_id)).await;
+}
+
+/// Creates an approved listing with a single free shipping option. Returns
+/// the public ids of the listing and the shipping option. The admin user
+/// stays logged in.
+async fn create_approved_listing(
+    client: &asynchronous::Client,
+    seller_email: &str,
+    price_sat: u64,
+    quantity: u32,
+) -> (String, String) {
+    let (listing_id, shipping_option_id) =
+        create_listing(client, seller_email, price_sat, quantity, false).await;
+    approve_listing(client, &listing_id).await;
+    (listing_id, shipping_option_id)
+}
+
+fn shipping_instructions() -> String {
+    Message::new_literal("", "123 Main St.")
+        .to_armored_string(None)
+        .unwrap()
+}
+
+/// Creates an order as the logged in buyer. Returns the order id, or None if
+/// the order was rejected.
+async fn create_order(
+    client: &asynchronous::Client,
+    listing_id: &str,
+    shipping_option_id: &str,
+    quantity: u32,
+) -> Option<i32> {
+    create_order_with_payment_method(client, listing_id, shipping_option_id, quantity, false).await
+}
+
+async fn create_order_with_payment_method(
+    client: &asynchronous::Client,
+    listing_id: &str,
+    shipping_option_id: &str,
+    quantity: u32,
+    onchain: bool,
+) -> Option<i32> {
+    let location = post_form(
+        client,
+        &format!("/prepare_order/{}/new", listing_id),
+        &[
+            ("quantity", &quantity.to_string()),
+            ("shipping_option_id", shipping_option_id),
+            ("shipping_instructions", &shipping_instructions()),
+            ("onchain", &onchain.to_string()),
+        ],
+    )
+    .await;
+    if !location.starts_with("/order/") {
+        return None;
+    }
+    Some(id_by_public_id(client, "orders", &public_id_from_location(&location)).await)
+}
+
+async fn get_order(client: &asynchronous::Client, order_id: i32) -> Order {
+    Order::single(&mut *db_conn(client).await, order_id)
+        .await
+        .unwrap()
+}
+
+/// Pays the invoice of an order, and waits until the order is marked as paid.
+async fn pay_order(client: &asynchronous::Client, order_id: i32) {
+    let order = get_order(client, order_id).await;
+    lightning_backend(client)
+        .pay_invoice(&order.invoice_payment_request)
+        .await
+        .unwrap();
+    wait_until(|| async { get_order(client, order_id).await.paid }).await;
+}
+
 #[test]
 fn test_routes() {
-    let config_figment = Config::get_config().merge(("db_url", "sqlite://:memory:".to_string()));
-    let config: Config = config_figment.extract().unwrap();
+    let config = test_config();
 
     test_admin_settings("/", crate::routes::stage(config.clone()), config);
 }
+
+#[rocket::async_test]
+async fn test_order_flow() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = signup_active_user(&client, "seller@example.com").await;
+    let buyer_user_id = signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+
+    // The buyer orders and pays.
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 2)
+        .await
+        .unwrap();
+    let order = get_order(&client, order_id).await;
+    assert_eq!(order.amount_owed_sat, 2000);
+    assert!(!order.paid);
+    pay_order(&client, order_id).await;
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 0);
+
+    // The seller is credited once the order is shipped and finalized.
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    let order_public_id = get_order(&client, order_id).await.public_id;
+    put(&client, &format!("/order/{}/ship", order_public_id)).await;
+    assert!(get_order(&client, order_id).await.shipped);
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    put(&client, &format!("/order/{}/finalize", order_public_id)).await;
+    let order = get_order(&client, order_id).await;
+    assert!(order.finalized);
+    assert_eq!(order.seller_credit_sat, 1900);
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 1900);
+    assert_eq!(account_balance_sat(&client, buyer_user_id).await, 0);
+}
+
+/// Returns the statuses recorded for an order, oldest first.
+async fn order_event_statuses(client: &asynchronous::Client, order_id: i32) -> Vec<String> {
+    sqlx::query_scalar("select status from orderevents where order_id = ? order by id")
+        .bind(order_id)
+        .fetch_all(&mut *db_conn(client).await)
+        .await
+        .unwrap()
+}
+
+#[rocket::async_test]
+async fn test_order_status_transitions() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = signup_active_user(&client, "seller@example.com").await;
+    let buyer_user_id = signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    let now = crate::util::current_time_millis();
+    let mut conn = db_conn(&client).await;
+
+    // An unpaid order cannot be shipped.
+    assert!(
+        Order::mark_as_shipped(&mut conn, order_id, seller_user_id, now, false)
+            .await
+            .is_err()
+    );
+    assert!(!get_order(&client, order_id).await.shipped);
+    assert_eq!(order_event_statuses(&client, order_id).await, ["unpaid"]);
+
+    // Each allowed transition records exactly one event.
+    pay_order(&client, order_id).await;
+    assert_eq!(
+        order_event_statuses(&client, order_id).await,
+        ["unpaid", "paid"]
+    );
+    Order::mark_as_shipped(&mut conn, order_id, seller_user_id, now, false)
+        .await
+        .unwrap();
+    assert_eq!(
+        order_event_statuses(&client, order_id).await,
+        ["unpaid", "paid", "shipped"]
+    );
+
+    // A shipped order cannot be canceled.
+    assert!(
+        Order::mark_as_canceled_by_seller(&mut conn, order_id, seller_user_id, now)
+            .await
+            .is_err()
+    );
+    assert!(!get_order(&client, order_id).await.canceled_by_seller);
+
+    // An order can only be finalized once.
+    Order::mark_as_finalized(&mut conn, order_id, buyer_user_id, now)
+        .await
+        .unwrap();
+    assert!(
+        Order::mark_as_finalized(&mut conn, order_id, buyer_user_id, now)
+            .await
+            .is_err()
+    );
+    assert_eq!(
+        order_event_statuses(&client, order_id).await,
+        ["unpaid", "paid", "shipped", "finalized"]
+    );
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 950);
+}
+
+/// Returns the unread message counter shown in the navigation bar of a page.
+async fn num_unread_messages_shown(client: &asynchronous::Client, uri: &str) -> u32 {
+    let page = client
+        .get(uri.to_string())
+        .dispatch()
+        .await
+        .into_string()
+        .await
+        .unwrap();
+    let counter_prefix = "href=\"/unread_messages\">Messages (";
+    let counter_start = page.find(counter_prefix).unwrap() + counter_prefix.len();
+    page[counter_start..]
+        .split(')')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+async fn num_order_messages(client: &asynchronous::Client, order_id: i32) -> i64 {
+    sqlx::query_scalar("select count(*) from ordermessages where order_id = ?")
+        .bind(order_id)
+        .fetch_one(&mut *db_conn(client).await)
+        .await
+        .unwrap()
+}
+
+#[rocket::async_test]
+async fn test_order_messages() {
+    let client = test_client(test_config()).await;
+    signup_active_user(&client, "seller@example.com").await;
+    signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    let order_uri = format!("/order/{}", get_order(&client, order_id).await.public_id);
+    let new_message_uri = format!("{}/new_message", order_uri);
+
+    // Messages must be PGP encrypted.
+    post_form(&client, &new_message_uri, &[("text", "Hello.")]).await;
+    assert_eq!(num_order_messages(&client, order_id).await, 0);
+    let message_text = Message::new_literal("", "Hello.")
+        .to_armored_string(None)
+        .unwrap();
+    post_form(&client, &new_message_uri, &[("text", &message_text)]).await;
+    assert_eq!(num_order_messages(&client, order_id).await, 1);
+    assert_eq!(
+        num_unread_messages_shown(&client, "/unread_messages").await,
+        0
+    );
+
+    // The message is unread by the seller until the order page is viewed.
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    assert_eq!(
+        num_unread_messages_shown(&client, "/unread_messages").await,
+        1
+    );
+    assert_eq!(num_unread_messages_shown(&client, &order_uri).await, 0);
+    assert_eq!(
+        num_unread_messages_shown(&client, "/unread_messages").await,
+        0
+    );
+}
+
+/// Creates a seller with the given account balance, from a finalized order.
+/// The seller stays logged in. Returns the user id.
+async fn seller_with_balance(client: &asynchronous::Client, price_sat: u64) -> i32 {
+    let seller_user_id = signup_active_user(client, "seller@example.com").await;
+    signup_active_user(client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(client, "seller@example.com", price_sat, 10).await;
+    login(client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    pay_order(client, order_id).await;
+    let order_public_id = get_order(client, order_id).await.public_id;
+    login(client, "seller@example.com", TEST_USER_PASSWORD).await;
+    put(client, &format!("/order/{}/ship", order_public_id)).await;
+    login(client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    put(client, &format!("/order/{}/finalize", order_public_id)).await;
+    login(client, "seller@example.com", TEST_USER_PASSWORD).await;
+    seller_user_id
+}
+
+async fn withdrawal_status(client: &asynchronous::Client, location: &str) -> String {
+    sqlx::query_scalar("select status from withdrawals where public_id = ?")
+        .bind(public_id_from_location(location))
+        .fetch_one(&mut *db_conn(client).await)
+        .await
+        .unwrap()
+}
+
+#[rocket::async_test]
+async fn test_withdrawal_flow() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = seller_with_balance(&client, 10000).await;
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 9500);
+
+    // A successful withdrawal debits the balance.
+    let lightning_backend = lightning_backend(&client);
+    let invoice_payment_request = MockLightningBackend::make_pay_req(4000 * 1000, &[1; 32]);
+    let location = post_form(
+        &client,
+        "/withdraw/new",
+        &[("invoice_payment_request", &invoice_payment_request)],
+    )
+    .await;
+    assert!(location.starts_with("/withdrawal/"));
+    assert_eq!(
+        withdrawal_status(&client, &location).await,
+        WithdrawalStatus::InFlight.as_str()
+    );
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 5500);
+    withdrawal_processor::track_withdrawal_payments(
+        lightning_backend.clone(),
+        db_conn(&client).await,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        withdrawal_status(&client, &location).await,
+        WithdrawalStatus::Succeeded.as_str()
+    );
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 5500);
+
+    // A withdrawal whose payment fails is refunded once the payment is tracked.
+    let invoice = lightning_backend.create_invoice(2000, 3600).await.unwrap();
+    lightning_backend
+        .cancel_invoice(invoice.r_hash.clone())
+        .await
+        .unwrap();
+    let location = post_form(
+        &client,
+        "/withdraw/new",
+        &[("invoice_payment_request", &invoice.payment_request)],
+    )
+    .await;
+    assert!(location.starts_with("/withdrawal/"));
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 3500);
+    withdrawal_processor::track_withdrawal_payments(lightning_backend, db_conn(&client).await)
+        .await
+        .unwrap();
+    assert_eq!(
+        withdrawal_status(&client, &location).await,
+        WithdrawalStatus::Failed.as_str()
+    );
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 5500);
+}
+
+#[rocket::async_test]
+async fn test_mock_lightning_backend() {
+    let lightning_backend = MockLightningBackend::new();
+    let invoice = lightning_backend.create_invoice(1000, 3600).await.unwrap();
+    let mut update_stream = lightning_backend.subscribe_invoices(0).await.unwrap();
+
+    // Paying the invoice settles it and notifies subscribers.
+    let decoded_pay_req = lightning_backend
+        .decode_pay_req(&invoice.payment_request)
+        .await
+        .unwrap();
+    assert_eq!(decoded_pay_req.num_satoshis, 1000);
+    lightning_backend
+        .pay_invoice(&invoice.payment_request)
+        .await
+        .unwrap();
+    let settled_invoice = update_stream.next().await.unwrap().unwrap();
+    assert_eq!(settled_invoice.r_hash, invoice.r_hash);
+    assert_eq!(settled_invoice.settle_index, 1);
+    assert_eq!(lightning_backend.payments().len(), 1);
+
+    // A settled invoice cannot be canceled.
+    assert!(lightning_backend
+        .cancel_invoice(invoice.r_hash.clone())
+        .await
+        .is_err());
+
+    // On-chain payments to a new address show up in the wallet transactions.
+    let mut transaction_stream = lightning_backend.subscribe_transactions().await.unwrap();
+    let address = lightning_backend.new_address().await.unwrap();
+    assert_ne!(address, lightning_backend.new_address().await.unwrap());
+    let tx_hash = lightning_backend.receive_onchain_payment(&address, 5000, 0);
+    let transaction = transaction_stream.next().await.unwrap().unwrap();
+    assert_eq!(transaction.tx_hash, tx_hash);
+    let transactions = lightning_backend.get_transactions().await.unwrap();
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0].output_details[0].address, address);
+    assert_eq!(transactions[0].amount, 5000);
+}
+
+async fn track_withdrawal_payments(client: &asynchronous::Client) {
+    withdrawal_processor::track_withdrawal_payments(
+        lightning_backend(client),
+        db_conn(client).await,
+    )
+    .await
+    .unwrap();
+}
+
+#[rocket::async_test]
+async fn test_withdrawal_payments_matched_by_hash() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = seller_with_balance(&client, 10000).await;
+
+    // An invoice that the node already paid cannot be withdrawn to again.
+    let invoice_payment_request = MockLightningBackend::make_pay_req(3000 * 1000, &[2; 32]);
+    let location = post_form(
+        &client,
+        "/withdraw/new",
+        &[("invoice_payment_request", &invoice_payment_request)],
+    )
+    .await;
+    track_withdrawal_payments(&client).await;
+    assert_eq!(
+        withdrawal_status(&client, &location).await,
+        WithdrawalStatus::Succeeded.as_str()
+    );
+    let location = post_form(
+        &client,
+        "/withdraw/new",
+        &[("invoice_payment_request", &invoice_payment_request)],
+    )
+    .await;
+    assert_eq!(location, "/withdraw");
+    let num_withdrawals: i64 =
+        sqlx::query_scalar("select count(id) from withdrawals where user_id = ?")
+            .bind(seller_user_id)
+            .fetch_one(&mut *db_conn(&client).await)
+            .await
+            .unwrap();
+    assert_eq!(num_withdrawals, 1);
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 6500);
+
+    // An invoice whose payment failed can be withdrawn to again, and each
+    // failed withdrawal is refunded.
+    let lightning_backend = lightning_backend(&client);
+    let invoice = lightning_backend.create_invoice(1000, 3600).await.unwrap();
+    lightning_backend
+        .cancel_invoice(invoice.r_hash.clone())
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        let location = post_form(
+            &client,
+            "/withdraw/new",
+            &[("invoice_payment_request", &invoice.payment_request)],
+        )
+        .await;
+        assert!(location.starts_with("/withdrawal/"));
+        track_withdrawal_payments(&client).await;
+        assert_eq!(
+            withdrawal_status(&client, &location).await,
+            WithdrawalStatus::Failed.as_str()
+        );
+    }
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 6500);
+}
+
+async fn get_json(
+    client: &asynchronous::Client,
+    uri: &str,
+    fields: &[(&str, &str)],
+) -> rocket::serde::json::Value {
+    client
+        .get(format!("{}?{}", uri, form_body(fields)))
+        .dispatch()
+        .await
+        .into_json()
+        .await
+        .unwrap()
+}
+
+/// Creates an LNURL-withdraw link for the logged in user, and returns its k1.
+async fn new_lnurl_withdrawal(client: &asynchronous::Client) -> String {
+    let location = post_form(client, "/lnurl_withdraw/new", &[]).await;
+    sqlx::query_scalar("select k1 from lnurlwithdrawals where public_id = ?")
+        .bind(public_id_from_location(&location))
+        .fetch_one(&mut *db_conn(client).await)
+        .await
+        .unwrap()
+}
+
+async fn lnurl_withdraw_callback(
+    client: &asynchronous::Client,
+    k1: &str,
+    amount_sat: u64,
+    payment_hash: &[u8],
+) -> rocket::serde::json::Value {
+    let invoice_payment_request =
+        MockLightningBackend::make_pay_req((amount_sat * 1000) as i64, payment_hash);
+    get_json(
+        client,
+        "/lnurl_withdraw/callback",
+        &[("k1", k1), ("pr", &invoice_payment_request)],
+    )
+    .await
+}
+
+#[rocket::async_test]
+async fn test_lnurl_withdraw() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = seller_with_balance(&client, 10000).await;
+    let max_withdrawable_sat =
+        withdraw::max_withdrawable_sat(&mut *db_conn(&client).await, seller_user_id)
+            .await
+            .unwrap();
+
+    // The wallet first gets the withdraw request.
+    let k1 = new_lnurl_withdrawal(&client).await;
+    let withdraw_request = get_json(&client, &format!("/lnurl_withdraw/request/{}", k1), &[]).await;
+    assert_eq!(withdraw_request["tag"], "withdrawRequest");
+    assert_eq!(withdraw_request["k1"], k1.as_str());
+    assert_eq!(
+        withdraw_request["maxWithdrawable"],
+        max_withdrawable_sat * 1000
+    );
+
+    // An invoice above the withdrawable amount is rejected, and leaves the
+    // link unused.
+    let response = lnurl_withdraw_callback(&client, &k1, max_withdrawable_sat + 1, &[1; 32]).await;
+    assert_eq!(response["status"], "ERROR");
+
+    // The link can only be redeemed once.
+    let response = lnurl_withdraw_callback(&client, &k1, 4000, &[2; 32]).await;
+    assert_eq!(response["status"], "OK");
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 5500);
+    let response = lnurl_withdraw_callback(&client, &k1, 4000, &[3; 32]).await;
+    assert_eq!(response["status"], "ERROR");
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 5500);
+
+    // An expired link cannot be redeemed.
+    let expired_k1 = new_lnurl_withdrawal(&client).await;
+    sqlx::query("update lnurlwithdrawals set created_time_ms = 0 where k1 = ?")
+        .bind(&expired_k1)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    let response = get_json(
+        &client,
+        &format!("/lnurl_withdraw/request/{}", expired_k1),
+        &[],
+    )
+    .await;
+    assert_eq!(response["status"], "ERROR");
+    let response = lnurl_withdraw_callback(&client, &expired_k1, 100, &[4; 32]).await;
+    assert_eq!(response["status"], "ERROR");
+
+    // Links count towards the daily withdrawal limit.
+    for i in 1..withdraw::MAX_WITHDRAWALS_PER_USER_PER_DAY {
+        let k1 = new_lnurl_withdrawal(&client).await;
+        let response = lnurl_withdraw_callback(&client, &k1, 100, &[4 + i as u8; 32]).await;
+        assert_eq!(response["status"], "OK");
+    }
+    let k1 = new_lnurl_withdrawal(&client).await;
+    let response = get_json(&client, &format!("/lnurl_withdraw/request/{}", k1), &[]).await;
+    assert_eq!(response["status"], "ERROR");
+    let response = lnurl_withdraw_callback(&client, &k1, 100, &[10; 32]).await;
+    assert_eq!(response["status"], "ERROR");
+}
+
+#[test]
+fn test_withdrawal_fee_limit() {
+    let admin_settings = AdminSettings {
+        max_withdrawal_fee_sat: 1000,
+        max_withdrawal_fee_basis_points: 100,
+        ..Default::default()
+    };
+
+    // Small withdrawals get the minimum limit instead of a rounded down one.
+    assert_eq!(admin_settings.withdrawal_fee_limit_sat(50), 10);
+    assert_eq!(admin_settings.withdrawal_fee_limit_sat(5000), 50);
+    assert_eq!(admin_settings.withdrawal_fee_limit_sat(1_000_000), 1000);
+
+    // The absolute limit set by the admin always applies.
+    let admin_settings = AdminSettings {
+        max_withdrawal_fee_sat: 5,
+        ..admin_settings
+    };
+    assert_eq!(admin_settings.withdrawal_fee_limit_sat(50), 5);
+}
+
+#[test]
+fn test_lnurl_pay_url() {
+    assert_eq!(
+        payout_resolver::lnurl_pay_url("Alice@example.com").unwrap(),
+        "https://example.com/.well-known/lnurlp/alice"
+    );
+    let lnurl = crate::util::encode_lnurl("https://example.com/lnurlp/alice");
+    assert!(payout_resolver::lnurl_pay_url(&lnurl).is_ok());
+
+    // Payout targets cannot make the market request plain http URLs, or
+    // hosts in its own network.
+    for url in [
+        "http://example.com/lnurlp/alice",
+        "https://127.0.0.1/lnurlp/alice",
+        "https://10.0.0.1/lnurlp/alice",
+        "https://169.254.169.254/lnurlp/alice",
+        "https://[::1]/lnurlp/alice",
+        "https://[::ffff:192.168.0.1]/lnurlp/alice",
+        "https://localhost/lnurlp/alice",
+    ] {
+        let lnurl = crate::util::encode_lnurl(url);
+        assert!(payout_resolver::lnurl_pay_url(&lnurl).is_err(), "{}", url);
+    }
+    assert!(payout_resolver::lnurl_pay_url("alice@localhost").is_err());
+}
+
+#[test]
+fn test_millis_to_minutes_rounded_up() {
+    assert_eq!(crate::util::millis_to_minutes_rounded_up(0), 0);
+    assert_eq!(crate::util::millis_to_minutes_rounded_up(1), 1);
+    assert_eq!(crate::util::millis_to_minutes_rounded_up(60_000), 1);
+    assert_eq!(crate::util::millis_to_minutes_rounded_up(60_001), 2);
+}
+
+async fn order_invoice_state(client: &asynchronous::Client, order_id: i32) -> i32 {
+    let order = get_order(client, order_id).await;
+    lightning_backend(client)
+        .lookup_invoice(crate::util::from_hex(&order.invoice_hash))
+        .await
+        .unwrap()
+        .state
+}
+
+#[rocket::async_test]
+async fn test_escrow_order() {
+    let config = Config {
+        hold_invoice_escrow: true,
+        ..test_config()
+    };
+    let client = test_client(config).await;
+    let seller_user_id = signup_active_user(&client, "seller@example.com").await;
+    let buyer_user_id = signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+
+    // Paid hold invoices are only accepted, and the orders are marked as paid.
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let shipped_order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    let canceled_order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    for order_id in [shipped_order_id, canceled_order_id] {
+        let order = get_order(&client, order_id).await;
+        assert!(order.escrow);
+        lightning_backend(&client)
+            .pay_invoice(&order.invoice_payment_request)
+            .await
+            .unwrap();
+    }
+    payment_processor::handle_accepted_hold_invoices(
+        lightning_backend(&client),
+        db_conn(&client).await,
+    )
+    .await
+    .unwrap();
+    for order_id in [shipped_order_id, canceled_order_id] {
+        assert!(get_order(&client, order_id).await.paid);
+        assert_eq!(
+            order_invoice_state(&client, order_id).await,
+            InvoiceState::Accepted as i32
+        );
+    }
+
+    // Canceling the order returns the payment, instead of crediting the buyer.
+    let canceled_order = get_order(&client, canceled_order_id).await;
+    put(
+        &client,
+        &format!("/order/{}/buyer_cancel", canceled_order.public_id),
+    )
+    .await;
+    assert!(
+        get_order(&client, canceled_order_id)
+            .await
+            .canceled_by_buyer
+    );
+    assert_eq!(
+        order_invoice_state(&client, canceled_order_id).await,
+        InvoiceState::Canceled as i32
+    );
+    assert_eq!(account_balance_sat(&client, buyer_user_id).await, 0);
+
+    // Shipping the order settles the payment.
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    let shipped_order = get_order(&client, shipped_order_id).await;
+    put(&client, &format!("/order/{}/ship", shipped_order.public_id)).await;
+    assert!(get_order(&client, shipped_order_id).await.shipped);
+    assert_eq!(
+        order_invoice_state(&client, shipped_order_id).await,
+        InvoiceState::Settled as i32
+    );
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    put(
+        &client,
+        &format!("/order/{}/finalize", shipped_order.public_id),
+    )
+    .await;
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 950);
+}
+
+#[rocket::async_test]
+async fn test_pay_order_from_balance() {
+    let client = test_client(test_config()).await;
+    let buyer_user_id = seller_with_balance(&client, 4000).await;
+    assert_eq!(account_balance_sat(&client, buyer_user_id).await, 3800);
+    let other_seller_user_id = signup_active_user(&client, "other-seller@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "other-seller@example.com", 1500, 10).await;
+
+    // An order is paid from the balance, and its invoice can no longer be paid.
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 2)
+        .await
+        .unwrap();
+    let order = get_order(&client, order_id).await;
+    put(
+        &client,
+        &format!("/order/{}/pay_from_balance", order.public_id),
+    )
+    .await;
+    let order = get_order(&client, order_id).await;
+    assert!(order.paid);
+    assert!(order.paid_from_balance);
+    assert_eq!(account_balance_sat(&client, buyer_user_id).await, 800);
+    assert!(lightning_backend(&client)
+        .pay_invoice(&order.invoice_payment_request)
+        .await
+        .is_err());
+
+    // An order that costs more than the balance is not paid.
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    let order = get_order(&client, order_id).await;
+    put(
+        &client,
+        &format!("/order/{}/pay_from_balance", order.public_id),
+    )
+    .await;
+    assert!(!get_order(&client, order_id).await.paid);
+    assert_eq!(account_balance_sat(&client, buyer_user_id).await, 800);
+    assert_eq!(account_balance_sat(&client, other_seller_user_id).await, 0);
+}
+
+fn market_withdrawal(user_id: i32, amount_sat: u64) -> Withdrawal {
+    Withdrawal {
+        id: None,
+        public_id: crate::util::create_uuid(),
+        user_id,
+        amount_sat,
+        invoice_hash: crate::util::create_uuid(),
+        invoice_payment_request: "".to_string(),
+        created_time_ms: crate::util::current_time_millis(),
+        status: WithdrawalStatus::Pending,
+        failure_reason: "".to_string(),
+        fee_limit_sat: 10,
+        fee_sat: 0,
+        fee_paid_by_user: false,
+        from_market_revenue: true,
+        keysend_pubkey: "".to_string(),
+    }
+}
+
+#[rocket::async_test]
+async fn test_market_withdrawal_node_balance() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = seller_with_balance(&client, 100000).await;
+    let mut conn = db_conn(&client).await;
+    assert_eq!(
+        AccountInfo::total_market_revenue_sat(&mut conn)
+            .await
+            .unwrap(),
+        5000
+    );
+    let total_market_liabilities_sat = AccountInfo::total_market_liabilities_sat(&mut conn)
+        .await
+        .unwrap();
+    let channel_balance_sat: u64 = (total_market_liabilities_sat + 1500).try_into().unwrap();
+
+    // Withdrawals that are not sent yet count against the node balance, even
+    // when the market revenue would cover them.
+    Withdrawal::insert_pending(
+        market_withdrawal(seller_user_id, 1000),
+        &mut conn,
+        10,
+        0,
+        Some(channel_balance_sat),
+    )
+    .await
+    .unwrap();
+    assert!(Withdrawal::insert_pending(
+        market_withdrawal(seller_user_id, 1000),
+        &mut conn,
+        10,
+        0,
+        Some(channel_balance_sat),
+    )
+    .await
+    .is_err());
+    let num_withdrawals: i64 = sqlx::query_scalar("select count(id) from withdrawals")
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap();
+    assert_eq!(num_withdrawals, 1);
+}
+
+async fn run_auto_payouts(client: &asynchronous::Client) {
+    auto_payout::run_auto_payouts(
+        lightning_backend(client),
+        Arc::new(payout_resolver::MockPayoutResolver {}),
+        db_conn(client).await,
+    )
+    .await
+    .unwrap();
+}
+
+#[rocket::async_test]
+async fn test_auto_payout() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = seller_with_balance(&client, 10000).await;
+    post_form(
+        &client,
+        "/update_auto_payout/change",
+        &[
+            ("auto_payout_enabled", "true"),
+            ("auto_payout_threshold_sat", "5000"),
+            ("auto_payout_target", "seller@example.com"),
+        ],
+    )
+    .await;
+
+    // A target that cannot be resolved does not record any withdrawal, so
+    // it does not use up the daily withdrawal limit.
+    sqlx::query("update usersettings set auto_payout_target = 'unresolvable' where user_id = ?")
+        .bind(seller_user_id)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        run_auto_payouts(&client).await;
+    }
+    let num_withdrawals: i64 = sqlx::query_scalar("select count(id) from withdrawals")
+        .fetch_one(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    assert_eq!(num_withdrawals, 0);
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 9500);
+
+    // Once the target resolves, the whole balance is paid out.
+    sqlx::query(
+        "update usersettings set auto_payout_target = 'seller@example.com' where user_id = ?",
+    )
+    .bind(seller_user_id)
+    .execute(&mut *db_conn(&client).await)
+    .await
+    .unwrap();
+    run_auto_payouts(&client).await;
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 0);
+}
+
+#[rocket::async_test]
+async fn test_dispute() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = signup_active_user(&client, "seller@example.com").await;
+    let buyer_user_id = signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    pay_order(&client, order_id).await;
+    let order_public_id = get_order(&client, order_id).await.public_id;
+    let open_uri = format!("/dispute/{}/open", order_public_id);
+
+    // Only the buyer can open a dispute, once the order is shipped.
+    post_form(&client, &open_uri, &[("text", "Item never shipped.")]).await;
+    assert!(!get_order(&client, order_id).await.disputed);
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    put(&client, &format!("/order/{}/ship", order_public_id)).await;
+    post_form(&client, &open_uri, &[("text", "Buyer never answers.")]).await;
+    assert!(!get_order(&client, order_id).await.disputed);
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    post_form(&client, &open_uri, &[("text", "Item never arrived.")]).await;
+    assert!(get_order(&client, order_id).await.disputed);
+
+    // A disputed order cannot be finalized by the buyer.
+    put(&client, &format!("/order/{}/finalize", order_public_id)).await;
+    assert!(!get_order(&client, order_id).await.finalized);
+
+    // The admin splits the payment, and the seller credit is capped by the
+    // part that is not refunded.
+    login_as_admin(&client).await;
+    let resolve_uri = format!("/dispute/{}/resolve", order_public_id);
+    post_form(&client, &resolve_uri, &[("buyer_refund_sat", "1001")]).await;
+    assert!(!get_order(&client, order_id).await.dispute_resolved);
+    post_form(&client, &resolve_uri, &[("buyer_refund_sat", "600")]).await;
+    let order = get_order(&client, order_id).await;
+    assert!(order.dispute_resolved);
+    assert_eq!(order.dispute_buyer_refund_sat, 600);
+    assert_eq!(order.dispute_seller_credit_sat, 400);
+    assert_eq!(account_balance_sat(&client, buyer_user_id).await, 600);
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 400);
+
+    // A resolved dispute cannot be resolved again.
+    post_form(&client, &resolve_uri, &[("buyer_refund_sat", "0")]).await;
+    assert_eq!(
+        get_order(&client, order_id).await.dispute_buyer_refund_sat,
+        600
+    );
+}
+
+#[rocket::async_test]
+async fn test_order_finalization() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = seller_with_balance(&client, 1000).await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    pay_order(&client, order_id).await;
+    let order_public_id = get_order(&client, order_id).await.public_id;
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    put(&client, &format!("/order/{}/ship", order_public_id)).await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    post_form(
+        &client,
+        &format!("/order/{}/new_review", order_public_id),
+        &[("review_rating", "5"), ("review_text", "Fast shipping.")],
+    )
+    .await;
+
+    // A shipped order is only finalized once the auto-finalize period has
+    // passed since it was shipped.
+    order_finalization::finalize_expired_orders(db_conn(&client).await)
+        .await
+        .unwrap();
+    assert!(!get_order(&client, order_id).await.finalized);
+    let fifteen_days_ms: i64 = 15 * 24 * 60 * 60 * 1000;
+    sqlx::query("update orders set shipped_time_ms = shipped_time_ms - ? where id = ?")
+        .bind(fifteen_days_ms)
+        .bind(order_id)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    order_finalization::finalize_expired_orders(db_conn(&client).await)
+        .await
+        .unwrap();
+    assert!(get_order(&client, order_id).await.finalized);
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 1900);
+
+    // A seller with enough sales and a good rating has orders finalized as
+    // soon as they are shipped.
+    login_as_admin(&client).await;
+    post_form(
+        &client,
+        "/update_finalization/change",
+        &[
+            ("order_auto_finalize_days", "14"),
+            ("early_finalization_min_amount_sold_sat", "2000"),
+            ("early_finalization_min_rating", "4"),
+        ],
+    )
+    .await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    pay_order(&client, order_id).await;
+    let order_public_id = get_order(&client, order_id).await.public_id;
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    put(&client, &format!("/order/{}/ship", order_public_id)).await;
+    assert!(get_order(&client, order_id).await.finalized);
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 2850);
+}
+
+async fn listing_remaining_quantity(client: &asynchronous::Client, listing_id: &str) -> u32 {
+    let listing_id = id_by_public_id(client, "listings", listing_id).await;
+    Listing::remaining_quantity(&mut *db_conn(client).await, listing_id)
+        .await
+        .unwrap()
+}
+
+#[rocket::async_test]
+async fn test_listing_stock() {
+    let client = test_client(test_config()).await;
+    signup_active_user(&client, "seller@example.com").await;
+    signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 3).await;
+
+    // Orders reserve stock as soon as they are created, before payment.
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    assert!(create_order(&client, &listing_id, &shipping_option_id, 4)
+        .await
+        .is_none());
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 2)
+        .await
+        .unwrap();
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 1);
+    assert!(create_order(&client, &listing_id, &shipping_option_id, 2)
+        .await
+        .is_none());
+
+    // A canceled order returns its units to the stock.
+    pay_order(&client, order_id).await;
+    let order_public_id = get_order(&client, order_id).await.public_id;
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    put(
+        &client,
+        &format!("/order/{}/seller_cancel", order_public_id),
+    )
+    .await;
+    assert!(get_order(&client, order_id).await.canceled_by_seller);
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 3);
+
+    // The seller can change the stock of the listing.
+    post_form(
+        &client,
+        &format!("/listing/{}/update_quantity", listing_id),
+        &[("quantity", "5")],
+    )
+    .await;
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 5);
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    assert!(create_order(&client, &listing_id, &shipping_option_id, 5)
+        .await
+        .is_some());
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 0);
+}
+
+async fn num_cart_items(client: &asynchronous::Client, user_id: i32) -> i64 {
+    sqlx::query_scalar("select count(id) from cartitems where user_id = ?")
+        .bind(user_id)
+        .fetch_one(&mut *db_conn(client).await)
+        .await
+        .unwrap()
+}
+
+#[rocket::async_test]
+async fn test_cart_checkout() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = signup_active_user(&client, "seller@example.com").await;
+    signup_active_user(&client, "other-seller@example.com").await;
+    let buyer_user_id = signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+    let (second_listing_id, second_shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 500, 10).await;
+    let (other_listing_id, other_shipping_option_id) =
+        create_approved_listing(&client, "other-seller@example.com", 2000, 10).await;
+
+    // Items are only added to the cart if they are in stock.
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    for (listing_id, shipping_option_id, quantity) in [
+        (&listing_id, &shipping_option_id, "11"),
+        (&listing_id, &shipping_option_id, "2"),
+        (&second_listing_id, &second_shipping_option_id, "1"),
+        (&other_listing_id, &other_shipping_option_id, "1"),
+    ] {
+        post_form(
+            &client,
+            &format!("/cart/add/{}", listing_id),
+            &[
+                ("quantity", quantity),
+                ("shipping_option_id", shipping_option_id),
+            ],
+        )
+        .await;
+    }
+    assert_eq!(num_cart_items(&client, buyer_user_id).await, 3);
+
+    // Checking out creates a single order for the items of one seller, and
+    // leaves the items of other sellers in the cart.
+    let location = post_form(
+        &client,
+        "/cart/checkout",
+        &[
+            ("seller_user_id", &seller_user_id.to_string()),
+            ("shipping_instructions", &shipping_instructions()),
+        ],
+    )
+    .await;
+    assert!(location.starts_with("/order/"));
+    assert_eq!(num_cart_items(&client, buyer_user_id).await, 1);
+    let order_id = id_by_public_id(&client, "orders", &public_id_from_location(&location)).await;
+    let order = get_order(&client, order_id).await;
+    assert_eq!(order.seller_user_id, seller_user_id);
+    assert_eq!(order.quantity, 3);
+    assert_eq!(order.amount_owed_sat, 2500);
+    assert_eq!(order.seller_credit_sat, 2375);
+    let order_items = OrderItem::all_for_order(&mut *db_conn(&client).await, order_id)
+        .await
+        .unwrap();
+    assert_eq!(order_items.len(), 2);
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 8);
+    assert_eq!(
+        listing_remaining_quantity(&client, &second_listing_id).await,
+        9
+    );
+
+    // The order is paid and finalized like any other.
+    pay_order(&client, order_id).await;
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    put(&client, &format!("/order/{}/ship", order.public_id)).await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    put(&client, &format!("/order/{}/finalize", order.public_id)).await;
+    assert_eq!(account_balance_sat(&client, seller_user_id).await, 2375);
+}
+
+async fn add_to_cart(
+    client: &asynchronous::Client,
+    listing_id: &str,
+    shipping_option_id: &str,
+    variant_id: &str,
+    quantity: u32,
+) -> String {
+    post_form(
+        client,
+        &format!("/cart/add/{}", listing_id),
+        &[
+            ("quantity", &quantity.to_string()),
+            ("shipping_option_id", shipping_option_id),
+            ("variant_id", variant_id),
+        ],
+    )
+    .await
+}
+
+#[rocket::async_test]
+async fn test_listing_variants() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = signup_active_user(&client, "seller@example.com").await;
+    let buyer_user_id = signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_listing(&client, "seller@example.com", 1000, 1, false).await;
+    for (title, price_sat) in [("Small", "1000"), ("Large", "1500")] {
+        post_form(
+            &client,
+            &format!("/update_listing_variants/{}/add_variant", listing_id),
+            &[
+                ("title", title),
+                ("price_sat", price_sat),
+                ("quantity", "2"),
+            ],
+        )
+        .await;
+    }
+    approve_listing(&client, &listing_id).await;
+    let variant_id = |title: &'static str| {
+        let client = &client;
+        async move {
+            sqlx::query_scalar::<_, String>("select public_id from listingvariants where title = ?")
+                .bind(title)
+                .fetch_one(&mut *db_conn(client).await)
+                .await
+                .unwrap()
+        }
+    };
+    let small_variant_id = variant_id("Small").await;
+    let large_variant_id = variant_id("Large").await;
+
+    // The stock of the listing is the total stock of its variants, and each
+    // variant has its own price.
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 4);
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    assert_eq!(
+        add_to_cart(&client, &listing_id, &shipping_option_id, "", 1).await,
+        format!("/listing/{}", listing_id)
+    );
+    assert_eq!(
+        add_to_cart(
+            &client,
+            &listing_id,
+            &shipping_option_id,
+            &small_variant_id,
+            3
+        )
+        .await,
+        format!("/listing/{}", listing_id)
+    );
+    add_to_cart(
+        &client,
+        &listing_id,
+        &shipping_option_id,
+        &small_variant_id,
+        2,
+    )
+    .await;
+
+    // Variants of the same listing together cannot take more than the stock
+    // of the listing.
+    sqlx::query("update listings set quantity = 3 where public_id = ?")
+        .bind(&listing_id)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    assert_eq!(
+        add_to_cart(
+            &client,
+            &listing_id,
+            &shipping_option_id,
+            &large_variant_id,
+            2
+        )
+        .await,
+        format!("/listing/{}", listing_id)
+    );
+    add_to_cart(
+        &client,
+        &listing_id,
+        &shipping_option_id,
+        &large_variant_id,
+        1,
+    )
+    .await;
+    assert_eq!(num_cart_items(&client, buyer_user_id).await, 2);
+    sqlx::query("update listings set quantity = 2 where public_id = ?")
+        .bind(&listing_id)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    let checkout_fields = [
+        ("seller_user_id", seller_user_id.to_string()),
+        ("shipping_instructions", shipping_instructions()),
+    ];
+    let checkout_fields = checkout_fields
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        post_form(&client, "/cart/checkout", &checkout_fields).await,
+        "/cart"
+    );
+
+    // With enough stock, the order is charged the price of each variant.
+    sqlx::query("update listings set quantity = 3 where public_id = ?")
+        .bind(&listing_id)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    let location = post_form(&client, "/cart/checkout", &checkout_fields).await;
+    assert!(location.starts_with("/order/"));
+    let order_id = id_by_public_id(&client, "orders", &public_id_from_location(&location)).await;
+    assert_eq!(get_order(&client, order_id).await.amount_owed_sat, 3500);
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 0);
+}
+
+const BUYER_PGP_PUBLIC_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+xjMEatSfTxYJKwYBBAHaRw8BAQdAUbosKog6ZEF2AmYd139lJoraMWNMWurMXDAm
+V+Ksac/NHlRlc3QgQnV5ZXIgPGJ1eWVyQGV4YW1wbGUuY29tPsKEBBAWCAAsAhkB
+BQJq1J9PAhsDAgsHAhUIAhYCFiEE4PXHO15z38QXLLEm+f5gJGUKPyYACgkQ+f5g
+JGUKPya/ygD8DtwHZS2hOuoSCGRajhgk3tneBgZv8WQNzbPjKTqYm/gBAJWSeSwT
+Ty9VP3TOdHVRg0GQoPdAGErn519P/u/18eMMzjgEatSfTxIKKwYBBAGXVQEFAQEH
+QOtQTng4TYy3rKQIs8OqZeXIW97hBGJJJWikokWe8T0nAwEIB8J4BBgWCAAgBQJq
+1J9PAhsMFiEE4PXHO15z38QXLLEm+f5gJGUKPyYACgkQ+f5gJGUKPyaocAD/Qbbe
+ooDAKO/6oSypr3hxE4lo2uMbE+R6Wvt8J/iln4wA/i7fk+h0CtIw1M4cz4XY01Bv
++1eWVXPdiExOEgAE/6IN
+=bFSf
+-----END PGP PUBLIC KEY BLOCK-----
+";
+
+#[rocket::async_test]
+async fn test_digital_delivery() {
+    let client = test_client(test_config()).await;
+    signup_active_user(&client, "seller@example.com").await;
+    signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_listing(&client, "seller@example.com", 1000, 0, true).await;
+    post_form(
+        &client,
+        &format!("/update_digital_payloads/{}/add_codes", listing_id),
+        &[("codes", "code-1\ncode-2\ncode-3")],
+    )
+    .await;
+    approve_listing(&client, &listing_id).await;
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 3);
+
+    // Digital items can only be bought with a PGP key to encrypt them to.
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    assert!(create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .is_none());
+    post_form(
+        &client,
+        "/update_user_pgp_info/change",
+        &[("pgp_key", BUYER_PGP_PUBLIC_KEY)],
+    )
+    .await;
+
+    // Two items of the same listing in one order get different payloads.
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    sqlx::query(
+        "insert into orderitems (order_id, listing_id, quantity, price_sat, fee_rate_basis_points, market_fee_sat, variant_id, variant_title) select order_id, listing_id, quantity, price_sat, fee_rate_basis_points, market_fee_sat, variant_id, variant_title from orderitems where order_id = ?",
+    )
+    .bind(order_id)
+    .execute(&mut *db_conn(&client).await)
+    .await
+    .unwrap();
+
+    // The order is delivered and shipped as soon as it is paid.
+    pay_order(&client, order_id).await;
+    wait_until(|| async { get_order(&client, order_id).await.shipped }).await;
+    let digital_payloads = DigitalPayload::all_for_order(&mut *db_conn(&client).await, order_id)
+        .await
+        .unwrap();
+    assert_eq!(digital_payloads.len(), 2);
+    assert_ne!(digital_payloads[0].id, digital_payloads[1].id);
+    for digital_payload in &digital_payloads {
+        assert!(digital_payload
+            .encrypted_payload
+            .starts_with("-----BEGIN PGP MESSAGE-----"));
+        assert!(digital_payload.payload_data.is_empty());
+    }
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 1);
+}
+
+#[rocket::async_test]
+async fn test_shipping_deadline() {
+    let client = test_client(test_config()).await;
+    let seller_user_id = signup_active_user(&client, "seller@example.com").await;
+    let buyer_user_id = signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+    post_form(
+        &client,
+        "/update_shipping_deadline/change",
+        &[("order_shipping_deadline_days", "3")],
+    )
+    .await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let shipped_order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+    let unshipped_order_id = create_order(&client, &listing_id, &shipping_option_id, 2)
+        .await
+        .unwrap();
+    for order_id in [shipped_order_id, unshipped_order_id] {
+        pay_order(&client, order_id).await;
+    }
+    login(&client, "seller@example.com", TEST_USER_PASSWORD).await;
+    let shipped_order = get_order(&client, shipped_order_id).await;
+    put(&client, &format!("/order/{}/ship", shipped_order.public_id)).await;
+    let remove_expired_orders = || async {
+        order_expiry::remove_expired_orders(lightning_backend(&client), db_conn(&client).await)
+            .await
+            .unwrap();
+    };
+
+    // Orders are not canceled before the deadline.
+    remove_expired_orders().await;
+    assert!(
+        !get_order(&client, unshipped_order_id)
+            .await
+            .shipping_expired
+    );
+
+    // After the deadline, the unshipped order is canceled and refunded to
+    // the buyer, and counts against the seller.
+    let four_days_ms: i64 = 4 * 24 * 60 * 60 * 1000;
+    sqlx::query("update orders set payment_time_ms = payment_time_ms - ?")
+        .bind(four_days_ms)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    remove_expired_orders().await;
+    let unshipped_order = get_order(&client, unshipped_order_id).await;
+    assert!(unshipped_order.shipping_expired);
+    assert!(unshipped_order.canceled_by_seller);
+    assert!(!get_order(&client, shipped_order_id).await.shipping_expired);
+    assert_eq!(account_balance_sat(&client, buyer_user_id).await, 2000);
+    assert_eq!(listing_remaining_quantity(&client, &listing_id).await, 9);
+    let seller_info = Order::seller_info_for_user(&mut *db_conn(&client).await, seller_user_id)
+        .await
+        .unwrap();
+    assert_eq!(seller_info.num_shipping_strikes, 1);
+}
+
+#[rocket::async_test]
+async fn test_reconcile_unpaid_invoices() {
+    let client = test_client(test_config()).await;
+    signup_active_user(&client, "seller@example.com").await;
+    signup_active_user(&client, "buyer@example.com").await;
+    let unpaid_user_id = signup_user(&client, "unpaid@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 1000, 10).await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let order_id = create_order(&client, &listing_id, &shipping_option_id, 1)
+        .await
+        .unwrap();
+
+    // A node that is not watched by any invoice subscription settles the
+    // order invoice, more than a page of invoices back, and then the account
+    // invoice.
+    let lightning_backend = Arc::new(MockLightningBackend::new());
+    let order_invoice = lightning_backend.create_invoice(1000, 3600).await.unwrap();
+    lightning_backend
+        .receive_payment(&order_invoice.r_hash)
+        .unwrap();
+    for _ in 0..payment_processor::LIST_INVOICES_PAGE_SIZE {
+        lightning_backend.create_invoice(1, 3600).await.unwrap();
+    }
+    let account_invoice = lightning_backend.create_invoice(1, 3600).await.unwrap();
+    lightning_backend
+        .receive_payment(&account_invoice.r_hash)
+        .unwrap();
+    for (table, invoice, id_column, id) in [
+        ("orders", &order_invoice, "id", order_id),
+        ("useraccounts", &account_invoice, "user_id", unpaid_user_id),
+    ] {
+        sqlx::query(&format!(
+            "update {} set invoice_hash = ? where {} = ?",
+            table, id_column
+        ))
+        .bind(crate::util::to_hex(&invoice.r_hash))
+        .bind(id)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    }
+    assert!(!get_order(&client, order_id).await.paid);
+    assert!(!user_account_paid(&client, unpaid_user_id).await);
+
+    // Both are marked as paid when the payment processor starts.
+    let payment_processor_task = rocket::tokio::spawn(payment_processor::handle_received_payments(
+        lightning_backend,
+        db_conn(&client).await,
+    ));
+    wait_until(|| async { get_order(&client, order_id).await.paid }).await;
+    wait_until(|| user_account_paid(&client, unpaid_user_id)).await;
+    payment_processor_task.abort();
+}
+
+#[rocket::async_test]
+async fn test_onchain_payments() {
+    let config = Config {
+        onchain_payments: true,
+        ..test_config()
+    };
+    let client = test_client(config.clone()).await;
+    signup_active_user(&client, "seller@example.com").await;
+    let buyer_user_id = signup_active_user(&client, "buyer@example.com").await;
+    let (listing_id, shipping_option_id) =
+        create_approved_listing(&client, "seller@example.com", 10000, 10).await;
+    login(&client, "buyer@example.com", TEST_USER_PASSWORD).await;
+    let mut order_ids = vec![];
+    for _ in 0..3 {
+        order_ids.push(
+            create_order_with_payment_method(&client, &listing_id, &shipping_option_id, 1, true)
+                .await
+                .unwrap(),
+        );
+    }
+    let (underpaid_order_id, overpaid_order_id, expired_order_id) =
+        (order_ids[0], order_ids[1], order_ids[2]);
+    let amount_owed_sat = get_order(&client, underpaid_order_id).await.amount_owed_sat;
+    let tolerance_sat =
+        amount_owed_sat * config.onchain_underpayment_tolerance_basis_points / 10000;
+    let overpayment_sat =
+        amount_owed_sat * config.onchain_overpayment_tolerance_basis_points / 10000 + 1;
+
+    // The payments are made to a wallet that the market only sees through
+    // `reconcile_onchain_payments`.
+    let wallet = MockLightningBackend::new();
+    for (order_id, amount_sat) in [
+        (underpaid_order_id, amount_owed_sat - tolerance_sat),
+        (overpaid_order_id, amount_owed_sat + overpayment_sat),
+        (expired_order_id, amount_owed_sat / 2),
+    ] {
+        let order = get_order(&client, order_id).await;
+        assert!(!order.onchain_address.is_empty());
+        wallet.receive_onchain_payment(
+            &order.onchain_address,
+            amount_sat,
+            config.onchain_min_confirmations as i32,
+        );
+    }
+    payment_processor::reconcile_onchain_payments(&mut db_conn(&client).await, &wallet, &config)
+        .await
+        .unwrap();
+
+    // An underpayment within the tolerance pays for the order.
+    let underpaid_order = get_order(&client, underpaid_order_id).await;
+    assert!(underpaid_order.paid);
+    assert_eq!(underpaid_order.onchain_buyer_credit_sat, 0);
+
+    // An overpayment beyond the tolerance is credited back to the buyer.
+    let overpaid_order = get_order(&client, overpaid_order_id).await;
+    assert!(overpaid_order.paid);
+    assert_eq!(overpaid_order.onchain_buyer_credit_sat, overpayment_sat);
+    assert_eq!(
+        account_balance_sat(&client, buyer_user_id).await,
+        overpayment_sat as i64
+    );
+
+    // An underpaid order is closed when its payment window ends, and the
+    // amount received is credited back to the buyer.
+    let expired_order = get_order(&client, expired_order_id).await;
+    assert!(!expired_order.paid);
+    assert_eq!(expired_order.onchain_received_sat, amount_owed_sat / 2);
+    sqlx::query("update orders set invoice_expiry_time_ms = ? where id = ?")
+        .bind((crate::util::current_time_millis() - 1) as i64)
+        .bind(expired_order_id)
+        .execute(&mut *db_conn(&client).await)
+        .await
+        .unwrap();
+    order_expiry::remove_expired_orders(lightning_backend(&client), db_conn(&client).await)
+        .await
+        .unwrap();
+    let expired_order = get_order(&client, expired_order_id).await;
+    assert!(expired_order.onchain_expired);
+    assert!(!expired_order.paid);
+    assert_eq!(expired_order.onchain_buyer_credit_sat, amount_owed_sat / 2);
+    assert_eq!(
+        account_balance_sat(&client, buyer_user_id).await,
+        (overpayment_sat + amount_owed_sat / 2) as i64
+    );
+}
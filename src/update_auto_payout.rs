@@ -0,0 +1,127 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::models::{AutoPayoutInput, UserSettings};
+use crate::payout_resolver;
+use crate::user_account::ActiveUser;
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+
+const MIN_AUTO_PAYOUT_THRESHOLD_SAT: u64 = 1000;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    user_settings: UserSettings,
+    min_auto_payout_threshold_sat: u64,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let user_settings = UserSettings::single(&mut db, user.id())
+            .await
+            .map_err(|_| "failed to get user settings.")?;
+
+        Ok(Context {
+            base_context,
+            flash,
+            user_settings,
+            min_auto_payout_threshold_sat: MIN_AUTO_PAYOUT_THRESHOLD_SAT,
+        })
+    }
+}
+
+#[post("/change", data = "<auto_payout_form>")]
+async fn update(
+    auto_payout_form: Form<AutoPayoutInput>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+) -> Flash<Redirect> {
+    let auto_payout_info = auto_payout_form.into_inner();
+
+    match change_auto_payout(active_user.user, auto_payout_info, &mut db).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_auto_payout", index())),
+            "Auto payout settings successfully updated.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_auto_payout", index())), e),
+    }
+}
+
+async fn change_auto_payout(
+    user: User,
+    auto_payout_info: AutoPayoutInput,
+    db: &mut Connection<Db>,
+) -> Result<(), String> {
+    if user.is_admin {
+        return Err("Admin user cannot withdraw funds.".to_string());
+    }
+    let auto_payout_threshold_sat = auto_payout_info
+        .auto_payout_threshold_sat
+        .unwrap_or(MIN_AUTO_PAYOUT_THRESHOLD_SAT);
+    if auto_payout_threshold_sat < MIN_AUTO_PAYOUT_THRESHOLD_SAT {
+        return Err(format!(
+            "Threshold must be at least {} sats.",
+            MIN_AUTO_PAYOUT_THRESHOLD_SAT
+        ));
+    }
+    let auto_payout_target = auto_payout_info.auto_payout_target.trim();
+    if auto_payout_target.is_empty() {
+        let user_settings = UserSettings::single(db, user.id())
+            .await
+            .map_err(|_| "failed to get user settings.")?;
+        if auto_payout_info.auto_payout_enabled && user_settings.payout_node_pubkey.is_empty() {
+            return Err(
+                "Register a payout node, or enter a Lightning address or LNURL.".to_string(),
+            );
+        }
+    } else {
+        payout_resolver::lnurl_pay_url(auto_payout_target)?;
+    }
+    UserSettings::set_auto_payout(
+        db,
+        user.id(),
+        auto_payout_info.auto_payout_enabled,
+        auto_payout_threshold_sat,
+        auto_payout_target,
+    )
+    .await
+    .map_err(|_| "failed to update auto payout settings.")?;
+    Ok(())
+}
+
+#[get("/")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    db: Connection<Db>,
+    active_user: ActiveUser,
+    admin_user: Option<AdminUser>,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, flash, active_user.user, admin_user)
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("updateautopayout", context))
+}
+
+pub fn update_auto_payout_stage() -> AdHoc {
+    AdHoc::on_ignite("Update Auto Payout Stage", |rocket| async {
+        rocket.mount("/update_auto_payout", routes![index, update])
+    })
+}
@@ -0,0 +1,318 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::models::{
+    DigitalPayload, DigitalPayloadCodesInput, DigitalPayloadDisplay, FileUploadForm, Listing,
+    ListingDisplay,
+};
+use crate::user_account::ActiveUser;
+use crate::util;
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::fs::TempFile;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+use std::fs;
+
+const MAX_UNDELIVERED_PAYLOADS: usize = 1000;
+const MAX_PAYLOAD_CODE_LENGTH: usize = 1024;
+const MAX_PAYLOAD_FILE_SIZE_BYTES: u64 = 1_000_000;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    listing_display: Option<ListingDisplay>,
+    digital_payload_displays: Vec<DigitalPayloadDisplay>,
+    remaining_quantity: u32,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        listing_id: &str,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let listing_display = ListingDisplay::single_by_public_id(&mut db, listing_id)
+            .await
+            .map_err(|_| "failed to get listing display.")?;
+        if listing_display.listing.user_id == user.id() {
+            let digital_payload_displays = DigitalPayload::all_displays_for_listing(
+                &mut db,
+                listing_display.listing.id.unwrap(),
+            )
+            .await
+            .map_err(|_| "failed to get payloads for listing.")?;
+            let remaining_quantity =
+                Listing::remaining_quantity(&mut db, listing_display.listing.id.unwrap())
+                    .await
+                    .map_err(|_| "failed to get remaining quantity of listing.")?;
+            Ok(Context {
+                base_context,
+                flash,
+                listing_display: Some(listing_display),
+                digital_payload_displays,
+                remaining_quantity,
+            })
+        } else {
+            error_!("Listing belongs to other user.");
+            Ok(Context {
+                base_context,
+                flash: Some(("error".into(), "Listing belongs to other user.".into())),
+                listing_display: None,
+                digital_payload_displays: vec![],
+                remaining_quantity: 0,
+            })
+        }
+    }
+}
+
+#[post("/<id>/add_codes", data = "<codes_form>")]
+async fn new_codes(
+    id: &str,
+    codes_form: Form<DigitalPayloadCodesInput>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+) -> Flash<Redirect> {
+    let codes_input = codes_form.into_inner();
+
+    match add_codes(id, codes_input, &mut db, active_user.user).await {
+        Ok(num_codes) => Flash::success(
+            Redirect::to(uri!("/update_digital_payloads", index(id))),
+            format!("{} codes successfully added.", num_codes),
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_digital_payloads", index(id))), e),
+    }
+}
+
+async fn add_codes(
+    id: &str,
+    codes_input: DigitalPayloadCodesInput,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<usize, String> {
+    let listing = Listing::single_by_public_id(db, id)
+        .await
+        .map_err(|_| "failed to get listing")?;
+    let now = util::current_time_millis();
+
+    // Each non-empty line is a separate code.
+    let codes = codes_input
+        .codes
+        .lines()
+        .map(str::trim)
+        .filter(|code| !code.is_empty())
+        .collect::<Vec<_>>();
+
+    if codes.is_empty() {
+        return Err("Codes cannot be empty.".to_string());
+    };
+    if codes
+        .iter()
+        .any(|code| code.len() > MAX_PAYLOAD_CODE_LENGTH)
+    {
+        return Err("Code length is too long.".to_string());
+    };
+    check_can_add_payloads(db, &listing, &user, codes.len()).await?;
+
+    let digital_payloads = codes
+        .iter()
+        .map(|code| DigitalPayload {
+            id: None,
+            public_id: util::create_uuid(),
+            listing_id: listing.id.unwrap(),
+            payload_name: "code.txt".to_string(),
+            payload_data: code.as_bytes().to_vec(),
+            order_id: 0,
+            encrypted_payload: "".to_string(),
+            created_time_ms: now,
+            delivered_time_ms: 0,
+        })
+        .collect::<Vec<_>>();
+    DigitalPayload::insert_batch(db, listing.id.unwrap(), digital_payloads).await?;
+
+    Ok(codes.len())
+}
+
+#[post("/<id>/add_file", data = "<upload_file_form>")]
+async fn new_file(
+    id: &str,
+    upload_file_form: Form<FileUploadForm<'_>>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+) -> Flash<Redirect> {
+    let file_info = upload_file_form.into_inner();
+    let file = file_info.file;
+
+    match add_file(id, file, &mut db, active_user.user).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_digital_payloads", index(id))),
+            "File successfully added.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_digital_payloads", index(id))), e),
+    }
+}
+
+async fn add_file(
+    id: &str,
+    tmp_file: TempFile<'_>,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let listing = Listing::single_by_public_id(db, id)
+        .await
+        .map_err(|_| "failed to get listing")?;
+    let now = util::current_time_millis();
+
+    if tmp_file.len() == 0 {
+        return Err("File is empty.".to_string());
+    };
+    if tmp_file.len() > MAX_PAYLOAD_FILE_SIZE_BYTES {
+        return Err("File is too large.".to_string());
+    };
+    check_can_add_payloads(db, &listing, &user, 1).await?;
+
+    let payload_name = match (
+        tmp_file.name(),
+        tmp_file
+            .content_type()
+            .and_then(|content_type| content_type.extension()),
+    ) {
+        (Some(name), Some(extension)) => format!("{}.{}", name, extension),
+        (Some(name), None) => name.to_string(),
+        _ => "payload".to_string(),
+    };
+    let path = tmp_file.path().ok_or("Path not found.")?;
+    let payload_data = fs::read(path).map_err(|_| "Unable to read bytes")?;
+
+    let digital_payload = DigitalPayload {
+        id: None,
+        public_id: util::create_uuid(),
+        listing_id: listing.id.unwrap(),
+        payload_name,
+        payload_data,
+        order_id: 0,
+        encrypted_payload: "".to_string(),
+        created_time_ms: now,
+        delivered_time_ms: 0,
+    };
+    DigitalPayload::insert_batch(db, listing.id.unwrap(), vec![digital_payload]).await?;
+
+    Ok(())
+}
+
+async fn check_can_add_payloads(
+    db: &mut Connection<Db>,
+    listing: &Listing,
+    user: &User,
+    num_new_payloads: usize,
+) -> Result<(), String> {
+    if listing.user_id != user.id() {
+        return Err("Listing belongs to a different user.".to_string());
+    };
+    if !listing.digital {
+        return Err("Listing is not a digital listing.".to_string());
+    };
+    let digital_payload_displays =
+        DigitalPayload::all_displays_for_listing(db, listing.id.unwrap())
+            .await
+            .map_err(|_| "failed to get payloads for listing")?;
+    let num_undelivered_payloads = digital_payload_displays
+        .iter()
+        .filter(|digital_payload_display| !digital_payload_display.delivered)
+        .count();
+    if num_undelivered_payloads + num_new_payloads > MAX_UNDELIVERED_PAYLOADS {
+        return Err("Maximum number of payloads already exist.".to_string());
+    };
+    Ok(())
+}
+
+#[delete("/<id>/payload/<payload_id>")]
+async fn delete(
+    id: &str,
+    payload_id: &str,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match delete_payload(id, payload_id, &mut db, active_user.user).await {
+        Ok(_) => Ok(Flash::success(
+            Redirect::to(uri!("/update_digital_payloads", index(id))),
+            "Payload was deleted.",
+        )),
+        Err(e) => {
+            error_!("DB deletion({}) error: {}", id, e);
+            Err(Flash::error(
+                Redirect::to(uri!("/update_digital_payloads", index(id))),
+                e,
+            ))
+        }
+    }
+}
+
+async fn delete_payload(
+    listing_id: &str,
+    payload_id: &str,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let listing = Listing::single_by_public_id(&mut *db, listing_id)
+        .await
+        .map_err(|_| "failed to get listing")?;
+    let digital_payload = DigitalPayload::single_by_public_id(&mut *db, payload_id)
+        .await
+        .map_err(|_| "failed to get payload")?;
+    let remaining_quantity = Listing::remaining_quantity(&mut *db, listing.id.unwrap())
+        .await
+        .map_err(|_| "failed to get remaining quantity of listing.")?;
+
+    if digital_payload.listing_id != listing.id.unwrap() {
+        return Err("Invalid listing id given.".to_string());
+    };
+    if listing.user_id != user.id() {
+        return Err("Listing belongs to a different user.".to_string());
+    };
+    if digital_payload.order_id != 0 {
+        return Err("Payload was already delivered.".to_string());
+    };
+    // Payloads that are not delivered yet may still be needed by unpaid orders.
+    if remaining_quantity == 0 {
+        return Err("All remaining payloads are reserved by orders.".to_string());
+    };
+
+    DigitalPayload::delete(&mut *db, &digital_payload).await?;
+
+    Ok(())
+}
+
+#[get("/<id>")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    id: &str,
+    db: Connection<Db>,
+    active_user: ActiveUser,
+    admin_user: Option<AdminUser>,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, id, flash, active_user.user, admin_user)
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("updatedigitalpayloads", context))
+}
+
+pub fn update_digital_payloads_stage() -> AdHoc {
+    AdHoc::on_ignite("Update Digital Payloads Stage", |rocket| async {
+        rocket.mount(
+            "/update_digital_payloads",
+            routes![index, new_codes, new_file, delete],
+        )
+    })
+}
@@ -0,0 +1,121 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::models::{AdminSettings, FinalizationInput};
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+
+const MIN_AUTO_FINALIZE_DAYS: u32 = 1;
+const MAX_AUTO_FINALIZE_DAYS: u32 = 90;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    admin_settings: AdminSettings,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let admin_settings = AdminSettings::single(&mut db)
+            .await
+            .map_err(|_| "failed to get admin settings.")?;
+        Ok(Context {
+            base_context,
+            flash,
+            admin_settings,
+        })
+    }
+}
+
+#[post("/change", data = "<finalization_form>")]
+async fn update(
+    finalization_form: Form<FinalizationInput>,
+    mut db: Connection<Db>,
+    _user: User,
+    _admin_user: AdminUser,
+) -> Flash<Redirect> {
+    let finalization_input = finalization_form.into_inner();
+
+    match change_finalization(finalization_input, &mut db).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_finalization", index())),
+            "Order finalization settings successfully updated.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_finalization", index())), e),
+    }
+}
+
+async fn change_finalization(
+    finalization_input: FinalizationInput,
+    db: &mut Connection<Db>,
+) -> Result<(), String> {
+    let new_order_auto_finalize_days = finalization_input.order_auto_finalize_days.unwrap_or(0);
+    let new_early_finalization_min_amount_sold_sat = finalization_input
+        .early_finalization_min_amount_sold_sat
+        .unwrap_or(0);
+    let new_early_finalization_min_rating = finalization_input
+        .early_finalization_min_rating
+        .unwrap_or(0);
+
+    if new_order_auto_finalize_days < MIN_AUTO_FINALIZE_DAYS {
+        return Err(format!(
+            "Auto-finalize period cannot be less than {} days.",
+            MIN_AUTO_FINALIZE_DAYS
+        ));
+    };
+    if new_order_auto_finalize_days > MAX_AUTO_FINALIZE_DAYS {
+        return Err(format!(
+            "Auto-finalize period cannot be more than {} days.",
+            MAX_AUTO_FINALIZE_DAYS
+        ));
+    };
+    if !(1..=5).contains(&new_early_finalization_min_rating) {
+        return Err("Minimum rating must be between 1 and 5.".to_string());
+    };
+
+    AdminSettings::set_finalization(
+        db,
+        new_order_auto_finalize_days,
+        new_early_finalization_min_amount_sold_sat,
+        new_early_finalization_min_rating,
+    )
+    .await
+    .map_err(|_| "failed to update order finalization settings.")?;
+
+    Ok(())
+}
+
+#[get("/")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    db: Connection<Db>,
+    user: User,
+    admin_user: AdminUser,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, flash, user, Some(admin_user))
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("updatefinalization", context))
+}
+
+pub fn update_finalization_stage() -> AdHoc {
+    AdHoc::on_ignite("Update Finalization Stage", |rocket| async {
+        rocket.mount("/update_finalization", routes![index, update])
+    })
+}
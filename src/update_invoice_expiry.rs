@@ -0,0 +1,120 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::models::{AdminSettings, InvoiceExpiryInput};
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+
+const MIN_INVOICE_EXPIRY_S: u32 = 60;
+// One week.
+const MAX_INVOICE_EXPIRY_S: u32 = 604800;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    admin_settings: AdminSettings,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let admin_settings = AdminSettings::single(&mut db)
+            .await
+            .map_err(|_| "failed to get admin settings.")?;
+        Ok(Context {
+            base_context,
+            flash,
+            admin_settings,
+        })
+    }
+}
+
+#[post("/change", data = "<invoice_expiry_form>")]
+async fn update(
+    invoice_expiry_form: Form<InvoiceExpiryInput>,
+    mut db: Connection<Db>,
+    _user: User,
+    _admin_user: AdminUser,
+) -> Flash<Redirect> {
+    let invoice_expiry_input = invoice_expiry_form.into_inner();
+
+    match change_invoice_expiry(invoice_expiry_input, &mut db).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_invoice_expiry", index())),
+            "Invoice expiry successfully updated.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_invoice_expiry", index())), e),
+    }
+}
+
+async fn change_invoice_expiry(
+    invoice_expiry_input: InvoiceExpiryInput,
+    db: &mut Connection<Db>,
+) -> Result<(), String> {
+    let new_order_invoice_expiry_s = invoice_expiry_input.order_invoice_expiry_s.unwrap_or(0);
+    let new_user_account_invoice_expiry_s = invoice_expiry_input
+        .user_account_invoice_expiry_s
+        .unwrap_or(0);
+
+    for expiry_s in [
+        new_order_invoice_expiry_s,
+        new_user_account_invoice_expiry_s,
+    ] {
+        if expiry_s < MIN_INVOICE_EXPIRY_S {
+            return Err(format!(
+                "Invoice expiry cannot be less than {} seconds.",
+                MIN_INVOICE_EXPIRY_S
+            ));
+        };
+        if expiry_s > MAX_INVOICE_EXPIRY_S {
+            return Err(format!(
+                "Invoice expiry cannot be more than {} seconds.",
+                MAX_INVOICE_EXPIRY_S
+            ));
+        };
+    }
+
+    AdminSettings::set_invoice_expiry(
+        db,
+        new_order_invoice_expiry_s,
+        new_user_account_invoice_expiry_s,
+    )
+    .await
+    .map_err(|_| "failed to update invoice expiry.")?;
+
+    Ok(())
+}
+
+#[get("/")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    db: Connection<Db>,
+    user: User,
+    admin_user: AdminUser,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, flash, user, Some(admin_user))
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("updateinvoiceexpiry", context))
+}
+
+pub fn update_invoice_expiry_stage() -> AdHoc {
+    AdHoc::on_ignite("Update Invoice Expiry Stage", |rocket| async {
+        rocket.mount("/update_invoice_expiry", routes![index, update])
+    })
+}
@@ -0,0 +1,257 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::models::{
+    Listing, ListingDisplay, ListingQuantityInput, ListingVariant, ListingVariantInfo,
+};
+use crate::new_listing::MAX_LISTING_QUANTITY;
+use crate::user_account::ActiveUser;
+use crate::util;
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+
+const MAX_LISTING_VARIANTS: usize = 10;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    listing_display: Option<ListingDisplay>,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        listing_id: &str,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let listing_display = ListingDisplay::single_by_public_id(&mut db, listing_id)
+            .await
+            .map_err(|_| "failed to get listing display.")?;
+        if listing_display.listing.user_id == user.id() {
+            Ok(Context {
+                base_context,
+                flash,
+                listing_display: Some(listing_display),
+            })
+        } else {
+            error_!("Listing belongs to other user.");
+            Ok(Context {
+                base_context,
+                flash: Some(("error".into(), "Listing belongs to other user.".into())),
+                listing_display: None,
+            })
+        }
+    }
+}
+
+#[post("/<id>/add_variant", data = "<variant_form>")]
+async fn new(
+    id: &str,
+    variant_form: Form<ListingVariantInfo>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+) -> Flash<Redirect> {
+    let variant_info = variant_form.into_inner();
+
+    match add_variant(id, variant_info, &mut db, active_user.user).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_listing_variants", index(id))),
+            "Variant successfully added.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_listing_variants", index(id))), e),
+    }
+}
+
+async fn add_variant(
+    id: &str,
+    variant_info: ListingVariantInfo,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let listing = Listing::single_by_public_id(db, id)
+        .await
+        .map_err(|_| "failed to get listing")?;
+    let variants = ListingVariant::all_for_listing(db, listing.id.unwrap())
+        .await
+        .map_err(|_| "failed to get variants for listing")?;
+    let title = variant_info.title;
+    let price_sat = variant_info.price_sat.unwrap_or(0);
+    let quantity = variant_info.quantity.unwrap_or(0);
+
+    if title.is_empty() {
+        return Err("Title cannot be empty.".to_string());
+    };
+    if title.len() > 64 {
+        return Err("Title length is too long.".to_string());
+    };
+    if price_sat == 0 {
+        return Err("Price must be a positive number.".to_string());
+    };
+    if quantity > MAX_LISTING_QUANTITY {
+        return Err("Quantity is too high.".to_string());
+    };
+    if listing.user_id != user.id() {
+        return Err("Listing belongs to a different user.".to_string());
+    };
+    if listing.submitted {
+        return Err("Listing is already submitted.".to_string());
+    };
+    // Variant prices are in sats, so they cannot follow the exchange rate.
+    if listing.fiat_price_cents > 0 {
+        return Err("Fiat-pegged listings cannot have variants.".to_string());
+    };
+    // Payloads are delivered for the listing as a whole.
+    if listing.digital {
+        return Err("Digital listings cannot have variants.".to_string());
+    };
+    if variants.len() >= MAX_LISTING_VARIANTS {
+        return Err("Maximum number of variants already exist.".to_string());
+    };
+
+    let variant = ListingVariant {
+        id: None,
+        public_id: util::create_uuid(),
+        listing_id: listing.id.unwrap(),
+        title,
+        price_sat,
+        quantity,
+    };
+    ListingVariant::insert(variant, db).await?;
+
+    Ok(())
+}
+
+#[post("/<id>/variant/<variant_id>/update_quantity", data = "<quantity_form>")]
+async fn update_quantity(
+    id: &str,
+    variant_id: &str,
+    quantity_form: Form<ListingQuantityInput>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+) -> Flash<Redirect> {
+    let quantity_input = quantity_form.into_inner();
+
+    match change_variant_quantity(id, variant_id, quantity_input, &mut db, active_user.user).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_listing_variants", index(id))),
+            "Quantity successfully updated.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_listing_variants", index(id))), e),
+    }
+}
+
+async fn change_variant_quantity(
+    listing_id: &str,
+    variant_id: &str,
+    quantity_input: ListingQuantityInput,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let listing = Listing::single_by_public_id(db, listing_id)
+        .await
+        .map_err(|_| "failed to get listing")?;
+    let variant = ListingVariant::single_by_public_id(db, variant_id)
+        .await
+        .map_err(|_| "failed to get variant")?;
+    let new_quantity = quantity_input.quantity.unwrap_or(0);
+
+    if variant.listing_id != listing.id.unwrap() {
+        return Err("Invalid listing id given.".to_string());
+    };
+    if listing.user_id != user.id() {
+        return Err("Listing belongs to a different user.".to_string());
+    };
+    if new_quantity > MAX_LISTING_QUANTITY {
+        return Err("Quantity is too high.".to_string());
+    };
+
+    ListingVariant::set_quantity(db, &variant, new_quantity).await?;
+
+    Ok(())
+}
+
+#[delete("/<id>/add_variant/<variant_id>")]
+async fn delete(
+    id: &str,
+    variant_id: &str,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match delete_variant(id, variant_id, &mut db, active_user.user).await {
+        Ok(_) => Ok(Flash::success(
+            Redirect::to(uri!("/update_listing_variants", index(id))),
+            "Variant was deleted.",
+        )),
+        Err(e) => {
+            error_!("DB deletion({}) error: {}", id, e);
+            Err(Flash::error(
+                Redirect::to(uri!("/update_listing_variants", index(id))),
+                "Failed to delete variant.",
+            ))
+        }
+    }
+}
+
+async fn delete_variant(
+    listing_id: &str,
+    variant_id: &str,
+    db: &mut Connection<Db>,
+    user: User,
+) -> Result<(), String> {
+    let listing = Listing::single_by_public_id(&mut *db, listing_id)
+        .await
+        .map_err(|_| "failed to get listing")?;
+    let variant = ListingVariant::single_by_public_id(&mut *db, variant_id)
+        .await
+        .map_err(|_| "failed to get variant")?;
+
+    if variant.listing_id != listing.id.unwrap() {
+        return Err("Invalid listing id given.".to_string());
+    };
+    if listing.submitted {
+        return Err("Listing is already submitted.".to_string());
+    };
+    if listing.user_id != user.id() {
+        return Err("Listing belongs to a different user.".to_string());
+    };
+
+    ListingVariant::delete(&mut *db, &variant).await?;
+
+    Ok(())
+}
+
+#[get("/<id>")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    id: &str,
+    db: Connection<Db>,
+    active_user: ActiveUser,
+    admin_user: Option<AdminUser>,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, id, flash, active_user.user, admin_user)
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("updatelistingvariants", context))
+}
+
+pub fn update_listing_variants_stage() -> AdHoc {
+    AdHoc::on_ignite("Update Listing Variants Stage", |rocket| async {
+        rocket.mount(
+            "/update_listing_variants",
+            routes![index, new, update_quantity, delete],
+        )
+    })
+}
@@ -0,0 +1,141 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::lightning::LightningBackend;
+use crate::models::{PayoutNodeInput, UserSettings};
+use crate::user_account::ActiveUser;
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket::State;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+use std::sync::Arc;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    user_settings: UserSettings,
+    payout_node_message: String,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let user_settings = UserSettings::single(&mut db, user.id())
+            .await
+            .map_err(|_| "failed to get user settings.")?;
+        let payout_node_message = payout_node_message(&user);
+
+        Ok(Context {
+            base_context,
+            flash,
+            user_settings,
+            payout_node_message,
+        })
+    }
+}
+
+/// Returns the message that the user signs with their node to prove that
+/// they own it.
+fn payout_node_message(user: &User) -> String {
+    format!("Register payout node for squeakroad user {}", user.email())
+}
+
+#[post("/change", data = "<payout_node_form>")]
+async fn update(
+    payout_node_form: Form<PayoutNodeInput>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+) -> Flash<Redirect> {
+    let payout_node_info = payout_node_form.into_inner();
+
+    match change_payout_node(
+        active_user.user,
+        payout_node_info,
+        &mut db,
+        lightning_backend.inner().as_ref(),
+    )
+    .await
+    {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_payout_node", index())),
+            "Payout node successfully updated.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_payout_node", index())), e),
+    }
+}
+
+async fn change_payout_node(
+    user: User,
+    payout_node_info: PayoutNodeInput,
+    db: &mut Connection<Db>,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<(), String> {
+    let signature = payout_node_info.signature.trim();
+    if signature.is_empty() {
+        return Err("Signature cannot be empty.".to_string());
+    }
+    let message = payout_node_message(&user);
+    let verify_message_resp = lightning_backend
+        .verify_message(message.as_bytes(), signature)
+        .await?;
+    if !verify_message_resp.valid {
+        return Err("Invalid signature.".to_string());
+    }
+    UserSettings::set_payout_node_pubkey(db, user.id(), &verify_message_resp.pubkey)
+        .await
+        .map_err(|_| "failed to update payout node pubkey.")?;
+    Ok(())
+}
+
+#[post("/remove")]
+async fn remove(
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+) -> Flash<Redirect> {
+    match UserSettings::set_payout_node_pubkey(&mut db, active_user.user.id(), "").await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_payout_node", index())),
+            "Payout node successfully removed.",
+        ),
+        Err(_) => Flash::error(
+            Redirect::to(uri!("/update_payout_node", index())),
+            "failed to remove payout node pubkey.",
+        ),
+    }
+}
+
+#[get("/")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    db: Connection<Db>,
+    active_user: ActiveUser,
+    admin_user: Option<AdminUser>,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, flash, active_user.user, admin_user)
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("updatepayoutnode", context))
+}
+
+pub fn update_payout_node_stage() -> AdHoc {
+    AdHoc::on_ignite("Update Payout Node Stage", |rocket| async {
+        rocket.mount("/update_payout_node", routes![index, update, remove])
+    })
+}
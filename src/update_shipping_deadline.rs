@@ -0,0 +1,109 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::models::{AdminSettings, ShippingDeadlineInput};
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+
+const MIN_SHIPPING_DEADLINE_DAYS: u32 = 1;
+const MAX_SHIPPING_DEADLINE_DAYS: u32 = 90;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    admin_settings: AdminSettings,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let admin_settings = AdminSettings::single(&mut db)
+            .await
+            .map_err(|_| "failed to get admin settings.")?;
+        Ok(Context {
+            base_context,
+            flash,
+            admin_settings,
+        })
+    }
+}
+
+#[post("/change", data = "<shipping_deadline_form>")]
+async fn update(
+    shipping_deadline_form: Form<ShippingDeadlineInput>,
+    mut db: Connection<Db>,
+    _user: User,
+    _admin_user: AdminUser,
+) -> Flash<Redirect> {
+    let shipping_deadline_input = shipping_deadline_form.into_inner();
+
+    match change_shipping_deadline(shipping_deadline_input, &mut db).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_shipping_deadline", index())),
+            "Shipping deadline successfully updated.",
+        ),
+        Err(e) => Flash::error(Redirect::to(uri!("/update_shipping_deadline", index())), e),
+    }
+}
+
+async fn change_shipping_deadline(
+    shipping_deadline_input: ShippingDeadlineInput,
+    db: &mut Connection<Db>,
+) -> Result<(), String> {
+    let new_order_shipping_deadline_days = shipping_deadline_input
+        .order_shipping_deadline_days
+        .unwrap_or(0);
+
+    if new_order_shipping_deadline_days < MIN_SHIPPING_DEADLINE_DAYS {
+        return Err(format!(
+            "Shipping deadline cannot be less than {} days.",
+            MIN_SHIPPING_DEADLINE_DAYS
+        ));
+    };
+    if new_order_shipping_deadline_days > MAX_SHIPPING_DEADLINE_DAYS {
+        return Err(format!(
+            "Shipping deadline cannot be more than {} days.",
+            MAX_SHIPPING_DEADLINE_DAYS
+        ));
+    };
+
+    AdminSettings::set_shipping_deadline(db, new_order_shipping_deadline_days)
+        .await
+        .map_err(|_| "failed to update shipping deadline.")?;
+
+    Ok(())
+}
+
+#[get("/")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    db: Connection<Db>,
+    user: User,
+    admin_user: AdminUser,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, flash, user, Some(admin_user))
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("updateshippingdeadline", context))
+}
+
+pub fn update_shipping_deadline_stage() -> AdHoc {
+    AdHoc::on_ignite("Update Shipping Deadline Stage", |rocket| async {
+        rocket.mount("/update_shipping_deadline", routes![index, update])
+    })
+}
@@ -0,0 +1,111 @@
+use crate::base::BaseContext;
+use crate::db::Db;
+use crate::models::{AdminSettings, WithdrawalFeeLimitInput};
+use rocket::fairing::AdHoc;
+use rocket::form::Form;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::Serialize;
+use rocket_auth::{AdminUser, User};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Context {
+    base_context: BaseContext,
+    flash: Option<(String, String)>,
+    admin_settings: AdminSettings,
+}
+
+impl Context {
+    pub async fn raw(
+        mut db: Connection<Db>,
+        flash: Option<(String, String)>,
+        user: User,
+        admin_user: Option<AdminUser>,
+    ) -> Result<Context, String> {
+        let base_context = BaseContext::raw(&mut db, Some(user.clone()), admin_user.clone())
+            .await
+            .map_err(|_| "failed to get base template.")?;
+        let admin_settings = AdminSettings::single(&mut db)
+            .await
+            .map_err(|_| "failed to get admin settings.")?;
+        Ok(Context {
+            base_context,
+            flash,
+            admin_settings,
+        })
+    }
+}
+
+#[post("/change", data = "<withdrawal_fee_limit_form>")]
+async fn update(
+    withdrawal_fee_limit_form: Form<WithdrawalFeeLimitInput>,
+    mut db: Connection<Db>,
+    _user: User,
+    _admin_user: AdminUser,
+) -> Flash<Redirect> {
+    let withdrawal_fee_limit_input = withdrawal_fee_limit_form.into_inner();
+
+    match change_withdrawal_fee_limit(withdrawal_fee_limit_input, &mut db).await {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!("/update_withdrawal_fee_limit", index())),
+            "Withdrawal fee limit successfully updated.",
+        ),
+        Err(e) => Flash::error(
+            Redirect::to(uri!("/update_withdrawal_fee_limit", index())),
+            e,
+        ),
+    }
+}
+
+async fn change_withdrawal_fee_limit(
+    withdrawal_fee_limit_input: WithdrawalFeeLimitInput,
+    db: &mut Connection<Db>,
+) -> Result<(), String> {
+    let new_max_withdrawal_fee_sat = withdrawal_fee_limit_input
+        .max_withdrawal_fee_sat
+        .unwrap_or(0);
+    let new_max_withdrawal_fee_basis_points = withdrawal_fee_limit_input
+        .max_withdrawal_fee_basis_points
+        .unwrap_or(0);
+
+    if new_max_withdrawal_fee_basis_points < 0 {
+        return Err("Fee limit cannot be negative.".to_string());
+    };
+    if new_max_withdrawal_fee_basis_points > 10000 {
+        return Err("Fee limit basis points cannot be > 10000.".to_string());
+    };
+
+    AdminSettings::set_withdrawal_fee_limit(
+        db,
+        new_max_withdrawal_fee_sat,
+        new_max_withdrawal_fee_basis_points,
+        withdrawal_fee_limit_input.withdrawal_fee_paid_by_user,
+    )
+    .await
+    .map_err(|_| "failed to update withdrawal fee limit.")?;
+
+    Ok(())
+}
+
+#[get("/")]
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    db: Connection<Db>,
+    user: User,
+    admin_user: AdminUser,
+) -> Result<Template, String> {
+    let flash = flash.map(FlashMessage::into_inner);
+    let context = Context::raw(db, flash, user, Some(admin_user))
+        .await
+        .map_err(|_| "failed to get template context.")?;
+    Ok(Template::render("updatewithdrawalfeelimit", context))
+}
+
+pub fn update_withdrawal_fee_limit_stage() -> AdHoc {
+    AdHoc::on_ignite("Update Withdrawal Fee Limit Stage", |rocket| async {
+        rocket.mount("/update_withdrawal_fee_limit", routes![index, update])
+    })
+}
@@ -21,6 +21,7 @@ struct Context {
     visited_user: RocketAuthUser,
     visited_user_account: UserAccount,
     weighted_average_rating: f32,
+    num_shipping_strikes: u32,
     listing_cards: Vec<ListingCardDisplay>,
     admin_user: Option<AdminUser>,
     page_num: u32,
@@ -57,12 +58,14 @@ impl Context {
             .await
             .map_err(|_| "failed to get weighted average rating for user.")?;
         let weighted_average_rating = seller_info.weighted_average_rating;
+        let num_shipping_strikes = seller_info.num_shipping_strikes;
         Ok(Context {
             base_context,
             flash,
             visited_user,
             visited_user_account,
             weighted_average_rating,
+            num_shipping_strikes,
             listing_cards,
             admin_user,
             page_num,
@@ -128,9 +131,7 @@ async fn enable_user(db: &mut Connection<Db>, username: &str) -> Result<(), Stri
     let rocket_auth_user = RocketAuthUser::single_by_username(db, username.to_string())
         .await
         .map_err(|_| "failed to get user")?;
-    UserAccount::mark_as_enabled(db, rocket_auth_user.id.unwrap())
-        .await
-        .map_err(|_| "failed to enable user account.")?;
+    UserAccount::mark_as_enabled(db, rocket_auth_user.id.unwrap()).await?;
     Ok(())
 }
 
@@ -1,5 +1,9 @@
+use bech32::FromBase32;
+use bech32::ToBase32;
 use qr_code::QrCode;
+use rand::RngCore;
 use rocket::serde::uuid::Uuid;
+use sha2::{Digest, Sha256};
 use std::io::Cursor;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
@@ -34,3 +38,70 @@ pub fn from_hex(hex_str: &str) -> Vec<u8> {
 pub fn to_base64(bytes: &Vec<u8>) -> String {
     base64::encode(bytes)
 }
+
+pub fn create_preimage() -> Vec<u8> {
+    let mut preimage = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut preimage);
+    preimage
+}
+
+pub fn sha256(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+/// Encodes a URL as an LNURL string: bech32 with the "lnurl" prefix,
+/// uppercased so that it fits in a smaller QR code.
+pub fn encode_lnurl(url: &str) -> String {
+    bech32::encode("lnurl", url.as_bytes().to_base32(), bech32::Variant::Bech32)
+        .unwrap()
+        .to_uppercase()
+}
+
+/// Decodes an LNURL string back into its URL.
+pub fn decode_lnurl(lnurl: &str) -> Result<String, String> {
+    let (hrp, data, _variant) =
+        bech32::decode(&lnurl.to_lowercase()).map_err(|_| "Invalid LNURL.")?;
+    if hrp != "lnurl" {
+        return Err("Invalid LNURL.".to_string());
+    }
+    let url_bytes = Vec::<u8>::from_base32(&data).map_err(|_| "Invalid LNURL.")?;
+    String::from_utf8(url_bytes).map_err(|_| "Invalid LNURL.".to_string())
+}
+
+/// Converts a duration to whole minutes, rounding up, so that a time left
+/// only reaches zero when it has really run out.
+// u64::div_ceil is not available in the Rust version used by the Dockerfile.
+#[allow(clippy::manual_div_ceil)]
+pub fn millis_to_minutes_rounded_up(millis: u64) -> u64 {
+    (millis + 59_999) / 60_000
+}
+
+/// Converts a fiat amount to sats, rounding to the nearest sat.
+pub fn fiat_cents_to_sat(fiat_cents: u64, fiat_cents_per_btc: u64) -> u64 {
+    (fiat_cents * 100_000_000 + fiat_cents_per_btc / 2) / fiat_cents_per_btc
+}
+
+/// Parses a fiat amount with at most two decimal places, such as "19.99".
+pub fn parse_fiat_cents(amount: &str) -> Result<u64, String> {
+    let (units, cents) = match amount.trim().split_once('.') {
+        Some((units, cents)) => (units, cents),
+        None => (amount.trim(), ""),
+    };
+    if units.is_empty()
+        || cents.len() > 2
+        || !units
+            .chars()
+            .chain(cents.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err("Invalid fiat amount.".to_string());
+    }
+    let units: u64 = units.parse().map_err(|_| "Invalid fiat amount.")?;
+    let cents: u64 = format!("{:0<2}", cents)
+        .parse()
+        .map_err(|_| "Invalid fiat amount.")?;
+    units
+        .checked_mul(100)
+        .and_then(|units_cents| units_cents.checked_add(cents))
+        .ok_or_else(|| "Invalid fiat amount.".to_string())
+}
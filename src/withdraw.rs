@@ -1,10 +1,13 @@
 use crate::base::BaseContext;
-use crate::config::Config;
 use crate::db::Db;
-use crate::lightning;
-use crate::models::{AccountInfo, Withdrawal, WithdrawalInfo};
+use crate::lightning::LightningBackend;
+use crate::models::{
+    AccountInfo, AdminSettings, KeysendPayoutInfo, UserSettings, Withdrawal, WithdrawalInfo,
+    WithdrawalStatus, MIN_WITHDRAWAL_FEE_LIMIT_SAT,
+};
 use crate::user_account::ActiveUser;
 use crate::util;
+use crate::withdrawal_processor;
 use rocket::fairing::AdHoc;
 use rocket::form::Form;
 use rocket::request::FlashMessage;
@@ -16,8 +19,10 @@ use rocket_auth::AdminUser;
 use rocket_auth::User;
 use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
+use sqlx::SqliteConnection;
+use std::sync::Arc;
 
-const MAX_WITHDRAWALS_PER_USER_PER_DAY: u32 = 5;
+pub const MAX_WITHDRAWALS_PER_USER_PER_DAY: u32 = 5;
 
 #[derive(Debug, Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -25,6 +30,10 @@ struct Context {
     base_context: BaseContext,
     flash: Option<(String, String)>,
     account_balance_sat: i64,
+    max_withdrawable_sat: u64,
+    min_withdrawal_fee_limit_sat: u64,
+    admin_settings: AdminSettings,
+    user_settings: UserSettings,
 }
 
 impl Context {
@@ -41,10 +50,21 @@ impl Context {
             .await
             .map_err(|_| "failed to get account info.")?;
         let account_balance_sat = account_info.account_balance_sat;
+        let max_withdrawable_sat = max_withdrawable_sat(&mut db, user.id).await?;
+        let admin_settings = AdminSettings::single(&mut db)
+            .await
+            .map_err(|_| "failed to get admin settings.")?;
+        let user_settings = UserSettings::single(&mut db, user.id())
+            .await
+            .map_err(|_| "failed to get user settings.")?;
         Ok(Context {
             base_context,
             flash,
             account_balance_sat,
+            max_withdrawable_sat,
+            min_withdrawal_fee_limit_sat: MIN_WITHDRAWAL_FEE_LIMIT_SAT,
+            admin_settings,
+            user_settings,
         })
     }
 }
@@ -55,20 +75,20 @@ async fn new(
     mut db: Connection<Db>,
     active_user: ActiveUser,
     _admin_user: Option<AdminUser>,
-    config: &State<Config>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
 ) -> Result<Flash<Redirect>, Flash<Redirect>> {
     let withdrawal_info = withdrawal_form.into_inner();
     match withdraw(
         withdrawal_info.clone(),
         &mut db,
         active_user.user.clone(),
-        config.inner().clone(),
+        lightning_backend.inner().as_ref(),
     )
     .await
     {
-        Ok(_) => Ok(Flash::success(
-            Redirect::to("/my_account_balance"),
-            "Funds successfully withdrawn.",
+        Ok(withdrawal_public_id) => Ok(Flash::success(
+            Redirect::to(format!("/{}/{}", "withdrawal", withdrawal_public_id)),
+            "Withdrawal payment sent.",
         )),
         Err(e) => {
             error_!("Withdrawal error: {}", e);
@@ -77,15 +97,46 @@ async fn new(
     }
 }
 
+#[post("/keysend", data = "<keysend_payout_form>")]
+async fn keysend(
+    keysend_payout_form: Form<KeysendPayoutInfo>,
+    mut db: Connection<Db>,
+    active_user: ActiveUser,
+    _admin_user: Option<AdminUser>,
+    lightning_backend: &State<Arc<dyn LightningBackend>>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let keysend_payout_info = keysend_payout_form.into_inner();
+    if active_user.user.is_admin {
+        return Err(Flash::error(
+            Redirect::to(uri!("/withdraw", index())),
+            "Admin user cannot withdraw funds.",
+        ));
+    }
+    match send_keysend_withdrawal(
+        keysend_payout_info.amount_sat,
+        &mut db,
+        active_user.user.id(),
+        lightning_backend.inner().as_ref(),
+    )
+    .await
+    {
+        Ok(withdrawal_public_id) => Ok(Flash::success(
+            Redirect::to(format!("/{}/{}", "withdrawal", withdrawal_public_id)),
+            "Keysend payment sent.",
+        )),
+        Err(e) => {
+            error_!("Keysend withdrawal error: {}", e);
+            Err(Flash::error(Redirect::to(uri!("/withdraw", index())), e))
+        }
+    }
+}
+
 async fn withdraw(
     withdrawal_info: WithdrawalInfo,
-    db: &mut Connection<Db>,
+    db: &mut SqliteConnection,
     user: User,
-    config: Config,
-) -> Result<(), String> {
-    let now = util::current_time_millis();
-    let one_day_in_ms = 24 * 60 * 60 * 1000;
-
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
     if withdrawal_info.invoice_payment_request.is_empty() {
         return Err("Invoice payment request cannot be empty.".to_string());
     };
@@ -93,76 +144,265 @@ async fn withdraw(
         return Err("Admin user cannot withdraw funds.".to_string());
     }
 
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
+    send_withdrawal(
+        &withdrawal_info.invoice_payment_request,
+        withdrawal_info.amount_sat,
+        db,
+        user.id(),
+        lightning_backend,
     )
     .await
-    .expect("failed to get lightning client");
-    let decoded_pay_req = lightning_client
-        .decode_pay_req(tonic_openssl_lnd::lnrpc::PayReqString {
-            pay_req: withdrawal_info.invoice_payment_request.clone(),
-        })
+}
+
+/// Records a withdrawal of the user's funds to the given invoice, and starts
+/// paying it. Returns the public id of the new withdrawal.
+///
+/// The amount is only needed for an invoice without an amount. If it is not
+/// given, the whole account balance is withdrawn.
+pub async fn send_withdrawal(
+    invoice_payment_request: &str,
+    maybe_amount_sat: Option<u64>,
+    db: &mut SqliteConnection,
+    user_id: i32,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
+    let now = util::current_time_millis();
+
+    let decoded_pay_req = lightning_backend
+        .decode_pay_req(invoice_payment_request)
+        .await?;
+    let invoice_amount_sat: u64 = decoded_pay_req.num_satoshis.try_into().unwrap();
+    let amount_sat = match (invoice_amount_sat, maybe_amount_sat) {
+        (0, Some(amount_sat)) => amount_sat,
+        (0, None) => max_withdrawable_sat(db, user_id).await?,
+        (invoice_amount_sat, None) => invoice_amount_sat,
+        (invoice_amount_sat, Some(amount_sat)) => {
+            if amount_sat != invoice_amount_sat {
+                return Err("Amount does not match the invoice amount.".to_string());
+            }
+            amount_sat
+        }
+    };
+    if amount_sat == 0 {
+        return Err("Withdrawal amount must be greater than zero.".to_string());
+    }
+    // Only an invoice without an amount can be paid with a chosen amount.
+    let maybe_send_amount_sat = if invoice_amount_sat == 0 {
+        Some(amount_sat)
+    } else {
+        None
+    };
+    let admin_settings = AdminSettings::single(db)
         .await
-        .map_err(|_| "failed to decode payment request string.")?
-        .into_inner();
-    let amount_sat: u64 = decoded_pay_req.num_satoshis.try_into().unwrap();
-    let invoice_payment_request = withdrawal_info.invoice_payment_request;
+        .map_err(|_| "failed to get admin settings.")?;
+    let fee_limit_sat = admin_settings.withdrawal_fee_limit_sat(amount_sat);
     let withdrawal = Withdrawal {
         id: None,
         public_id: util::create_uuid(),
-        user_id: user.id(),
+        user_id,
         amount_sat,
-        invoice_hash: "".to_string(),
-        invoice_payment_request: invoice_payment_request.clone(),
+        invoice_hash: decoded_pay_req.payment_hash,
+        invoice_payment_request: invoice_payment_request.to_string(),
         created_time_ms: now,
+        status: WithdrawalStatus::Pending,
+        failure_reason: "".to_string(),
+        fee_limit_sat,
+        fee_sat: 0,
+        fee_paid_by_user: admin_settings.withdrawal_fee_paid_by_user,
+        from_market_revenue: false,
+        keysend_pubkey: "".to_string(),
+    };
+
+    record_and_send_withdrawal(withdrawal, maybe_send_amount_sat, db, lightning_backend).await
+}
+
+/// Records a pending withdrawal, and starts paying its invoice. Returns the
+/// public id of the new withdrawal.
+pub async fn record_and_send_withdrawal(
+    withdrawal: Withdrawal,
+    maybe_send_amount_sat: Option<u64>,
+    db: &mut SqliteConnection,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
+    // A payment found later for this hash must belong to this withdrawal,
+    // so an invoice that the node already paid or is paying cannot be
+    // reused.
+    let maybe_payment = lightning_backend
+        .track_payment(util::from_hex(&withdrawal.invoice_hash))
+        .await?;
+    if !withdrawal_processor::is_unpaid(&maybe_payment) {
+        return Err("Invoice was already used for a payment.".to_string());
+    }
+
+    // Market revenue can only be withdrawn from funds that the node really
+    // has, so the channel balance is checked along with the revenue.
+    let maybe_channel_balance_sat = if withdrawal.from_market_revenue {
+        let channel_balance_resp = lightning_backend.channel_balance().await?;
+        Some(
+            channel_balance_resp
+                .local_balance
+                .map(|local_balance| local_balance.sat)
+                .unwrap_or(0),
+        )
+    } else {
+        None
     };
-    let send_withdrawal_funds_ret = send_withdrawal_funds(invoice_payment_request, config);
-    Withdrawal::do_withdrawal(
+    let withdrawal_id =
+        record_pending_withdrawal(&withdrawal, maybe_channel_balance_sat, db).await?;
+    let send_result = lightning_backend
+        .send_payment(
+            &withdrawal.invoice_payment_request,
+            maybe_send_amount_sat,
+            withdrawal.fee_limit_sat,
+        )
+        .await;
+    handle_send_result(
         withdrawal,
+        withdrawal_id,
+        send_result,
+        db,
+        lightning_backend,
+    )
+    .await
+}
+
+/// Records a withdrawal of the user's funds to their registered payout node,
+/// and starts a keysend payment to it. Returns the public id of the new
+/// withdrawal.
+///
+/// If the amount is not given, the whole account balance is withdrawn.
+pub async fn send_keysend_withdrawal(
+    maybe_amount_sat: Option<u64>,
+    db: &mut SqliteConnection,
+    user_id: i32,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
+    let user_settings = UserSettings::single(db, user_id)
+        .await
+        .map_err(|_| "failed to get user settings.")?;
+    if user_settings.payout_node_pubkey.is_empty() {
+        return Err("No payout node registered.".to_string());
+    }
+    let amount_sat = match maybe_amount_sat {
+        Some(amount_sat) => amount_sat,
+        None => max_withdrawable_sat(db, user_id).await?,
+    };
+    if amount_sat == 0 {
+        return Err("Withdrawal amount must be greater than zero.".to_string());
+    }
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    let preimage = util::create_preimage();
+    let withdrawal = Withdrawal {
+        id: None,
+        public_id: util::create_uuid(),
+        user_id,
+        amount_sat,
+        invoice_hash: util::to_hex(&util::sha256(&preimage)),
+        invoice_payment_request: "".to_string(),
+        created_time_ms: util::current_time_millis(),
+        status: WithdrawalStatus::Pending,
+        failure_reason: "".to_string(),
+        fee_limit_sat: admin_settings.withdrawal_fee_limit_sat(amount_sat),
+        fee_sat: 0,
+        fee_paid_by_user: admin_settings.withdrawal_fee_paid_by_user,
+        from_market_revenue: false,
+        keysend_pubkey: user_settings.payout_node_pubkey,
+    };
+
+    let withdrawal_id = record_pending_withdrawal(&withdrawal, None, db).await?;
+    let send_result = lightning_backend
+        .send_keysend_payment(
+            util::from_hex(&withdrawal.keysend_pubkey),
+            withdrawal.amount_sat,
+            preimage,
+            withdrawal.fee_limit_sat,
+        )
+        .await;
+    handle_send_result(
+        withdrawal,
+        withdrawal_id,
+        send_result,
+        db,
+        lightning_backend,
+    )
+    .await
+}
+
+/// Records the withdrawal before sending, so that the funds stay debited
+/// whatever happens to the payment.
+async fn record_pending_withdrawal(
+    withdrawal: &Withdrawal,
+    maybe_channel_balance_sat: Option<u64>,
+    db: &mut SqliteConnection,
+) -> Result<i32, String> {
+    let now = util::current_time_millis();
+    let one_day_in_ms = 24 * 60 * 60 * 1000;
+    Withdrawal::insert_pending(
+        withdrawal.clone(),
         db,
-        send_withdrawal_funds_ret,
         MAX_WITHDRAWALS_PER_USER_PER_DAY,
         now - one_day_in_ms,
+        maybe_channel_balance_sat,
     )
     .await
     .map_err(|e| {
         error_!("Failed withdrawal: {}", e);
         e
-    })?;
+    })
+}
+
+async fn handle_send_result(
+    withdrawal: Withdrawal,
+    withdrawal_id: i32,
+    send_result: Result<(), String>,
+    db: &mut SqliteConnection,
+    lightning_backend: &dyn LightningBackend,
+) -> Result<String, String> {
+    match send_result {
+        Ok(_) => {
+            Withdrawal::mark_as_in_flight(&mut *db, withdrawal_id)
+                .await
+                .map_err(|_| "failed to mark withdrawal as in flight.")?;
+        }
+        Err(e) => {
+            error_!("Failed to send withdrawal payment: {}", e);
+            // Only return the funds if the node has no payment in flight or
+            // succeeded. Otherwise the withdrawal processor finds out how it
+            // ended.
+            let is_unpaid = matches!(
+                withdrawal_processor::find_withdrawal_payment(lightning_backend, &withdrawal).await,
+                Ok(ref maybe_payment) if withdrawal_processor::is_unpaid(maybe_payment)
+            );
+            if is_unpaid {
+                Withdrawal::mark_as_failed(&mut *db, withdrawal_id, &e)
+                    .await
+                    .map_err(|_| "failed to mark withdrawal as failed.")?;
+                return Err(format!("failed to send withdrawal payment: {}", e));
+            }
+        }
+    }
 
-    Ok(())
+    Ok(withdrawal.public_id)
 }
 
-async fn send_withdrawal_funds(
-    invoice_payment_request: String,
-    config: Config,
-) -> Result<tonic_openssl_lnd::lnrpc::SendResponse, String> {
-    let mut lightning_client = lightning::get_lnd_lightning_client(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.lnd_tls_cert_path.clone(),
-        config.lnd_macaroon_path.clone(),
-    )
-    .await
-    .expect("failed to get lightning client");
-    let send_response = lightning_client
-        .send_payment_sync(tonic_openssl_lnd::lnrpc::SendRequest {
-            payment_request: invoice_payment_request,
-            ..Default::default()
-        })
+/// Returns the largest amount the user can withdraw, leaving room for the
+/// routing fee if the user pays it.
+pub async fn max_withdrawable_sat(db: &mut SqliteConnection, user_id: i32) -> Result<u64, String> {
+    let account_balance_sat = AccountInfo::total_account_balance_for_user(db, user_id)
         .await
-        .map_err(|e| format!("failed to send payment: {:?}", e))?
-        .into_inner();
-    if send_response.payment_preimage.is_empty() {
-        return Err(format!(
-            "Send Payment failure: {:?}.",
-            send_response.payment_error
-        ));
+        .map_err(|_| "failed to get account balance.")?;
+    let account_balance_sat: u64 = account_balance_sat.max(0).try_into().unwrap();
+    let admin_settings = AdminSettings::single(db)
+        .await
+        .map_err(|_| "failed to get admin settings.")?;
+    if admin_settings.withdrawal_fee_paid_by_user {
+        Ok(account_balance_sat
+            .saturating_sub(admin_settings.withdrawal_fee_limit_sat(account_balance_sat)))
+    } else {
+        Ok(account_balance_sat)
     }
-    Ok(send_response)
 }
 
 #[get("/")]
@@ -181,6 +421,6 @@ async fn index(
 
 pub fn withdraw_stage() -> AdHoc {
     AdHoc::on_ignite("Withdraw Stage", |rocket| async {
-        rocket.mount("/withdraw", routes![index, new])
+        rocket.mount("/withdraw", routes![index, new, keysend])
     })
 }
@@ -0,0 +1,100 @@
+use crate::lightning::LightningBackend;
+use crate::models::{Withdrawal, WithdrawalStatus};
+use crate::util;
+use sqlx::pool::PoolConnection;
+use sqlx::Sqlite;
+use std::sync::Arc;
+use tonic_openssl_lnd::lnrpc::payment::PaymentStatus;
+use tonic_openssl_lnd::lnrpc::PaymentFailureReason;
+
+/// How long a pending withdrawal can go without a payment on the node
+/// before it is marked as failed.
+const UNSENT_WITHDRAWAL_TIMEOUT_MS: u64 = 600000;
+
+/// Updates every pending and in-flight withdrawal with the state of its
+/// payment on the node.
+pub async fn track_withdrawal_payments(
+    lightning_backend: Arc<dyn LightningBackend>,
+    mut conn: PoolConnection<Sqlite>,
+) -> Result<(), String> {
+    let unresolved_withdrawals = Withdrawal::all_unresolved(&mut conn)
+        .await
+        .map_err(|_| "failed to get unresolved withdrawals.")?;
+
+    for withdrawal in unresolved_withdrawals {
+        track_withdrawal_payment(&mut conn, &*lightning_backend, &withdrawal).await?;
+    }
+    Ok(())
+}
+
+async fn track_withdrawal_payment(
+    conn: &mut PoolConnection<Sqlite>,
+    lightning_backend: &dyn LightningBackend,
+    withdrawal: &Withdrawal,
+) -> Result<(), String> {
+    let now = util::current_time_millis();
+    let withdrawal_id = withdrawal.id.unwrap();
+
+    match find_withdrawal_payment(lightning_backend, withdrawal).await? {
+        Some(payment) if payment.status == PaymentStatus::Succeeded as i32 => {
+            println!("Withdrawal payment succeeded: {:?}", withdrawal.public_id);
+            // Round up, so that a fee paid by the user is never undercounted.
+            let fee_sat = ((payment.fee_msat + 999) / 1000).try_into().unwrap();
+            Withdrawal::mark_as_succeeded(conn, withdrawal_id, fee_sat)
+                .await
+                .map_err(|_| "failed to mark withdrawal as succeeded.")?;
+        }
+        Some(payment) if payment.status == PaymentStatus::Failed as i32 => {
+            println!("Withdrawal payment failed: {:?}", withdrawal.public_id);
+            let failure_reason = match PaymentFailureReason::from_i32(payment.failure_reason) {
+                Some(reason) => format!("{:?}", reason),
+                None => "Unknown failure reason".to_string(),
+            };
+            Withdrawal::mark_as_failed(conn, withdrawal_id, &failure_reason)
+                .await
+                .map_err(|_| "failed to mark withdrawal as failed.")?;
+        }
+        Some(_) => {
+            if withdrawal.status == WithdrawalStatus::Pending {
+                Withdrawal::mark_as_in_flight(conn, withdrawal_id)
+                    .await
+                    .map_err(|_| "failed to mark withdrawal as in flight.")?;
+            }
+        }
+        None => {
+            // The process may have stopped between recording the withdrawal
+            // and sending the payment.
+            if now > withdrawal.created_time_ms + UNSENT_WITHDRAWAL_TIMEOUT_MS {
+                println!("Withdrawal payment never sent: {:?}", withdrawal.public_id);
+                Withdrawal::mark_as_failed(conn, withdrawal_id, "Payment was never sent")
+                    .await
+                    .map_err(|_| "failed to mark withdrawal as failed.")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the payment sent for a withdrawal, if the node started one.
+///
+/// Payments are matched by payment hash only. Withdrawals to an invoice
+/// that the node already paid or is paying are rejected before they are
+/// recorded, so a payment for the hash that is not failed belongs to this
+/// withdrawal.
+pub async fn find_withdrawal_payment(
+    lightning_backend: &dyn LightningBackend,
+    withdrawal: &Withdrawal,
+) -> Result<Option<tonic_openssl_lnd::lnrpc::Payment>, String> {
+    lightning_backend
+        .track_payment(util::from_hex(&withdrawal.invoice_hash))
+        .await
+}
+
+/// Returns true if the node has no payment for the hash that is in flight
+/// or succeeded, so that the funds can be returned to the user.
+pub fn is_unpaid(maybe_payment: &Option<tonic_openssl_lnd::lnrpc::Payment>) -> bool {
+    match maybe_payment {
+        Some(payment) => payment.status == PaymentStatus::Failed as i32,
+        None => true,
+    }
+}